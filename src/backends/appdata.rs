@@ -0,0 +1,212 @@
+//! Implements backup of Nextcloud's `appdata_<instanceid>` directory using [Appdata].
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{tree_size, write_tarball, Excludes};
+use crate::util::artifact::ArtifactDir;
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const APPDATA_BACKUP_DEST: &str = "appdata/";
+const APPDATA_PREFIX: &str = "appdata-";
+const APPDATA_SUFFIX: &str = ".tar.gz";
+/// Directory of the previews within the appdata directory.
+const PREVIEW_DIR: &str = "preview";
+
+/// What the [Appdata] backend archives.
+///
+/// The choice is recorded in the [run manifest](crate::report::manifest::RunManifest),
+/// so `after-restore` knows whether previews have to be regenerated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AppdataMode {
+    /// Archive the whole appdata directory.
+    Full,
+    /// Archive the appdata directory without the previews, which are regenerated.
+    #[default]
+    WithoutPreviews,
+    /// Deliberately archive nothing, e.g. if the appdata is regenerated anyway.
+    Skip,
+}
+
+impl AppdataMode {
+    /// Whether the previews are missing from backups made in this mode.
+    pub fn lacks_previews(self) -> bool {
+        self != Self::Full
+    }
+}
+
+/// Configuration of [Appdata].
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AppdataConfig {
+    /// What to archive, `full`, `without-previews` or `skip`.
+    pub mode: AppdataMode,
+
+    /// Compression of the archive.
+    pub compression: CompressionConfig,
+}
+
+/// The [Appdata] backend archives the `appdata_<instanceid>` directory in the data directory.
+///
+/// It holds the data of the apps, e.g. previews, theming and the avatars. Most of
+/// it is regenerated by Nextcloud, so [AppdataMode] allows to leave parts out.
+#[derive(Debug)]
+pub struct Appdata {
+    appdata_backups: ArtifactDir,
+    config: AppdataConfig,
+    retry: RetryConfig,
+}
+
+/// Error of the [Appdata] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum AppdataError {
+    /// The data directory or the `instanceid` couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+    /// Archiving the appdata failed.
+    Io(io::Error),
+}
+
+impl Appdata {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            appdata_backups: ArtifactDir::new(
+                backup_root.join(APPDATA_BACKUP_DEST),
+                APPDATA_PREFIX,
+                APPDATA_SUFFIX,
+            ),
+            config: AppdataConfig::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Archive the appdata as configured by [AppdataConfig].
+    pub fn config(mut self, config: AppdataConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.appdata_backups = self.appdata_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new appdata backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.appdata_backups = self.appdata_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new appdata backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.appdata_backups = self.appdata_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old appdata backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.appdata_backups = self.appdata_backups.with_cold_tier(
+            tiering.destination.join(APPDATA_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Returns the appdata directory and the paths excluded from its archive.
+    fn appdata_dir(&self, nextcloud: &Nextcloud) -> Result<(PathBuf, Excludes), OccError> {
+        let occ = nextcloud.occ();
+        let dir = occ
+            .data_directory()?
+            .join(format!("appdata_{}", occ.instance_id()?));
+        let excludes = match self.config.mode {
+            AppdataMode::WithoutPreviews => Excludes::paths(&[dir.join(PREVIEW_DIR)]),
+            _ => Excludes::default(),
+        };
+        Ok((dir, excludes))
+    }
+}
+
+impl Backup for Appdata {
+    type Error = AppdataError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        if self.config.mode == AppdataMode::Skip {
+            tracing::info!(target: "backend::appdata", "Skipping the backup of appdata as configured");
+            return Ok(Vec::new());
+        }
+        let (appdata_dir, excludes) = self.appdata_dir(nextcloud)?;
+        tracing::info!(target: "backend::appdata", "Create backup of Nextcloud appdata: {}", appdata_dir.display());
+
+        retry_io(&self.retry, "Creating appdata backup directory", || {
+            fs::create_dir_all(self.appdata_backups.dir())
+        })?;
+        let appdata_backup_file = self.appdata_backups.generate_filename();
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::appdata", "Backup Nextcloud appdata to: {}", appdata_backup_file.display());
+        retry_io(&self.retry, "Writing appdata backup", || {
+            write_tarball(
+                &appdata_backup_file,
+                std::slice::from_ref(&appdata_dir),
+                &excludes,
+                &self.config.compression,
+                dry_run,
+                progress,
+            )
+        })?;
+        tracing::info!(target: "backend::appdata", "Finished backup of Nextcloud appdata");
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Artifact::File(appdata_backup_file)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        if self.config.mode == AppdataMode::Skip {
+            return Ok(());
+        }
+        let (appdata_dir, excludes) = self.appdata_dir(nextcloud)?;
+        let appdata_size = tree_size(std::slice::from_ref(&appdata_dir), &excludes)?;
+        Ok(ensure_space(self.appdata_backups.dir(), appdata_size)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.appdata_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .appdata_backups
+            .retain(cfg, dry_run, "backend::appdata::retain")?)
+    }
+}
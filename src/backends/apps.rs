@@ -0,0 +1,166 @@
+//! Implements backup of Nextcloud's app directories using [Apps].
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{tree_size, write_tarball, Excludes};
+use crate::util::artifact::ArtifactDir;
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const APPS_BACKUP_DEST: &str = "apps/";
+const APPS_PREFIX: &str = "apps-";
+const APPS_SUFFIX: &str = ".tar.gz";
+
+/// The [Apps] backend archives the `apps/` directory and all `apps_paths` of Nextcloud.
+///
+/// This preserves custom and manually installed apps which can't be
+/// restored from the Nextcloud release.
+#[derive(Debug)]
+pub struct Apps {
+    apps_backups: ArtifactDir,
+    retry: RetryConfig,
+}
+
+/// Error of the [Apps] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum AppsError {
+    /// The app directories couldn't be determined.
+    #[display("Reading apps_paths failed: {_0}")]
+    Occ(OccError),
+    /// Archiving the app directories failed.
+    Io(io::Error),
+}
+
+impl Apps {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            apps_backups: ArtifactDir::new(
+                backup_root.join(APPS_BACKUP_DEST),
+                APPS_PREFIX,
+                APPS_SUFFIX,
+            ),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.apps_backups = self.apps_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new app backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.apps_backups = self.apps_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new app backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.apps_backups = self.apps_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old app backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.apps_backups = self.apps_backups.with_cold_tier(
+            tiering.destination.join(APPS_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+}
+
+/// Returns the `apps/` directory and all `apps_paths` of the `nextcloud` instance.
+fn app_dirs(nextcloud: &Nextcloud) -> Result<Vec<PathBuf>, OccError> {
+    let mut dirs = BTreeSet::from([nextcloud.document_root().join("apps")]);
+    match nextcloud.occ().apps_paths() {
+        Ok(apps_paths) => dirs.extend(apps_paths),
+        Err(OccError::OccCommandFailed { .. }) => {
+            tracing::debug!(target: "backend::apps", "No apps_paths configured");
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(dirs.into_iter().filter(|dir| dir.is_dir()).collect())
+}
+
+impl Backup for Apps {
+    type Error = AppsError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let app_dirs = app_dirs(nextcloud)?;
+        tracing::info!(target: "backend::apps", "Create backup of Nextcloud apps: {app_dirs:?}");
+
+        retry_io(&self.retry, "Creating apps backup directory", || {
+            std::fs::create_dir_all(self.apps_backups.dir())
+        })?;
+        let apps_backup_file = self.apps_backups.generate_filename();
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::apps", "Backup Nextcloud apps to: {}", apps_backup_file.display());
+        retry_io(&self.retry, "Writing apps backup", || {
+            write_tarball(
+                &apps_backup_file,
+                &app_dirs,
+                &Excludes::default(),
+                &CompressionConfig::default(),
+                dry_run,
+                progress,
+            )
+        })?;
+        tracing::info!(target: "backend::apps", "Finished backup of Nextcloud apps");
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Artifact::File(apps_backup_file)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        let apps_size = tree_size(&app_dirs(nextcloud)?, &Excludes::default())?;
+        Ok(ensure_space(self.apps_backups.dir(), apps_size)?)
+    }
+
+    /// Apps are only changed by updates, which are run after the backup.
+    fn requires_maintenance(&self) -> bool {
+        false
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.apps_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .apps_backups
+            .retain(cfg, dry_run, "backend::apps::retain")?)
+    }
+}
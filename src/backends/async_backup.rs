@@ -0,0 +1,196 @@
+//! Async variant of the [Backup] trait for embedding the backends in async services.
+//!
+//! Requires the `tokio` feature. Every [Backup] can be used as [AsyncBackup] by
+//! wrapping it in [Blocking], which runs it on tokio's blocking thread pool.
+//! Backends spending most of their time waiting on the network can implement
+//! [AsyncBackup] directly instead of occupying a thread each.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use std::sync::Arc;
+//! # use nc_backup_lib::backends::{AsyncBackup, Blocking, Config};
+//! # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
+//! # use nc_backup_lib::util::progress::NoProgress;
+//! # async fn run() {
+//! let nextcloud = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
+//! let config = Blocking::new(Config::new(Path::new("/nextcloud/backup")));
+//! let artifacts = config
+//!     .backup(&nextcloud, false, Arc::new(NoProgress))
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+
+use std::future::Future;
+use std::sync::Arc;
+
+use super::{Artifact, Backup, BackupEntry};
+use crate::nextcloud::Nextcloud;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+
+/// Async variant of [Backup].
+///
+/// The methods behave as their counterparts of [Backup]. The [Progress] is
+/// shared as the backup may continue on another thread.
+pub trait AsyncBackup: Send + Sync {
+    /// Error that may happen on backup.
+    type Error;
+
+    /// See [Backup::backup].
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: Arc<dyn Progress>,
+    ) -> impl Future<Output = Result<Vec<Artifact>, Self::Error>> + Send;
+
+    /// See [Backup::finalize].
+    fn finalize(
+        &self,
+        _nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        _progress: Arc<dyn Progress>,
+    ) -> impl Future<Output = Result<Vec<Artifact>, Self::Error>> + Send {
+        async { Ok(artifacts) }
+    }
+
+    /// See [Backup::retention].
+    fn retention(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// See [Backup::list].
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        _cfg: &RetentionConfig,
+    ) -> impl Future<Output = Result<Vec<BackupEntry>, Self::Error>> + Send {
+        async { Ok(Vec::new()) }
+    }
+
+    /// See [Backup::preflight].
+    fn preflight(
+        &self,
+        _nextcloud: &Nextcloud,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// See [Backup::requires_maintenance].
+    fn requires_maintenance(&self) -> bool {
+        true
+    }
+}
+
+/// Runs a blocking [Backup] as [AsyncBackup] on tokio's blocking thread pool.
+///
+/// Has to be used within a tokio runtime.
+#[derive(Debug)]
+pub struct Blocking<B>(Arc<B>);
+
+impl<B> Blocking<B> {
+    pub fn new(backend: B) -> Self {
+        Self(Arc::new(backend))
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.0
+    }
+}
+
+impl<B> Clone for Blocking<B> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+/// Runs `f` with the backend on the blocking thread pool.
+async fn spawn_blocking<B, T, F>(backend: &Arc<B>, nextcloud: &Nextcloud, f: F) -> T
+where
+    B: Send + Sync + 'static,
+    T: Send + 'static,
+    F: FnOnce(&B, &Nextcloud) -> T + Send + 'static,
+{
+    let backend = Arc::clone(backend);
+    let nextcloud = nextcloud.clone();
+    tokio::task::spawn_blocking(move || f(&backend, &nextcloud))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+impl<B> AsyncBackup for Blocking<B>
+where
+    B: Backup + Send + Sync + 'static,
+    B::Error: Send + 'static,
+{
+    type Error = B::Error;
+
+    async fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: Arc<dyn Progress>,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        spawn_blocking(&self.0, nextcloud, move |backend, nextcloud| {
+            backend.backup(nextcloud, dry_run, progress.as_ref())
+        })
+        .await
+    }
+
+    async fn finalize(
+        &self,
+        nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        dry_run: bool,
+        progress: Arc<dyn Progress>,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        spawn_blocking(&self.0, nextcloud, move |backend, nextcloud| {
+            backend.finalize(nextcloud, artifacts, dry_run, progress.as_ref())
+        })
+        .await
+    }
+
+    async fn retention(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        let cfg = *cfg;
+        spawn_blocking(&self.0, nextcloud, move |backend, nextcloud| {
+            backend.retention(nextcloud, &cfg, dry_run)
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let cfg = *cfg;
+        spawn_blocking(&self.0, nextcloud, move |backend, nextcloud| {
+            backend.list(nextcloud, &cfg)
+        })
+        .await
+    }
+
+    async fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        spawn_blocking(&self.0, nextcloud, |backend, nextcloud| {
+            backend.preflight(nextcloud)
+        })
+        .await
+    }
+
+    fn requires_maintenance(&self) -> bool {
+        self.0.requires_maintenance()
+    }
+}
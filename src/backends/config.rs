@@ -1,105 +1,268 @@
-//! Implements backup of Nextcloud's `config.php` using [Config].
+//! Implements backup of Nextcloud's `config/` directory using [Config].
 
-use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, Write};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
-use chrono::{Local, NaiveDateTime};
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use regex::Regex;
+use chrono::TimeDelta;
 
-use crate::backends::Backup;
+use crate::backends::{apply_retention, list_artifacts, Artifact, Backup, BackupEntry};
 use crate::nextcloud::Nextcloud;
-use crate::util::retention::{Retention, RetentionConfig};
+use crate::util::archive::{tree_size, write_tarball_with, Excludes};
+use crate::util::artifact::{
+    discard_artifact, partition_retained, remove_artifact, secrets_path, write_artifact,
+    ArtifactDir,
+};
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::encrypt::Encryption;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::mask::{MaskedEntry, Masker, MaskingConfig};
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
 
 const CONFIG_BACKUP_DEST: &str = "config/";
-const CONFIG_PREFIX: &str = "config-";
-const CONFIG_TS: &str = "%Y-%m-%dT%H-%M-%S";
-const CONFIG_SUFFIX: &str = ".php.gz";
+/// Prefix of the names of the config backups.
+pub const CONFIG_PREFIX: &str = "config-";
+/// Suffix of the names of the config backups.
+pub const CONFIG_SUFFIX: &str = ".tar.gz";
+/// Suffix of the backups of `config.php` alone made by earlier versions.
+const LEGACY_CONFIG_SUFFIX: &str = ".php.gz";
+/// Flag file of the web installer, which must not be restored.
+const CAN_INSTALL: &str = "CAN_INSTALL";
 
-/// The [Config] backend allows you to backup Nextcloud's `config.php`.
-#[derive(Debug, serde::Deserialize)]
+/// The [Config] backend allows you to backup Nextcloud's `config/` directory.
+///
+/// Besides `config.php` this includes split config files like `s3.config.php`.
+#[derive(Debug)]
 pub struct Config {
-    config_backup_dest: PathBuf,
+    config_backups: ArtifactDir,
+    legacy_backups: ArtifactDir,
+    masker: Masker,
+    secrets: Option<Encryption>,
+    retry: RetryConfig,
 }
 
+/// Secrets masked in a config backup by the archived file they belong to.
+///
+/// Written [encrypted](Config::secrets) to the [secrets sidecar](secrets_path)
+/// and restored by [ConfigRestore](crate::restore::config::ConfigRestore).
+pub type MaskedSecrets = BTreeMap<String, Vec<MaskedEntry>>;
+
 impl Config {
     pub fn new(backup_root: &Path) -> Self {
         let config_backup_root = backup_root.join(CONFIG_BACKUP_DEST);
         if config_backup_root.is_relative() {
-            log::warn!(target: "backend::config", "config_backup_root is relative: {}", config_backup_root.display());
+            tracing::warn!(target: "backend::config", "config_backup_root is relative: {}", config_backup_root.display());
         }
 
         Self {
-            config_backup_dest: config_backup_root,
+            config_backups: ArtifactDir::new(
+                config_backup_root.clone(),
+                CONFIG_PREFIX,
+                CONFIG_SUFFIX,
+            ),
+            legacy_backups: ArtifactDir::new(
+                config_backup_root,
+                CONFIG_PREFIX,
+                LEGACY_CONFIG_SUFFIX,
+            ),
+            masker: Masker::new(&MaskingConfig::default()).expect("default rules should be valid"),
+            secrets: None,
+            retry: RetryConfig::default(),
         }
     }
 
-    fn generate_config_backup_filename(&self) -> PathBuf {
-        let timestamp = Local::now().format(CONFIG_TS);
+    /// Mask the secrets of the config files using `masker`.
+    ///
+    /// By default the rules of the default [MaskingConfig] are applied.
+    pub fn masking(mut self, masker: Masker) -> Self {
+        self.masker = masker;
+        self
+    }
+
+    /// Write the masked secrets to a sidecar of every backup encrypted using `encryption`.
+    ///
+    /// This allows you to restore the config unmasked, while the backup itself
+    /// can be stored anywhere.
+    pub fn secrets(mut self, encryption: Encryption) -> Self {
+        self.secrets = Some(encryption);
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.config_backups = self.config_backups.with_retry(retry.clone());
+        self.legacy_backups = self.legacy_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
 
-        let path = self
-            .config_backup_dest
-            .join(format!("{CONFIG_PREFIX}{timestamp}{CONFIG_SUFFIX}"));
-        assert!(!path.exists(), "config backup file should not exist prior");
+    /// Timestamp new config backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.config_backups = self.config_backups.with_clock(clock);
+        self.legacy_backups = self.legacy_backups.with_clock(clock);
+        self
+    }
 
-        path
+    /// Name new config backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.config_backups = self.config_backups.with_generation(generation);
+        self.legacy_backups = self.legacy_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old config backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.config_backups = self.config_backups.with_cold_tier(
+            tiering.destination.join(CONFIG_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self.legacy_backups = self.legacy_backups.with_cold_tier(
+            tiering.destination.join(CONFIG_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
     }
 }
 
+/// Writes the `config_dir` compressed to the tarball `config_backup_file` with secrets masked by `masker`.
+///
+/// Every PHP file is masked, other files are archived unchanged. The installer's
+/// `CAN_INSTALL` flag isn't archived. Returns the masked entries by archived file.
+/// If writing fails the incomplete `config_backup_file` is removed.
+fn write_masked_config(
+    config_dir: &Path,
+    config_backup_file: &Path,
+    masker: &Masker,
+    dry_run: bool,
+    progress: &dyn Progress,
+) -> io::Result<MaskedSecrets> {
+    let mut secrets = MaskedSecrets::new();
+    write_tarball_with(
+        config_backup_file,
+        &[config_dir.to_path_buf()],
+        &Excludes::paths(&[config_dir.join(CAN_INSTALL)]),
+        &CompressionConfig::default(),
+        dry_run,
+        progress,
+        &mut |path| {
+            if !masker.enabled() || path.extension().is_none_or(|ext| ext != "php") {
+                return Ok(None);
+            }
+            tracing::trace!(target: "backend::config", "Masking {}", path.display());
+            let masked = masker.mask(&fs::read_to_string(path)?);
+            if !masked.entries.is_empty() {
+                let name = path.strip_prefix("/").unwrap_or(path);
+                secrets.insert(name.to_string_lossy().into_owned(), masked.entries);
+            }
+            Ok(Some(masked.content.into_bytes()))
+        },
+    )?;
+
+    Ok(secrets)
+}
+
+/// Writes the `secrets` masked in `config_backup_file` encrypted using `encryption` to its sidecar.
+fn write_secrets(
+    config_backup_file: &Path,
+    secrets: &MaskedSecrets,
+    encryption: &Encryption,
+) -> io::Result<()> {
+    let secrets_file = secrets_path(config_backup_file, encryption.tool());
+    tracing::debug!(target: "backend::config", "Write masked secrets to: {}", secrets_file.display());
+    let plaintext = serde_json::to_vec_pretty(secrets)?;
+    write_artifact(&secrets_file, |partial| {
+        encryption.encrypt(&plaintext, partial)
+    })
+}
+
+/// Directory of `config.php` and the split config files of `nextcloud`.
+fn config_dir(nextcloud: &Nextcloud) -> PathBuf {
+    nextcloud.document_root().join("config")
+}
+
 impl Backup for Config {
     type Error = io::Error;
 
-    fn backup(&self, nextcloud: &Nextcloud, dry_run: bool) -> Result<(), Self::Error> {
-        let config_path = nextcloud.config();
-        log::info!(target: "backend::config", "Create backup of Nextcloud config: {}", config_path.display());
-
-        let config_file = File::open(config_path)?;
-        let config_reader = BufReader::new(config_file);
-
-        fs::create_dir_all(&self.config_backup_dest)?;
-        let config_backup_file = self.generate_config_backup_filename();
-        log::debug!(target: "backend::config", "Backup Nextcloud config to: {}", config_backup_file.display());
-        let mut encoder = if dry_run {
-            None
-        } else {
-            let config_backup_file = File::create_new(&config_backup_file)?;
-            let encoder = GzEncoder::new(config_backup_file, Compression::default());
-            Some(encoder)
-        };
-
-        // Mask dbpassword, since we don't need it when restoring.
-        // https://github.com/nextcloud-snap/nextcloud-snap/blob/43ef350cff3d63a40e7868c408e792b5b0023375/src/import-export/bin/export-data#L64-L66
-        let re = Regex::new(r"(dbpassword.*=>\s*).*,").unwrap();
-        let mut replaced = false;
-        for line in config_reader.lines() {
-            let line = line?;
-
-            let processed_line = if !replaced && re.is_match(&line) {
-                replaced = true;
-                log::trace!(target: "backend::config", "Masked dbpassword");
-                re.replace(&line, "$1'DBPASSWORD',").into()
-            } else {
-                line
-            };
-
-            if let Some(ref mut encoder) = encoder {
-                writeln!(encoder, "{processed_line}")?;
-            }
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let config_dir = config_dir(nextcloud);
+        tracing::info!(target: "backend::config", "Create backup of Nextcloud config: {}", config_dir.display());
+
+        retry_io(&self.retry, "Creating config backup directory", || {
+            fs::create_dir_all(self.config_backups.dir())
+        })?;
+        let config_backup_file = self.config_backups.generate_filename();
+        tracing::debug!(target: "backend::config", "Backup Nextcloud config to: {}", config_backup_file.display());
+        let secrets = retry_io(&self.retry, "Writing config backup", || {
+            write_masked_config(
+                &config_dir,
+                &config_backup_file,
+                &self.masker,
+                dry_run,
+                progress,
+            )
+        })?;
+
+        if !self.masker.enabled() {
+            tracing::debug!(target: "backend::config", "Masking of secrets is disabled");
+        } else if !secrets
+            .values()
+            .flatten()
+            .any(|entry| entry.key == "dbpassword")
+        {
+            tracing::warn!(target: "backend::config", "No dbpassword config entry found and masked!");
+            //std::fs::remove_file(config_backup_file)?;
         }
+        tracing::info!(target: "backend::config", "Finished backup of Nextcloud config");
 
-        if let Some(encoder) = encoder {
-            encoder.finish()?;
+        if dry_run {
+            return Ok(Vec::new());
         }
+        if let Some(encryption) = &self.secrets {
+            if let Err(e) = write_secrets(&config_backup_file, &secrets, encryption) {
+                let _ = remove_artifact(&config_backup_file);
+                return Err(e);
+            }
+        }
+        Ok(vec![Artifact::File(config_backup_file)])
+    }
 
-        if !replaced {
-            log::warn!(target: "backend::config", "No dbpassword config entry found and masked!");
-            //std::fs::remove_file(config_backup_file)?;
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        if let Some(encryption) = &self.secrets {
+            encryption.check()?;
         }
-        log::info!(target: "backend::config", "Finished backup of Nextcloud config");
+        let config_dir = config_dir(nextcloud);
+        let config_size = tree_size(
+            std::slice::from_ref(&config_dir),
+            &Excludes::paths(&[config_dir.join(CAN_INSTALL)]),
+        )?;
+        ensure_space(self.config_backups.dir(), config_size)
+    }
 
-        Ok(())
+    /// The config files are copied at once and don't depend on the other data.
+    fn requires_maintenance(&self) -> bool {
+        false
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let mut entries = list_artifacts(&self.config_backups, cfg)?;
+        entries.extend(list_artifacts(&self.legacy_backups, cfg)?);
+        apply_retention(&mut entries, *cfg);
+        Ok(entries)
     }
 
     fn retention(
@@ -108,44 +271,21 @@ impl Backup for Config {
         cfg: &RetentionConfig,
         dry_run: bool,
     ) -> Result<(), Self::Error> {
-        if !fs::exists(&self.config_backup_dest)? {
-            log::debug!(target: "backend::config::retain", "Backup directory doesn't exist. Nothing to retain.");
+        self.config_backups.remove_partials(dry_run)?;
+        let mut backups = self.config_backups.artifacts()?;
+        backups.extend(self.legacy_backups.artifacts()?);
+        backups.sort_by_key(|(_, date)| std::cmp::Reverse(*date));
+        if backups.is_empty() {
+            tracing::debug!(target: "backend::config::retain", "No backups found. Nothing to retain.");
             return Ok(());
         }
 
-        // collect all backups created so far and parse their creation date
-        let mut backups: Vec<_> = fs::read_dir(&self.config_backup_dest)?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let Ok(file_name) = entry.file_name().into_string() else {
-                    return None;
-                };
-                let timestamp = NaiveDateTime::parse_from_str(
-                    &file_name,
-                    format!("{CONFIG_PREFIX}{CONFIG_TS}{CONFIG_SUFFIX}").as_str(),
-                )
-                .ok()?;
-                Some((entry.path(), timestamp))
-            })
-            .collect();
-        // keep the most recent backups of each kind
-        backups.sort_by(|(_, ts_1), (_, ts_2)| ts_1.cmp(ts_2).reverse());
-
-        let mut retention = Retention::from(*cfg);
-        for (path, date) in backups {
-            if retention.retain(date) {
-                log::debug!(target: "backend::config::retain", "Backup retained: {}", path.display());
-                continue;
-            }
-
-            log::info!(target: "backend::config::retain", "Discarding backup: {}", path.display());
-            if !dry_run {
-                if let Err(e) = fs::remove_file(path) {
-                    log::error!(target: "backend::config::retain", "Unable to delete backup: {e}");
-                }
-            }
+        let (_, discarded) = partition_retained(backups, cfg, "backend::config::retain");
+        for path in discarded {
+            discard_artifact(&path, dry_run, &self.retry, "backend::config::retain");
         }
 
-        Ok(())
+        self.config_backups.tier(dry_run)?;
+        self.legacy_backups.tier(dry_run)
     }
 }
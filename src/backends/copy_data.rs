@@ -0,0 +1,276 @@
+//! Implements backup of Nextcloud's data as hard linked copies using [CopyData].
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use derive_more::{Display, Error, From};
+
+use crate::backends::{apply_retention, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{copy_tree, tree_size, Excludes};
+use crate::util::artifact::{
+    is_pinned, partial_path, partition_retained, ArtifactDir, PARTIAL_SUFFIX,
+};
+use crate::util::clock::Clock;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::glob::Glob;
+use crate::util::progress::{NoProgress, Progress};
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+
+use super::data::default_excludes;
+
+const COPY_BACKUP_DEST: &str = "copy/";
+const COPY_PREFIX: &str = "data-";
+const COPY_SUFFIX: &str = "";
+
+/// Configuration of [CopyData].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CopyDataConfig {
+    /// Patterns of the paths not to copy, relative to the data directory.
+    ///
+    /// Defaults to the [DEFAULT_EXCLUDES](super::data::DEFAULT_EXCLUDES) of the [Data](super::Data) backend.
+    pub excludes: Vec<Glob>,
+}
+
+impl Default for CopyDataConfig {
+    fn default() -> Self {
+        Self {
+            excludes: default_excludes(),
+        }
+    }
+}
+
+/// The [CopyData] backend copies the data directory into dated directories.
+///
+/// Files unchanged since the latest copy are hard linked to it instead of copied,
+/// like `cp -al` followed by `rsync`. So every copy is a complete tree browsable
+/// without any tools, while only changed files take up space. It's meant for small
+/// installations, large ones should use a snapshot backend like [Snapper](super::Snapper).
+///
+/// The copies aren't moved to a cold tier, as that would break up the hard links.
+#[derive(Debug)]
+pub struct CopyData {
+    copies: ArtifactDir,
+    config: CopyDataConfig,
+    retry: RetryConfig,
+}
+
+/// Error of the [CopyData] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum CopyDataError {
+    /// The data directory couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+    /// Copying the data directory failed.
+    Io(io::Error),
+}
+
+impl CopyData {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            copies: ArtifactDir::new(backup_root.join(COPY_BACKUP_DEST), COPY_PREFIX, COPY_SUFFIX),
+            config: CopyDataConfig::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Copy the data directory as configured by [CopyDataConfig].
+    pub fn config(mut self, config: CopyDataConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retry writing copies on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.copies = self.copies.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new copies using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.copies = self.copies.with_clock(clock);
+        self
+    }
+
+    /// Name new copies after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.copies = self.copies.with_generation(generation);
+        self
+    }
+
+    /// Returns the data directory and the paths excluded from its copy.
+    fn data_dir(&self, nextcloud: &Nextcloud) -> Result<(PathBuf, Excludes), OccError> {
+        let data_dir = nextcloud.occ().data_directory()?;
+        // don't copy the copies if they're stored in the data directory
+        let mut excludes = Excludes::paths(&[self.copies.dir().to_path_buf()]);
+        for glob in &self.config.excludes {
+            excludes = excludes.glob(&data_dir, glob.clone());
+        }
+        Ok((data_dir, excludes))
+    }
+
+    /// Remove the partial copies left over by interrupted backups.
+    fn remove_partials(&self, dry_run: bool) -> io::Result<()> {
+        if !fs::exists(self.copies.dir())? {
+            return Ok(());
+        }
+        for entry in fs::read_dir(self.copies.dir())? {
+            let entry = entry?;
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let is_partial = file_name
+                .strip_suffix(PARTIAL_SUFFIX)
+                .is_some_and(|name| self.copies.parse_timestamp(name).is_some());
+            if !is_partial || !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let path = entry.path();
+            tracing::warn!(target: "backend::copy_data", "Removing incomplete copy of interrupted backup: {}", path.display());
+            if !dry_run {
+                retry_io(&self.retry, "Removing incomplete copy", || {
+                    fs::remove_dir_all(&path)
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backup for CopyData {
+    type Error = CopyDataError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let (data_dir, excludes) = self.data_dir(nextcloud)?;
+        tracing::info!(target: "backend::copy_data", "Create copy of Nextcloud data: {}", data_dir.display());
+
+        retry_io(&self.retry, "Creating data copy directory", || {
+            fs::create_dir_all(self.copies.dir())
+        })?;
+        self.remove_partials(dry_run)?;
+        let previous = self.copies.artifacts()?.into_iter().next();
+        if let Some((previous, _)) = &previous {
+            tracing::debug!(target: "backend::copy_data", "Link unchanged files to: {}", previous.display());
+        }
+
+        let copy_dir = self.copies.generate_filename();
+        let partial = partial_path(&copy_dir);
+        progress.phase("copy", None);
+        tracing::debug!(target: "backend::copy_data", "Copy Nextcloud data to: {}", copy_dir.display());
+        let copied = copy_tree(
+            &data_dir,
+            &partial,
+            previous.as_ref().map(|(path, _)| path.as_path()),
+            &excludes,
+            dry_run,
+            progress,
+        );
+        let stats = match copied {
+            Ok(stats) => stats,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&partial);
+                return Err(e.into());
+            }
+        };
+        tracing::info!(
+            target: "backend::copy_data",
+            "Finished copy of Nextcloud data, copied {} bytes and linked {} bytes",
+            stats.copied,
+            stats.linked
+        );
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        retry_io(&self.retry, "Completing data copy", || {
+            fs::rename(&partial, &copy_dir)
+        })?;
+        Ok(vec![Artifact::Directory(copy_dir)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        let (data_dir, excludes) = self.data_dir(nextcloud)?;
+        // unchanged files are linked, so only the first copy needs the full size
+        let required = match self.copies.artifacts()?.into_iter().next() {
+            Some((previous, _)) => {
+                let dest = self.copies.dir();
+                copy_tree(
+                    &data_dir,
+                    dest,
+                    Some(&previous),
+                    &excludes,
+                    true,
+                    &NoProgress,
+                )?
+                .copied
+            }
+            None => tree_size(std::slice::from_ref(&data_dir), &excludes)?,
+        };
+        Ok(ensure_space(self.copies.dir(), required)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let mut entries: Vec<_> = self
+            .copies
+            .artifacts()?
+            .into_iter()
+            .map(|(path, date)| BackupEntry {
+                // files are shared between the copies
+                size: None,
+                pinned: is_pinned(&path),
+                generation: path
+                    .file_name()
+                    .and_then(|name| self.copies.parse_generation(&name.to_string_lossy())),
+                artifact: Artifact::Directory(path),
+                date,
+                retained_by: Vec::new(),
+            })
+            .collect();
+        apply_retention(&mut entries, *cfg);
+
+        Ok(entries)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        self.remove_partials(dry_run)?;
+        let copies = self.copies.artifacts()?;
+        if copies.is_empty() {
+            tracing::debug!(target: "backend::copy_data::retain", "No copies found. Nothing to retain.");
+            return Ok(());
+        }
+
+        let (_, discarded) = partition_retained(copies, cfg, "backend::copy_data::retain");
+        for path in discarded {
+            // removing a copy only drops its links, files still linked by other copies stay
+            tracing::info!(target: "backend::copy_data::retain", "Discarding copy: {}", path.display());
+            if !dry_run {
+                if let Err(e) = retry_io(&self.retry, "Deleting copy", || fs::remove_dir_all(&path))
+                {
+                    tracing::error!(target: "backend::copy_data::retain", "Unable to delete copy: {e}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! Implements backup of the directories of selected users using [Data].
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{tree_size, write_tarball, Excludes};
+use crate::util::artifact::ArtifactDir;
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::glob::Glob;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const DATA_BACKUP_DEST: &str = "data/";
+const DATA_PREFIX: &str = "data-";
+const DATA_SUFFIX: &str = ".tar.gz";
+
+/// Configuration of [Data].
+///
+/// ```toml
+/// [data]
+/// users = ["alice", "bob"]
+/// user_excludes = { alice = ["files/scratch/**"] }
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DataConfig {
+    /// Ids of the users whose directories are archived.
+    ///
+    /// Defaults to all users.
+    pub users: Vec<String>,
+
+    /// Patterns of the ids of users not to archive, e.g. `guest-*`.
+    pub exclude_users: Vec<Glob>,
+
+    /// Patterns of the paths not to archive by the user id, relative to the directory of the user.
+    pub user_excludes: BTreeMap<String, Vec<Glob>>,
+
+    /// Patterns of the paths not to archive, relative to the data directory.
+    ///
+    /// Defaults to [DEFAULT_EXCLUDES], which Nextcloud regenerates or only
+    /// keeps temporarily. Set to `[]` to archive everything.
+    pub excludes: Vec<Glob>,
+
+    /// Compression of the archive.
+    pub compression: CompressionConfig,
+}
+
+/// Paths in the data directory not archived by default: previews, caches,
+/// deleted files and incomplete uploads.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "appdata_*/preview",
+    "*/cache/*",
+    "*/files_trashbin/*",
+    "*/uploads/*",
+];
+
+/// [DEFAULT_EXCLUDES] compiled.
+pub(crate) fn default_excludes() -> Vec<Glob> {
+    DEFAULT_EXCLUDES
+        .iter()
+        .map(|pattern| Glob::new(pattern).expect("default excludes should be valid"))
+        .collect()
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        Self {
+            users: Vec::new(),
+            exclude_users: Vec::new(),
+            user_excludes: BTreeMap::new(),
+            excludes: default_excludes(),
+            compression: CompressionConfig::default(),
+        }
+    }
+}
+
+/// The [Data] backend archives the directories of selected users in the data directory.
+///
+/// Unlike the [Snapper](super::Snapper) backend, which snapshots the whole data
+/// directory, single users or paths of users can be left out. The directory of
+/// every user is taken from `occ user:list`, so users with a custom home are
+/// archived as well.
+#[derive(Debug)]
+pub struct Data {
+    data_backups: ArtifactDir,
+    config: DataConfig,
+    retry: RetryConfig,
+}
+
+/// Error of the [Data] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum DataError {
+    /// The data directory or the users couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+    /// A selected user doesn't exist.
+    #[display("User {_0} doesn't exist")]
+    #[from(ignore)]
+    UnknownUser(#[error(not(source))] String),
+    /// Archiving the directories failed.
+    Io(io::Error),
+}
+
+impl Data {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            data_backups: ArtifactDir::new(
+                backup_root.join(DATA_BACKUP_DEST),
+                DATA_PREFIX,
+                DATA_SUFFIX,
+            ),
+            config: DataConfig::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Archive the directories of the users as configured by [DataConfig].
+    pub fn config(mut self, config: DataConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.data_backups = self.data_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new data backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.data_backups = self.data_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new data backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.data_backups = self.data_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old data backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.data_backups = self.data_backups.with_cold_tier(
+            tiering.destination.join(DATA_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Returns the data directory and the directories of the selected users by their id.
+    ///
+    /// Users who never logged in don't have a directory and are skipped.
+    fn user_dirs(
+        &self,
+        nextcloud: &Nextcloud,
+    ) -> Result<(PathBuf, BTreeMap<String, PathBuf>), DataError> {
+        let data_directory = nextcloud.occ().data_directory()?;
+        let mut users = nextcloud.occ().user_list()?;
+        if !self.config.users.is_empty() {
+            if let Some(unknown) = self.config.users.iter().find(|id| !users.contains_key(*id)) {
+                return Err(DataError::UnknownUser(unknown.clone()));
+            }
+            users.retain(|id, _| self.config.users.contains(id));
+        }
+
+        let mut dirs = BTreeMap::new();
+        for (id, info) in users {
+            if let Some(glob) = self
+                .config
+                .exclude_users
+                .iter()
+                .find(|glob| glob.matches(Path::new(&id)))
+            {
+                tracing::debug!(target: "backend::data", "User {id} excluded by {}", glob.as_str());
+                continue;
+            }
+            let dir = info
+                .other
+                .get("user_directory")
+                .and_then(|dir| dir.as_str())
+                .map_or_else(|| data_directory.join(&id), PathBuf::from);
+            if !dir.is_dir() {
+                tracing::debug!(target: "backend::data", "User {id} has no directory: {}", dir.display());
+                continue;
+            }
+            dirs.insert(id, dir);
+        }
+
+        Ok((data_directory, dirs))
+    }
+
+    /// Returns the paths excluded from the archive of the user directories `dirs` in `data_directory`.
+    fn excludes(&self, data_directory: &Path, dirs: &BTreeMap<String, PathBuf>) -> Excludes {
+        // don't archive the backups if they're stored in the data directory
+        let mut excludes = Excludes::paths(&[self.data_backups.dir().to_path_buf()]);
+        for glob in &self.config.excludes {
+            excludes = excludes.glob(data_directory, glob.clone());
+        }
+        for (id, dir) in dirs {
+            for glob in self.config.user_excludes.get(id).into_iter().flatten() {
+                excludes = excludes.glob(dir, glob.clone());
+            }
+        }
+        excludes
+    }
+}
+
+impl Backup for Data {
+    type Error = DataError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let (data_directory, dirs) = self.user_dirs(nextcloud)?;
+        let excludes = self.excludes(&data_directory, &dirs);
+        tracing::info!(target: "backend::data", "Create backup of the data of {} users", dirs.len());
+
+        retry_io(&self.retry, "Creating data backup directory", || {
+            fs::create_dir_all(self.data_backups.dir())
+        })?;
+        let data_backup_file = self.data_backups.generate_filename();
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::data", "Backup user data to: {}", data_backup_file.display());
+        let sources: Vec<_> = dirs.into_values().collect();
+        retry_io(&self.retry, "Writing data backup", || {
+            write_tarball(
+                &data_backup_file,
+                &sources,
+                &excludes,
+                &self.config.compression,
+                dry_run,
+                progress,
+            )
+        })?;
+        tracing::info!(target: "backend::data", "Finished backup of user data");
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Artifact::File(data_backup_file)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        let (data_directory, dirs) = self.user_dirs(nextcloud)?;
+        let excludes = self.excludes(&data_directory, &dirs);
+        let sources: Vec<_> = dirs.into_values().collect();
+        let data_size = tree_size(&sources, &excludes)?;
+        Ok(ensure_space(self.data_backups.dir(), data_size)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.data_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .data_backups
+            .retain(cfg, dry_run, "backend::data::retain")?)
+    }
+}
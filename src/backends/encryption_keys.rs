@@ -0,0 +1,232 @@
+//! Implements backup of the keys of Nextcloud's server-side encryption using [EncryptionKeys].
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{count_files, read_tarball, tree_size, write_tarball, Excludes};
+use crate::util::artifact::{remove_artifact, ArtifactDir};
+use crate::util::checksum::write_checksum;
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const KEYS_BACKUP_DEST: &str = "encryption_keys/";
+const KEYS_PREFIX: &str = "encryption-keys-";
+const KEYS_SUFFIX: &str = ".tar.gz";
+/// Directory holding the keys below the data directory and the home of every user.
+const KEYS_DIR: &str = "files_encryption";
+
+/// Configuration of [EncryptionKeys].
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct EncryptionKeysConfig {
+    /// Directory to write the key backups to instead of the backup root.
+    ///
+    /// Keeping the keys apart from the encrypted data, e.g. on a separate
+    /// drive, limits the damage if either gets leaked.
+    pub destination: Option<PathBuf>,
+}
+
+/// The [EncryptionKeys] backend archives the keys of Nextcloud's server-side encryption.
+///
+/// Without the keys every backup of the encrypted files is useless. Restoring
+/// them also requires the `secret` of `config.php` backed up by [Config](super::Config).
+/// Every archive is read back after writing and its file count is compared.
+#[derive(Debug)]
+pub struct EncryptionKeys {
+    key_backups: ArtifactDir,
+    retry: RetryConfig,
+}
+
+/// Error of the [EncryptionKeys] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum EncryptionKeysError {
+    /// The data directory or the encryption status couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+    /// The written archive doesn't contain all keys.
+    #[display("Verification of {} failed: {archived} of {expected} keys archived", archive.display())]
+    VerificationFailed {
+        /// Archive failing the verification.
+        #[error(not(source))]
+        archive: PathBuf,
+        /// Number of key files found.
+        #[error(not(source))]
+        expected: usize,
+        /// Number of key files in the archive.
+        #[error(not(source))]
+        archived: usize,
+    },
+    /// Archiving the keys failed.
+    Io(io::Error),
+}
+
+impl EncryptionKeys {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            key_backups: ArtifactDir::new(
+                backup_root.join(KEYS_BACKUP_DEST),
+                KEYS_PREFIX,
+                KEYS_SUFFIX,
+            ),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Archive the keys as configured by [EncryptionKeysConfig].
+    pub fn config(mut self, config: EncryptionKeysConfig) -> Self {
+        if let Some(destination) = config.destination {
+            self.key_backups = ArtifactDir::new(destination, KEYS_PREFIX, KEYS_SUFFIX)
+                .with_retry(self.retry.clone());
+        }
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.key_backups = self.key_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new key backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.key_backups = self.key_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new key backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.key_backups = self.key_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old key backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.key_backups = self.key_backups.with_cold_tier(
+            tiering.destination.join(KEYS_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+}
+
+/// Returns the key directories of the system and of every user in `data_dir`.
+fn key_dirs(data_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = vec![data_dir.join(KEYS_DIR)];
+    for entry in fs::read_dir(data_dir)? {
+        dirs.push(entry?.path().join(KEYS_DIR));
+    }
+    dirs.retain(|dir| dir.is_dir());
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+/// Reads back the `archive` and checks that it contains all `expected` key files.
+///
+/// On success the checksum of the archive is written.
+fn verify(archive: &Path, expected: usize) -> Result<(), EncryptionKeysError> {
+    let summary = read_tarball(archive)?;
+    if summary.files != expected {
+        return Err(EncryptionKeysError::VerificationFailed {
+            archive: archive.to_path_buf(),
+            expected,
+            archived: summary.files,
+        });
+    }
+
+    Ok(write_checksum(archive, &summary.hex_digest)?)
+}
+
+impl Backup for EncryptionKeys {
+    type Error = EncryptionKeysError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        if !nextcloud.occ().encryption_enabled()? {
+            tracing::info!(target: "backend::encryption_keys", "Server-side encryption is disabled, no keys to back up");
+            return Ok(Vec::new());
+        }
+
+        let key_dirs = key_dirs(&nextcloud.occ().data_directory()?)?;
+        tracing::info!(target: "backend::encryption_keys", "Create backup of {} key directories", key_dirs.len());
+
+        retry_io(&self.retry, "Creating key backup directory", || {
+            fs::create_dir_all(self.key_backups.dir())
+        })?;
+        let keys_backup_file = self.key_backups.generate_filename();
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::encryption_keys", "Backup encryption keys to: {}", keys_backup_file.display());
+        retry_io(&self.retry, "Writing key backup", || {
+            write_tarball(
+                &keys_backup_file,
+                &key_dirs,
+                &Excludes::default(),
+                &CompressionConfig::default(),
+                dry_run,
+                progress,
+            )
+        })?;
+        if dry_run {
+            return Ok(Vec::new());
+        }
+
+        progress.phase("verify", None);
+        if let Err(e) = verify(
+            &keys_backup_file,
+            count_files(&key_dirs, &Excludes::default())?,
+        ) {
+            let _ = remove_artifact(&keys_backup_file);
+            return Err(e);
+        }
+        tracing::info!(target: "backend::encryption_keys", "Finished backup of encryption keys");
+
+        Ok(vec![Artifact::File(keys_backup_file)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        if !nextcloud.occ().encryption_enabled()? {
+            return Ok(());
+        }
+        let keys_size = tree_size(
+            &key_dirs(&nextcloud.occ().data_directory()?)?,
+            &Excludes::default(),
+        )?;
+        Ok(ensure_space(self.key_backups.dir(), keys_size)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.key_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .key_backups
+            .retain(cfg, dry_run, "backend::encryption_keys::retain")?)
+    }
+}
@@ -0,0 +1,100 @@
+//! Export of the [BackendsConfig] into configurations of other backup tools.
+//!
+//! The exported configuration copies the backup root and the Nextcloud data
+//! directory off-host using the same retention and hooks. The repositories
+//! respectively backends have to be filled in by the user.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+
+use super::BackendsConfig;
+
+/// Supported formats of [BackendsConfig::export].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// Configuration file of borgmatic.
+    Borgmatic,
+    /// `.autorestic.yml` of autorestic.
+    Autorestic,
+}
+
+/// Quote `s` as YAML string.
+///
+/// JSON strings are valid YAML strings.
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).expect("strings are serializable")
+}
+
+impl BackendsConfig {
+    /// Render the configuration as `format` backing up the `sources`.
+    ///
+    /// Quarterly retention is not supported by either format and is skipped.
+    pub fn export(&self, format: ExportFormat, sources: &[PathBuf]) -> String {
+        if self.retention.quarterly.is_some_and(|keep| keep > 0) {
+            tracing::warn!(target: "backend::export", "Quarterly retention can't be exported and is skipped");
+        }
+        let keep = [
+            ("daily", self.retention.daily),
+            ("weekly", self.retention.weekly),
+            ("monthly", self.retention.monthly),
+            ("yearly", self.retention.yearly),
+        ];
+        let keep = keep
+            .into_iter()
+            .filter_map(|(interval, keep)| Some((interval, keep?)));
+        let hooks = &self.hooks.run;
+
+        let mut out = String::new();
+        match format {
+            ExportFormat::Borgmatic => {
+                writeln!(out, "# generated by nc_backup").unwrap();
+                writeln!(out, "source_directories:").unwrap();
+                for source in sources {
+                    writeln!(out, "    - {}", quote(&source.to_string_lossy())).unwrap();
+                }
+                writeln!(out, "# add the borg repositories to back up to").unwrap();
+                writeln!(out, "repositories: []").unwrap();
+                for (interval, keep) in keep {
+                    writeln!(out, "keep_{interval}: {keep}").unwrap();
+                }
+                if let Some(pre) = &hooks.pre {
+                    writeln!(out, "before_backup:\n    - {}", quote(pre)).unwrap();
+                }
+                if let Some(post) = &hooks.post {
+                    writeln!(out, "after_backup:\n    - {}", quote(post)).unwrap();
+                }
+            }
+            ExportFormat::Autorestic => {
+                writeln!(out, "# generated by nc_backup").unwrap();
+                writeln!(out, "version: 2").unwrap();
+                writeln!(out, "locations:").unwrap();
+                writeln!(out, "  nextcloud:").unwrap();
+                writeln!(out, "    from:").unwrap();
+                for source in sources {
+                    writeln!(out, "      - {}", quote(&source.to_string_lossy())).unwrap();
+                }
+                writeln!(out, "    # add the names of the backends to back up to").unwrap();
+                writeln!(out, "    to: []").unwrap();
+                if hooks.pre.is_some() || hooks.post.is_some() {
+                    writeln!(out, "    hooks:").unwrap();
+                    if let Some(pre) = &hooks.pre {
+                        writeln!(out, "      before:\n        - {}", quote(pre)).unwrap();
+                    }
+                    if let Some(post) = &hooks.post {
+                        writeln!(out, "      after:\n        - {}", quote(post)).unwrap();
+                    }
+                }
+                writeln!(out, "    options:").unwrap();
+                writeln!(out, "      forget:").unwrap();
+                for (interval, keep) in keep {
+                    writeln!(out, "        keep-{interval}: {keep}").unwrap();
+                }
+                writeln!(out, "backends: {{}}").unwrap();
+            }
+        }
+
+        out
+    }
+}
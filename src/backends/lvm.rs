@@ -0,0 +1,429 @@
+//! Implements backup of Nextcloud's data using LVM snapshots with [Lvm].
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{tree_size, write_tarball_as, Excludes};
+use crate::util::artifact::ArtifactDir;
+use crate::util::clock::Clock;
+use crate::util::command::{self, CommandRunner};
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::privilege::Privilege;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const LVM_BACKUP_DEST: &str = "lvm/";
+const LVM_PREFIX: &str = "data-";
+const LVM_SUFFIX: &str = ".tar.gz";
+/// Suffix of the name of the snapshot volume appended to the name of the origin.
+const SNAPSHOT_SUFFIX: &str = "-nc_backup";
+
+/// Configuration of [Lvm].
+///
+/// ```toml
+/// [lvm]
+/// volume = "vg0/nextcloud"
+/// snapshot_size = "20%ORIGIN"
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct LvmConfig {
+    /// Logical volume holding the data directory as `<volume group>/<logical volume>`.
+    ///
+    /// Defaults to the volume mounted at the data directory.
+    pub volume: Option<String>,
+
+    /// Size of the snapshot, e.g. `10G` or `20%ORIGIN`.
+    ///
+    /// The snapshot has to hold all changes to the volume while it's archived,
+    /// otherwise it becomes invalid and the backup fails.
+    pub snapshot_size: String,
+
+    /// Directory the snapshot is mounted at while it's archived.
+    pub mount_dir: PathBuf,
+
+    /// Compression of the archive.
+    pub compression: CompressionConfig,
+}
+
+impl Default for LvmConfig {
+    fn default() -> Self {
+        Self {
+            volume: None,
+            snapshot_size: "10%ORIGIN".to_string(),
+            mount_dir: PathBuf::from("/run/nc_backup/lvm"),
+            compression: CompressionConfig::default(),
+        }
+    }
+}
+
+/// Logical volume holding the data directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Volume {
+    /// `<volume group>/<logical volume>`
+    name: String,
+    /// File system type, e.g. `ext4`.
+    fstype: String,
+    /// Directory the volume is mounted at.
+    mountpoint: PathBuf,
+}
+
+impl Volume {
+    /// `<volume group>/<snapshot volume>`
+    fn snapshot(&self) -> String {
+        format!("{}{SNAPSHOT_SUFFIX}", self.name)
+    }
+
+    /// Device node of the snapshot volume.
+    fn snapshot_device(&self) -> PathBuf {
+        Path::new("/dev").join(self.snapshot())
+    }
+}
+
+/// The [Lvm] backend archives the data directory from a temporary LVM snapshot.
+///
+/// The snapshot is taken in maintenance mode and archived once maintenance mode
+/// is off, which keeps the maintenance window short on file systems without
+/// snapshots of their own like ext4 or XFS. The snapshot is removed afterwards.
+#[derive(Debug)]
+pub struct Lvm {
+    lvm_backups: ArtifactDir,
+    config: LvmConfig,
+    privilege: Privilege,
+    retry: RetryConfig,
+    runner: Arc<dyn CommandRunner>,
+}
+
+/// Error of the [Lvm] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum LvmError {
+    /// An LVM or mount command couldn't be run or failed.
+    #[display("LVM failed: {_0}")]
+    Lvm(io::Error),
+    /// The data directory isn't on a logical volume.
+    #[display("Data directory {} isn't on a logical volume, set lvm.volume or disable the lvm backend", _0.display())]
+    #[from(ignore)]
+    NoVolume(#[error(not(source))] PathBuf),
+    /// Archiving the snapshot failed.
+    #[display("Archiving the snapshot failed: {_0}")]
+    #[from(ignore)]
+    Archive(io::Error),
+    /// Running commands with root privileges isn't possible without interaction.
+    #[display("Privilege escalation failed: {_0}")]
+    #[from(ignore)]
+    Privilege(io::Error),
+    /// The data directory couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+}
+
+impl Lvm {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            lvm_backups: ArtifactDir::new(
+                backup_root.join(LVM_BACKUP_DEST),
+                LVM_PREFIX,
+                LVM_SUFFIX,
+            ),
+            config: LvmConfig::default(),
+            privilege: Privilege::default(),
+            retry: RetryConfig::default(),
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Snapshot and archive as configured by [LvmConfig].
+    pub fn config(mut self, config: LvmConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Run the LVM and mount commands using `privilege`.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Run the LVM and mount commands using `runner`.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.lvm_backups = self.lvm_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.lvm_backups = self.lvm_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.lvm_backups = self.lvm_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.lvm_backups = self.lvm_backups.with_cold_tier(
+            tiering.destination.join(LVM_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Run the privileged `program` with `args` and return its stdout.
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<String> {
+        let mut cmd = self.privilege.command(program, &[("LC_ALL", "C")]);
+        cmd.args(args);
+        self.output(cmd, program)
+    }
+
+    fn output(&self, mut cmd: Command, name: &str) -> io::Result<String> {
+        tracing::trace!(target: "backend::lvm", "Running: {cmd:?}");
+        let output = self.runner.output(&mut cmd, name)?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{name} failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The logical volume mounted at or above the data directory `data_dir`.
+    fn volume(&self, data_dir: &Path) -> Result<Volume, LvmError> {
+        let mut findmnt = Command::new("findmnt");
+        findmnt
+            .args(["-n", "-r", "-o", "SOURCE,FSTYPE,TARGET", "--target"])
+            .arg(data_dir);
+        let mount = self.output(findmnt, "findmnt")?;
+        let mut columns = mount.split_whitespace();
+        let (Some(source), Some(fstype), Some(target)) =
+            (columns.next(), columns.next(), columns.next())
+        else {
+            return Err(LvmError::NoVolume(data_dir.to_path_buf()));
+        };
+
+        let name = match &self.config.volume {
+            Some(volume) => volume.clone(),
+            None => {
+                let lvs = self
+                    .run(
+                        "lvs",
+                        &[
+                            "--noheadings",
+                            "--separator",
+                            "/",
+                            "-o",
+                            "vg_name,lv_name",
+                            source,
+                        ],
+                    )
+                    .map_err(|e| {
+                        tracing::debug!(target: "backend::lvm", "{source} isn't a logical volume: {e}");
+                        LvmError::NoVolume(data_dir.to_path_buf())
+                    })?;
+                lvs.trim().to_string()
+            }
+        };
+        if !name.contains('/') {
+            return Err(LvmError::NoVolume(data_dir.to_path_buf()));
+        }
+
+        Ok(Volume {
+            name,
+            fstype: fstype.to_string(),
+            // findmnt escapes spaces in raw output
+            mountpoint: PathBuf::from(target.replace("\\x20", " ")),
+        })
+    }
+
+    /// Remove the snapshot of `volume` left behind by an interrupted backup.
+    fn remove_stale_snapshot(&self, volume: &Volume) -> io::Result<()> {
+        let snapshot = volume.snapshot();
+        if self.run("lvs", &["--noheadings", &snapshot]).is_err() {
+            return Ok(());
+        }
+        tracing::warn!(target: "backend::lvm", "Removing snapshot of interrupted backup: {snapshot}");
+        let mount_dir = self.config.mount_dir.to_string_lossy();
+        if self.run("mountpoint", &["-q", &mount_dir]).is_ok() {
+            self.run("umount", &[&mount_dir])?;
+        }
+        self.run("lvremove", &["-y", &snapshot]).map(drop)
+    }
+
+    /// Mount the snapshot of `volume` read-only and archive the data directory to `backup_file`.
+    fn archive(
+        &self,
+        volume: &Volume,
+        data_dir: &Path,
+        backup_file: &Path,
+        progress: &dyn Progress,
+    ) -> Result<(), LvmError> {
+        fs::create_dir_all(&self.config.mount_dir)?;
+        let mount_dir = self.config.mount_dir.to_string_lossy();
+        // XFS refuses to mount a snapshot next to its origin with the same UUID
+        let options = match volume.fstype.as_str() {
+            "xfs" => "ro,nouuid",
+            _ => "ro",
+        };
+        let device = volume.snapshot_device();
+        tracing::debug!(target: "backend::lvm", "Mount snapshot {} at {mount_dir}", device.display());
+        self.run(
+            "mount",
+            &["-o", options, &device.to_string_lossy(), &mount_dir],
+        )?;
+
+        let relative = data_dir
+            .strip_prefix(&volume.mountpoint)
+            .unwrap_or(data_dir);
+        let source = self.config.mount_dir.join(relative);
+        // don't archive the backups if they're stored in the data directory
+        let excludes = match self.lvm_backups.dir().strip_prefix(data_dir) {
+            Ok(backups) => Excludes::paths(&[source.join(backups)]),
+            Err(_) => Excludes::default(),
+        };
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::lvm", "Backup Nextcloud data to: {}", backup_file.display());
+        let archived = retry_io(&self.retry, "Writing data backup", || {
+            write_tarball_as(
+                backup_file,
+                &source,
+                data_dir,
+                &excludes,
+                &self.config.compression,
+                false,
+                progress,
+            )
+        })
+        .map_err(LvmError::Archive);
+
+        let unmounted = self.run("umount", &[&mount_dir]);
+        archived?;
+        unmounted?;
+        Ok(())
+    }
+}
+
+impl Backup for Lvm {
+    type Error = LvmError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let data_dir = nextcloud.occ().data_directory()?;
+        let volume = self.volume(&data_dir)?;
+        let snapshot = volume.snapshot();
+
+        progress.phase("snapshot", None);
+        if dry_run {
+            tracing::info!(target: "backend::lvm", "Would create snapshot {snapshot} of {}", volume.name);
+            return Ok(Vec::new());
+        }
+        self.remove_stale_snapshot(&volume)?;
+        tracing::info!(target: "backend::lvm", "Create snapshot {snapshot} of {}", volume.name);
+        let size_flag = match self.config.snapshot_size.contains('%') {
+            true => "-l",
+            false => "-L",
+        };
+        let snapshot_name = snapshot.rsplit('/').next().unwrap_or(&snapshot);
+        self.run(
+            "lvcreate",
+            &[
+                "-s",
+                "-n",
+                snapshot_name,
+                size_flag,
+                &self.config.snapshot_size,
+                &volume.name,
+            ],
+        )?;
+
+        // archived by finalize outside of maintenance mode
+        Ok(vec![Artifact::File(self.lvm_backups.generate_filename())])
+    }
+
+    /// Archives the snapshot outside of maintenance mode and removes it.
+    fn finalize(
+        &self,
+        nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let Some(Artifact::File(backup_file)) = artifacts.first() else {
+            return Ok(artifacts);
+        };
+        let data_dir = nextcloud.occ().data_directory()?;
+        let volume = self.volume(&data_dir)?;
+
+        retry_io(&self.retry, "Creating data backup directory", || {
+            fs::create_dir_all(self.lvm_backups.dir())
+        })?;
+        let archived = self.archive(&volume, &data_dir, backup_file, progress);
+        tracing::debug!(target: "backend::lvm", "Remove snapshot {}", volume.snapshot());
+        let removed = self.run("lvremove", &["-y", &volume.snapshot()]);
+        if let Err(e) = archived {
+            if removed.is_err() {
+                tracing::error!(target: "backend::lvm", "Removing snapshot {} failed, it's removed on the next run", volume.snapshot());
+            }
+            return Err(e);
+        }
+        removed?;
+        tracing::info!(target: "backend::lvm", "Finished backup of Nextcloud data");
+
+        Ok(artifacts)
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        self.privilege.check().map_err(LvmError::Privilege)?;
+        let data_dir = nextcloud.occ().data_directory()?;
+        self.volume(&data_dir)?;
+        let excludes = Excludes::paths(&[self.lvm_backups.dir().to_path_buf()]);
+        let data_size =
+            tree_size(std::slice::from_ref(&data_dir), &excludes).map_err(LvmError::Archive)?;
+        ensure_space(self.lvm_backups.dir(), data_size).map_err(LvmError::Archive)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        list_artifacts(&self.lvm_backups, cfg).map_err(LvmError::Archive)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        self.lvm_backups
+            .retain(cfg, dry_run, "backend::lvm::retain")
+            .map_err(LvmError::Archive)
+    }
+}
@@ -1,53 +1,250 @@
 //! Implements backup of Nextcloud's mariadb using [MariaDb].
 
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, Read};
+use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
 
-use chrono::{Local, NaiveDateTime};
+use chrono::TimeDelta;
 use derive_more::{Display, Error, From};
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use flate2::read::MultiGzDecoder;
 
-use crate::backends::Backup;
-use crate::nextcloud::{Nextcloud, OccError};
-use crate::util::retention::{Retention, RetentionConfig};
+use crate::backends::{apply_retention, list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{DbConfig, Nextcloud, OccError};
+use crate::util::artifact::{
+    base_path, complete_partial, discard_artifact, partial_path, partition_retained,
+    remove_artifact, ArtifactDir,
+};
+use crate::util::checksum::{checksum_path, write_checksum, HashingReader};
+use crate::util::chunkstore::{ChunkStore, Manifest, CHUNKS_DIR};
+use crate::util::clock::Clock;
+use crate::util::command::{self, CommandRunner, TimedOut, Watchdog};
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::{Progress, ProgressReader};
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{is_transient, retry, retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
 
 const DB_DUMP_DEST: &str = "db/";
 const DB_DUMP_PREFIX: &str = "database-";
-const DB_DUMP_TS: &str = "%Y-%m-%dT%H-%M-%S";
 const DB_DUMP_SUFFIX: &str = ".sql.gz";
+/// Suffix of the [manifests](Manifest) of deduplicated dumps.
+const DB_MANIFEST_SUFFIX: &str = ".sql.chunks";
+/// Suffix of [differential dumps](MariaDbConfig::full_every).
+const DB_DIFF_SUFFIX: &str = ".sql.zst";
+/// Extension replacing `gz` of the uncompressed dump while [spooling](MariaDbConfig::spool).
+const DB_SPOOL_EXTENSION: &str = "spool";
 
 /// Allows you to backup the
 #[derive(Debug)]
 pub struct MariaDb {
-    db_dump_dest: PathBuf,
+    db_dumps: ArtifactDir,
+    db_manifests: ArtifactDir,
+    db_diffs: ArtifactDir,
+    retry: RetryConfig,
+    config: MariaDbConfig,
+    runner: Arc<dyn CommandRunner>,
 }
 
 /// Configuration of [MariaDb].
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
-pub struct MariaDbConfig;
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MariaDbConfig {
+    /// Only dump these tables.
+    ///
+    /// If empty all tables of Nextcloud's database are dumped.
+    pub include_tables: Vec<String>,
+
+    /// Dump only the schema but no data of these tables.
+    ///
+    /// Useful for huge tables which Nextcloud can recreate, e.g. `oc_filecache` or `oc_activity`.
+    pub exclude_tables: Vec<String>,
+
+    /// Test-decompress every dump after writing it.
+    ///
+    /// Detects dumps truncated e.g. by a full disk. The decompressed dump has to end
+    /// with the completion marker of the dump client and match its checksum.
+    pub verify: bool,
+
+    /// Dump uncompressed to a spool file and compress it after maintenance mode was disabled.
+    ///
+    /// Shortens the maintenance window of huge databases at the cost of the disk
+    /// space of the uncompressed dump.
+    pub spool: bool,
+
+    /// Compression of the dump.
+    ///
+    /// Compressing on multiple threads speeds up dumping huge databases.
+    pub compression: CompressionConfig,
+
+    /// Store the dumps deduplicated in a [ChunkStore] instead of compressing each of them.
+    ///
+    /// Only the parts of the dump which changed since any retained dump take up
+    /// space. Restore a dump using `nc_backup extract`.
+    pub dedup: bool,
+
+    /// Dump the full database only every this many dumps.
+    ///
+    /// The dumps in between are stored as `zstd` patch against the latest full dump,
+    /// which takes a fraction of the space. Ignored if [dedup](Self::dedup) is enabled.
+    pub full_every: Option<NonZeroU32>,
+
+    /// Connect to the database server at this host instead of the `dbhost` of Nextcloud.
+    ///
+    /// Needed if this host reaches the database server under a different name,
+    /// e.g. the name of a container only resolvable by Nextcloud.
+    pub host: Option<String>,
+
+    /// Connect to the database server at this port instead of the one of `dbhost`.
+    pub port: Option<u16>,
+
+    /// Connect to the database server by this Unix socket instead of the one of `dbhost`.
+    pub socket: Option<PathBuf>,
+}
+
+impl MariaDbConfig {
+    /// Apply the configured [host](Self::host), [port](Self::port) and
+    /// [socket](Self::socket) to the connection settings `db` of Nextcloud.
+    ///
+    /// A configured host replaces the socket of `dbhost`.
+    pub fn connection(&self, mut db: DbConfig) -> DbConfig {
+        if let Some(host) = &self.host {
+            db.host = Some(host.clone());
+            db.socket = None;
+        }
+        if let Some(port) = self.port {
+            db.port = Some(port);
+        }
+        if let Some(socket) = &self.socket {
+            db.socket = Some(socket.clone());
+        }
+
+        db
+    }
+
+    /// Whether the dump is spooled, either as configured or to diff it afterwards.
+    fn spools(&self) -> bool {
+        self.spool || self.differential()
+    }
+
+    /// Whether differential dumps are created.
+    fn differential(&self) -> bool {
+        self.full_every.is_some() && !self.dedup
+    }
+}
 
 impl MariaDb {
     pub fn new(backup_root: &Path) -> Self {
         let db_dump_dest = backup_root.join(DB_DUMP_DEST);
         if db_dump_dest.is_relative() {
-            log::warn!(target: "backend::mariadb", "db_dump_dest is relative: {}", db_dump_dest.display());
+            tracing::warn!(target: "backend::mariadb", "db_dump_dest is relative: {}", db_dump_dest.display());
+        }
+
+        Self {
+            db_dumps: ArtifactDir::new(db_dump_dest.clone(), DB_DUMP_PREFIX, DB_DUMP_SUFFIX),
+            db_manifests: ArtifactDir::new(
+                db_dump_dest.clone(),
+                DB_DUMP_PREFIX,
+                DB_MANIFEST_SUFFIX,
+            ),
+            db_diffs: ArtifactDir::new(db_dump_dest, DB_DUMP_PREFIX, DB_DIFF_SUFFIX),
+            retry: RetryConfig::default(),
+            config: MariaDbConfig::default(),
+            runner: command::system_runner(),
         }
+    }
+
+    /// Select the dumped tables as configured by [MariaDbConfig].
+    pub fn config(mut self, config: MariaDbConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Run the queries of the database clients using `runner`.
+    ///
+    /// The dump client streams its output and is always run directly.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Retry dumping the database on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.db_dumps = self.db_dumps.with_retry(retry.clone());
+        self.db_manifests = self.db_manifests.with_retry(retry.clone());
+        self.db_diffs = self.db_diffs.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
 
-        Self { db_dump_dest }
+    /// Timestamp new database dumps using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.db_dumps = self.db_dumps.with_clock(clock);
+        self.db_manifests = self.db_manifests.with_clock(clock);
+        self.db_diffs = self.db_diffs.with_clock(clock);
+        self
     }
 
-    fn generate_db_dump_filename(&self) -> PathBuf {
-        let timestamp = Local::now().format(DB_DUMP_TS);
+    /// Name new database dumps after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.db_dumps = self.db_dumps.with_generation(generation);
+        self.db_manifests = self.db_manifests.with_generation(generation);
+        self.db_diffs = self.db_diffs.with_generation(generation);
+        self
+    }
+
+    /// Move old database dumps to the cold storage configured by [TieringConfig].
+    ///
+    /// Deduplicated and differential dumps stay in the backup root next to their
+    /// chunk store or full dump.
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.db_dumps = self.db_dumps.with_cold_tier(
+            tiering.destination.join(DB_DUMP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Connection settings of Nextcloud's database with the configured overrides applied.
+    fn db_config(&self, nextcloud: &Nextcloud) -> Result<DbConfig, MariaDbError> {
+        let db = self.config.connection(nextcloud.occ().db_config()?);
+        tracing::debug!(target: "backend::mariadb", "Connecting to database {} at {}", db.name, endpoint(&db));
+        Ok(db)
+    }
 
-        let path = self
-            .db_dump_dest
-            .join(format!("{DB_DUMP_PREFIX}{timestamp}{DB_DUMP_SUFFIX}"));
-        assert!(!path.exists(), "db dump file should not exist prior");
+    /// Returns the full dump the next dump should be diffed against.
+    ///
+    /// Returns [None] if differential dumps are disabled or the next dump has to be full.
+    fn diff_base(&self) -> io::Result<Option<PathBuf>> {
+        let Some(full_every) = self
+            .config
+            .full_every
+            .filter(|_| self.config.differential())
+        else {
+            return Ok(None);
+        };
+        // full dumps moved to the cold tier are no longer next to the new diffs
+        let Some((base, base_date)) = self
+            .db_dumps
+            .artifacts()?
+            .into_iter()
+            .find(|(path, _)| path.parent() == Some(self.db_dumps.dir()))
+        else {
+            return Ok(None);
+        };
+        let diffs = self
+            .db_diffs
+            .artifacts()?
+            .into_iter()
+            .filter(|(_, date)| *date > base_date)
+            .count();
 
-        path
+        Ok((diffs + 1 < full_every.get() as usize).then_some(base))
     }
 }
 
@@ -55,18 +252,57 @@ impl MariaDb {
 /// Error on backup of the database.
 pub enum MariaDbError {
     /// Failed to dump the database.
-    #[display("mariadb-dump failed with {_0}")]
+    #[display("Database dump failed with {_0}")]
     DumpFailed(#[error(ignore)] ExitStatus),
-    /// Failed to spawn the `mariadb-dump` process.
-    ///
-    /// Usually this is caused by not having `mariadb-dump` installed.
-    #[display("Failed to spawn mariadb-dump: {_0}")]
+    /// The dump client didn't finish in time.
+    #[from]
+    TimedOut(TimedOut),
+    /// Loading a dump into the database failed.
+    #[display("Loading the dump failed with {_0}")]
+    LoadFailed(#[error(ignore)] ExitStatus),
+    /// A query of the database server failed.
+    #[display("Query failed: {_0}")]
+    QueryFailed(#[error(not(source))] String),
+    /// `zstd` failed to create or apply a differential dump.
+    #[display("zstd failed with {_0}")]
+    DiffFailed(#[error(ignore)] ExitStatus),
+    /// The full dump a differential dump is based on doesn't exist.
+    #[display("Full dump {} of the differential dump is missing", _0.display())]
+    DiffBaseMissing(#[error(ignore)] PathBuf),
+    /// The database server didn't accept a connection.
+    #[display("Connecting to the database server at {endpoint} failed: {error}")]
+    Unreachable {
+        /// Host, port or socket connected to.
+        #[error(not(source))]
+        endpoint: String,
+        /// Output of the client.
+        #[error(not(source))]
+        error: String,
+    },
+    /// Neither `mariadb-dump` nor `mysqldump` is installed.
+    #[display("Neither mariadb-dump nor mysqldump could be found")]
+    NoDumpClient,
+    /// Failed to spawn the `mariadb-dump` or `mysqldump` process.
+    #[display("Failed to spawn the database dump client: {_0}")]
     MariaDbDump(io::Error),
     /// Destination of the dump already exists.
     ///
     /// To save you from potential data loss the backup won't overwrite old backups.
     #[display("Dump destination already exists: {_0}")]
     DestinationExists(io::Error),
+    /// Written dump is incomplete or doesn't match its checksum.
+    #[display("Verification of the dump {} failed: {reason}", dump.display())]
+    VerificationFailed {
+        /// Dump failing the verification.
+        #[error(not(source))]
+        dump: PathBuf,
+        /// Cause of the failure.
+        #[error(not(source))]
+        reason: &'static str,
+    },
+    /// Nextcloud doesn't use a MySQL/MariaDB database.
+    #[display("Database type {_0:?} isn't supported by the mariadb backend")]
+    UnsupportedDatabase(#[error(ignore)] String),
 
     /// Error on running an `occ` command.
     #[from]
@@ -78,60 +314,926 @@ pub enum MariaDbError {
     Io(io::Error),
 }
 
-impl Backup for MariaDb {
-    type Error = MariaDbError;
+impl MariaDbError {
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(e) => is_transient(e),
+            _ => false,
+        }
+    }
+}
+
+/// Client used to dump the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DumpClient {
+    /// `mariadb-dump` of MariaDB.
+    MariaDbDump,
+    /// `mysqldump` of MariaDB (older releases) or MySQL.
+    MysqlDump {
+        /// Whether `mysqldump` is the one shipped by MariaDB.
+        mariadb: bool,
+    },
+}
 
-    fn backup(&self, nextcloud: &Nextcloud, dry_run: bool) -> Result<(), Self::Error> {
-        let table_name = nextcloud.occ().db_name()?;
-        let table_usr = nextcloud.occ().db_user()?;
-        log::info!(target: "backend::mariadb", "Create database dump of the Nextcloud table: {table_name}");
-        log::debug!(target: "backend::mariadb", "Using dbuser '{table_usr}' for backup");
+impl DumpClient {
+    /// Detect the installed client preferring `mariadb-dump` over `mysqldump`.
+    fn detect(runner: &dyn CommandRunner) -> Result<Self, MariaDbError> {
+        for program in ["mariadb-dump", "mysqldump"] {
+            tracing::trace!(target: "backend::mariadb", "Running: {program} --version");
+            let output = match runner.output(Command::new(program).arg("--version"), program) {
+                Ok(output) => output,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(MariaDbError::MariaDbDump(e)),
+            };
+            let version = String::from_utf8_lossy(&output.stdout);
+            tracing::debug!(target: "backend::mariadb", "Using {}", version.trim());
 
-        fs::create_dir_all(&self.db_dump_dest)?;
-        let db_dump_file = self.generate_db_dump_filename();
-        log::debug!(target: "backend::mariadb", "Save Nextcloud database dump at: {}", db_dump_file.display());
+            return Ok(match program {
+                "mariadb-dump" => Self::MariaDbDump,
+                _ => Self::MysqlDump {
+                    mariadb: version.contains("MariaDB"),
+                },
+            });
+        }
 
-        log::trace!(
-            target: "backend::mariadb",
-            "Running: mariadb-dump --opt --single-transaction --user={table_usr} {table_name}"
-        );
-        let mut dump_process = Command::new("mariadb-dump")
-            .arg("--opt") // sensible dump defaults
-            .arg("--single-transaction")
-            .arg(format!("--user={table_usr}"))
-            .arg(table_name)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(MariaDbError::MariaDbDump)?;
-        log::trace!(target: "backend::mariadb", "Started mariadb-dump process.");
-
-        // compress and capture stdout of mariadb-dump
+        Err(MariaDbError::NoDumpClient)
+    }
+
+    fn program(&self) -> &'static str {
+        match self {
+            Self::MariaDbDump => "mariadb-dump",
+            Self::MysqlDump { .. } => "mysqldump",
+        }
+    }
+
+    /// Command-line client of the same distribution used for queries.
+    fn query_program(&self) -> &'static str {
+        match self {
+            Self::MariaDbDump => "mariadb",
+            Self::MysqlDump { .. } => "mysql",
+        }
+    }
+
+    /// Client specific arguments.
+    fn args(&self) -> &'static [&'static str] {
+        match self {
+            // dumping tablespaces requires the PROCESS privilege on MySQL
+            Self::MysqlDump { mariadb: false } => &["--no-tablespaces"],
+            _ => &[],
+        }
+    }
+}
+
+/// Returns the [partial](partial_path) file to write the new dump `db_dump_file` to.
+///
+/// A partial file left over by an interrupted dump is removed.
+pub(crate) fn start_partial(db_dump_file: &Path) -> Result<PathBuf, MariaDbError> {
+    if db_dump_file.exists() {
+        return Err(MariaDbError::DestinationExists(
+            io::ErrorKind::AlreadyExists.into(),
+        ));
+    }
+    let partial = partial_path(db_dump_file);
+    match fs::remove_file(&partial) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(partial),
+    }
+}
+
+/// Moves the [partial](partial_path) file of the complete `db_dump_file` into place.
+///
+/// The SHA-256 `hex_digest` of the dump is written to the [checksum](checksum_path) sidecar.
+/// On failure neither the partial file nor `db_dump_file` are kept.
+pub(crate) fn complete_dump(db_dump_file: &Path, hex_digest: &str) -> Result<(), MariaDbError> {
+    complete_partial(db_dump_file)?;
+    if let Err(e) = write_checksum(db_dump_file, hex_digest) {
+        let _ = remove_artifact(db_dump_file);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Compresses everything from `reader` into the new file `db_dump_file`.
+///
+/// Returns the SHA-256 of the uncompressed content.
+/// If writing fails the incomplete `db_dump_file` is removed.
+pub(crate) fn write_compressed(
+    reader: impl Read,
+    db_dump_file: &Path,
+    compression: &CompressionConfig,
+) -> Result<String, MariaDbError> {
+    let db_dump = File::create_new(db_dump_file).map_err(MariaDbError::DestinationExists)?;
+    let mut encoder = compression.encoder(db_dump);
+    let mut reader = HashingReader::new(reader);
+
+    let written = std::io::copy(&mut reader, &mut encoder).and_then(|_| encoder.finish());
+    if let Err(e) = written {
+        let _ = fs::remove_file(db_dump_file);
+        return Err(e.into());
+    }
+
+    Ok(reader.hex_digest())
+}
+
+/// Writes everything from `reader` uncompressed into the new file `spool_file`.
+///
+/// If writing fails the incomplete `spool_file` is removed.
+fn write_spool(mut reader: impl Read, spool_file: &Path) -> Result<(), MariaDbError> {
+    let spool = File::create_new(spool_file).map_err(MariaDbError::DestinationExists)?;
+    let mut writer = io::BufWriter::new(spool);
+
+    let written = std::io::copy(&mut reader, &mut writer)
+        .and_then(|_| writer.into_inner().map_err(io::IntoInnerError::into_error))
+        .and_then(|spool| spool.sync_all());
+    if let Err(e) = written {
+        let _ = fs::remove_file(spool_file);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Stores everything from `reader` in the [ChunkStore] next to the new manifest `manifest_file`.
+///
+/// Returns the SHA-256 of the dump.
+fn write_chunked(reader: impl Read, manifest_file: &Path) -> Result<String, MariaDbError> {
+    if manifest_file.exists() {
+        return Err(MariaDbError::DestinationExists(
+            io::ErrorKind::AlreadyExists.into(),
+        ));
+    }
+    let manifest = ChunkStore::beside(manifest_file).write(reader)?;
+    manifest
+        .write(manifest_file)
+        .map_err(MariaDbError::DestinationExists)?;
+
+    Ok(manifest.hex_digest)
+}
+
+/// Compresses or [deduplicates](MariaDbConfig::dedup) the `spool_file` into the
+/// dump next to it and removes the spool.
+///
+/// Returns the path of the dump. On failure the spool is kept.
+fn compress_spool(
+    spool_file: &Path,
+    config: &MariaDbConfig,
+    progress: &dyn Progress,
+) -> Result<PathBuf, MariaDbError> {
+    let spool = File::open(spool_file)?;
+    progress.phase("compress", Some(spool.metadata()?.len()));
+    let reader = BufReader::new(ProgressReader::new(spool, progress));
+    let db_dump_file = if config.dedup {
+        let manifest_file = spool_file.with_extension("chunks");
+        tracing::debug!(target: "backend::mariadb", "Deduplicate spooled dump to: {}", manifest_file.display());
+        let hex_digest = write_chunked(reader, &start_partial(&manifest_file)?)?;
+        complete_dump(&manifest_file, &hex_digest)?;
+        manifest_file
+    } else {
+        let db_dump_file = spool_file.with_extension("gz");
+        tracing::debug!(target: "backend::mariadb", "Compress spooled dump to: {}", db_dump_file.display());
+        let hex_digest =
+            write_compressed(reader, &start_partial(&db_dump_file)?, &config.compression)?;
+        complete_dump(&db_dump_file, &hex_digest)?;
+        db_dump_file
+    };
+    fs::remove_file(spool_file)?;
+
+    Ok(db_dump_file)
+}
+
+/// Returns the full dump the differential dump `diff` is based on.
+///
+/// The full dump is expected next to the differential dump.
+pub fn diff_base(diff: &Path) -> io::Result<PathBuf> {
+    let name = fs::read_to_string(base_path(diff))?;
+    let dir = diff.parent().unwrap_or(Path::new("."));
+    Ok(dir.join(name.trim()))
+}
+
+/// Full dumps the differential dumps among `dumps` are based on.
+fn diff_bases<'a>(dumps: impl IntoIterator<Item = &'a PathBuf>) -> HashSet<PathBuf> {
+    dumps
+        .into_iter()
+        .filter(|path| path.to_string_lossy().ends_with(DB_DIFF_SUFFIX))
+        .filter_map(|path| match diff_base(path) {
+            Ok(base) => Some(base),
+            Err(e) => {
+                tracing::warn!(target: "backend::mariadb", "Full dump of {} is unknown: {e}", path.display());
+                None
+            }
+        })
+        .collect()
+}
+
+/// Decompresses the full dump `base` into the new file `dest`.
+fn decompress_base(base: &Path, dest: &Path) -> io::Result<()> {
+    let mut reader = MultiGzDecoder::new(File::open(base)?);
+    let mut file = File::create_new(dest)?;
+    io::copy(&mut reader, &mut file).map(drop)
+}
+
+/// Runs `zstd` with `args` on the full dump `base`, which is decompressed for it.
+fn run_zstd(base: &Path, args: &[&std::ffi::OsStr]) -> Result<(), MariaDbError> {
+    if !base.is_file() {
+        return Err(MariaDbError::DiffBaseMissing(base.to_path_buf()));
+    }
+    let mut decompressed = base.as_os_str().to_owned();
+    decompressed.push(".tmp");
+    let decompressed = PathBuf::from(decompressed);
+    let _ = fs::remove_file(&decompressed);
+
+    let run = || {
+        decompress_base(base, &decompressed)?;
+        let mut patch_from = std::ffi::OsString::from("--patch-from=");
+        patch_from.push(&decompressed);
+        tracing::trace!(target: "backend::mariadb", "Running: zstd -q -f --long=31 {} {:?}", patch_from.to_string_lossy(), args);
+        let mut zstd = command::new("zstd");
+        zstd.args(["-q", "-f", "--long=31"])
+            .arg(&patch_from)
+            .args(args);
+        let status = command::status(&mut zstd, "zstd").map_err(MariaDbError::MariaDbDump)?;
+        if !status.success() {
+            return Err(MariaDbError::DiffFailed(status));
+        }
+        Ok(())
+    };
+    let result = run();
+    let _ = fs::remove_file(&decompressed);
+    result
+}
+
+/// Stores the `spool_file` as patch against the full dump `base` next to it and removes the spool.
+///
+/// Returns the path of the differential dump. On failure the spool is kept.
+fn write_diff(
+    spool_file: &Path,
+    base: &Path,
+    progress: &dyn Progress,
+) -> Result<PathBuf, MariaDbError> {
+    let diff_file = spool_file.with_extension("zst");
+    tracing::debug!(target: "backend::mariadb", "Diff spooled dump against {} to: {}", base.display(), diff_file.display());
+    progress.phase("diff", None);
+
+    let mut spool = HashingReader::new(File::open(spool_file)?);
+    io::copy(&mut spool, &mut io::sink())?;
+    let partial = start_partial(&diff_file)?;
+    run_zstd(
+        base,
+        &[spool_file.as_os_str(), "-o".as_ref(), partial.as_os_str()],
+    )
+    .inspect_err(|_| {
+        let _ = fs::remove_file(&partial);
+    })?;
+    complete_partial(&diff_file)?;
+    let name = base.file_name().expect("full dump should have a file name");
+    let written = fs::write(base_path(&diff_file), name.as_encoded_bytes())
+        .and_then(|()| write_checksum(&diff_file, &spool.hex_digest()));
+    if let Err(e) = written {
+        let _ = remove_artifact(&diff_file);
+        return Err(e.into());
+    }
+    fs::remove_file(spool_file)?;
+
+    Ok(diff_file)
+}
+
+/// Restores the differential dump `diff` into the new file `dest` or to stdout.
+///
+/// The full dump it's based on is determined by [diff_base] unless `base` is given.
+/// `zstd` checks the restored dump against the checksum stored in the diff.
+pub fn restore_diff(
+    diff: &Path,
+    base: Option<&Path>,
+    dest: Option<&Path>,
+) -> Result<(), MariaDbError> {
+    let base = match base {
+        Some(base) => base.to_path_buf(),
+        None => diff_base(diff)?,
+    };
+    match dest {
+        Some(dest) if dest.exists() => Err(MariaDbError::DestinationExists(
+            io::ErrorKind::AlreadyExists.into(),
+        )),
+        Some(dest) => run_zstd(
+            &base,
+            &[
+                "-d".as_ref(),
+                diff.as_os_str(),
+                "-o".as_ref(),
+                dest.as_os_str(),
+            ],
+        ),
+        None => run_zstd(&base, &["-d".as_ref(), "-c".as_ref(), diff.as_os_str()]),
+    }
+}
+
+/// Opens the uncompressed content of the compressed or deduplicated `db_dump_file`.
+fn open_dump(db_dump_file: &Path) -> io::Result<Box<dyn Read>> {
+    if db_dump_file.extension().is_some_and(|ext| ext == "chunks") {
+        let manifest = Manifest::read(db_dump_file)?;
+        return Ok(Box::new(ChunkStore::beside(db_dump_file).reader(manifest)));
+    }
+    Ok(Box::new(MultiGzDecoder::new(File::open(db_dump_file)?)))
+}
+
+/// Marker of the last line of a complete dump.
+const DUMP_COMPLETED: &[u8] = b"-- Dump completed";
+
+/// Verifies `db_dump_file` by decompressing it.
+///
+/// Differential dumps are restored next to them for verification.
+///
+/// The dump has to end with the [completion marker](DUMP_COMPLETED) and match
+/// the [checksum](checksum_path) if present.
+fn verify_dump(db_dump_file: &Path) -> Result<(), MariaDbError> {
+    tracing::debug!(target: "backend::mariadb", "Verify database dump: {}", db_dump_file.display());
+    if !db_dump_file.to_string_lossy().ends_with(DB_DIFF_SUFFIX) {
+        return verify_content(db_dump_file, open_dump(db_dump_file)?);
+    }
+
+    let restored = db_dump_file.with_extension("verify");
+    let _ = fs::remove_file(&restored);
+    let result = restore_diff(db_dump_file, None, Some(&restored))
+        .and_then(|()| verify_content(db_dump_file, File::open(&restored)?));
+    let _ = fs::remove_file(&restored);
+    result
+}
+
+/// Verifies the uncompressed `content` of `db_dump_file` as described by [verify_dump].
+fn verify_content(db_dump_file: &Path, content: impl Read) -> Result<(), MariaDbError> {
+    let failed = |reason| MariaDbError::VerificationFailed {
+        dump: db_dump_file.to_path_buf(),
+        reason,
+    };
+
+    let mut reader = HashingReader::new(content);
+    let mut buf = vec![0; 64 * 1024];
+    let mut tail = Vec::new();
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(failed("compressed stream is truncated"))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        tail.extend_from_slice(&buf[..read]);
+        tail.drain(..tail.len().saturating_sub(256));
+    }
+
+    let last_line = tail
+        .trim_ascii_end()
+        .rsplit(|&b| b == b'\n')
+        .next()
+        .unwrap_or_default();
+    if !last_line.starts_with(DUMP_COMPLETED) {
+        return Err(failed("completion marker is missing"));
+    }
+
+    match fs::read_to_string(checksum_path(db_dump_file)) {
+        Ok(checksum) => {
+            let expected = checksum.split_whitespace().next().unwrap_or_default();
+            if expected != reader.hex_digest() {
+                return Err(failed("checksum mismatch"));
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+/// Arguments connecting a client to the database `db`.
+///
+/// The password has to be passed using [set_password].
+pub(crate) fn connection_args(db: &DbConfig) -> Vec<String> {
+    let mut connection = vec![format!("--user={}", db.user)];
+    if let Some(host) = &db.host {
+        connection.push(format!("--host={host}"));
+    }
+    if let Some(port) = db.port {
+        connection.push(format!("--port={port}"));
+    }
+    if let Some(socket) = &db.socket {
+        connection.push(format!("--socket={}", socket.display()));
+    }
+
+    connection
+}
+
+/// Pass the password of `db` to the client `command`.
+fn set_password(command: &mut Command, db: &DbConfig) {
+    if let Some(password) = &db.password {
+        // keep the password out of the process list
+        command.env("MYSQL_PWD", password);
+    }
+}
+
+/// Describes where the client connects to for the database `db`.
+pub(crate) fn endpoint(db: &DbConfig) -> String {
+    match (&db.socket, &db.host, db.port) {
+        (Some(socket), _, _) => socket.display().to_string(),
+        (None, Some(host), Some(port)) => format!("{host}:{port}"),
+        (None, Some(host), None) => host.clone(),
+        (None, None, Some(port)) => format!("localhost:{port}"),
+        (None, None, None) => "localhost".into(),
+    }
+}
+
+/// Checks the database server of `db` accepts connections of the client `program`.
+pub(crate) fn check_connection(
+    runner: &dyn CommandRunner,
+    program: &str,
+    db: &DbConfig,
+) -> Result<(), MariaDbError> {
+    let connection = connection_args(db);
+    tracing::trace!(
+        target: "backend::mariadb",
+        "Running: {} {} -N -B -e \"SELECT 1\"",
+        program,
+        connection.join(" ")
+    );
+    let mut query_command = Command::new(program);
+    query_command
+        .args(&connection)
+        .arg("-N")
+        .arg("-B")
+        .arg("-e")
+        .arg("SELECT 1");
+    set_password(&mut query_command, db);
+    let output = runner
+        .output(&mut query_command, program)
+        .map_err(MariaDbError::MariaDbDump)?;
+    if !output.status.success() {
+        return Err(MariaDbError::Unreachable {
+            endpoint: endpoint(db),
+            error: String::from_utf8_lossy(&output.stderr).trim().into(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Estimates the size of the database `db` in bytes using `information_schema`.
+///
+/// The query is run by the client `program`.
+pub(crate) fn database_size(
+    runner: &dyn CommandRunner,
+    program: &str,
+    db: &DbConfig,
+) -> Result<u64, MariaDbError> {
+    let query = format!(
+        "SELECT COALESCE(SUM(data_length + index_length), 0) FROM information_schema.tables WHERE table_schema = '{}'",
+        db.name.replace('\'', "''")
+    );
+    let connection = connection_args(db);
+    tracing::trace!(
+        target: "backend::mariadb",
+        "Running: {} {} -N -B -e \"{query}\"",
+        program,
+        connection.join(" ")
+    );
+    let mut query_command = Command::new(program);
+    query_command
+        .args(&connection)
+        .arg("-N")
+        .arg("-B")
+        .arg("-e")
+        .arg(&query);
+    set_password(&mut query_command, db);
+    let output = runner
+        .output(&mut query_command, program)
+        .map_err(MariaDbError::MariaDbDump)?;
+    if !output.status.success() {
+        return Err(MariaDbError::DumpFailed(output.status));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+/// Quotes the identifier `name` for SQL, e.g. a database name.
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Quotes `value` as SQL string literal.
+pub(crate) fn quote_string(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// Runs the SQL `query` on the server of `db` without selecting a database.
+///
+/// The query is run by the client of the installed [DumpClient].
+pub(crate) fn run_query(
+    runner: &dyn CommandRunner,
+    db: &DbConfig,
+    query: &str,
+) -> Result<String, MariaDbError> {
+    let program = DumpClient::detect(runner)?.query_program();
+    let connection = connection_args(db);
+    tracing::trace!(
+        target: "backend::mariadb",
+        "Running: {} {} -N -B -e \"{query}\"",
+        program,
+        connection.join(" ")
+    );
+    let mut query_command = Command::new(program);
+    query_command
+        .args(&connection)
+        .arg("-N")
+        .arg("-B")
+        .arg("-e")
+        .arg(query);
+    set_password(&mut query_command, db);
+    let output = runner
+        .output(&mut query_command, program)
+        .map_err(MariaDbError::MariaDbDump)?;
+    if !output.status.success() {
+        return Err(MariaDbError::QueryFailed(
+            String::from_utf8_lossy(&output.stderr).trim().into(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Creates the empty database named by `db`.
+pub(crate) fn create_database(
+    runner: &dyn CommandRunner,
+    db: &DbConfig,
+) -> Result<(), MariaDbError> {
+    let query = format!(
+        "CREATE DATABASE {} CHARACTER SET utf8mb4 COLLATE utf8mb4_general_ci",
+        quote_identifier(&db.name)
+    );
+    run_query(runner, db, &query).map(drop)
+}
+
+/// Drops the database named by `db` if it exists.
+pub(crate) fn drop_database(runner: &dyn CommandRunner, db: &DbConfig) -> Result<(), MariaDbError> {
+    let query = format!("DROP DATABASE IF EXISTS {}", quote_identifier(&db.name));
+    run_query(runner, db, &query).map(drop)
+}
+
+/// Loads the dump `db_dump_file` into the existing database named by `db`.
+///
+/// Compressed, deduplicated and differential dumps are supported. The dumps
+/// don't name the database they were taken of, so they can be loaded into any.
+/// Differential dumps are restored next to them first.
+pub(crate) fn load_dump(
+    runner: &dyn CommandRunner,
+    db_dump_file: &Path,
+    db: &DbConfig,
+    progress: &dyn Progress,
+) -> Result<(), MariaDbError> {
+    if db_dump_file.to_string_lossy().ends_with(DB_DIFF_SUFFIX) {
+        let restored = db_dump_file.with_extension("load");
+        let _ = fs::remove_file(&restored);
+        let result = restore_diff(db_dump_file, None, Some(&restored)).and_then(|()| {
+            progress.phase("load", None);
+            run_sql(runner, File::open(&restored)?, db, progress)
+        });
+        let _ = fs::remove_file(&restored);
+        return result;
+    }
+    progress.phase("load", None);
+    run_sql(runner, open_dump(db_dump_file)?, db, progress)
+}
+
+/// Pipes the statements of `sql` into the client of the installed [DumpClient]
+/// connected to the database named by `db`.
+///
+/// Unlike with [run_query] the statements don't show up in the process list.
+pub(crate) fn run_sql(
+    runner: &dyn CommandRunner,
+    sql: impl Read,
+    db: &DbConfig,
+    progress: &dyn Progress,
+) -> Result<(), MariaDbError> {
+    let program = DumpClient::detect(runner)?.query_program();
+    let connection = connection_args(db);
+    tracing::trace!(target: "backend::mariadb", "Running: {program} {} {}", connection.join(" "), db.name);
+    let mut load_command = command::new(program);
+    load_command
+        .args(&connection)
+        .arg(&db.name)
+        .stdin(Stdio::piped());
+    set_password(&mut load_command, db);
+    let mut load_process = load_command.spawn().map_err(MariaDbError::MariaDbDump)?;
+
+    let watchdog = Watchdog::start(program, &[load_process.id()]);
+    let mut stdin = load_process.stdin.take().expect("stdin should be piped");
+    let copied = io::copy(&mut ProgressReader::new(sql, progress), &mut stdin);
+    drop(stdin);
+    if copied.is_err() {
+        let _ = load_process.kill();
+    }
+    let status = load_process.wait()?;
+
+    watchdog.stop()?;
+    copied?;
+    if !status.success() {
+        return Err(MariaDbError::LoadFailed(status));
+    }
+    Ok(())
+}
+
+/// Spawns the dump `client` for the database `db` with the additional `args`.
+fn spawn_dump(client: DumpClient, db: &DbConfig, args: &[String]) -> Result<Child, MariaDbError> {
+    let mut connection: Vec<_> = client.args().iter().map(|arg| arg.to_string()).collect();
+    connection.extend(connection_args(db));
+    tracing::trace!(
+        target: "backend::mariadb",
+        "Running: {} --opt --single-transaction {} {}",
+        client.program(),
+        connection.join(" "),
+        args.join(" ")
+    );
+    let mut dump_command = command::new(client.program());
+    dump_command
+        .arg("--opt") // sensible dump defaults
+        .arg("--single-transaction")
+        .args(&connection)
+        .args(args)
+        .stdout(Stdio::piped());
+    set_password(&mut dump_command, db);
+    let dump_process = dump_command.spawn().map_err(MariaDbError::MariaDbDump)?;
+    tracing::trace!(target: "backend::mariadb", "Started {} process.", client.program());
+
+    Ok(dump_process)
+}
+
+/// Dumps the `tables` of the database configured by `db` compressed into `db_dump_file`.
+///
+/// If [spooling](MariaDbConfig::spool) the dump is written uncompressed instead.
+/// If [deduplicating](MariaDbConfig::dedup) `db_dump_file` is the manifest of the dump.
+/// Excluded tables are dumped without their data so a restore still recreates them.
+/// The dump is written to the [partial](partial_path) file of `db_dump_file`,
+/// which is moved into place once the dump clients succeeded and removed otherwise.
+fn dump(
+    client: DumpClient,
+    db: &DbConfig,
+    tables: &MariaDbConfig,
+    db_dump_file: &Path,
+    dry_run: bool,
+    progress: &dyn Progress,
+) -> Result<(), MariaDbError> {
+    let spool = tables.spools();
+    let partial = match dry_run {
+        true => PathBuf::new(),
+        false => start_partial(db_dump_file)?,
+    };
+    let mut data_args: Vec<_> = tables
+        .exclude_tables
+        .iter()
+        .map(|table| format!("--ignore-table={}.{table}", db.name))
+        .collect();
+    data_args.push(db.name.clone());
+    data_args.extend(tables.include_tables.iter().cloned());
+    let mut dump_processes = vec![spawn_dump(client, db, &data_args)?];
+
+    if !tables.exclude_tables.is_empty() {
+        let mut schema_args = vec!["--no-data".to_string(), db.name.clone()];
+        schema_args.extend(tables.exclude_tables.iter().cloned());
+        match spawn_dump(client, db, &schema_args) {
+            Ok(schema_process) => dump_processes.push(schema_process),
+            Err(e) => {
+                for dump_process in &mut dump_processes {
+                    let _ = dump_process.kill();
+                    let _ = dump_process.wait();
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let pids: Vec<_> = dump_processes.iter().map(Child::id).collect();
+    let watchdog = Watchdog::start(client.program(), &pids);
+
+    // compress and capture stdout of the dump clients
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for dump_process in &mut dump_processes {
         let stdout = dump_process
             .stdout
             .take()
             .expect("stdout should be untaken");
-        let mut reader = BufReader::new(stdout);
-        if dry_run {
-            log::trace!(target: "backend::mariadb", "Discarding output of mariadb-dump on dry-run");
-            let mut sink = io::sink();
-            std::io::copy(&mut reader, &mut sink)?;
+        reader = Box::new(reader.chain(stdout));
+    }
+    progress.phase("dump", None);
+    let mut reader = BufReader::new(ProgressReader::new(reader, progress));
+    let written = if dry_run {
+        tracing::trace!(target: "backend::mariadb", "Discarding output of the dump client on dry-run");
+        let mut sink = io::sink();
+        std::io::copy(&mut reader, &mut sink)
+            .map(|_| None)
+            .map_err(MariaDbError::from)
+    } else if spool {
+        // the checksum is written once the spool is compressed
+        write_spool(reader, &partial).map(|()| None)
+    } else if tables.dedup {
+        write_chunked(reader, &partial).map(Some)
+    } else {
+        write_compressed(reader, &partial, &tables.compression).map(Some)
+    };
+    if written.is_err() {
+        for dump_process in &mut dump_processes {
+            let _ = dump_process.kill();
+        }
+    }
+
+    let mut failed = None;
+    for mut dump_process in dump_processes {
+        let exit_status = dump_process.wait().expect("dump client should be running");
+        if !exit_status.success() {
+            failed.get_or_insert(exit_status);
+        }
+    }
+    let result = watchdog
+        .stop()
+        // the output of the killed clients ends early
+        .map_err(MariaDbError::from)
+        .and(written)
+        .and_then(|hex_digest| match failed {
+            Some(exit_status) => Err(MariaDbError::DumpFailed(exit_status)),
+            None => Ok(hex_digest),
+        });
+    if dry_run {
+        return result.map(drop);
+    }
+    match result {
+        Ok(Some(hex_digest)) => complete_dump(db_dump_file, &hex_digest),
+        Ok(None) => Ok(complete_partial(db_dump_file)?),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+impl Backup for MariaDb {
+    type Error = MariaDbError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let db = self.db_config(nextcloud)?;
+        if db.dbtype != "mysql" {
+            return Err(MariaDbError::UnsupportedDatabase(db.dbtype));
+        }
+        let client = DumpClient::detect(self.runner.as_ref())?;
+        tracing::info!(target: "backend::mariadb", "Create database dump of the Nextcloud table: {}", db.name);
+        tracing::debug!(target: "backend::mariadb", "Using dbuser '{}' for backup", db.user);
+
+        retry_io(&self.retry, "Creating database dump directory", || {
+            fs::create_dir_all(self.db_dumps.dir())
+        })?;
+        let mut db_dump_file = if self.config.dedup {
+            self.db_manifests.generate_filename()
         } else {
-            let db_dump_file =
-                File::create_new(db_dump_file).map_err(MariaDbError::DestinationExists)?;
-            let mut encoder = GzEncoder::new(db_dump_file, Compression::default());
+            self.db_dumps.generate_filename()
+        };
+        if self.config.spools() {
+            db_dump_file.set_extension(DB_SPOOL_EXTENSION);
+        }
+        tracing::debug!(target: "backend::mariadb", "Save Nextcloud database dump at: {}", db_dump_file.display());
+
+        retry(
+            &self.retry,
+            "Database dump",
+            MariaDbError::is_transient,
+            || dump(client, &db, &self.config, &db_dump_file, dry_run, progress),
+        )?;
 
-            std::io::copy(&mut reader, &mut encoder)?;
-            encoder.finish()?;
+        tracing::info!(target: "backend::mariadb-dump", "Finished Nextcloud database dump.");
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        if self.config.spools() {
+            // compressed and verified by finalize
+            return Ok(vec![Artifact::File(db_dump_file)]);
+        }
+        if self.config.verify {
+            progress.phase("verify", None);
+            if let Err(e) = verify_dump(&db_dump_file) {
+                let _ = remove_artifact(&db_dump_file);
+                return Err(e);
+            }
         }
+        Ok(vec![Artifact::File(db_dump_file)])
+    }
 
-        let exit_status = dump_process.wait().expect("mariadb-dump should be running");
-        if !exit_status.success() {
-            return Err(MariaDbError::DumpFailed(exit_status));
+    /// Compresses or deduplicates a spooled dump outside of maintenance mode.
+    fn finalize(
+        &self,
+        _nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let mut finalized = Vec::new();
+        for artifact in artifacts {
+            let spool_file = match artifact {
+                Artifact::File(path)
+                    if path
+                        .extension()
+                        .is_some_and(|ext| ext == DB_SPOOL_EXTENSION) =>
+                {
+                    path
+                }
+                artifact => {
+                    finalized.push(artifact);
+                    continue;
+                }
+            };
+
+            let written = match self.diff_base()? {
+                Some(base) => write_diff(&spool_file, &base, progress),
+                None => compress_spool(&spool_file, &self.config, progress),
+            };
+            let db_dump_file = match written {
+                Ok(db_dump_file) => db_dump_file,
+                Err(e) => {
+                    tracing::error!(target: "backend::mariadb", "Uncompressed dump kept at: {}", spool_file.display());
+                    return Err(e);
+                }
+            };
+            if self.config.verify {
+                progress.phase("verify", None);
+                if let Err(e) = verify_dump(&db_dump_file) {
+                    let _ = remove_artifact(&db_dump_file);
+                    return Err(e);
+                }
+            }
+            finalized.push(Artifact::File(db_dump_file));
         }
 
-        log::info!(target: "backend::mariadb-dump", "Finished Nextcloud database dump.");
+        Ok(finalized)
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        let db = self.db_config(nextcloud)?;
+        if db.dbtype != "mysql" {
+            return Err(MariaDbError::UnsupportedDatabase(db.dbtype));
+        }
+        let client = DumpClient::detect(self.runner.as_ref())?;
+        check_connection(self.runner.as_ref(), client.query_program(), &db)?;
 
-        Ok(())
+        // the compressed dump is usually way smaller than the tables
+        let db_size = match database_size(self.runner.as_ref(), client.query_program(), &db) {
+            Ok(db_size) => db_size,
+            Err(e) => {
+                tracing::warn!(target: "backend::mariadb", "Estimating the database size failed: {e}");
+                0
+            }
+        };
+        tracing::debug!(target: "backend::mariadb", "Estimated database size: {db_size} bytes");
+        let mut required = db_size;
+        if self.config.spools() {
+            // the spooled dump is uncompressed
+            required += db_size;
+        }
+        if self.diff_base()?.is_some() {
+            // zstd reads the full dump decompressed next to it
+            required += db_size;
+        }
+
+        Ok(ensure_space(self.db_dumps.dir(), required)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let mut entries = list_artifacts(&self.db_dumps, cfg)?;
+        entries.extend(list_artifacts(&self.db_manifests, cfg)?);
+        entries.extend(list_artifacts(&self.db_diffs, cfg)?);
+        apply_retention(&mut entries, *cfg);
+
+        let bases = diff_bases(entries.iter().filter_map(|entry| match &entry.artifact {
+            Artifact::File(path) if entry.pinned || !entry.retained_by.is_empty() => Some(path),
+            _ => None,
+        }));
+        for entry in &mut entries {
+            if let Artifact::File(path) = &entry.artifact {
+                if entry.retained_by.is_empty() && bases.contains(path) {
+                    entry.retained_by.push("base");
+                }
+            }
+        }
+
+        Ok(entries)
     }
 
     fn retention(
@@ -140,44 +1242,45 @@ impl Backup for MariaDb {
         cfg: &RetentionConfig,
         dry_run: bool,
     ) -> Result<(), Self::Error> {
-        if !fs::exists(&self.db_dump_dest)? {
-            log::debug!(target: "backend::mariadb-dump::retain", "Backup directory doesn't exist. Nothing to retain.");
+        self.db_dumps.remove_partials(dry_run)?;
+        self.db_manifests.remove_partials(dry_run)?;
+        self.db_diffs.remove_partials(dry_run)?;
+        let mut backups = self.db_dumps.artifacts()?;
+        backups.extend(self.db_manifests.artifacts()?);
+        backups.extend(self.db_diffs.artifacts()?);
+        backups.sort_by_key(|(_, date)| std::cmp::Reverse(*date));
+        if backups.is_empty() {
+            tracing::debug!(target: "backend::mariadb-dump::retain", "No backups found. Nothing to retain.");
             return Ok(());
         }
 
-        // collect all backups created so far and parse their creation date
-        let mut backups: Vec<_> = fs::read_dir(&self.db_dump_dest)?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let Ok(file_name) = entry.file_name().into_string() else {
-                    return None;
-                };
-                let timestamp = NaiveDateTime::parse_from_str(
-                    &file_name,
-                    format!("{DB_DUMP_PREFIX}{DB_DUMP_TS}{DB_DUMP_SUFFIX}").as_str(),
-                )
-                .ok()?;
-                Some((entry.path(), timestamp))
-            })
-            .collect();
-        // keep the most recent backups of each kind
-        backups.sort_by(|(_, ts_1), (_, ts_2)| ts_1.cmp(ts_2).reverse());
-
-        let mut retention = Retention::from(*cfg);
-        for (path, date) in backups {
-            if retention.retain(date) {
-                log::debug!(target: "backend::mariadb-dump::retain", "Backup retained: {}", path.display());
+        let (kept, discarded) = partition_retained(backups, cfg, "backend::mariadb-dump::retain");
+        let bases = diff_bases(&kept);
+        for path in discarded {
+            if bases.contains(&path) {
+                tracing::debug!(target: "backend::mariadb-dump::retain", "Backup retained as base of a differential dump: {}", path.display());
                 continue;
             }
 
-            log::info!(target: "backend::mariadb-dump::retain", "Discarding backup: {}", path.display());
-            if !dry_run {
-                if let Err(e) = fs::remove_file(path) {
-                    log::error!(target: "backend::mariadb-dump::retain", "Unable to delete backup: {e}");
-                }
-            }
+            discard_artifact(&path, dry_run, &self.retry, "backend::mariadb-dump::retain");
         }
 
-        Ok(())
+        let store = ChunkStore::new(self.db_manifests.dir().join(CHUNKS_DIR));
+        let manifests: Vec<_> = self
+            .db_manifests
+            .artifacts()?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        let removed = store.gc(manifests.iter().map(PathBuf::as_path), dry_run)?;
+        if removed > 0 {
+            tracing::info!(target: "backend::mariadb-dump::retain", "Discarding {removed} unreferenced chunks");
+        }
+
+        if self.config.differential() {
+            // differential dumps need their full dump next to them
+            return Ok(());
+        }
+        Ok(self.db_dumps.tier(dry_run)?)
     }
 }
@@ -0,0 +1,469 @@
+//! Implements physical backup of Nextcloud's mariadb using [MariaDbPhysical].
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+use flate2::read::MultiGzDecoder;
+
+use crate::backends::mariadb::{
+    check_connection, complete_dump, connection_args, database_size, endpoint, start_partial,
+    write_compressed,
+};
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry, MariaDbConfig, MariaDbError};
+use crate::nextcloud::{DbConfig, Nextcloud};
+use crate::util::artifact::ArtifactDir;
+use crate::util::checksum::{checksum_path, HashingReader};
+use crate::util::clock::Clock;
+use crate::util::command::{self, CommandRunner, TimedOut, Watchdog};
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::privilege::Privilege;
+use crate::util::progress::{Progress, ProgressReader};
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry, retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const DB_PHYSICAL_DEST: &str = "db-physical/";
+const DB_PHYSICAL_PREFIX: &str = "database-";
+const DB_PHYSICAL_SUFFIX: &str = ".xbstream.gz";
+/// Client used to check the connection to the database server.
+const QUERY_PROGRAM: &str = "mariadb";
+
+/// Configuration of [MariaDbPhysical].
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MariaDbPhysicalConfig {
+    /// Connect as this user instead of Nextcloud's `dbuser`.
+    ///
+    /// `mariabackup` requires the `RELOAD`, `PROCESS`, `LOCK TABLES` and
+    /// `BINLOG MONITOR` privileges, which Nextcloud's user usually lacks.
+    /// The password of Nextcloud's user isn't passed to other users, configure
+    /// it in the `[mariabackup]` group of `my.cnf` or use `unix_socket` authentication.
+    pub user: Option<String>,
+
+    /// Copy this many files of the data directory in parallel.
+    pub parallel: Option<NonZeroU32>,
+
+    /// Compression of the backup stream.
+    pub compression: CompressionConfig,
+}
+
+/// The [MariaDbPhysical] backend copies the data directory of the database server using `mariabackup`.
+///
+/// Restoring a physical backup is way faster than replaying a logical dump of
+/// a large database. As `mariabackup` reads the data directory, it has to run
+/// on the host of the database server. The backup contains all databases of the server.
+#[derive(Debug)]
+pub struct MariaDbPhysical {
+    db_backups: ArtifactDir,
+    retry: RetryConfig,
+    config: MariaDbPhysicalConfig,
+    connection: MariaDbConfig,
+    privilege: Privilege,
+    runner: Arc<dyn CommandRunner>,
+}
+
+/// Error of the [MariaDbPhysical] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum MariaDbPhysicalError {
+    /// `mariabackup` isn't installed.
+    #[display("mariabackup could not be found")]
+    NoMariabackup,
+    /// Failed to spawn `mariabackup` or `mbstream`.
+    #[display("Failed to spawn {program}: {error}")]
+    Spawn {
+        /// The spawned program.
+        #[error(not(source))]
+        program: &'static str,
+        /// Cause of the failure.
+        error: io::Error,
+    },
+    /// `mariabackup` or `mbstream` failed.
+    #[display("{program} failed with {status}")]
+    Failed {
+        /// The failed program.
+        #[error(not(source))]
+        program: &'static str,
+        /// Exit status of the program.
+        #[error(not(source))]
+        status: ExitStatus,
+    },
+    /// `mariabackup` didn't finish in time.
+    #[from]
+    TimedOut(TimedOut),
+    /// Error shared with the [MariaDb](super::MariaDb) backend, e.g. connecting
+    /// to the database server or writing the backup.
+    #[from]
+    MariaDb(MariaDbError),
+}
+
+impl From<io::Error> for MariaDbPhysicalError {
+    fn from(e: io::Error) -> Self {
+        Self::MariaDb(e.into())
+    }
+}
+
+impl MariaDbPhysicalError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::MariaDb(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+impl MariaDbPhysical {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            db_backups: ArtifactDir::new(
+                backup_root.join(DB_PHYSICAL_DEST),
+                DB_PHYSICAL_PREFIX,
+                DB_PHYSICAL_SUFFIX,
+            ),
+            retry: RetryConfig::default(),
+            config: MariaDbPhysicalConfig::default(),
+            connection: MariaDbConfig::default(),
+            privilege: Privilege::default(),
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Run `mariabackup` as configured by [MariaDbPhysicalConfig].
+    pub fn config(mut self, config: MariaDbPhysicalConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Connect to the database server using the host, port and socket of `connection`.
+    pub fn connection(mut self, connection: MariaDbConfig) -> Self {
+        self.connection = connection;
+        self
+    }
+
+    /// Run `mariabackup` using the [Privilege] escalation to read the data directory.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Run the version check and the queries of the database client using `runner`.
+    ///
+    /// `mariabackup` streams its output and is always run directly.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Retry the backup on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.db_backups = self.db_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.db_backups = self.db_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.db_backups = self.db_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.db_backups = self.db_backups.with_cold_tier(
+            tiering.destination.join(DB_PHYSICAL_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Connection settings of the database server with the configured overrides applied.
+    fn db_config(&self, nextcloud: &Nextcloud) -> Result<DbConfig, MariaDbPhysicalError> {
+        let mut db = self
+            .connection
+            .connection(nextcloud.occ().db_config().map_err(MariaDbError::from)?);
+        if db.dbtype != "mysql" {
+            return Err(MariaDbError::UnsupportedDatabase(db.dbtype).into());
+        }
+        if let Some(user) = &self.config.user {
+            db.user = user.clone();
+            db.password = None;
+        }
+        tracing::debug!(target: "backend::mariadb_physical", "Connecting as {} to {}", db.user, endpoint(&db));
+        Ok(db)
+    }
+
+    /// Checks `mariabackup` is installed.
+    fn detect(&self) -> Result<(), MariaDbPhysicalError> {
+        tracing::trace!(target: "backend::mariadb_physical", "Running: mariabackup --version");
+        let output = match self
+            .runner
+            .output(Command::new("mariabackup").arg("--version"), "mariabackup")
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(MariaDbPhysicalError::NoMariabackup)
+            }
+            Err(e) => {
+                return Err(MariaDbPhysicalError::Spawn {
+                    program: "mariabackup",
+                    error: e,
+                })
+            }
+        };
+        // mariabackup prints its version to stderr
+        let version = String::from_utf8_lossy(&output.stderr);
+        tracing::debug!(target: "backend::mariadb_physical", "Using {}", version.trim());
+
+        Ok(())
+    }
+}
+
+/// Streams the backup of the database server `db` compressed into `backup_file`.
+///
+/// The backup is written to the [partial](crate::util::artifact::partial_path) file of `backup_file`,
+/// which is moved into place once `mariabackup` succeeded and removed otherwise.
+fn stream_backup(
+    db: &DbConfig,
+    config: &MariaDbPhysicalConfig,
+    privilege: Privilege,
+    backup_file: &Path,
+    dry_run: bool,
+    progress: &dyn Progress,
+) -> Result<(), MariaDbPhysicalError> {
+    let partial = match dry_run {
+        true => PathBuf::new(),
+        false => start_partial(backup_file)?,
+    };
+    let mut args = vec!["--backup".to_string(), "--stream=xbstream".to_string()];
+    args.extend(connection_args(db));
+    if let Some(parallel) = config.parallel {
+        args.push(format!("--parallel={parallel}"));
+    }
+    tracing::trace!(target: "backend::mariadb_physical", "Running: mariabackup {}", args.join(" "));
+
+    // escalated, the password is visible in the process list of `env`
+    let envs: Vec<_> = db
+        .password
+        .iter()
+        .map(|password| ("MYSQL_PWD", password.as_str()))
+        .collect();
+    let mut backup_command = privilege.command("mariabackup", &envs);
+    backup_command.args(&args).stdout(Stdio::piped());
+    let mut backup_process =
+        backup_command
+            .spawn()
+            .map_err(|error| MariaDbPhysicalError::Spawn {
+                program: "mariabackup",
+                error,
+            })?;
+    let watchdog = Watchdog::start("mariabackup", &[backup_process.id()]);
+
+    let stdout = backup_process
+        .stdout
+        .take()
+        .expect("stdout should be piped");
+    progress.phase("backup", None);
+    let mut reader = BufReader::new(ProgressReader::new(stdout, progress));
+    let written = if dry_run {
+        tracing::trace!(target: "backend::mariadb_physical", "Discarding output of mariabackup on dry-run");
+        io::copy(&mut reader, &mut io::sink())
+            .map(|_| String::new())
+            .map_err(MariaDbError::from)
+    } else {
+        write_compressed(reader, &partial, &config.compression)
+    };
+    if written.is_err() {
+        let _ = backup_process.kill();
+    }
+
+    let status = backup_process
+        .wait()
+        .expect("mariabackup should be running");
+    let result = watchdog
+        .stop()
+        .map_err(MariaDbPhysicalError::from)
+        .and(written.map_err(MariaDbPhysicalError::from))
+        .and_then(|hex_digest| match status.success() {
+            true => Ok(hex_digest),
+            false => Err(MariaDbPhysicalError::Failed {
+                program: "mariabackup",
+                status,
+            }),
+        });
+    if dry_run {
+        return result.map(drop);
+    }
+    match result {
+        Ok(hex_digest) => Ok(complete_dump(backup_file, &hex_digest)?),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Extracts the physical backup `backup` into the new directory `target_dir` and prepares it.
+///
+/// The stream is checked against its [checksum](checksum_path) while it's extracted.
+/// Afterwards `mariabackup --prepare` makes the data files consistent, so the
+/// directory can be restored using `mariabackup --copy-back`.
+pub fn restore(backup: &Path, target_dir: &Path) -> Result<(), MariaDbPhysicalError> {
+    fs::create_dir(target_dir).map_err(MariaDbError::DestinationExists)?;
+
+    tracing::trace!(target: "backend::mariadb_physical", "Running: mbstream -x -C {}", target_dir.display());
+    let mut extract = command::new("mbstream")
+        .arg("-x")
+        .arg("-C")
+        .arg(target_dir)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| MariaDbPhysicalError::Spawn {
+            program: "mbstream",
+            error,
+        })?;
+    let mut stdin = extract.stdin.take().expect("stdin should be piped");
+    let mut reader = HashingReader::new(MultiGzDecoder::new(File::open(backup)?));
+    let copied = io::copy(&mut reader, &mut stdin);
+    drop(stdin);
+    if copied.is_err() {
+        let _ = extract.kill();
+    }
+    let status = extract.wait()?;
+    copied?;
+    if !status.success() {
+        return Err(MariaDbPhysicalError::Failed {
+            program: "mbstream",
+            status,
+        });
+    }
+
+    match fs::read_to_string(checksum_path(backup)) {
+        Ok(checksum) => {
+            let expected = checksum.split_whitespace().next().unwrap_or_default();
+            if expected != reader.hex_digest() {
+                return Err(MariaDbError::VerificationFailed {
+                    dump: backup.to_path_buf(),
+                    reason: "checksum mismatch",
+                }
+                .into());
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut prepare = command::new("mariabackup");
+    prepare
+        .arg("--prepare")
+        .arg(format!("--target-dir={}", target_dir.display()));
+    tracing::trace!(target: "backend::mariadb_physical", "Running: {prepare:?}");
+    let status = command::status(&mut prepare, "mariabackup").map_err(|error| {
+        MariaDbPhysicalError::Spawn {
+            program: "mariabackup",
+            error,
+        }
+    })?;
+    if !status.success() {
+        return Err(MariaDbPhysicalError::Failed {
+            program: "mariabackup",
+            status,
+        });
+    }
+    tracing::info!(target: "backend::mariadb_physical", "Prepared backup in {}, restore it using: mariabackup --copy-back --target-dir={}", target_dir.display(), target_dir.display());
+
+    Ok(())
+}
+
+impl Backup for MariaDbPhysical {
+    type Error = MariaDbPhysicalError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let db = self.db_config(nextcloud)?;
+        self.detect()?;
+        tracing::info!(target: "backend::mariadb_physical", "Create physical backup of the database server");
+
+        retry_io(&self.retry, "Creating database backup directory", || {
+            fs::create_dir_all(self.db_backups.dir())
+        })?;
+        let backup_file = self.db_backups.generate_filename();
+        tracing::debug!(target: "backend::mariadb_physical", "Save physical database backup at: {}", backup_file.display());
+
+        retry(
+            &self.retry,
+            "Physical database backup",
+            MariaDbPhysicalError::is_transient,
+            || {
+                stream_backup(
+                    &db,
+                    &self.config,
+                    self.privilege,
+                    &backup_file,
+                    dry_run,
+                    progress,
+                )
+            },
+        )?;
+        tracing::info!(target: "backend::mariadb_physical", "Finished physical database backup.");
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Artifact::File(backup_file)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        let db = self.db_config(nextcloud)?;
+        self.detect()?;
+        self.privilege.check()?;
+        check_connection(self.runner.as_ref(), QUERY_PROGRAM, &db)?;
+
+        // the data files of the other databases are copied as well
+        let db_size = match database_size(self.runner.as_ref(), QUERY_PROGRAM, &db) {
+            Ok(db_size) => db_size,
+            Err(e) => {
+                tracing::warn!(target: "backend::mariadb_physical", "Estimating the database size failed: {e}");
+                0
+            }
+        };
+        tracing::debug!(target: "backend::mariadb_physical", "Estimated database size: {db_size} bytes");
+
+        Ok(ensure_space(self.db_backups.dir(), db_size)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.db_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .db_backups
+            .retain(cfg, dry_run, "backend::mariadb_physical::retain")?)
+    }
+}
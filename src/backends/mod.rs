@@ -3,19 +3,215 @@
 //! Currently the following backends are implemented:
 //!
 //! - [MariaDb]: Compressed backup of the Nextcloud MariaDB tables.
+//! - [MariaDbPhysical]: Physical backup of the MariaDB server using `mariabackup`
 //! - [Snapper]: Atomic backup of user-data of the Nextcloud.
-//! - [Config]: Backup of Nextcloud's `config.php`
+//! - [Zfs]: Atomic backup of user-data on a ZFS dataset
+//! - [Lvm]: Archive of user-data from a temporary LVM snapshot
+//! - [Config]: Backup of Nextcloud's `config/` directory
+//! - [Apps]: Archive of Nextcloud's app directories
+//! - [Appdata]: Archive of Nextcloud's `appdata_<instanceid>` directory
+//! - [Webroot]: Archive of Nextcloud's document root
+//! - [Data]: Archive of the directories of selected users
+//! - [TarData]: Incremental archive of Nextcloud's data directory
+//! - [CopyData]: Hard linked copies of Nextcloud's data directory
+//! - [EncryptionKeys]: Verified archive of the keys of Nextcloud's server-side encryption
+//! - [ObjectStore]: Object list or mirror of Nextcloud's S3 primary storage
+//! - [Users]: JSON export of Nextcloud's users and groups
+//!
+//! Backends are created by name using the [BackendRegistry], which also accepts custom backends.
+//! With the `tokio` feature backends can be run from async code using `AsyncBackup`.
 
+pub mod appdata;
+pub mod apps;
+#[cfg(feature = "tokio")]
+pub mod async_backup;
 pub mod config;
+pub mod copy_data;
+pub mod data;
+pub mod encryption_keys;
+pub mod export;
+pub mod lvm;
 pub mod mariadb;
+pub mod mariadb_physical;
+pub mod objectstore;
+pub mod pin;
+pub mod registry;
 pub mod snapper;
+pub mod tar_data;
+pub mod users;
+pub mod webroot;
+pub mod zfs;
+
+pub use appdata::{Appdata, AppdataConfig, AppdataError, AppdataMode};
+pub use apps::{Apps, AppsError};
+#[cfg(feature = "tokio")]
+pub use async_backup::{AsyncBackup, Blocking};
+pub use config::{Config, MaskedSecrets};
+pub use copy_data::{CopyData, CopyDataConfig, CopyDataError};
+pub use data::{Data, DataConfig, DataError};
+pub use encryption_keys::{EncryptionKeys, EncryptionKeysConfig, EncryptionKeysError};
+pub use export::ExportFormat;
+pub use lvm::{Lvm, LvmConfig, LvmError};
+pub use mariadb::{MariaDb, MariaDbConfig, MariaDbError};
+pub use mariadb_physical::{MariaDbPhysical, MariaDbPhysicalConfig, MariaDbPhysicalError};
+pub use objectstore::{ObjectStore, ObjectStoreConfig, ObjectStoreError};
+pub use pin::{PinError, Pinner};
+pub use registry::{BackendContext, BackendRegistry, RegistryError};
+pub use snapper::{Snapper, SnapperBackupError, SnapperBuilder};
+pub use tar_data::{TarData, TarDataConfig, TarDataError};
+pub use users::{UserExport, Users, UsersError};
+pub use webroot::{Webroot, WebrootConfig, WebrootError};
+pub use zfs::{Zfs, ZfsConfig, ZfsError};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use derive_more::{Display, Error, From};
+
+use crate::nextcloud::{Nextcloud, OccBuilder};
+use crate::report::email::EmailConfig;
+use crate::report::manifest::ManifestConfig;
+use crate::report::metrics::MetricsConfig;
+use crate::report::webhook::WebhookConfig;
+use crate::runner::window::WindowConfig;
+use crate::runner::{BackupLayout, HookError, HooksConfig, InstanceConfig};
+use crate::util::artifact::{is_pinned, ArtifactDir};
+use crate::util::command::{PriorityConfig, TimeoutConfig};
+use crate::util::generation::BackupGeneration;
+use crate::util::mask::MaskingConfig;
+use crate::util::privilege::Privilege;
+use crate::util::progress::Progress;
+use crate::util::retention::{Retention, RetentionConfig};
+use crate::util::retry::RetryConfig;
+use crate::util::tiering::TieringConfig;
+
+/// Artifact created by a [Backup].
+#[derive(Debug, Clone, Display, PartialEq, Eq, serde::Serialize)]
+pub enum Artifact {
+    /// A file in the backup root.
+    #[display("{}", _0.display())]
+    File(PathBuf),
+    /// A directory in the backup root, e.g. a copy made by [CopyData].
+    #[display("{}/", _0.display())]
+    Directory(PathBuf),
+    /// A snapshot managed by [Snapper].
+    #[display("snapper:{config}:{id}")]
+    Snapshot {
+        /// Id of the Snapper config.
+        config: String,
+        /// Number of the snapshot.
+        id: u64,
+    },
+    /// A snapshot managed by [Zfs], e.g. `tank/nextcloud@nc_backup-2025-01-01T02-30-00`.
+    #[display("zfs:{_0}")]
+    ZfsSnapshot(String),
+}
+
+/// The string isn't an [Artifact] as it's displayed.
+#[derive(Debug, Display, Error)]
+#[display("Invalid backup {_0:?}, expected a path, snapper:<config>:<id> or zfs:<snapshot>")]
+pub struct ParseArtifactError(#[error(not(source))] String);
 
-pub use config::Config;
-pub use mariadb::MariaDb;
-pub use snapper::Snapper;
+impl FromStr for Artifact {
+    type Err = ParseArtifactError;
 
-use crate::nextcloud::Nextcloud;
-use crate::util::retention::RetentionConfig;
+    /// Parses an artifact as it's displayed, e.g. by the `list` action.
+    ///
+    /// Paths ending with `/` are [directories](Artifact::Directory).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseArtifactError(s.to_string());
+        if let Some(snapshot) = s.strip_prefix("snapper:") {
+            let (config, id) = snapshot.rsplit_once(':').ok_or_else(invalid)?;
+            return Ok(Artifact::Snapshot {
+                config: config.to_string(),
+                id: id.parse().map_err(|_| invalid())?,
+            });
+        }
+        if let Some(snapshot) = s.strip_prefix("zfs:") {
+            return match snapshot.contains('@') {
+                true => Ok(Artifact::ZfsSnapshot(snapshot.to_string())),
+                false => Err(invalid()),
+            };
+        }
+        match s.strip_suffix('/') {
+            _ if s.is_empty() => Err(invalid()),
+            Some(dir) => Ok(Artifact::Directory(dir.into())),
+            None => Ok(Artifact::File(s.into())),
+        }
+    }
+}
+
+/// Existing backup of a [Backup] as returned by [Backup::list].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BackupEntry {
+    /// The backup itself.
+    pub artifact: Artifact,
+    /// Creation date of the backup.
+    pub date: NaiveDateTime,
+    /// Size in bytes if known.
+    pub size: Option<u64>,
+    /// Whether the backup is exempt from retention.
+    pub pinned: bool,
+    /// Generation of the run which created the backup, if recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<BackupGeneration>,
+    /// Retention categories keeping the backup, e.g. `daily`.
+    ///
+    /// Unpinned backups without any are discarded by the next retention.
+    pub retained_by: Vec<&'static str>,
+}
+
+/// [BackupEntry] along with the name of the backend it belongs to.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BackendEntry {
+    /// Name of the backend, see [BackendRegistry].
+    pub backend: String,
+    /// The backup.
+    #[serde(flatten)]
+    pub entry: BackupEntry,
+}
+
+/// Fill in [retained_by](BackupEntry::retained_by) of `entries` as the retention using `cfg` would.
+///
+/// Pinned backups don't count against the retention.
+pub fn apply_retention(entries: &mut [BackupEntry], cfg: RetentionConfig) {
+    // keep the most recent backups of each kind
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.date));
+
+    let mut retention = Retention::from(cfg);
+    for entry in entries.iter_mut().filter(|entry| !entry.pinned) {
+        entry.retained_by = retention.retained_by(entry.date);
+    }
+}
+
+/// List the artifacts of `dir` as the retention using `cfg` would handle them.
+pub(crate) fn list_artifacts(
+    dir: &ArtifactDir,
+    cfg: &RetentionConfig,
+) -> io::Result<Vec<BackupEntry>> {
+    let mut entries = Vec::new();
+    for (path, date) in dir.artifacts()? {
+        let generation = path
+            .file_name()
+            .and_then(|name| dir.parse_generation(&name.to_string_lossy()));
+        entries.push(BackupEntry {
+            size: Some(fs::metadata(&path)?.len()),
+            pinned: is_pinned(&path),
+            generation,
+            artifact: Artifact::File(path),
+            date,
+            retained_by: Vec::new(),
+        });
+    }
+    apply_retention(&mut entries, *cfg);
+
+    Ok(entries)
+}
 
 #[allow(missing_docs)]
 /// Generic backup backend.
@@ -32,7 +228,32 @@ pub trait Backup {
     ///
     /// Instead sanity checks are performed to determine if a "real" backup
     /// would succeed under the present conditions.
-    fn backup(&self, nextcloud: &Nextcloud, dry_run: bool) -> Result<(), Self::Error>;
+    ///
+    /// Returns the [Artifact]s created by the backup. A dry run creates none.
+    ///
+    /// The phases of the backup and the bytes processed are reported to `progress`.
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error>;
+
+    /// Completes a [backup](Self::backup) after maintenance mode was disabled.
+    ///
+    /// Receives the [Artifact]s created by the backup and returns the final ones.
+    /// Slow work not affecting the consistency of the backup, e.g. compressing or
+    /// transferring it, belongs here to keep the maintenance window short.
+    /// Defaults to returning the `artifacts` unchanged.
+    fn finalize(
+        &self,
+        _nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        _progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        Ok(artifacts)
+    }
 
     /// Applies the [RetentionConfig] to all backups created by the [Backup].
     fn retention(
@@ -41,14 +262,295 @@ pub trait Backup {
         cfg: &RetentionConfig,
         dry_run: bool,
     ) -> Result<(), Self::Error>;
+
+    /// Lists all existing backups created by the [Backup].
+    ///
+    /// The backups are categorized as the [retention](Self::retention) using `cfg` would.
+    /// Defaults to listing none.
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        _cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Checks whether a [backup](Self::backup) can succeed before it's started.
+    ///
+    /// This is run before maintenance mode is enabled and usually checks that
+    /// the destination has enough free space for the estimated backup size.
+    fn preflight(&self, _nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Whether a [backup](Self::backup) has to be run in maintenance mode to be consistent.
+    ///
+    /// Defaults to `true`.
+    fn requires_maintenance(&self) -> bool {
+        true
+    }
 }
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Display, Error, From)]
+/// Unified error of all backends.
+///
+/// Custom backends can be used as [DynBackup] by implementing
+/// `From<CustomError> for BackupError`, usually by wrapping it as [BackupError::Other].
+pub enum BackupError {
+    /// Error of the [Snapper] backend.
+    Snapper(SnapperBackupError),
+    /// Error of the [Zfs] backend.
+    Zfs(ZfsError),
+    /// Error of the [Lvm] backend.
+    Lvm(LvmError),
+    /// Error of the [MariaDb] backend.
+    MariaDb(MariaDbError),
+    /// Error of the [MariaDbPhysical] backend.
+    MariaDbPhysical(MariaDbPhysicalError),
+    /// Error of the [Config] backend.
+    Config(io::Error),
+    /// Error of the [Apps] backend.
+    Apps(AppsError),
+    /// Error of the [Appdata] backend.
+    Appdata(AppdataError),
+    /// Error of the [Webroot] backend.
+    Webroot(WebrootError),
+    /// Error of the [Data] backend.
+    Data(DataError),
+    /// Error of the [TarData] backend.
+    TarData(TarDataError),
+    /// Error of the [CopyData] backend.
+    CopyData(CopyDataError),
+    /// Error of the [EncryptionKeys] backend.
+    EncryptionKeys(EncryptionKeysError),
+    /// Error of the [ObjectStore] backend.
+    ObjectStore(ObjectStoreError),
+    /// Error of the [Users] backend.
+    Users(UsersError),
+    /// Error of a hook run around the backend.
+    Hook(HookError),
+    /// Error of any other backend.
+    Other(#[error(ignore)] Box<dyn Error + Send + Sync>),
+}
+
+/// Object safe variant of [Backup].
+///
+/// Every [Backup] whose error converts into the unified [BackupError] is a [DynBackup].
+/// This allows you to store different backends in a `Vec<Box<dyn DynBackup>>`.
+pub trait DynBackup: Send {
+    /// See [Backup::backup].
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError>;
+
+    /// See [Backup::finalize].
+    fn finalize(
+        &self,
+        nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError>;
+
+    /// See [Backup::retention].
+    fn retention(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), BackupError>;
+
+    /// See [Backup::list].
+    fn list(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, BackupError>;
+
+    /// See [Backup::preflight].
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), BackupError>;
+
+    /// See [Backup::requires_maintenance].
+    fn requires_maintenance(&self) -> bool;
+}
+
+impl<B> DynBackup for B
+where
+    B: Backup + Send,
+    B::Error: Into<BackupError>,
+{
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError> {
+        Backup::backup(self, nextcloud, dry_run, progress).map_err(Into::into)
+    }
+
+    fn finalize(
+        &self,
+        nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError> {
+        Backup::finalize(self, nextcloud, artifacts, dry_run, progress).map_err(Into::into)
+    }
+
+    fn retention(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), BackupError> {
+        Backup::retention(self, nextcloud, cfg, dry_run).map_err(Into::into)
+    }
+
+    fn list(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, BackupError> {
+        Backup::list(self, nextcloud, cfg).map_err(Into::into)
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), BackupError> {
+        Backup::preflight(self, nextcloud).map_err(Into::into)
+    }
+
+    fn requires_maintenance(&self) -> bool {
+        Backup::requires_maintenance(self)
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 /// Configuration of all available backends.
 pub struct BackendsConfig {
+    /// Names of the backends to run, see [BackendRegistry].
+    ///
+    /// Overridden by `--enabled-backends`. Defaults to [DEFAULT_BACKENDS](registry::DEFAULT_BACKENDS).
+    pub backends: Option<Vec<String>>,
+
+    /// Layout of the backups in the backup root, e.g. `instance-id` to nest them
+    /// by the `instanceid` of Nextcloud.
+    #[serde(default)]
+    pub layout: BackupLayout,
+
+    /// Escalation of commands requiring root privileges if not run as root.
+    #[serde(default)]
+    pub privilege: Privilege,
+
     /// Configuration of the [Snapper] backend.
-    pub snapper: Snapper,
+    pub snapper: SnapperBuilder,
+
+    /// Configuration of the [Zfs] backend.
+    #[serde(default)]
+    pub zfs: ZfsConfig,
+
+    /// Configuration of the [Lvm] backend.
+    #[serde(default)]
+    pub lvm: LvmConfig,
+
+    /// Masking of secrets by the [Config] backend.
+    #[serde(default)]
+    pub config: MaskingConfig,
+
+    /// Configuration of the [MariaDb] backend.
+    #[serde(default)]
+    pub mariadb: MariaDbConfig,
+
+    /// Configuration of the [MariaDbPhysical] backend.
+    ///
+    /// Connects using the overrides of [mariadb](Self::mariadb).
+    #[serde(default)]
+    pub mariadb_physical: MariaDbPhysicalConfig,
+
+    /// Configuration of the [Webroot] backend.
+    #[serde(default)]
+    pub webroot: WebrootConfig,
+
+    /// Configuration of the [Data] backend.
+    #[serde(default)]
+    pub data: DataConfig,
+
+    /// Configuration of the [CopyData] backend.
+    #[serde(default)]
+    pub copy_data: CopyDataConfig,
+
+    /// Configuration of the [TarData] backend.
+    #[serde(default)]
+    pub tar_data: TarDataConfig,
+
+    /// Configuration of the [Appdata] backend.
+    #[serde(default)]
+    pub appdata: AppdataConfig,
+
+    /// Configuration of the [EncryptionKeys] backend.
+    #[serde(default)]
+    pub encryption_keys: EncryptionKeysConfig,
+
+    /// Configuration of the [ObjectStore] backend.
+    #[serde(default)]
+    pub objectstore: ObjectStoreConfig,
 
     /// Retention config.
     pub retention: RetentionConfig,
+
+    /// Move old backups to a cold storage.
+    ///
+    /// If unset all backups are kept in the backup root.
+    pub tiering: Option<TieringConfig>,
+
+    /// Retrying of file operations failing due to transient I/O errors.
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Timeouts after which hung external commands are killed.
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+
+    /// CPU and I/O priority of the external commands, e.g. `mariadb-dump` or `btrfs send`.
+    #[serde(default)]
+    pub priority: PriorityConfig,
+
+    /// Commands run around the whole run and around each backend.
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
+    /// Write metrics of every backup run for Prometheus.
+    pub metrics: Option<MetricsConfig>,
+
+    /// Write a manifest of the files created by every backup run, optionally signed.
+    pub manifest: Option<ManifestConfig>,
+
+    /// Only start backups within a time window and while the system isn't busy.
+    pub window: Option<WindowConfig>,
+
+    /// Send reports of failed runs via email.
+    pub email: Option<EmailConfig>,
+
+    /// Ping URLs before and after every run.
+    pub webhook: Option<WebhookConfig>,
+
+    /// Invocation of Nextcloud's `occ`.
+    #[serde(default)]
+    pub occ: OccBuilder,
+
+    /// Further instances backed up instead of the one given on the command line.
+    ///
+    /// Configured by `[instance.<name>]` sections, see [InstanceConfig].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub instance: BTreeMap<String, InstanceConfig>,
+
+    /// Run the [instances](Self::instance) in parallel instead of one after another.
+    #[serde(default)]
+    pub parallel_instances: bool,
+
+    /// Sections of custom backends, see [BackendContext::section].
+    #[serde(flatten)]
+    pub custom: toml::Table,
 }
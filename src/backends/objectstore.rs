@@ -0,0 +1,385 @@
+//! Implements backup of Nextcloud's S3 primary storage using [ObjectStore].
+//!
+//! If Nextcloud stores the user files in an object store (`objectstore` in
+//! `config.php`) the data directory only contains logs and app data. A snapshot
+//! of it misses all user files.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, Occ, OccError};
+use crate::util::artifact::{write_artifact, ArtifactDir};
+use crate::util::clock::Clock;
+use crate::util::command::{self, Watchdog};
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::{Progress, ProgressReader};
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{is_transient, retry, retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const OBJECTSTORE_BACKUP_DEST: &str = "objectstore/";
+const OBJECTS_PREFIX: &str = "objects-";
+const OBJECTS_SUFFIX: &str = ".json.gz";
+const MIRROR_DEST: &str = "mirror/";
+
+/// Class of the S3 primary storage in `config.php`.
+const S3_CLASS: &str = "S3";
+
+/// Primary object storage configured by `objectstore` in `config.php`.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct PrimaryStorage {
+    /// PHP class implementing the object store.
+    pub class: String,
+    /// Arguments of the object store, see [S3Arguments] for S3.
+    pub arguments: serde_json::Value,
+}
+
+/// Connection arguments of an S3 object store.
+#[derive(Clone, serde::Deserialize)]
+pub struct S3Arguments {
+    /// Name of the bucket.
+    pub bucket: String,
+    /// Access key.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Secret key.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Host of the S3 API if not AWS.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Port of the S3 API.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Whether to connect using TLS.
+    #[serde(default = "default_use_ssl")]
+    pub use_ssl: bool,
+    /// Region of the bucket.
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+fn default_use_ssl() -> bool {
+    true
+}
+
+impl std::fmt::Debug for S3Arguments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Arguments")
+            .field("bucket", &self.bucket)
+            .field("key", &self.key)
+            .field("secret", &self.secret.as_ref().map(|_| "***"))
+            .field("hostname", &self.hostname)
+            .field("port", &self.port)
+            .field("use_ssl", &self.use_ssl)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl PrimaryStorage {
+    /// Returns the primary object storage of Nextcloud.
+    ///
+    /// Returns [None] if the user files are stored in the data directory.
+    pub fn detect(occ: &Occ) -> Result<Option<Self>, OccError> {
+        match occ.config_system_get_json(&["objectstore"]) {
+            Ok(storage) => Ok(Some(storage)),
+            Err(OccError::OccCommandFailed { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the object store is S3 compatible.
+    pub fn is_s3(&self) -> bool {
+        self.class.ends_with(S3_CLASS)
+    }
+}
+
+/// How the [ObjectStore] backend backs up the bucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectStoreMode {
+    /// Export the list of objects with their metadata.
+    ///
+    /// Together with the database dump this documents which objects are needed
+    /// for a restore, but doesn't copy the user files.
+    #[default]
+    List,
+    /// Mirror the bucket into the backup root using `aws s3 sync`.
+    ///
+    /// Objects deleted from the bucket are kept in the mirror.
+    Mirror,
+}
+
+/// Configuration of [ObjectStore].
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ObjectStoreConfig {
+    /// How to back up the bucket.
+    pub mode: ObjectStoreMode,
+}
+
+/// The [ObjectStore] backend backs up Nextcloud's S3 primary storage using the `aws` CLI.
+#[derive(Debug)]
+pub struct ObjectStore {
+    object_lists: ArtifactDir,
+    mirror: PathBuf,
+    config: ObjectStoreConfig,
+    retry: RetryConfig,
+}
+
+/// Error of the [ObjectStore] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum ObjectStoreError {
+    /// Nextcloud doesn't use an object store as primary storage.
+    #[display("Nextcloud doesn't use an object store as primary storage")]
+    NotConfigured,
+    /// The object store isn't S3 compatible.
+    #[display("Object store {_0} isn't supported")]
+    Unsupported(#[error(ignore)] String),
+    /// The `aws` CLI failed.
+    #[display("aws failed: {_0}")]
+    Failed(#[error(ignore)] ExitStatus),
+    /// Reading the object store config failed.
+    #[from]
+    Occ(OccError),
+    /// Running `aws` or writing the backup failed.
+    #[from]
+    Io(io::Error),
+}
+
+impl ObjectStoreError {
+    /// Returns whether the error is likely transient, e.g. due to a network blip.
+    fn is_transient(&self) -> bool {
+        match self {
+            // 1: some transfers failed, 255: the command failed, e.g. on connection errors
+            Self::Failed(status) => matches!(status.code(), Some(1 | 255)),
+            Self::Io(e) => is_transient(e),
+            _ => false,
+        }
+    }
+}
+
+impl ObjectStore {
+    pub fn new(backup_root: &Path) -> Self {
+        let dest = backup_root.join(OBJECTSTORE_BACKUP_DEST);
+        Self {
+            object_lists: ArtifactDir::new(dest.clone(), OBJECTS_PREFIX, OBJECTS_SUFFIX),
+            mirror: dest.join(MIRROR_DEST),
+            config: ObjectStoreConfig::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Back up the bucket as configured by [ObjectStoreConfig].
+    pub fn config(mut self, config: ObjectStoreConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.object_lists = self.object_lists.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new object lists using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.object_lists = self.object_lists.with_clock(clock);
+        self
+    }
+
+    /// Name new object lists after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.object_lists = self.object_lists.with_generation(generation);
+        self
+    }
+
+    /// Move old object lists to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.object_lists = self.object_lists.with_cold_tier(
+            tiering.destination.join(OBJECTSTORE_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+}
+
+/// Returns the S3 arguments of Nextcloud's primary storage.
+fn s3_arguments(nextcloud: &Nextcloud) -> Result<S3Arguments, ObjectStoreError> {
+    let storage =
+        PrimaryStorage::detect(nextcloud.occ())?.ok_or(ObjectStoreError::NotConfigured)?;
+    if !storage.is_s3() {
+        return Err(ObjectStoreError::Unsupported(storage.class));
+    }
+
+    Ok(serde_json::from_value(storage.arguments).map_err(OccError::from)?)
+}
+
+/// Prepare an `aws` command connecting to the object store of `s3`.
+///
+/// The credentials are passed by environment to keep them out of the process list.
+fn aws(s3: &S3Arguments, args: &[&str]) -> Command {
+    let mut cmd = command::new("aws");
+    if let Some(hostname) = &s3.hostname {
+        let scheme = if s3.use_ssl { "https" } else { "http" };
+        let endpoint = match s3.port {
+            Some(port) => format!("{scheme}://{hostname}:{port}"),
+            None => format!("{scheme}://{hostname}"),
+        };
+        cmd.args(["--endpoint-url", &endpoint]);
+    }
+    if let Some(key) = &s3.key {
+        cmd.env("AWS_ACCESS_KEY_ID", key);
+    }
+    if let Some(secret) = &s3.secret {
+        cmd.env("AWS_SECRET_ACCESS_KEY", secret);
+    }
+    if let Some(region) = &s3.region {
+        cmd.env("AWS_DEFAULT_REGION", region);
+    }
+    cmd.args(args);
+
+    tracing::trace!(target: "backend::objectstore", "Running: aws {}", args.join(" "));
+    cmd
+}
+
+fn check_status(status: ExitStatus) -> Result<(), ObjectStoreError> {
+    if !status.success() {
+        return Err(ObjectStoreError::Failed(status));
+    }
+    Ok(())
+}
+
+/// Writes the compressed list of objects in the bucket of `s3` to `list_file`.
+///
+/// The list is written to a partial file first and only moved into place once complete.
+fn write_object_list(
+    s3: &S3Arguments,
+    list_file: &Path,
+    progress: &dyn Progress,
+) -> Result<(), ObjectStoreError> {
+    let mut list = aws(
+        s3,
+        &[
+            "s3api",
+            "list-objects-v2",
+            "--bucket",
+            &s3.bucket,
+            "--output",
+            "json",
+        ],
+    )
+    .stdout(Stdio::piped())
+    .spawn()?;
+    let watchdog = Watchdog::start("aws", &[list.id()]);
+    let stdout = list.stdout.take().expect("stdout should be piped");
+    let mut stdout = ProgressReader::new(stdout, progress);
+
+    write_artifact(list_file, |partial| {
+        let mut encoder = GzEncoder::new(File::create_new(partial)?, Compression::default());
+        let copied = io::copy(&mut stdout, &mut encoder);
+        let status = list.wait();
+        watchdog.stop().map_err(io::Error::from)?;
+        copied?;
+        encoder.finish()?.sync_all()?;
+        check_status(status?)
+    })
+}
+
+impl Backup for ObjectStore {
+    type Error = ObjectStoreError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let s3 = s3_arguments(nextcloud)?;
+        tracing::info!(target: "backend::objectstore", "Create backup of bucket {} ({:?})", s3.bucket, self.config.mode);
+
+        let sync_retry = self.retry.policy("sync");
+        let artifact = match self.config.mode {
+            ObjectStoreMode::List if dry_run => {
+                retry(
+                    &sync_retry,
+                    "Checking bucket",
+                    ObjectStoreError::is_transient,
+                    || {
+                        let mut head = aws(&s3, &["s3api", "head-bucket", "--bucket", &s3.bucket]);
+                        check_status(command::status(&mut head, "aws")?)
+                    },
+                )?;
+                None
+            }
+            ObjectStoreMode::List => {
+                retry_io(
+                    &self.retry,
+                    "Creating object store backup directory",
+                    || fs::create_dir_all(self.object_lists.dir()),
+                )?;
+                let list_file = self.object_lists.generate_filename();
+                tracing::debug!(target: "backend::objectstore", "Write object list to: {}", list_file.display());
+                progress.phase("list", None);
+                retry(
+                    &sync_retry,
+                    "Listing objects",
+                    ObjectStoreError::is_transient,
+                    || write_object_list(&s3, &list_file, progress),
+                )?;
+                Some(list_file)
+            }
+            ObjectStoreMode::Mirror => {
+                if !dry_run {
+                    fs::create_dir_all(&self.mirror)?;
+                }
+                let source = format!("s3://{}", s3.bucket);
+                let mirror = self.mirror.to_string_lossy();
+                let mut args = vec!["s3", "sync", &source, &mirror, "--only-show-errors"];
+                if dry_run {
+                    args.push("--dryrun");
+                }
+                progress.phase("sync", None);
+                retry(
+                    &sync_retry,
+                    "Syncing bucket",
+                    ObjectStoreError::is_transient,
+                    || check_status(command::status(&mut aws(&s3, &args), "aws")?),
+                )?;
+                (!dry_run).then(|| self.mirror.clone())
+            }
+        };
+        tracing::info!(target: "backend::objectstore", "Finished backup of bucket {}", s3.bucket);
+
+        Ok(artifact.map(Artifact::File).into_iter().collect())
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.object_lists, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .object_lists
+            .retain(cfg, dry_run, "backend::objectstore::retain")?)
+    }
+}
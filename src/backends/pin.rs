@@ -0,0 +1,122 @@
+//! Pinning backups using [Pinner], exempting them from retention.
+
+use std::fs::{self, File};
+use std::io;
+use std::sync::Arc;
+
+use derive_more::{Display, Error, From};
+
+use crate::backends::snapper::{SnapperConfig, SnapperConfigError, SNAPPER_PIN_TAG};
+use crate::backends::zfs::ZFS_PIN_PROPERTY;
+use crate::backends::Artifact;
+use crate::util::artifact::pin_path;
+use crate::util::command::{self, CommandRunner};
+use crate::util::privilege::Privilege;
+
+/// Error of [Pinner].
+#[derive(Debug, Display, Error, From)]
+pub enum PinError {
+    /// The snapshot couldn't be found or modified.
+    #[display("Modifying the snapshot failed: {_0}")]
+    Snapper(SnapperConfigError),
+    /// The snapshot doesn't exist.
+    #[display("Snapshot {_1} of snapper config {_0} not found")]
+    #[from(ignore)]
+    SnapshotNotFound(#[error(not(source))] String, #[error(not(source))] u64),
+    /// Writing the sidecar file or running `zfs` failed.
+    Io(io::Error),
+}
+
+/// Pins and unpins backups of any backend.
+///
+/// Files and directories are pinned by their [sidecar file](pin_path), snapper
+/// snapshots by the userdata [SNAPPER_PIN_TAG]`=true` and zfs snapshots by the
+/// property [ZFS_PIN_PROPERTY]. Pinned backups are kept by the retention and
+/// shown as pinned by [Backup::list](crate::backends::Backup::list). The run
+/// manifests aren't touched.
+#[derive(Debug, Clone)]
+pub struct Pinner {
+    privilege: Privilege,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl Default for Pinner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pinner {
+    /// Create a pinner using the default privilege and command runner.
+    pub fn new() -> Self {
+        Self {
+            privilege: Privilege::default(),
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Run `snapper` and `zfs` using `privilege`.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Run the external commands using `runner`.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Pin `artifact` or, if `pinned` is `false`, unpin it.
+    pub fn set(&self, artifact: &Artifact, pinned: bool, dry_run: bool) -> Result<(), PinError> {
+        let verb = if pinned { "Pin" } else { "Unpin" };
+        if dry_run {
+            tracing::info!(target: "backend::pin", "Would {} {artifact}", verb.to_lowercase());
+            return Ok(());
+        }
+        tracing::info!(target: "backend::pin", "{verb} {artifact}");
+
+        match artifact {
+            Artifact::File(path) | Artifact::Directory(path) => {
+                // don't leave a sidecar file without its artifact
+                fs::symlink_metadata(path)?;
+                let pin = pin_path(path);
+                match pinned {
+                    true => File::create(&pin).map(drop)?,
+                    false => match fs::remove_file(&pin) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    },
+                }
+            }
+            Artifact::Snapshot { config, id } => {
+                let not_found = || PinError::SnapshotNotFound(config.clone(), *id);
+                let cfg = SnapperConfig::config_by_id(config, self.privilege, self.runner.clone())?
+                    .ok_or_else(not_found)?;
+                let mut snapshot = cfg.snapshot(*id)?.ok_or_else(not_found)?;
+                let value = if pinned { "true" } else { "" };
+                let mut metadata = snapshot.metadata();
+                metadata.set(SNAPPER_PIN_TAG, value);
+                metadata.flush()?;
+            }
+            Artifact::ZfsSnapshot(snapshot) => {
+                let mut zfs = self.privilege.command("zfs", &[]);
+                match pinned {
+                    true => zfs.arg("set").arg(format!("{ZFS_PIN_PROPERTY}=true")),
+                    false => zfs.arg("inherit").arg(ZFS_PIN_PROPERTY),
+                };
+                zfs.arg(snapshot);
+                tracing::trace!(target: "backend::pin", "Running: {zfs:?}");
+                let output = self.runner.output(&mut zfs, "zfs")?;
+                if !output.status.success() {
+                    return Err(PinError::Io(io::Error::other(format!(
+                        "{zfs:?} failed with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ))));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+//! Construction of backends by name using the [BackendRegistry].
+//!
+//! The CLI and the `backends` list of the config file refer to backends by name.
+//! Downstream crates can register custom backends and read their configuration
+//! from a section of the same name in the config file:
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use nc_backup_lib::backends::{BackendContext, BackendRegistry, BackendsConfig, Config};
+//! #[derive(serde::Deserialize)]
+//! struct BorgConfig {
+//!     repository: String,
+//! }
+//!
+//! let registry = BackendRegistry::default().register("borg", |ctx| {
+//!     let borg: BorgConfig = ctx.section("borg")?;
+//!     // construct a custom backend using `borg` instead
+//!     Ok(Box::new(Config::new(ctx.backup_root)))
+//! });
+//!
+//! let config = BackendsConfig::default();
+//! let ctx = BackendContext::new(Path::new("/nextcloud/backup"), &config);
+//! let backends = registry.create_all(&["config", "borg"], &ctx).unwrap();
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use derive_more::{Display, Error};
+use serde::de::DeserializeOwned;
+
+use super::{
+    Appdata, Apps, BackendsConfig, BackupError, Config, CopyData, Data, DynBackup, EncryptionKeys,
+    Lvm, MariaDb, MariaDbPhysical, ObjectStore, TarData, Users, Webroot, Zfs,
+};
+use crate::util::clock::Clock;
+use crate::util::generation::BackupGeneration;
+use crate::util::mask::Masker;
+
+/// Backends enabled if neither the CLI nor the config file lists any.
+pub const DEFAULT_BACKENDS: &[&str] = &["config", "mariadb", "snapper"];
+
+/// Constructor of a backend registered in the [BackendRegistry].
+pub type Constructor =
+    Box<dyn Fn(&BackendContext) -> Result<Box<dyn DynBackup>, BackupError> + Send + Sync>;
+
+/// Backend created by the [BackendRegistry] along with its canonical name.
+pub type NamedBackend = (String, Box<dyn DynBackup>);
+
+/// Everything a [Constructor] needs to set up its backend.
+#[derive(Debug, Clone, Copy)]
+pub struct BackendContext<'a> {
+    /// Root directory of all backups.
+    pub backup_root: &'a Path,
+    /// The loaded config file.
+    pub config: &'a BackendsConfig,
+    /// Clock to timestamp new backups with.
+    pub clock: Clock,
+    /// Generation of the run to name new backups after.
+    pub generation: Option<BackupGeneration>,
+}
+
+impl<'a> BackendContext<'a> {
+    pub fn new(backup_root: &'a Path, config: &'a BackendsConfig) -> Self {
+        Self {
+            backup_root,
+            config,
+            clock: Clock::System,
+            generation: None,
+        }
+    }
+
+    /// Timestamp new backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Name new backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    /// Deserialize the section `name` of the config file belonging to a custom backend.
+    ///
+    /// A missing section is deserialized from an empty table.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [BackupError::Other] if the section is invalid.
+    pub fn section<T: DeserializeOwned>(&self, name: &str) -> Result<T, BackupError> {
+        let section = self
+            .config
+            .custom
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| toml::Value::Table(toml::Table::new()));
+        section
+            .try_into()
+            .map_err(|e: toml::de::Error| BackupError::Other(Box::new(e)))
+    }
+}
+
+/// Error creating a backend using the [BackendRegistry].
+#[derive(Debug, Display, Error)]
+pub enum RegistryError {
+    /// No backend is registered under the name.
+    #[display("Unknown backend {name}, available backends: {}", available.join(", "))]
+    Unknown {
+        /// Requested name.
+        #[error(not(source))]
+        name: String,
+        /// Names of all registered backends.
+        available: Vec<String>,
+    },
+    /// The constructor of the backend failed.
+    #[display("Setting up backend {name} failed: {source}")]
+    Backend {
+        /// Name of the backend.
+        #[error(not(source))]
+        name: String,
+        /// Cause of the failure.
+        source: BackupError,
+    },
+}
+
+/// Named [Constructor]s of backends.
+///
+/// The [default](BackendRegistry::default) registry contains all built-in backends.
+pub struct BackendRegistry {
+    constructors: BTreeMap<String, Constructor>,
+    aliases: BTreeMap<String, String>,
+}
+
+impl BackendRegistry {
+    /// Create a registry without any backends.
+    pub fn empty() -> Self {
+        Self {
+            constructors: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// Register the backend `name` created by `constructor`.
+    ///
+    /// A backend already registered under `name` is replaced.
+    pub fn register<F>(mut self, name: impl Into<String>, constructor: F) -> Self
+    where
+        F: Fn(&BackendContext) -> Result<Box<dyn DynBackup>, BackupError> + Send + Sync + 'static,
+    {
+        self.constructors.insert(name.into(), Box::new(constructor));
+        self
+    }
+
+    /// Accept `alias` as another name of the backend `name`.
+    pub fn alias(mut self, alias: impl Into<String>, name: impl Into<String>) -> Self {
+        self.aliases.insert(alias.into(), name.into());
+        self
+    }
+
+    /// Iterate over the names of all registered backends.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.constructors.keys().map(String::as_str)
+    }
+
+    /// Create the backend `name`.
+    ///
+    /// Returns the backend along with its canonical name, which differs from
+    /// `name` if an [alias](Self::alias) was used.
+    pub fn create(&self, name: &str, ctx: &BackendContext) -> Result<NamedBackend, RegistryError> {
+        let name = self.aliases.get(name).map_or(name, String::as_str);
+        let constructor = self
+            .constructors
+            .get(name)
+            .ok_or_else(|| RegistryError::Unknown {
+                name: name.to_string(),
+                available: self.names().map(str::to_string).collect(),
+            })?;
+        let backend = constructor(ctx).map_err(|source| RegistryError::Backend {
+            name: name.to_string(),
+            source,
+        })?;
+
+        Ok((name.to_string(), backend))
+    }
+
+    /// Create all backends in `names` in order.
+    ///
+    /// Backends listed more than once are only created once.
+    pub fn create_all<S: AsRef<str>>(
+        &self,
+        names: &[S],
+        ctx: &BackendContext,
+    ) -> Result<Vec<NamedBackend>, RegistryError> {
+        let mut backends: Vec<NamedBackend> = Vec::new();
+        for name in names {
+            let (name, backend) = self.create(name.as_ref(), ctx)?;
+            if backends.iter().any(|(created, _)| *created == name) {
+                tracing::debug!(target: "backends::registry", "Backend {name} enabled more than once");
+                continue;
+            }
+            backends.push((name, backend));
+        }
+
+        Ok(backends)
+    }
+}
+
+impl Default for BackendRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .register("snapper", snapper)
+            .register("zfs", zfs)
+            .register("lvm", lvm)
+            .register("config", config)
+            .register("apps", apps)
+            .register("appdata", appdata)
+            .register("webroot", webroot)
+            .register("data", data)
+            .register("tar_data", tar_data)
+            .register("copy_data", copy_data)
+            .register("encryption_keys", encryption_keys)
+            .register("users", users)
+            .register("objectstore", objectstore)
+            .register("mariadb", mariadb)
+            .register("mariadb_physical", mariadb_physical)
+            // name used by the CLI before the registry existed
+            .alias("maria-db", "mariadb")
+    }
+}
+
+impl std::fmt::Debug for BackendRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackendRegistry")
+            .field("constructors", &self.constructors.keys())
+            .field("aliases", &self.aliases)
+            .finish()
+    }
+}
+
+fn snapper(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut builder = ctx
+        .config
+        .snapper
+        .clone()
+        .streams_root(ctx.backup_root)
+        .privilege(ctx.config.privilege);
+    if let Some(generation) = ctx.generation {
+        builder = builder.generation(generation);
+    }
+    Ok(Box::new(builder.build()?))
+}
+
+fn zfs(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Zfs::new()
+        .config(ctx.config.zfs.clone())
+        .privilege(ctx.config.privilege)
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    Ok(Box::new(backend))
+}
+
+fn lvm(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Lvm::new(ctx.backup_root)
+        .config(ctx.config.lvm.clone())
+        .privilege(ctx.config.privilege)
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn config(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let masker = Masker::new(&ctx.config.config)
+        .map_err(|e| BackupError::Config(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+    let mut backend = Config::new(ctx.backup_root)
+        .masking(masker)
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(secrets) = &ctx.config.config.secrets {
+        backend = backend.secrets(secrets.clone());
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn apps(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Apps::new(ctx.backup_root)
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn appdata(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Appdata::new(ctx.backup_root)
+        .config(ctx.config.appdata.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn webroot(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Webroot::new(ctx.backup_root)
+        .config(ctx.config.webroot.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn data(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Data::new(ctx.backup_root)
+        .config(ctx.config.data.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn copy_data(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = CopyData::new(ctx.backup_root)
+        .config(ctx.config.copy_data.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    Ok(Box::new(backend))
+}
+
+fn tar_data(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = TarData::new(ctx.backup_root)
+        .config(ctx.config.tar_data.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn encryption_keys(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = EncryptionKeys::new(ctx.backup_root)
+        .config(ctx.config.encryption_keys.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn users(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = Users::new(ctx.backup_root)
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn objectstore(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = ObjectStore::new(ctx.backup_root)
+        .config(ctx.config.objectstore.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn mariadb(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = MariaDb::new(ctx.backup_root)
+        .config(ctx.config.mariadb.clone())
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
+
+fn mariadb_physical(ctx: &BackendContext) -> Result<Box<dyn DynBackup>, BackupError> {
+    let mut backend = MariaDbPhysical::new(ctx.backup_root)
+        .config(ctx.config.mariadb_physical.clone())
+        .connection(ctx.config.mariadb.clone())
+        .privilege(ctx.config.privilege)
+        .retry(ctx.config.retry.clone())
+        .clock(ctx.clock);
+    if let Some(generation) = ctx.generation {
+        backend = backend.generation(generation);
+    }
+    if let Some(tiering) = &ctx.config.tiering {
+        backend = backend.tiering(tiering);
+    }
+    Ok(Box::new(backend))
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{stream, SendConfig, Snapper, SnapperBackupError, SnapperCleanupAlgorithm};
+use crate::nextcloud::redis::RedisAction;
+use crate::util::command::{self, CommandRunner};
+use crate::util::generation::BackupGeneration;
+use crate::util::privilege::Privilege;
+
+/// Configuration of the [Snapper] backend.
+///
+/// Created by [Snapper::builder] or read from the `[snapper]` section of the config file.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SnapperBuilder {
+    /// Algorithms to clean up old snapshots.
+    ///
+    /// Cleanups are made by *independently* of this backend by snapper itself.
+    /// For information on how to configure [Snapper] to perform periodic cleanups
+    /// consult [`snapper(8)`]
+    ///
+    /// <div class="warning">
+    /// Clean up algorithms don't distinguish between snapshots created
+    /// by this tool or by snapper itself.
+    /// </div>
+    ///
+    /// [`snapper(8)`]: https://man.archlinux.org/man/snapper.8
+    cleanup_algorithm: Option<SnapperCleanupAlgorithm>,
+
+    /// Prepare Nextcloud's Redis file locking state before the snapshot is taken.
+    ///
+    /// Only applied if `memcache.locking` is Redis. Restoring a snapshot
+    /// alongside stale Redis locks results in file lock errors.
+    #[serde(default)]
+    redis: Option<RedisAction>,
+
+    /// Additionally write every snapshot as compressed `btrfs send` stream to the backup root.
+    ///
+    /// This allows you to keep the snapshots on a destination not formatted with btrfs.
+    #[serde(default)]
+    send: Option<SendConfig>,
+
+    /// Id of the snapper config to snapshot, e.g. `nextcloud`.
+    ///
+    /// Defaults to the config of the innermost subvolume containing the data directory.
+    #[serde(default)]
+    config: Option<String>,
+
+    /// Subvolumes in the data directory to snapshot along with it, relative to the data directory.
+    ///
+    /// Subvolumes nested in the data directory are detected, these are snapshotted in addition.
+    #[serde(default)]
+    subvolumes: Vec<PathBuf>,
+
+    /// Compare every snapshot to the previous one and list the changes in the run manifest.
+    ///
+    /// See [Snapper::changes].
+    #[serde(default)]
+    diff: bool,
+
+    /// Create the snapper config [CREATED_CONFIG](super::CREATED_CONFIG) if none covers the data directory.
+    ///
+    /// Snapper requires the data directory to be a btrfs subvolume.
+    #[serde(default)]
+    create_config: bool,
+
+    /// Directory of the stream files, see [SnapperBuilder::streams_root].
+    #[serde(skip)]
+    streams: Option<PathBuf>,
+
+    /// Generation of the run recorded by new snapshots, see [SnapperBuilder::generation].
+    #[serde(skip)]
+    generation: Option<BackupGeneration>,
+
+    /// Escalation of `snapper` and `btrfs`, see [SnapperBuilder::privilege].
+    #[serde(skip)]
+    privilege: Privilege,
+
+    /// Runner of `snapper`, see [SnapperBuilder::runner].
+    #[serde(skip)]
+    runner: Option<Arc<dyn CommandRunner>>,
+}
+
+impl Default for SnapperBuilder {
+    fn default() -> Self {
+        Self {
+            cleanup_algorithm: Some(Default::default()),
+            redis: None,
+            send: None,
+            config: None,
+            subvolumes: Vec::new(),
+            diff: false,
+            create_config: false,
+            streams: None,
+            generation: None,
+            privilege: Privilege::default(),
+            runner: None,
+        }
+    }
+}
+
+impl SnapperBuilder {
+    /// Let snapper clean up the snapshots using `cleanup`.
+    ///
+    /// Without algorithm the snapshots have to be deleted by the retention.
+    pub fn cleanup(mut self, cleanup: Option<SnapperCleanupAlgorithm>) -> Self {
+        self.cleanup_algorithm = cleanup;
+        self
+    }
+
+    /// Prepare Nextcloud's Redis file locking state using `action` before the snapshot is taken.
+    pub fn redis(mut self, action: RedisAction) -> Self {
+        self.redis = Some(action);
+        self
+    }
+
+    /// Write every snapshot as `btrfs send` stream as configured by `send`.
+    pub fn send(mut self, send: SendConfig) -> Self {
+        self.send = Some(send);
+        self
+    }
+
+    /// Additionally write the `btrfs send` streams to `destination`, e.g. an offsite mount.
+    ///
+    /// Enables sending streams with the default [SendConfig] if not yet configured.
+    pub fn sync_destination(mut self, destination: PathBuf) -> Self {
+        self.send
+            .get_or_insert_with(SendConfig::default)
+            .destinations
+            .push(destination);
+        self
+    }
+
+    /// Snapshot the snapper config `config_id` instead of the one covering the data directory.
+    pub fn config_id(mut self, config_id: String) -> Self {
+        self.config = Some(config_id);
+        self
+    }
+
+    /// Snapshot the subvolume `subvolume` in the data directory along with it.
+    ///
+    /// Relative paths are relative to the data directory.
+    pub fn subvolume(mut self, subvolume: PathBuf) -> Self {
+        self.subvolumes.push(subvolume);
+        self
+    }
+
+    /// Compare every snapshot to the previous one, see [Snapper::changes].
+    pub fn diff(mut self, diff: bool) -> Self {
+        self.diff = diff;
+        self
+    }
+
+    /// Create a snapper config if none covers the data directory.
+    pub fn create_config(mut self, create_config: bool) -> Self {
+        self.create_config = create_config;
+        self
+    }
+
+    /// Write the streams configured by [send](Self::send) below `backup_root`.
+    ///
+    /// Without a backup root no streams are written.
+    pub fn streams_root(mut self, backup_root: &Path) -> Self {
+        self.streams = Some(backup_root.join(stream::STREAMS_DEST));
+        self
+    }
+
+    /// Record the [BackupGeneration] of the run in the userdata of new snapshots.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    /// Run `snapper` and `btrfs` using the [Privilege] escalation.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Run `snapper` using `runner`, e.g. a [ScriptedRunner](crate::util::command::ScriptedRunner) in tests.
+    ///
+    /// `btrfs send` streams its output and is always run directly, while
+    /// `btrfs property` is run using `runner` as well.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Create the [Snapper] backend.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [SnapperBackupError::InvalidConfig] if streams are sent without
+    /// any snapshot per chain or if a stream destination is relative or used twice.
+    pub fn build(&self) -> Result<Snapper, SnapperBackupError> {
+        if let Some(send) = &self.send {
+            if send.max_chain == 0 {
+                return Err(SnapperBackupError::InvalidConfig(
+                    "send.max_chain has to be at least 1".into(),
+                ));
+            }
+            let mut destinations: HashSet<&Path> =
+                self.streams.iter().map(|p| p.as_path()).collect();
+            for destination in &send.destinations {
+                if destination.is_relative() {
+                    return Err(SnapperBackupError::InvalidConfig(format!(
+                        "stream destination {} isn't absolute",
+                        destination.display()
+                    )));
+                }
+                if !destinations.insert(destination) {
+                    return Err(SnapperBackupError::InvalidConfig(format!(
+                        "stream destination {} is used more than once",
+                        destination.display()
+                    )));
+                }
+            }
+        }
+
+        Ok(Snapper {
+            cleanup_algorithm: self.cleanup_algorithm,
+            redis: self.redis,
+            send: self.send.clone(),
+            config_id: self.config.clone(),
+            subvolumes: self.subvolumes.clone(),
+            diff: self.diff,
+            create_config: self.create_config,
+            streams: self.streams.clone(),
+            generation: self.generation,
+            privilege: self.privilege,
+            runner: self.runner.clone().unwrap_or_else(command::system_runner),
+        })
+    }
+}
@@ -1,21 +1,34 @@
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 
 use chrono::NaiveDateTime;
 use derive_more::{Display, Error};
 use serde_json::Value;
 
 use super::snapshot::Snapshot;
+use super::version::{parse_table, SnapperVersion};
 use super::SnapperCleanupAlgorithm;
+use crate::util::command::CommandRunner;
+use crate::util::generation::BackupGeneration;
+use crate::util::privilege::Privilege;
 
 pub(super) const SNAPPER_USERDATA_TAG: &str = "nc_backup";
+/// Userdata key pinning a snapshot, i.e. exempting it from retention.
+pub const SNAPPER_PIN_TAG: &str = "pinned";
+/// Userdata key marking a snapshot whose stream is being written.
+pub(super) const SNAPPER_SENDING_TAG: &str = "sending";
+/// Userdata key holding the [BackupGeneration] of the run creating a snapshot.
+pub(super) const SNAPPER_GENERATION_TAG: &str = "generation";
 
 #[derive(Debug, Clone)]
 /// A configuration of snapper.
 pub struct SnapperConfig {
     pub(super) subvolume: PathBuf,
     pub(super) config_id: String,
+    pub(super) privilege: Privilege,
+    pub(super) runner: Arc<dyn CommandRunner>,
 }
 
 impl PartialEq for SnapperConfig {
@@ -42,26 +55,76 @@ pub enum SnapperConfigError {
         #[error(ignore)]
         error: String,
     },
+    /// The JSON output of snapper couldn't be parsed.
+    #[display("Parsing the output of snapper failed: {_0}")]
+    InvalidJson(serde_json::Error),
+    /// The output of snapper lacks an expected entry.
+    #[display("Unexpected output of snapper: {_0}")]
+    InvalidOutput(#[error(ignore)] String),
+    /// The snapshot just created couldn't be found.
+    #[display("Created snapshot {_0} not found")]
+    SnapshotNotFound(#[error(ignore)] u64),
 }
 
 type Result<T> = std::result::Result<T, SnapperConfigError>;
 
+/// Parse the `--jsonout` output of snapper.
+fn parse_json(stdout: &[u8]) -> Result<Value> {
+    serde_json::from_slice(stdout).map_err(SnapperConfigError::InvalidJson)
+}
+
+/// Run `snapper` with `args` by `runner` using `privilege` and return its stdout.
+pub(super) fn run_snapper(
+    runner: &dyn CommandRunner,
+    privilege: Privilege,
+    args: &[&str],
+) -> Result<Vec<u8>> {
+    tracing::trace!(
+        target: "backends::snapper::config",
+        "Running: snapper {}",
+        args.join(" ")
+    );
+    // plain output is localized
+    let mut snapper_command = privilege.command("snapper", &[("LC_ALL", "C")]);
+    snapper_command.args(args);
+    let snapper_output = runner
+        .output(&mut snapper_command, "snapper")
+        .map_err(SnapperConfigError::SnapperNotRun)?;
+    let stderr = String::from_utf8_lossy(&snapper_output.stderr);
+    if !snapper_output.status.success() {
+        return Err(SnapperConfigError::SnapperCommandFailed {
+            command: Box::new(snapper_command),
+            error: stderr.into(),
+        });
+    }
+    if !stderr.is_empty() {
+        tracing::warn!(target: "backend::snapper", "{stderr}" );
+    }
+
+    Ok(snapper_output.stdout)
+}
+
 impl SnapperConfig {
-    /// Create a new [SnapperConfig].
-    pub fn new(subvolume: PathBuf, config_id: String) -> Result<Self> {
-        log::trace!(
+    /// Create a new [SnapperConfig] running `snapper` by `runner` using `privilege`.
+    pub fn new(
+        subvolume: PathBuf,
+        config_id: String,
+        privilege: Privilege,
+        runner: Arc<dyn CommandRunner>,
+    ) -> Result<Self> {
+        tracing::trace!(
             target: "backends::snapper::config",
             "Running: snapper -c {config_id} create-config {subvolume:#?}"
         );
 
-        let mut snapper_command = Command::new("snapper");
+        let mut snapper_command = privilege.command("snapper", &[]);
         snapper_command
             .arg("-c")
             .arg(&config_id)
             .arg("create-config")
             .arg(subvolume.as_os_str());
-        let snapper_output = snapper_command
-            .output()
+        let snapper_output = runner
+            .output(&mut snapper_command, "snapper")
             .map_err(SnapperConfigError::SnapperNotRun)?;
         let stderr = String::from_utf8_lossy(&snapper_output.stderr);
         if !snapper_output.status.success() {
@@ -71,102 +134,113 @@ impl SnapperConfig {
             });
         }
         if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
+            tracing::warn!(target: "backend::snapper", "{stderr}" );
         }
 
         Ok(SnapperConfig {
             subvolume,
             config_id,
+            privilege,
+            runner,
         })
     }
 
-    /// Find an *existing* snapper config by directory.
-    pub fn by_dir(dir: &Path) -> Result<Option<SnapperConfig>> {
-        log::trace!(
-            target: "backends::snapper::config",
-            "Running: snapper --jsonout list-configs"
-        );
-        let mut snapper_command = Command::new("snapper");
-        snapper_command.arg("--jsonout").arg("list-configs");
-        let snapper_output = snapper_command
-            .output()
-            .map_err(SnapperConfigError::SnapperNotRun)?;
-        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
-        if !snapper_output.status.success() {
-            return Err(SnapperConfigError::SnapperCommandFailed {
-                command: Box::new(snapper_command),
-                error: stderr.into(),
-            });
-        }
-        if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
-        }
-
-        let jsonout: Value = serde_json::from_slice(&snapper_output.stdout)
-            .expect("snapper json output should be valid");
-        let configs = jsonout
-            .get("configs")
-            .expect("command should return a list of configs")
-            .as_array()
-            .expect("json list of configs should be an array");
-
-        Ok(configs.iter().find_map(|config| {
-            let config_id = config.get("config").and_then(Value::as_str)?;
-            let subvolume = PathBuf::from(config.get("subvolume").and_then(Value::as_str)?);
-
-            if subvolume == dir {
-                Some(Self {
-                    config_id: config_id.to_string(),
-                    subvolume,
+    /// List all *existing* snapper configs.
+    ///
+    /// `snapper` is run by `runner` using `privilege`.
+    pub fn all(privilege: Privilege, runner: Arc<dyn CommandRunner>) -> Result<Vec<SnapperConfig>> {
+        let configs: Vec<(String, PathBuf)> = if SnapperVersion::detect().supports_jsonout() {
+            let stdout = run_snapper(runner.as_ref(), privilege, &["--jsonout", "list-configs"])?;
+            let jsonout = parse_json(&stdout)?;
+            jsonout
+                .get("configs")
+                .and_then(Value::as_array)
+                .ok_or_else(|| SnapperConfigError::InvalidOutput("no list of configs".into()))?
+                .iter()
+                .filter_map(|config| {
+                    let config_id = config.get("config").and_then(Value::as_str)?;
+                    let subvolume = config.get("subvolume").and_then(Value::as_str)?;
+                    Some((config_id.to_string(), PathBuf::from(subvolume)))
                 })
-            } else {
-                None
-            }
-        }))
-    }
+                .collect()
+        } else {
+            let stdout = run_snapper(runner.as_ref(), privilege, &["list-configs"])?;
+            parse_table(&String::from_utf8_lossy(&stdout))
+                .into_iter()
+                .filter_map(|mut row| {
+                    let config_id = row.remove("Config")?;
+                    let subvolume = row.remove("Subvolume")?;
+                    Some((config_id, PathBuf::from(subvolume)))
+                })
+                .collect()
+        };
 
-    /// Find an *existing* [SnapperConfig] by its config-id.
-    pub fn config_by_id(config_id: &str) -> Result<Option<SnapperConfig>> {
-        log::trace!(
-            target: "backends::snapper::config",
-            "Running: snapper --jsonout -c {config_id} get-config"
-        );
-        let mut snapper_command = Command::new("snapper");
-        snapper_command
-            .arg("--jsonout")
-            .arg("-c")
-            .arg(config_id)
-            .arg("get-config");
-        let snapper_output = snapper_command
-            .output()
-            .map_err(SnapperConfigError::SnapperNotRun)?;
-        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
-        if !snapper_output.status.success() {
-            return Err(SnapperConfigError::SnapperCommandFailed {
-                command: Box::new(snapper_command),
-                error: stderr.into(),
-            });
-        }
-        if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
-        }
+        Ok(configs
+            .into_iter()
+            .map(|(config_id, subvolume)| Self {
+                config_id,
+                subvolume,
+                privilege,
+                runner: runner.clone(),
+            })
+            .collect())
+    }
 
-        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
-        if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
-        }
+    /// Find an *existing* snapper config by directory.
+    ///
+    /// This is the config of the subvolume `dir` or, if there is none, of the
+    /// innermost subvolume containing `dir`, e.g. if `dir` is bind mounted or
+    /// a plain directory within the subvolume.
+    ///
+    /// `snapper` is run by `runner` using `privilege`.
+    pub fn by_dir(
+        dir: &Path,
+        privilege: Privilege,
+        runner: Arc<dyn CommandRunner>,
+    ) -> Result<Option<SnapperConfig>> {
+        Ok(Self::all(privilege, runner)?
+            .into_iter()
+            .filter(|config| dir.starts_with(&config.subvolume))
+            .max_by_key(|config| config.subvolume.components().count()))
+    }
 
-        let jsonout: Value = serde_json::from_slice(&snapper_output.stdout)
-            .expect("snapper json output should be valid");
-        let Some(subvolume) = jsonout.get("SUBVOLUME").and_then(Value::as_str) else {
+    /// Find an *existing* [SnapperConfig] by its config-id.
+    ///
+    /// `snapper` is run by `runner` using `privilege`.
+    pub fn config_by_id(
+        config_id: &str,
+        privilege: Privilege,
+        runner: Arc<dyn CommandRunner>,
+    ) -> Result<Option<SnapperConfig>> {
+        let subvolume = if SnapperVersion::detect().supports_jsonout() {
+            let stdout = run_snapper(
+                runner.as_ref(),
+                privilege,
+                &["--jsonout", "-c", config_id, "get-config"],
+            )?;
+            let jsonout = parse_json(&stdout)?;
+            jsonout
+                .get("SUBVOLUME")
+                .and_then(Value::as_str)
+                .map(PathBuf::from)
+        } else {
+            let stdout = run_snapper(runner.as_ref(), privilege, &["-c", config_id, "get-config"])?;
+            parse_table(&String::from_utf8_lossy(&stdout))
+                .into_iter()
+                .find(|row| row.get("Key").is_some_and(|key| key == "SUBVOLUME"))
+                .and_then(|mut row| row.remove("Value"))
+                .map(PathBuf::from)
+        };
+        let Some(subvolume) = subvolume else {
             return Ok(None);
         };
-        let subvolume = PathBuf::from(subvolume);
         let config_id = config_id.to_string();
 
         Ok(Some(Self {
             config_id,
             subvolume,
+            privilege,
+            runner,
         }))
     }
 
@@ -184,41 +258,33 @@ impl SnapperConfig {
 impl SnapperConfig {
     /// List all snapshots associated with the [SnapperConfig].
     pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
-        log::trace!(
-            target: "backends::snapper::config",
-            "Running: snapper --jsonout -c {} list --columns number,userdata,cleanup,date,description",
-            self.config_id
-        );
-        let mut snapper_command = Command::new("snapper");
-        snapper_command
-            .arg("--jsonout")
-            .arg("-c")
-            .arg(&self.config_id)
-            .arg("list")
-            .arg("--columns")
-            .arg("number,userdata,cleanup,date,description");
-        let snapper_output = snapper_command
-            .output()
-            .map_err(SnapperConfigError::SnapperNotRun)?;
-        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
-        if !snapper_output.status.success() {
-            return Err(SnapperConfigError::SnapperCommandFailed {
-                command: Box::new(snapper_command),
-                error: stderr.into(),
-            });
-        }
-        if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
+        if !SnapperVersion::detect().supports_jsonout() {
+            return self.snapshots_plain();
         }
 
-        let jsonout: Value = serde_json::from_slice(&snapper_output.stdout)
-            .expect("snapper json output should be valid");
+        let stdout = run_snapper(
+            self.runner.as_ref(),
+            self.privilege,
+            &[
+                "--jsonout",
+                "-c",
+                &self.config_id,
+                "list",
+                "--columns",
+                "number,userdata,cleanup,date,description",
+            ],
+        )?;
+        let jsonout = parse_json(&stdout)?;
 
         let snapshots = jsonout
             .get(&self.config_id)
-            .expect("command should return snapshots matching the supplied configuration")
-            .as_array()
-            .expect("json snapshot list should be an array");
+            .and_then(Value::as_array)
+            .ok_or_else(|| {
+                SnapperConfigError::InvalidOutput(format!(
+                    "no list of snapshots of config {}",
+                    self.config_id
+                ))
+            })?;
 
         Ok(snapshots
             .iter()
@@ -260,6 +326,51 @@ impl SnapperConfig {
             .collect())
     }
 
+    /// List all snapshots by parsing the plain table of snapper versions without `--jsonout`.
+    fn snapshots_plain(&self) -> Result<Vec<Snapshot>> {
+        let stdout = run_snapper(
+            self.runner.as_ref(),
+            self.privilege,
+            &["--iso", "-c", &self.config_id, "list"],
+        )?;
+
+        Ok(parse_table(&String::from_utf8_lossy(&stdout))
+            .into_iter()
+            .filter_map(|row| {
+                let snap_id = row
+                    .get("#")?
+                    .trim_end_matches(['*', '-', '+'])
+                    .parse()
+                    .ok()?;
+
+                let userdata = row
+                    .get("Userdata")
+                    .map(|userdata| {
+                        userdata
+                            .split(", ")
+                            .filter_map(|pair| {
+                                let (k, v) = pair.split_once('=')?;
+                                Some((k.to_string(), v.to_string()))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                let cleanup = row.get("Cleanup").and_then(|s| s.parse().ok());
+
+                let date = row
+                    .get("Date")
+                    .and_then(|s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok())?;
+
+                let description = row.get("Description").filter(|s| !s.is_empty()).cloned();
+
+                let snapshot =
+                    Snapshot::new(self.clone(), snap_id, userdata, cleanup, date, description);
+                Some(snapshot)
+            })
+            .collect())
+    }
+
     /// Return snapshot with `snapshot_id` if present.
     pub fn snapshot(&self, snapshot_id: u64) -> Result<Option<Snapshot>> {
         Ok(self
@@ -272,32 +383,93 @@ impl SnapperConfig {
     ///
     /// If no [SnapperCleanupAlgorithm] is provided the snapshot must be manually deleted later.
     pub fn create_snapshot(&self, cleanup: Option<SnapperCleanupAlgorithm>) -> Result<Snapshot> {
-        Ok(self
-            .create_snapshot_maybe_dry_run(cleanup, false)?
-            .expect("non dry run should create snapshot on success"))
+        self.create_snapshot_with(cleanup, None)
+    }
+
+    /// Like [create_snapshot](Self::create_snapshot), storing the `generation` of the run in
+    /// the userdata of the snapshot.
+    pub fn create_snapshot_with(
+        &self,
+        cleanup: Option<SnapperCleanupAlgorithm>,
+        generation: Option<BackupGeneration>,
+    ) -> Result<Snapshot> {
+        let mut snapper_command = self.create_command(cleanup, generation);
+        let snapper_output = self
+            .runner
+            .output(&mut snapper_command, "snapper")
+            .map_err(SnapperConfigError::SnapperNotRun)?;
+        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
+        if !snapper_output.status.success() {
+            return Err(SnapperConfigError::SnapperCommandFailed {
+                command: Box::new(snapper_command),
+                error: stderr.into(),
+            });
+        }
+        if !stderr.is_empty() {
+            tracing::warn!(target: "backend::snapper", "{stderr}" );
+        }
+
+        let stdout = String::from_utf8_lossy(&snapper_output.stdout);
+        let id = stdout.trim().parse().map_err(|_| {
+            SnapperConfigError::InvalidOutput(format!("invalid snapshot number {stdout:?}"))
+        })?;
+        tracing::info!(target: "backends::snapper::config", snapshot_id = id, "Created snapshot: {id}");
+
+        self.snapshot(id)?
+            .ok_or(SnapperConfigError::SnapshotNotFound(id))
     }
 
+    /// Log the snapshot [create_snapshot](Self::create_snapshot) would create.
     pub fn create_snapshot_dry_run(&self, cleanup: Option<SnapperCleanupAlgorithm>) -> Result<()> {
-        let res = self.create_snapshot_maybe_dry_run(cleanup, true)?;
-        assert_eq!(res, None, "dry run should not create snapshot on success");
+        self.create_snapshot_dry_run_with(cleanup, None)
+    }
+
+    /// Log the snapshot [create_snapshot_with](Self::create_snapshot_with) would create.
+    pub fn create_snapshot_dry_run_with(
+        &self,
+        cleanup: Option<SnapperCleanupAlgorithm>,
+        generation: Option<BackupGeneration>,
+    ) -> Result<()> {
+        self.create_command(cleanup, generation);
         Ok(())
     }
 
+    /// Create a new snapshot unless this is a `dry_run`.
     pub fn create_snapshot_maybe_dry_run(
         &self,
         cleanup: Option<SnapperCleanupAlgorithm>,
         dry_run: bool,
     ) -> Result<Option<Snapshot>> {
-        log::info!(target: "backends::snapper::config", "Create snapshot: {}", self.config_id);
+        if dry_run {
+            self.create_snapshot_dry_run(cleanup)?;
+            return Ok(None);
+        }
+        self.create_snapshot(cleanup).map(Some)
+    }
+
+    /// Prepare the `snapper create` command echoing the number of the new snapshot.
+    fn create_command(
+        &self,
+        cleanup: Option<SnapperCleanupAlgorithm>,
+        generation: Option<BackupGeneration>,
+    ) -> Command {
+        tracing::info!(target: "backends::snapper::config", "Create snapshot: {}", self.config_id);
+
+        let user_data = match generation {
+            Some(generation) => {
+                format!("{SNAPPER_USERDATA_TAG}=true,{SNAPPER_GENERATION_TAG}={generation}")
+            }
+            None => format!("{SNAPPER_USERDATA_TAG}=true"),
+        };
 
-        let mut snapper_command = Command::new("snapper");
+        let mut snapper_command = self.privilege.command("snapper", &[]);
         snapper_command
             .arg("-c")
             .arg(&self.config_id)
             .arg("create")
             .arg("-p") // echo snapshot id
             .arg("--userdata")
-            .arg(format!("{SNAPPER_USERDATA_TAG}=true"))
+            .arg(&user_data)
             .arg("--description")
             .arg("Full Nextcloud Backup");
 
@@ -305,52 +477,19 @@ impl SnapperConfig {
             snapper_command.arg("-c");
             snapper_command.arg(algorithm.to_string());
 
-            log::trace!(
+            tracing::trace!(
                 target: "backends::snapper::config",
-                "Running: snapper -c {} create -p  --userdata {SNAPPER_USERDATA_TAG}=true --description 'Full Nextcloud Backup' -c {algorithm}",
+                "Running: snapper -c {} create -p --userdata {user_data} --description 'Full Nextcloud Backup' -c {algorithm}",
                 self.config_id,
             );
         } else {
-            log::trace!(
+            tracing::trace!(
                 target: "backends::snapper::config",
-                "Running: snapper -c {} create -p --userdata {SNAPPER_USERDATA_TAG}=true --description 'Full Nextcloud Backup'",
+                "Running: snapper -c {} create -p --userdata {user_data} --description 'Full Nextcloud Backup'",
                 self.config_id,
             );
         }
-        if dry_run {
-            return Ok(None);
-        }
-
-        let snapper_output = snapper_command
-            .output()
-            .map_err(SnapperConfigError::SnapperNotRun)?;
-        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
-        if !snapper_output.status.success() {
-            return Err(SnapperConfigError::SnapperCommandFailed {
-                command: Box::new(snapper_command),
-                error: stderr.into(),
-            });
-        }
-        if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
-        }
 
-        let stdout = String::from_utf8_lossy(&snapper_output.stdout);
-        let stderr = String::from_utf8_lossy(&snapper_output.stderr);
-
-        if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
-        }
-
-        let id = stdout
-            .trim()
-            .parse()
-            .expect("snapper should output valid snapshot id");
-        log::info!(target: "backends::snapper::config", "Created snapshot: {id}");
-
-        Ok(Some(
-            self.snapshot(id)?
-                .expect("just created snapshot should exist"),
-        ))
+        snapper_command
     }
 }
@@ -1,57 +1,626 @@
 //! Implements backup of Nextcloud's data using [Snapper].
 
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{io, path::PathBuf};
+use std::sync::Arc;
 
+use chrono::{DateTime, Local};
 use clap::ValueEnum;
 use derive_more::{Display, Error, From};
 
-use super::Backup;
-use crate::backends::snapper::config::SNAPPER_USERDATA_TAG;
+use super::objectstore::PrimaryStorage;
+use super::{apply_retention, Artifact, Backup, BackupEntry};
+use crate::backends::snapper::config::{
+    SNAPPER_GENERATION_TAG, SNAPPER_SENDING_TAG, SNAPPER_USERDATA_TAG,
+};
+use crate::nextcloud::redis::{Redis, RedisAction, RedisError};
 use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::artifact::is_pinned;
+use crate::util::command::CommandRunner;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::privilege::Privilege;
+use crate::util::progress::Progress;
 use crate::util::retention::{Retention, RetentionConfig};
 
+mod builder;
 mod config;
+mod restore;
 mod snapshot;
+pub mod stream;
+mod version;
 
-pub use config::{SnapperConfig, SnapperConfigError};
-pub use snapshot::Snapshot;
+pub use builder::SnapperBuilder;
+pub use config::{SnapperConfig, SnapperConfigError, SNAPPER_PIN_TAG};
+pub use snapshot::{ChangedFile, Snapshot, SnapshotDiff, SnapshotMetadata};
+pub use stream::{stream_chain, stream_files, SendConfig, StreamFile};
+pub use version::SnapperVersion;
 
 /// [Snapper](http://snapper.io): A backend utilizing the btrfs snapshot capabilities.
 ///
 /// It's possible to additionally send snapshots to different locations
-/// for redundancy. See [`sync_desetionation`](Self::sync_destination) for more details.
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// for redundancy. See [`sync_destination`](SnapperBuilder::sync_destination) for more details.
+///
+/// Created by a [SnapperBuilder].
+///
+/// # Example
+///
+/// ```no_run
+/// # use nc_backup_lib::backends::snapper::{Snapper, SnapperCleanupAlgorithm};
+/// let snapper = Snapper::builder()
+///     .cleanup(Some(SnapperCleanupAlgorithm::Timeline))
+///     .sync_destination("/mnt/offsite/nextcloud".into())
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
 pub struct Snapper {
-    /// Algorithms to clean up old snapshots.
+    cleanup_algorithm: Option<SnapperCleanupAlgorithm>,
+    redis: Option<RedisAction>,
+    send: Option<SendConfig>,
+    config_id: Option<String>,
+    subvolumes: Vec<PathBuf>,
+    diff: bool,
+    create_config: bool,
+    streams: Option<PathBuf>,
+    generation: Option<BackupGeneration>,
+    privilege: Privilege,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl Snapper {
+    /// Configure a new [Snapper] backend, see [SnapperBuilder].
+    pub fn builder() -> SnapperBuilder {
+        SnapperBuilder::default()
+    }
+
+    /// The snapper config to snapshot the data directory `data_dir` with.
+    ///
+    /// This is the [configured](SnapperBuilder::config_id) one or the one [covering](SnapperConfig::by_dir) `data_dir`.
+    fn config(&self, data_dir: &Path) -> Result<Option<SnapperConfig>, SnapperBackupError> {
+        match &self.config_id {
+            Some(config_id) => {
+                match SnapperConfig::config_by_id(config_id, self.privilege, self.runner.clone())
+                    .map_err(SnapperBackupError::SnapperConfig)?
+                {
+                    Some(cfg) => Ok(Some(cfg)),
+                    None => Err(SnapperBackupError::UnknownSnapperConfig(config_id.clone())),
+                }
+            }
+            None => SnapperConfig::by_dir(data_dir, self.privilege, self.runner.clone())
+                .map_err(SnapperBackupError::SnapperConfig),
+        }
+    }
+
+    /// Summarize the changes since the previous snapshot of this backend for every
+    /// snapshot in `artifacts`, if [enabled](SnapperBuilder::diff).
     ///
-    /// Cleanups are made by *independently* of this backend by snapper itself.
-    /// For information on how to configure [Snapper] to perform periodic cleanups
-    /// consult [`snapper(8)`]
+    /// This shows what changed between backups, e.g. a user uploading large files
+    /// every night. The first snapshot of a config has nothing to compare to.
+    pub fn changes(&self, artifacts: &[Artifact]) -> Result<Vec<SnapshotDiff>, SnapperBackupError> {
+        if !self.diff {
+            return Ok(Vec::new());
+        }
+
+        let mut diffs = Vec::new();
+        for artifact in artifacts {
+            let Artifact::Snapshot { config, id } = artifact else {
+                continue;
+            };
+            let Some(cfg) =
+                SnapperConfig::config_by_id(config, self.privilege, self.runner.clone())
+                    .map_err(SnapperBackupError::SnapperConfig)?
+            else {
+                return Err(SnapperBackupError::UnknownSnapperConfig(config.clone()));
+            };
+            let snapshots: Vec<_> = cfg
+                .snapshots()
+                .map_err(SnapperBackupError::ListSnapshotsFailed)?
+                .into_iter()
+                .filter(|s| s.user_data().contains_key(SNAPPER_USERDATA_TAG) && s.id() <= *id)
+                .collect();
+            let Some(snapshot) = snapshots.iter().find(|s| s.id() == *id) else {
+                continue;
+            };
+            let Some(previous) = snapshots
+                .iter()
+                .filter(|s| s.id() < *id)
+                .max_by_key(|s| s.id())
+            else {
+                tracing::debug!(target: "backend::snapper", "No snapshot to compare snapshot {id} of {config} to");
+                continue;
+            };
+
+            let diff = previous.diff(snapshot).map_err(SnapperBackupError::Diff)?;
+            tracing::info!(
+                target: "backend::snapper",
+                "Snapshot {id} of {config}: {} files created, {} modified, {} deleted, {} bytes changed",
+                diff.created,
+                diff.modified,
+                diff.deleted,
+                diff.changed_bytes
+            );
+            diffs.push(diff);
+        }
+
+        Ok(diffs)
+    }
+
+    /// Snapper configs of the subvolumes nested in the data directory `data_dir` covered by `main`.
+    ///
+    /// These are the [configured](SnapperBuilder::subvolume) and the detected subvolumes.
+    /// Missing configs are created if [enabled](SnapperBuilder::create_config), except on a dry run.
+    fn nested_configs(
+        &self,
+        data_dir: &Path,
+        main: &SnapperConfig,
+        dry_run: bool,
+    ) -> Result<Vec<SnapperConfig>, SnapperBackupError> {
+        let mut subvolumes: Vec<_> = self.subvolumes.iter().map(|s| data_dir.join(s)).collect();
+        subvolumes.extend(nested_subvolumes(data_dir).map_err(SnapperBackupError::Preflight)?);
+        subvolumes.sort();
+        subvolumes.dedup();
+        subvolumes.retain(|subvolume| *subvolume != main.subvolume());
+        if subvolumes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let configs = SnapperConfig::all(self.privilege, self.runner.clone())
+            .map_err(SnapperBackupError::SnapperConfig)?;
+        let mut nested = Vec::new();
+        for subvolume in subvolumes {
+            match configs.iter().find(|cfg| cfg.subvolume() == subvolume) {
+                Some(cfg) => nested.push(cfg.clone()),
+                None if self.create_config => {
+                    let relative = subvolume.strip_prefix(data_dir).unwrap_or(&subvolume);
+                    let config_id = format!(
+                        "{CREATED_CONFIG}-{}",
+                        relative.to_string_lossy().replace('/', "-")
+                    );
+                    if dry_run {
+                        tracing::info!(target: "backend::snapper", "Would create snapper config {config_id} of {}", subvolume.display());
+                        continue;
+                    }
+                    check_subvolume(&subvolume)?;
+                    tracing::info!(target: "backend::snapper", "Creating snapper config {config_id} of {}", subvolume.display());
+                    nested.push(
+                        SnapperConfig::new(
+                            subvolume,
+                            config_id,
+                            self.privilege,
+                            self.runner.clone(),
+                        )
+                        .map_err(SnapperBackupError::SnapperConfig)?,
+                    );
+                }
+                None => return Err(SnapperBackupError::SnapperConfigNotFound(subvolume)),
+            }
+        }
+
+        Ok(nested)
+    }
+
+    /// Directories to write the streams of `nested` to: the backup root followed by the
+    /// additional [destinations](SendConfig::destinations).
     ///
-    /// <div class="warning">
-    /// Clean up algorithms don't distinguish between snapshots created
-    /// by this tool or by snapper itself.
-    /// </div>
+    /// Streams of the configs of nested subvolumes are written to a directory named
+    /// by the config, as every config numbers its snapshots on its own.
+    fn stream_dirs(&self, nested: Option<&SnapperConfig>) -> Vec<PathBuf> {
+        let destinations = self.send.iter().flat_map(|send| send.destinations.iter());
+        self.streams
+            .iter()
+            .chain(destinations)
+            .map(|dir| match nested {
+                Some(cfg) => dir.join(cfg.config_id()),
+                None => dir.clone(),
+            })
+            .collect()
+    }
+
+    /// Send the snapshot `id` of `cfg` to a stream file in every [stream directory](Self::stream_dirs).
     ///
-    /// [`snapper(8)`]: https://man.archlinux.org/man/snapper.8
-    pub cleanup_algorithm: Option<SnapperCleanupAlgorithm>,
+    /// Every directory has its own chain of streams. Snapshots of this backend
+    /// newer than the latest stream of a directory which couldn't be sent on
+    /// earlier runs are caught up on. The directories are written in parallel.
+    fn send_streams(
+        &self,
+        cfg: &SnapperConfig,
+        nested: bool,
+        id: u64,
+        progress: &dyn Progress,
+    ) -> Result<Vec<PathBuf>, SnapperBackupError> {
+        let Some(send) = &self.send else {
+            return Ok(Vec::new());
+        };
+
+        let mut snapshots = cfg
+            .snapshots()
+            .map_err(SnapperBackupError::ListSnapshotsFailed)?;
+        for snapshot in &mut snapshots {
+            if snapshot
+                .user_data()
+                .get(SNAPPER_SENDING_TAG)
+                .is_some_and(|v| v == "true")
+            {
+                tracing::warn!(target: "backend::snapper", "Sending snapshot {} was interrupted", snapshot.id());
+                set_sending(snapshot, false);
+            }
+        }
+
+        let mut plans = Vec::new();
+        for dir in self.stream_dirs(nested.then_some(cfg)) {
+            stream::remove_partial(&dir).map_err(SnapperBackupError::SendStream)?;
+            let streams = stream_files(&dir).map_err(SnapperBackupError::SendStream)?;
+            let pending = pending_snapshots(&streams, &snapshots, id);
+            if pending.len() > 1 {
+                tracing::warn!(target: "backend::snapper", "Catching up on {} snapshots without stream in {}", pending.len() - 1, dir.display());
+            }
+            fs::create_dir_all(&dir).map_err(SnapperBackupError::SendStream)?;
+            plans.push((dir, streams, pending));
+        }
+
+        let sending: HashSet<_> = plans
+            .iter()
+            .flat_map(|(_, _, pending)| pending.iter().copied())
+            .collect();
+        for snapshot in snapshots.iter().filter(|s| sending.contains(&s.id())) {
+            self.ensure_read_only(&snapshot.snapshot_path())?;
+        }
+        mark_sending(&mut snapshots, &sending, true);
+        progress.phase("send", None);
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = plans
+                .into_iter()
+                .map(|(dir, streams, pending)| {
+                    let snapshots = &snapshots;
+                    scope.spawn(move || {
+                        self.send_to(&dir, streams, &pending, snapshots, send, progress)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("sending thread panicked"))
+                .collect()
+        });
+        mark_sending(&mut snapshots, &sending, false);
+
+        let mut sent = Vec::new();
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(paths) => sent.extend(paths),
+                Err(e) => {
+                    tracing::error!(target: "backend::snapper", "Sending snapshots failed: {e}");
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(SnapperBackupError::SendStream(e)),
+            None => Ok(sent),
+        }
+    }
+
+    /// Make sure the snapshot at `path` is read-only, which `btrfs send` requires.
+    ///
+    /// Snapshots created writable, e.g. due to the snapper config, are made read-only.
+    fn ensure_read_only(&self, path: &Path) -> Result<(), SnapperBackupError> {
+        let btrfs_property = |args: &[&str]| -> io::Result<String> {
+            let mut cmd = self.privilege.command("btrfs", &[]);
+            cmd.args(["property", args[0], "-ts"])
+                .arg(path)
+                .args(&args[1..]);
+            let output = self.runner.output(&mut cmd, "btrfs")?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "{cmd:?} failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+
+        let ro = btrfs_property(&["get", "ro"]).map_err(SnapperBackupError::SendStream)?;
+        if ro.trim() == "ro=true" {
+            return Ok(());
+        }
+        tracing::warn!(target: "backend::snapper", "Snapshot {} is writable, making it read-only to send it", path.display());
+        btrfs_property(&["set", "ro", "true"]).map_err(|e| {
+            tracing::error!(target: "backend::snapper", "Making {} read-only failed: {e}", path.display());
+            SnapperBackupError::SnapshotNotReadOnly(path.to_path_buf())
+        })?;
+        Ok(())
+    }
+
+    /// Send the `pending` snapshots in order of their number to `dir` already containing `streams`.
+    ///
+    /// See [stream_parent] for the parent of incremental streams.
+    fn send_to(
+        &self,
+        dir: &Path,
+        mut streams: Vec<StreamFile>,
+        pending: &[u64],
+        snapshots: &[Snapshot],
+        send: &SendConfig,
+        progress: &dyn Progress,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut sent = Vec::new();
+        for &id in pending {
+            let parent = stream_parent(&streams, snapshots, id, send)
+                .map(|parent| (parent.id(), parent.snapshot_path()));
+            let stream = StreamFile::new(dir, id, parent.as_ref().map(|(id, _)| *id));
+            let snapshot = snapshots
+                .iter()
+                .find(|snapshot| snapshot.id() == id)
+                .expect("pending snapshot should be listed");
+
+            tracing::info!(target: "backend::snapper", "Send snapshot {id} to: {}", stream.path.display());
+            stream::send(
+                &snapshot.snapshot_path(),
+                parent.as_ref().map(|(_, path)| path.as_path()),
+                &stream.path,
+                self.privilege,
+                progress,
+            )?;
+
+            sent.push(stream.path.clone());
+            streams.push(stream);
+        }
+
+        Ok(sent)
+    }
+
+    /// Returns the numbers of the `streams` to keep along with the reason.
+    ///
+    /// Streams are kept by the [retention of the streams](SendConfig::retention)
+    /// if configured. Otherwise the streams of the retained `snapshots` are kept.
+    /// Pinned streams and the streams of the `pinned` snapshots are always kept.
+    fn keep_streams(
+        &self,
+        streams: &[StreamFile],
+        snapshots: &HashSet<u64>,
+        pinned: &HashSet<u64>,
+    ) -> io::Result<(HashSet<u64>, &'static str)> {
+        let (mut keep, reason) = match self.send.as_ref().and_then(|send| send.retention) {
+            Some(cfg) => (stream::retained_streams(streams, cfg)?, "stream"),
+            None => (snapshots.clone(), "snapshot"),
+        };
+        keep.extend(pinned);
+        keep.extend(
+            streams
+                .iter()
+                .filter(|stream| is_pinned(&stream.path))
+                .map(|stream| stream.id),
+        );
+
+        Ok((keep, reason))
+    }
+
+    /// List the streams in `dir` as [retain_streams](Self::retain_streams) would handle them.
+    ///
+    /// Streams are categorized as kept by their own retention respectively by the
+    /// retained `snapshots`, or as `chain` if they're only needed by another stream.
+    fn list_streams(
+        &self,
+        dir: &Path,
+        snapshots: &HashSet<u64>,
+        pinned: &HashSet<u64>,
+    ) -> io::Result<Vec<BackupEntry>> {
+        let streams = stream_files(dir)?;
+        let (keep, reason) = self.keep_streams(&streams, snapshots, pinned)?;
+        let obsolete: HashSet<_> = stream::obsolete_streams(&streams, &keep)
+            .into_iter()
+            .map(|stream| stream.id)
+            .collect();
+
+        let mut entries = Vec::new();
+        for stream in streams {
+            let metadata = fs::metadata(&stream.path)?;
+            let stream_pinned = is_pinned(&stream.path);
+            let retained_by = if obsolete.contains(&stream.id) || stream_pinned {
+                Vec::new()
+            } else if pinned.contains(&stream.id) {
+                vec!["snapshot"]
+            } else if keep.contains(&stream.id) {
+                vec![reason]
+            } else {
+                vec!["chain"]
+            };
+            entries.push(BackupEntry {
+                artifact: Artifact::File(stream.path),
+                date: DateTime::<Local>::from(metadata.modified()?).naive_local(),
+                size: Some(metadata.len()),
+                pinned: stream_pinned,
+                generation: None,
+                retained_by,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Remove the stream files in `dirs` not needed to restore any retained stream.
+    ///
+    /// The streams to keep are chosen by [keep_streams](Self::keep_streams).
+    /// Every [stream directory](Self::stream_dirs) is retained on its own.
+    fn retain_streams(
+        &self,
+        dirs: Vec<PathBuf>,
+        snapshots: &HashSet<u64>,
+        pinned: &HashSet<u64>,
+        dry_run: bool,
+    ) -> io::Result<()> {
+        for dir in dirs {
+            let streams = stream_files(&dir)?;
+            let (keep, _) = self.keep_streams(&streams, snapshots, pinned)?;
+            for stream in stream::obsolete_streams(&streams, &keep) {
+                tracing::info!(target: "backend::snapper::retain", "Discarding stream: {}", stream.path.display());
+                if !dry_run {
+                    if let Err(e) = fs::remove_file(&stream.path) {
+                        tracing::error!(target: "backend::snapper::retain", "Unable to delete stream: {e}");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the snapshots to send in order of their number next to the existing `streams`.
+///
+/// These are the snapshots of this backend newer than the latest stream.
+/// Without any stream only the new snapshot `id` is sent.
+fn pending_snapshots(streams: &[StreamFile], snapshots: &[Snapshot], id: u64) -> Vec<u64> {
+    let latest = streams.last().map(|stream| stream.id);
+    let mut pending: Vec<_> = snapshots
+        .iter()
+        .filter(|snapshot| match latest {
+            Some(latest) => {
+                snapshot.id() > latest && snapshot.user_data().contains_key(SNAPPER_USERDATA_TAG)
+            }
+            None => snapshot.id() == id,
+        })
+        .map(|snapshot| snapshot.id())
+        .collect();
+    pending.sort_unstable();
+    pending
 }
 
-impl Default for Snapper {
-    fn default() -> Self {
-        Self {
-            cleanup_algorithm: Some(Default::default()),
+/// [Mark](set_sending) all `snapshots` in `ids` as sending or remove the mark.
+fn mark_sending(snapshots: &mut [Snapshot], ids: &HashSet<u64>, sending: bool) {
+    for snapshot in snapshots {
+        if ids.contains(&snapshot.id()) {
+            set_sending(snapshot, sending);
         }
     }
 }
 
+/// Mark `snapshot` as [sending](SNAPPER_SENDING_TAG) or remove the mark.
+fn set_sending(snapshot: &mut Snapshot, sending: bool) {
+    let mut metadata = snapshot.metadata();
+    if sending {
+        metadata.set(SNAPPER_SENDING_TAG, "true");
+    } else {
+        metadata.remove(SNAPPER_SENDING_TAG);
+    }
+}
+
+/// Returns the parent of the incremental stream of the snapshot `id`.
+///
+/// This is the snapshot of the latest earlier stream if it still exists and
+/// its chain is complete and not already [too long](SendConfig::max_chain).
+/// Otherwise [None] is returned and a full stream has to be sent.
+fn stream_parent<'a>(
+    streams: &[StreamFile],
+    snapshots: &'a [Snapshot],
+    id: u64,
+    send: &SendConfig,
+) -> Option<&'a Snapshot> {
+    let latest = streams.iter().rev().find(|stream| stream.id < id)?;
+    let Some(parent) = snapshots.iter().find(|snapshot| snapshot.id() == latest.id) else {
+        tracing::debug!(target: "backend::snapper", "Snapshot {} of the latest stream is gone", latest.id);
+        return None;
+    };
+    match stream_chain(streams, latest.id) {
+        Some(chain) if chain.len() < send.max_chain as usize => Some(parent),
+        Some(_) => {
+            tracing::debug!(target: "backend::snapper", "Chain of snapshot {} reached max_chain", latest.id);
+            None
+        }
+        None => {
+            tracing::warn!(target: "backend::snapper", "Chain of snapshot {} is broken, sending a full stream", latest.id);
+            None
+        }
+    }
+}
+
+/// Name of the snapper config created with [SnapperBuilder::create_config].
+pub const CREATED_CONFIG: &str = "nextcloud-data";
+
+/// `f_type` of btrfs reported by `statfs(2)`.
+const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+/// Inode number of the root directory of every btrfs subvolume.
+const BTRFS_SUBVOLUME_INO: u64 = 256;
+
+/// Checks that snapper is able to create a config for `dir`.
+fn check_subvolume(dir: &Path) -> Result<(), SnapperBackupError> {
+    let stat = rustix::fs::statfs(dir).map_err(|e| SnapperBackupError::Preflight(e.into()))?;
+    // the width of `f_type` differs between architectures
+    if stat.f_type as u32 != BTRFS_SUPER_MAGIC {
+        return Err(SnapperBackupError::NotBtrfs(dir.to_path_buf()));
+    }
+    let ino = fs::metadata(dir)
+        .map_err(SnapperBackupError::Preflight)?
+        .ino();
+    if ino != BTRFS_SUBVOLUME_INO {
+        return Err(SnapperBackupError::NotSubvolume(dir.to_path_buf()));
+    }
+
+    Ok(())
+}
+
+/// Returns the directories below `dir` which are btrfs subvolumes of their own.
+///
+/// Snapshots of a subvolume don't contain the subvolumes nested in it.
+/// The `.snapshots` directories of snapper are skipped.
+fn nested_subvolumes(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    // the width of `f_type` differs between architectures
+    if rustix::fs::statfs(dir)?.f_type as u32 != BTRFS_SUPER_MAGIC {
+        return Ok(Vec::new());
+    }
+
+    let mut nested = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::debug!(target: "backend::snapper", "Skipping {} looking for subvolumes: {e}", dir.display());
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || entry.file_name() == ".snapshots" {
+                continue;
+            }
+            let path = entry.path();
+            if entry.metadata()?.ino() == BTRFS_SUBVOLUME_INO {
+                tracing::debug!(target: "backend::snapper", "Found nested subvolume: {}", path.display());
+                nested.push(path.clone());
+            }
+            pending.push(path);
+        }
+    }
+
+    Ok(nested)
+}
+
 #[derive(Debug, Display, Error, From)]
 /// Errors on backup of the data directory of the [Nextcloud] installation.
 pub enum SnapperBackupError {
+    /// The data directory of [Nextcloud] doesn't exist.
+    #[display("Data directory {} not found", _0.display())]
+    DataDirNotFound(#[error(ignore)] PathBuf),
     /// No Snapper config for the data directory of [Nextcloud] found.
-    #[display("Snapper config not found")]
+    #[display("No snapper config covers {}, create one or pass --snapper-create-config", _0.display())]
     SnapperConfigNotFound(#[error(ignore)] PathBuf),
+    /// The [configured](SnapperBuilder::config_id) snapper config doesn't exist.
+    #[display("Snapper config {_0} not found")]
+    UnknownSnapperConfig(#[error(ignore)] String),
+    /// The data directory isn't on btrfs, hence snapper can't snapshot it.
+    #[display("Data directory {} isn't on btrfs, snapper requires a btrfs subvolume. Move the data directory to btrfs or disable the snapper backend", _0.display())]
+    NotBtrfs(#[error(ignore)] PathBuf),
+    /// The data directory is on btrfs but isn't a subvolume of its own.
+    #[display("Data directory {} isn't a btrfs subvolume, snapper requires one. Convert it using `btrfs subvolume create`", _0.display())]
+    NotSubvolume(#[error(ignore)] PathBuf),
     /// Sync destination can't be created.
     #[display("Unable to create sync destination folder")]
     SyncDestinationCantBeCreated(io::Error),
@@ -65,6 +634,51 @@ pub enum SnapperBackupError {
     #[display("Listing snapshots failed: {_0}")]
     ListSnapshotsFailed(SnapperConfigError),
 
+    /// Preflight check of the data directory failed.
+    #[display("Preflight check failed: {_0}")]
+    Preflight(io::Error),
+
+    /// Running commands with root privileges isn't possible without interaction.
+    #[display("Privilege escalation failed: {_0}")]
+    Privilege(io::Error),
+
+    /// Comparing snapshots failed.
+    #[display("Comparing snapshots failed: {_0}")]
+    Diff(SnapperConfigError),
+
+    /// The snapshot to send is writable and couldn't be made read-only.
+    #[display("Snapshot {} isn't read-only, which btrfs send requires, and couldn't be made read-only", _0.display())]
+    SnapshotNotReadOnly(#[error(ignore)] PathBuf),
+
+    /// Writing the `btrfs send` stream of the snapshot failed.
+    #[display("Sending the snapshot to a file failed: {_0}")]
+    SendStream(io::Error),
+
+    /// The path to restore isn't relative to the data directory.
+    #[display("Path {} to restore must be relative to the data directory", _0.display())]
+    InvalidRestorePath(#[error(ignore)] PathBuf),
+
+    /// The snapshot to restore from is neither present nor in the streams.
+    #[display("Snapshot {_1} of snapper config {_0} not found, neither locally nor as stream")]
+    SnapshotNotFound(#[error(ignore)] String, #[error(ignore)] u64),
+
+    /// The path to restore doesn't exist in the snapshot.
+    #[display("{} not found in the snapshot", _0.display())]
+    RestorePathNotFound(#[error(ignore)] PathBuf),
+
+    /// Receiving the snapshot or copying files out of it failed.
+    #[display("Restoring from the snapshot failed: {_0}")]
+    Restore(io::Error),
+
+    /// The backend is configured inconsistently.
+    #[display("Invalid snapper configuration: {_0}")]
+    InvalidConfig(#[error(ignore)] String),
+
+    /// Preparing the Redis locking state failed.
+    #[display("Preparing Redis failed: {_0}")]
+    #[from]
+    Redis(RedisError),
+
     /// Nextcloud `occ` command failed.
     #[from]
     Occ(OccError),
@@ -73,24 +687,163 @@ pub enum SnapperBackupError {
 impl Backup for Snapper {
     type Error = SnapperBackupError;
 
-    fn backup(&self, nextcloud: &Nextcloud, dry_run: bool) -> Result<(), Self::Error> {
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
         let data_dir = nextcloud.occ().data_directory()?;
-        assert!(data_dir.is_dir(), "Nextcloud Data directory should exist");
+        if !data_dir.is_dir() {
+            return Err(SnapperBackupError::DataDirNotFound(data_dir));
+        }
 
-        let cfg = SnapperConfig::by_dir(&data_dir)
-            .map_err(SnapperBackupError::SnapperConfig)?
-            .ok_or(SnapperBackupError::SnapperConfigNotFound(data_dir))?;
+        if PrimaryStorage::detect(nextcloud.occ())?.is_some() {
+            tracing::warn!(
+                target: "backend::snapper",
+                "Nextcloud stores the user files in an object store, the snapshot of {} doesn't contain them! Enable the objectstore backend.",
+                data_dir.display()
+            );
+        }
+
+        let cfg = match self.config(&data_dir)? {
+            Some(cfg) => cfg,
+            None if self.create_config => {
+                check_subvolume(&data_dir)?;
+                if dry_run {
+                    tracing::info!(target: "backend::snapper", "Would create snapper config {CREATED_CONFIG} of {}", data_dir.display());
+                    return Ok(Vec::new());
+                }
+                tracing::info!(target: "backend::snapper", "Creating snapper config {CREATED_CONFIG} of {}", data_dir.display());
+                SnapperConfig::new(
+                    data_dir.clone(),
+                    CREATED_CONFIG.to_string(),
+                    self.privilege,
+                    self.runner.clone(),
+                )
+                .map_err(SnapperBackupError::SnapperConfig)?
+            }
+            None => {
+                if let Err(e) = check_subvolume(&data_dir) {
+                    tracing::error!(target: "backend::snapper", "{e}");
+                }
+                return Err(SnapperBackupError::SnapperConfigNotFound(data_dir));
+            }
+        };
+
+        if cfg.subvolume() != data_dir {
+            tracing::debug!(target: "backend::snapper", "Data directory {} is covered by snapper config {} of {}", data_dir.display(), cfg.config_id(), cfg.subvolume().display());
+            // nested subvolumes aren't part of the snapshots of the outer one
+            if check_subvolume(&data_dir).is_ok() {
+                tracing::warn!(target: "backend::snapper", "Data directory {} is a subvolume of its own, the snapshots of {} don't contain it! Create a snapper config of the data directory.", data_dir.display(), cfg.subvolume().display());
+            }
+        }
+
+        let nested = self.nested_configs(&data_dir, &cfg, dry_run)?;
+
+        if let Some(action) = self.redis {
+            match Redis::locking(nextcloud.occ())? {
+                Some(redis) => redis.apply(action, dry_run)?,
+                None => {
+                    tracing::debug!(target: "backend::snapper", "File locking doesn't use Redis")
+                }
+            }
+        }
 
+        progress.phase("snapshot", None);
         if dry_run {
-            cfg.create_snapshot_dry_run(self.cleanup_algorithm)
-                .map_err(SnapperBackupError::CreationFailed)?;
-        } else {
-            let _snapshot = cfg
-                .create_snapshot(self.cleanup_algorithm)
+            for cfg in std::iter::once(&cfg).chain(&nested) {
+                cfg.create_snapshot_dry_run_with(self.cleanup_algorithm, self.generation)
+                    .map_err(SnapperBackupError::CreationFailed)?;
+            }
+            return Ok(Vec::new());
+        }
+
+        // the snapshot of the data directory comes first, followed by the nested subvolumes
+        let mut artifacts = Vec::new();
+        for cfg in std::iter::once(&cfg).chain(&nested) {
+            let snapshot = cfg
+                .create_snapshot_with(self.cleanup_algorithm, self.generation)
                 .map_err(SnapperBackupError::CreationFailed)?;
+            artifacts.push(Artifact::Snapshot {
+                config: cfg.config_id().to_string(),
+                id: snapshot.id(),
+            });
         }
 
-        Ok(())
+        Ok(artifacts)
+    }
+
+    /// Sends the created snapshots to the stream directories outside of maintenance mode.
+    fn finalize(
+        &self,
+        _nextcloud: &Nextcloud,
+        mut artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        if self.send.is_none() {
+            return Ok(artifacts);
+        }
+        let snapshots: Vec<_> = artifacts
+            .iter()
+            .filter_map(|artifact| match artifact {
+                Artifact::Snapshot { config, id } => Some((config.clone(), *id)),
+                Artifact::File(..) | Artifact::Directory(..) | Artifact::ZfsSnapshot(..) => None,
+            })
+            .collect();
+
+        for (i, (config, id)) in snapshots.into_iter().enumerate() {
+            let Some(cfg) =
+                SnapperConfig::config_by_id(&config, self.privilege, self.runner.clone())
+                    .map_err(SnapperBackupError::SnapperConfig)?
+            else {
+                return Err(SnapperBackupError::UnknownSnapperConfig(config));
+            };
+            // all but the snapshot of the data directory are of nested subvolumes
+            let streams = self.send_streams(&cfg, i > 0, id, progress)?;
+            artifacts.extend(streams.into_iter().map(Artifact::File));
+        }
+
+        Ok(artifacts)
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        // snapshots share their data with the subvolume but still need room for metadata
+        let data_dir = nextcloud.occ().data_directory()?;
+        ensure_space(&data_dir, 0).map_err(SnapperBackupError::Preflight)?;
+        self.privilege
+            .check()
+            .map_err(SnapperBackupError::Privilege)?;
+
+        match self.config(&data_dir)? {
+            Some(cfg) => self.nested_configs(&data_dir, &cfg, true).map(drop),
+            None if self.create_config => check_subvolume(&data_dir),
+            None => Err(SnapperBackupError::SnapperConfigNotFound(data_dir)),
+        }
+    }
+
+    fn list(
+        &self,
+        nextcloud: &Nextcloud,
+        retention_cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let data_dir = nextcloud.occ().data_directory()?;
+        let cfg = match self.config(&data_dir)? {
+            Some(cfg) => Some(cfg),
+            None if self.create_config => None,
+            None => return Err(SnapperBackupError::SnapperConfigNotFound(data_dir)),
+        };
+
+        let mut entries = self.list_config(cfg.as_ref(), self.stream_dirs(None), retention_cfg)?;
+        if let Some(cfg) = &cfg {
+            for nested in self.nested_configs(&data_dir, cfg, true)? {
+                let stream_dirs = self.stream_dirs(Some(&nested));
+                entries.extend(self.list_config(Some(&nested), stream_dirs, retention_cfg)?);
+            }
+        }
+
+        Ok(entries)
     }
 
     fn retention(
@@ -100,10 +853,95 @@ impl Backup for Snapper {
         dry_run: bool,
     ) -> Result<(), Self::Error> {
         let data_dir = nextcloud.occ().data_directory()?;
-        let cfg = SnapperConfig::by_dir(&data_dir)
-            .map_err(SnapperBackupError::SnapperConfig)?
-            .ok_or(SnapperBackupError::SnapperConfigNotFound(data_dir))?;
+        let cfg = match self.config(&data_dir)? {
+            Some(cfg) => cfg,
+            // the config isn't created during dry runs, thus there are no snapshots yet
+            None if self.create_config && dry_run => return Ok(()),
+            None => return Err(SnapperBackupError::SnapperConfigNotFound(data_dir)),
+        };
 
+        // configs of nested subvolumes aren't created by the retention
+        let nested = self.nested_configs(&data_dir, &cfg, true)?;
+        self.retain_config(&cfg, self.stream_dirs(None), retention_cfg, dry_run)?;
+        for nested in nested {
+            let stream_dirs = self.stream_dirs(Some(&nested));
+            self.retain_config(&nested, stream_dirs, retention_cfg, dry_run)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapper {
+    /// List the snapshots of `cfg` and their streams in `stream_dirs` as [Backup::list] does.
+    fn list_config(
+        &self,
+        cfg: Option<&SnapperConfig>,
+        stream_dirs: Vec<PathBuf>,
+        retention_cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, SnapperBackupError> {
+        let mut entries = match cfg {
+            Some(cfg) => cfg
+                .snapshots()
+                .map_err(SnapperBackupError::ListSnapshotsFailed)?
+                .into_iter()
+                .filter(|s| s.user_data().contains_key(SNAPPER_USERDATA_TAG))
+                .map(|s| BackupEntry {
+                    artifact: Artifact::Snapshot {
+                        config: cfg.config_id().to_string(),
+                        id: s.id(),
+                    },
+                    date: *s.date(),
+                    size: None,
+                    pinned: s
+                        .user_data()
+                        .get(SNAPPER_PIN_TAG)
+                        .is_some_and(|v| v == "true"),
+                    generation: s
+                        .user_data()
+                        .get(SNAPPER_GENERATION_TAG)
+                        .and_then(|generation| generation.parse().ok()),
+                    retained_by: Vec::new(),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        apply_retention(&mut entries, *retention_cfg);
+
+        let kept: HashSet<_> = entries
+            .iter()
+            .filter_map(|entry| match entry.artifact {
+                Artifact::Snapshot { id, .. } if entry.pinned || !entry.retained_by.is_empty() => {
+                    Some(id)
+                }
+                _ => None,
+            })
+            .collect();
+        let pinned: HashSet<_> = entries
+            .iter()
+            .filter_map(|entry| match entry.artifact {
+                Artifact::Snapshot { id, .. } if entry.pinned => Some(id),
+                _ => None,
+            })
+            .collect();
+        for dir in stream_dirs {
+            entries.extend(
+                self.list_streams(&dir, &kept, &pinned)
+                    .map_err(SnapperBackupError::SendStream)?,
+            );
+        }
+
+        Ok(entries)
+    }
+
+    /// Apply the retention to the snapshots of `cfg` and their streams in `stream_dirs`.
+    fn retain_config(
+        &self,
+        cfg: &SnapperConfig,
+        stream_dirs: Vec<PathBuf>,
+        retention_cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), SnapperBackupError> {
         let mut snapshots: Vec<_> = cfg
             .snapshots()
             .map_err(SnapperBackupError::ListSnapshotsFailed)?
@@ -114,23 +952,37 @@ impl Backup for Snapper {
         snapshots.sort_by(|s1, s2| s1.date().cmp(s2.date()).reverse());
 
         let mut retention = Retention::from(*retention_cfg);
+        let mut kept = HashSet::new();
+        let mut pinned = HashSet::new();
         for snapshot in snapshots {
+            if snapshot
+                .user_data()
+                .get(SNAPPER_PIN_TAG)
+                .is_some_and(|v| v == "true")
+            {
+                tracing::debug!(target: "backend::snapper::retain", "Snapshot pinned: {}", snapshot.id());
+                kept.insert(snapshot.id());
+                pinned.insert(snapshot.id());
+                continue;
+            }
             if retention.retain(*snapshot.date()) {
-                log::debug!(target: "backend::config::retain", "Snapshot retained: {}", snapshot.id());
+                tracing::debug!(target: "backend::snapper::retain", "Snapshot retained: {}", snapshot.id());
+                kept.insert(snapshot.id());
                 continue;
             }
 
-            log::info!(target: "backend::config::retain", "Discarding snapshot: {}", snapshot.id());
+            tracing::info!(target: "backend::snapper::retain", "Discarding snapshot: {}", snapshot.id());
             if dry_run {
                 if let Err(e) = snapshot.delete_dry_run() {
-                    log::error!(target: "backend::config::retain", "Error deleting snapshot: {e}");
+                    tracing::error!(target: "backend::snapper::retain", "Error deleting snapshot: {e}");
                 }
             } else if let Err(e) = snapshot.delete() {
-                log::error!(target: "backend::config::retain", "Error deleting snapshot: {e}");
+                tracing::error!(target: "backend::snapper::retain", "Error deleting snapshot: {e}");
             }
         }
 
-        Ok(())
+        self.retain_streams(stream_dirs, &kept, &pinned, dry_run)
+            .map_err(SnapperBackupError::SendStream)
     }
 }
 
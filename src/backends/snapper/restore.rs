@@ -0,0 +1,201 @@
+//! Restoring single files and directories from the snapshots of [Snapper].
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use super::stream::{self, stream_chain, stream_files};
+use super::{Snapper, SnapperBackupError, SnapperConfig};
+use crate::nextcloud::Nextcloud;
+use crate::util::archive::{chown_tree, copy_tree, Excludes};
+use crate::util::progress::Progress;
+
+/// Directory in the snapshotted subvolume the streams of snapshots gone locally are received to.
+const RECEIVE_DIR: &str = ".nc_backup-restore";
+
+impl Snapper {
+    /// Restore the file or directory `path` of the data directory from the snapshot `id`.
+    ///
+    /// `path` is relative to the data directory, e.g. `alice/files/Photos`. The snapshot
+    /// is one of the config covering `path`, i.e. of the [nested](super::SnapperBuilder::subvolume)
+    /// subvolume if `path` is in one. Snapshots gone locally are received from their
+    /// [streams](super::SnapperBuilder::send) and deleted again afterwards.
+    ///
+    /// The files are copied to `target` or, if unset, back to `path` replacing files
+    /// of the same name. Files restored to the data directory are owned by the owner
+    /// of the data directory and rescanned by Nextcloud unless maintenance mode is on.
+    ///
+    /// Returns the restored path.
+    pub fn restore_path(
+        &self,
+        nextcloud: &Nextcloud,
+        id: u64,
+        path: &Path,
+        target: Option<&Path>,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<PathBuf, SnapperBackupError> {
+        let relative = !path.as_os_str().is_empty()
+            && path.components().all(|c| matches!(c, Component::Normal(_)));
+        if !relative {
+            return Err(SnapperBackupError::InvalidRestorePath(path.to_path_buf()));
+        }
+
+        let data_dir = nextcloud.occ().data_directory()?;
+        let Some(main) = self.config(&data_dir)? else {
+            return Err(SnapperBackupError::SnapperConfigNotFound(data_dir));
+        };
+        let live = data_dir.join(path);
+        let nested = self
+            .nested_configs(&data_dir, &main, true)?
+            .into_iter()
+            .filter(|cfg| live.starts_with(cfg.subvolume()))
+            .max_by_key(|cfg| cfg.subvolume().components().count());
+        let cfg = nested.as_ref().unwrap_or(&main);
+        let Ok(in_snapshot) = live.strip_prefix(cfg.subvolume()) else {
+            return Err(SnapperBackupError::SnapperConfigNotFound(live));
+        };
+        let dest = target.map_or_else(|| live.clone(), Path::to_path_buf);
+
+        let snapshot = cfg
+            .snapshot(id)
+            .map_err(SnapperBackupError::ListSnapshotsFailed)?;
+        let received = match &snapshot {
+            Some(_) => None,
+            None => Some(self.receive_snapshot(cfg, nested.is_some(), id, dry_run)?),
+        };
+        if dry_run {
+            tracing::info!(target: "backend::snapper::restore", "Would restore {} from snapshot {id} of {} to {}", path.display(), cfg.config_id(), dest.display());
+            return Ok(dest);
+        }
+        let root = match (&snapshot, &received) {
+            (Some(snapshot), _) => snapshot.snapshot_path(),
+            (None, Some(received)) => received.join(id.to_string()).join("snapshot"),
+            (None, None) => unreachable!("snapshot should be local or received"),
+        };
+
+        let restored = self.copy_from(&root.join(in_snapshot), &dest, progress);
+        if let Some(received) = &received {
+            if let Err(e) = self.remove_received(received) {
+                tracing::error!(target: "backend::snapper::restore", "Unable to delete the received snapshots in {}: {e}", received.display());
+            }
+        }
+        restored?;
+
+        if target.is_none() {
+            let owner = fs::metadata(&data_dir).map_err(SnapperBackupError::Restore)?;
+            chown_tree(&dest, owner.uid(), owner.gid()).map_err(SnapperBackupError::Restore)?;
+            let occ = nextcloud.occ();
+            if occ.maintenance()? {
+                tracing::warn!(target: "backend::snapper::restore", "Maintenance mode is on, disable it and run occ files:scan --path=/{}", path.display());
+            } else {
+                occ.scan_path(path)?;
+            }
+        }
+
+        Ok(dest)
+    }
+
+    /// Copy the file or directory `source` of a snapshot to `dest`.
+    fn copy_from(
+        &self,
+        source: &Path,
+        dest: &Path,
+        progress: &dyn Progress,
+    ) -> Result<(), SnapperBackupError> {
+        if fs::symlink_metadata(source).is_err() {
+            return Err(SnapperBackupError::RestorePathNotFound(
+                source.to_path_buf(),
+            ));
+        }
+
+        tracing::info!(target: "backend::snapper::restore", "Restore {} to {}", source.display(), dest.display());
+        progress.phase("restore", None);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(SnapperBackupError::Restore)?;
+        }
+        let stats = copy_tree(source, dest, None, &Excludes::default(), false, progress)
+            .map_err(SnapperBackupError::Restore)?;
+        tracing::info!(target: "backend::snapper::restore", "Restored {} bytes", stats.copied);
+
+        Ok(())
+    }
+
+    /// Receive the snapshot `id` of `cfg` from the first [stream directory](Self::stream_dirs)
+    /// with a complete chain of streams.
+    ///
+    /// Every stream of the chain is received to a directory named by its snapshot below the
+    /// returned directory in the subvolume of `cfg`, as `btrfs receive` requires a btrfs target.
+    fn receive_snapshot(
+        &self,
+        cfg: &SnapperConfig,
+        nested: bool,
+        id: u64,
+        dry_run: bool,
+    ) -> Result<PathBuf, SnapperBackupError> {
+        let mut chain = None;
+        for dir in self.stream_dirs(nested.then_some(cfg)) {
+            let streams = stream_files(&dir).map_err(SnapperBackupError::Restore)?;
+            chain = stream_chain(&streams, id);
+            if chain.is_some() {
+                break;
+            }
+        }
+        let Some(chain) = chain else {
+            return Err(SnapperBackupError::SnapshotNotFound(
+                cfg.config_id().to_string(),
+                id,
+            ));
+        };
+
+        let received = cfg.subvolume().join(RECEIVE_DIR);
+        if dry_run {
+            for stream in &chain {
+                tracing::info!(target: "backend::snapper::restore", "Would receive {}", stream.path.display());
+            }
+            return Ok(received);
+        }
+        if fs::exists(&received).map_err(SnapperBackupError::Restore)? {
+            tracing::warn!(target: "backend::snapper::restore", "Removing snapshots received by an interrupted restore: {}", received.display());
+            self.remove_received(&received)
+                .map_err(SnapperBackupError::Restore)?;
+        }
+
+        for stream in &chain {
+            tracing::info!(target: "backend::snapper::restore", "Receive {}", stream.path.display());
+            let dir = received.join(stream.id.to_string());
+            let result = fs::create_dir_all(&dir)
+                .and_then(|()| stream::receive(&stream.path, &dir, self.privilege));
+            if let Err(e) = result {
+                if let Err(e) = self.remove_received(&received) {
+                    tracing::error!(target: "backend::snapper::restore", "Unable to delete the received snapshots in {}: {e}", received.display());
+                }
+                return Err(SnapperBackupError::Restore(e));
+            }
+        }
+
+        Ok(received)
+    }
+
+    /// Delete the snapshots received by [receive_snapshot](Self::receive_snapshot) to `received`.
+    fn remove_received(&self, received: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(received)? {
+            let snapshot = entry?.path().join("snapshot");
+            if !fs::exists(&snapshot)? {
+                continue;
+            }
+            let mut cmd = self.privilege.command("btrfs", &[]);
+            cmd.args(["subvolume", "delete"]).arg(&snapshot);
+            let output = self.runner.output(&mut cmd, "btrfs")?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "{cmd:?} failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
+        fs::remove_dir_all(received)
+    }
+}
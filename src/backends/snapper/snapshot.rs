@@ -1,17 +1,20 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fs,
     hash::Hash,
-    ops::{Deref, DerefMut},
-    path::PathBuf,
-    process::Command,
+    path::{Path, PathBuf},
 };
 
 use chrono::NaiveDateTime;
 
 use crate::backends::snapper::SnapperConfigError;
 
+use super::config::run_snapper;
 use super::{SnapperCleanupAlgorithm, SnapperConfig};
 
+/// Number of the largest changed files listed in a [SnapshotDiff].
+const DIFF_LARGEST: usize = 10;
+
 /// A snapshot created by snapper.
 #[derive(Debug)]
 pub struct Snapshot {
@@ -67,34 +70,123 @@ impl Snapshot {
     }
 
     /// Path to the snapshot.
-    fn snapshot_path(&self) -> PathBuf {
+    pub fn snapshot_path(&self) -> PathBuf {
         self.config
             .subvolume()
             .join(format!(".snapshots/{}/snapshot", self.id))
     }
 }
 
+/// Summary of the files changed between two snapshots, see [Snapshot::diff].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotDiff {
+    /// Id of the snapper config.
+    pub config: String,
+    /// Number of the older snapshot.
+    pub from: u64,
+    /// Number of the newer snapshot.
+    pub to: u64,
+    /// Number of files created.
+    pub created: usize,
+    /// Number of files whose content, type or metadata changed.
+    pub modified: usize,
+    /// Number of files deleted.
+    pub deleted: usize,
+    /// Total size in bytes of the created and modified files in the newer snapshot.
+    pub changed_bytes: u64,
+    /// Largest created or modified files, largest first.
+    pub largest: Vec<ChangedFile>,
+}
+
+/// File created or modified between two snapshots.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChangedFile {
+    /// Path of the file in the subvolume.
+    pub path: PathBuf,
+    /// Size in bytes in the newer snapshot.
+    pub size: u64,
+}
+
+impl Snapshot {
+    /// Summarize the files changed from this snapshot to the newer snapshot `other`.
+    ///
+    /// Uses `snapper status`, which compares the whole trees of both snapshots.
+    /// Sizes of files not readable in `other` aren't counted.
+    pub fn diff(&self, other: &Snapshot) -> Result<SnapshotDiff, SnapperConfigError> {
+        let range = format!("{}..{}", self.id, other.id);
+        let stdout = run_snapper(
+            self.config.runner.as_ref(),
+            self.config.privilege,
+            &["-c", &self.config.config_id, "status", &range],
+        )?;
+
+        let mut diff = SnapshotDiff {
+            config: self.config.config_id.clone(),
+            from: self.id,
+            to: other.id,
+            ..Default::default()
+        };
+        let mut changed = Vec::new();
+        for line in String::from_utf8_lossy(&stdout).lines() {
+            // e.g. `c..... /srv/nextcloud/alice/files/notes.md`
+            let Some((status, path)) = line.split_once(' ') else {
+                continue;
+            };
+            let path = Path::new(path);
+            match status.chars().next() {
+                Some('+') => diff.created += 1,
+                Some('-') => {
+                    diff.deleted += 1;
+                    continue;
+                }
+                Some(_) => diff.modified += 1,
+                None => continue,
+            }
+
+            let relative = path.strip_prefix(&self.config.subvolume).unwrap_or(path);
+            let relative = relative.strip_prefix("/").unwrap_or(relative);
+            let Ok(metadata) = fs::symlink_metadata(other.snapshot_path().join(relative)) else {
+                continue;
+            };
+            if metadata.is_file() {
+                diff.changed_bytes += metadata.len();
+                changed.push(ChangedFile {
+                    path: path.to_path_buf(),
+                    size: metadata.len(),
+                });
+            }
+        }
+        changed.sort_by_key(|file| std::cmp::Reverse(file.size));
+        changed.truncate(DIFF_LARGEST);
+        diff.largest = changed;
+
+        Ok(diff)
+    }
+}
+
 // snapshot manipulation
 impl Snapshot {
-    fn update(&mut self) {
-        // FIXME: cover deletion of keys
+    /// Write the metadata of the snapshot removing the user data keys `removed`.
+    fn update(&mut self, removed: &HashSet<String>) -> Result<(), SnapperConfigError> {
+        // snapper deletes keys assigned an empty value
         let user_data = self
             .user_data()
             .iter()
             .map(|(k, v)| format!("{k}={v}"))
+            .chain(removed.iter().map(|k| format!("{k}=")))
             .collect::<Vec<_>>()
             .join(",");
         let cleanup = self.cleanup.map(|c| c.to_string()).unwrap_or_default();
 
         if let Some(description) = &self.description {
-            log::trace!(
+            tracing::trace!(
                 target: "backend::snapper::snapshot",
                 "Running: snapper --jsonout -c {} modify -u {user_data} -c {cleanup} -d {description} {}",
                 self.config.config_id(),
                 self.id
             );
         } else {
-            log::trace!(
+            tracing::trace!(
                 target: "backend::snapper::snapshot",
                 "Running: snapper --jsonout -c {} modify -u {user_data} -c {cleanup} {}",
                 self.config.config_id(),
@@ -102,7 +194,7 @@ impl Snapshot {
             );
         }
 
-        let mut snapper_cmd = Command::new("snapper");
+        let mut snapper_cmd = self.config.privilege.command("snapper", &[]);
         snapper_cmd
             .arg("--jsonout")
             .arg("-c")
@@ -118,24 +210,37 @@ impl Snapshot {
             snapper_cmd.arg("-d").arg(description);
         }
 
-        let snapper_output = snapper_cmd
-            .output()
-            .expect("Failed to execute snapper command");
+        let snapper_output = self
+            .config
+            .runner
+            .output(&mut snapper_cmd, "snapper")
+            .map_err(SnapperConfigError::SnapperNotRun)?;
+        if !snapper_output.status.success() {
+            return Err(SnapperConfigError::SnapperCommandFailed {
+                command: Box::new(snapper_cmd),
+                error: String::from_utf8_lossy(&snapper_output.stderr).into(),
+            });
+        }
 
-        log::debug!(target: "backend::snapper::snapshot", "Updated snapshot meta data: {self:?}");
-        assert!(snapper_output.status.success());
+        tracing::debug!(target: "backend::snapper::snapshot", "Updated snapshot meta data: {self:?}");
+        Ok(())
     }
 
     /// Set the cleanup algorithm.
-    pub fn set_cleanup(&mut self, cleanup_algorithm: Option<SnapperCleanupAlgorithm>) {
-        self.cleanup = cleanup_algorithm;
-        self.update();
+    pub fn set_cleanup(
+        &mut self,
+        cleanup_algorithm: Option<SnapperCleanupAlgorithm>,
+    ) -> Result<(), SnapperConfigError> {
+        let mut metadata = self.metadata();
+        metadata.cleanup(cleanup_algorithm);
+        metadata.flush()
     }
 
     /// Set the description.
-    pub fn set_description(&mut self, description: String) {
-        self.description = Some(description);
-        self.update();
+    pub fn set_description(&mut self, description: String) -> Result<(), SnapperConfigError> {
+        let mut metadata = self.metadata();
+        metadata.description(description);
+        metadata.flush()
     }
 
     /// Returns a map of the user data saved in the [Snapshot].
@@ -143,9 +248,15 @@ impl Snapshot {
         &self.user_data
     }
 
-    /// Returns a mutable map of the user data saved in the [Snapshot].
-    pub fn user_data_mut<'a>(&'a mut self) -> UserData<'a> {
-        UserData { inner: self }
+    /// Edit the metadata of the [Snapshot].
+    ///
+    /// The changes are written by a single snapper call, see [SnapshotMetadata].
+    pub fn metadata(&mut self) -> SnapshotMetadata<'_> {
+        SnapshotMetadata {
+            snapshot: self,
+            removed: HashSet::new(),
+            dirty: false,
+        }
     }
 
     pub fn delete(self) -> Result<(), SnapperConfigError> {
@@ -156,14 +267,14 @@ impl Snapshot {
     }
 
     fn delete_maybe_dry_run(self, dry_run: bool) -> Result<(), SnapperConfigError> {
-        let mut snapper_command = Command::new("snapper");
+        let mut snapper_command = self.config.privilege.command("snapper", &[]);
         snapper_command
             .arg("-c")
             .arg(&self.config.config_id)
             .arg("delete")
             .arg(format!("{}", self.id));
 
-        log::trace!(
+        tracing::trace!(
             target: "backends::snapper::config",
             "Running: snapper -c {} remove {}",
             self.id,
@@ -173,8 +284,10 @@ impl Snapshot {
             return Ok(());
         }
 
-        let snapper_output = snapper_command
-            .output()
+        let snapper_output = self
+            .config
+            .runner
+            .output(&mut snapper_command, "snapper")
             .map_err(SnapperConfigError::SnapperNotRun)?;
         let stderr = String::from_utf8_lossy(&snapper_output.stderr);
         if !snapper_output.status.success() {
@@ -184,38 +297,85 @@ impl Snapshot {
             });
         }
         if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
+            tracing::warn!(target: "backend::snapper", "{stderr}" );
         }
 
         let stderr = String::from_utf8_lossy(&snapper_output.stderr);
 
         if !stderr.is_empty() {
-            log::warn!(target: "backend::snapper", "{stderr}" );
+            tracing::warn!(target: "backend::snapper", "{stderr}" );
         }
         Ok(())
     }
 }
 
-pub struct UserData<'a> {
-    inner: &'a mut Snapshot,
+/// Batched changes to the metadata of a [Snapshot].
+///
+/// The changes are written once the guard is [flushed](Self::flush) or
+/// dropped. Failures on drop are only logged.
+pub struct SnapshotMetadata<'a> {
+    snapshot: &'a mut Snapshot,
+    removed: HashSet<String>,
+    dirty: bool,
 }
 
-impl<'a> Deref for UserData<'a> {
-    type Target = HashMap<String, String>;
+impl SnapshotMetadata<'_> {
+    /// Set the user data `key` to `value`.
+    ///
+    /// An empty `value` removes the key.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let (key, value) = (key.into(), value.into());
+        if value.is_empty() {
+            return self.remove(&key);
+        }
+        self.removed.remove(&key);
+        self.snapshot.user_data.insert(key, value);
+        self.dirty = true;
+        self
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner.user_data
+    /// Remove the user data `key`.
+    pub fn remove(&mut self, key: &str) -> &mut Self {
+        if self.snapshot.user_data.remove(key).is_some() {
+            self.removed.insert(key.to_string());
+            self.dirty = true;
+        }
+        self
     }
-}
 
-impl<'a> DerefMut for UserData<'a> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner.user_data
+    /// Set the cleanup algorithm.
+    pub fn cleanup(&mut self, cleanup_algorithm: Option<SnapperCleanupAlgorithm>) -> &mut Self {
+        self.snapshot.cleanup = cleanup_algorithm;
+        self.dirty = true;
+        self
+    }
+
+    /// Set the description.
+    pub fn description(&mut self, description: String) -> &mut Self {
+        self.snapshot.description = Some(description);
+        self.dirty = true;
+        self
+    }
+
+    /// Write the changes.
+    pub fn flush(mut self) -> Result<(), SnapperConfigError> {
+        self.write()
+    }
+
+    fn write(&mut self) -> Result<(), SnapperConfigError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+        let removed = std::mem::take(&mut self.removed);
+        self.snapshot.update(&removed)
     }
 }
 
-impl<'a> Drop for UserData<'a> {
+impl Drop for SnapshotMetadata<'_> {
     fn drop(&mut self) {
-        self.inner.update()
+        if let Err(e) = self.write() {
+            tracing::error!(target: "backend::snapper::snapshot", "Updating the metadata of snapshot {} failed: {e}", self.snapshot.id);
+        }
     }
 }
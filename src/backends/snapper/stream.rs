@@ -0,0 +1,327 @@
+//! Archiving snapshots as compressed `btrfs send` streams.
+//!
+//! Streams are written to `snapshot-<id>.btrfs.zst` if they're full and to
+//! `snapshot-<id>-from-<parent>.btrfs.zst` if they're incremental to the
+//! snapshot `parent`. Use [stream_chain] to find the streams to receive in order
+//! to restore a snapshot.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+
+use chrono::{DateTime, Local};
+
+use crate::util::command::{self, Watchdog};
+use crate::util::privilege::Privilege;
+use crate::util::progress::{Progress, ProgressReader};
+use crate::util::retention::{Retention, RetentionConfig};
+
+/// Directory of the streams in the backup root.
+pub(super) const STREAMS_DEST: &str = "snapper/";
+const STREAM_PREFIX: &str = "snapshot-";
+const STREAM_SUFFIX: &str = ".btrfs.zst";
+const INCREMENTAL_INFIX: &str = "-from-";
+/// Suffix of streams which are still being written.
+const PART_SUFFIX: &str = ".part";
+
+/// Configuration of sending snapshots of the [Snapper](super::Snapper) backend to files.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SendConfig {
+    /// Maximum number of streams needed to restore a snapshot.
+    ///
+    /// Once the chain of incremental streams reaches this length a full stream is sent.
+    /// A value of `1` sends only full streams.
+    pub max_chain: u32,
+
+    /// Retention of the streams independent of the snapshots.
+    ///
+    /// If unset, streams are kept as long as a retained snapshot depends on them.
+    /// The latest stream is always kept as parent of the next incremental stream.
+    pub retention: Option<RetentionConfig>,
+
+    /// Additional directories to write the streams to, e.g. an offsite mount.
+    ///
+    /// Every directory keeps its own chain of streams next to the one in the backup root.
+    pub destinations: Vec<PathBuf>,
+}
+
+impl Default for SendConfig {
+    fn default() -> Self {
+        Self {
+            max_chain: 7,
+            retention: None,
+            destinations: Vec::new(),
+        }
+    }
+}
+
+/// A `btrfs send` stream of a snapshot written to a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamFile {
+    /// Path to the stream.
+    pub path: PathBuf,
+    /// Number of the snapshot.
+    pub id: u64,
+    /// Number of the parent snapshot if the stream is incremental.
+    pub parent: Option<u64>,
+}
+
+impl StreamFile {
+    /// Returns the stream file of the snapshot `id` in `dir`.
+    pub fn new(dir: &Path, id: u64, parent: Option<u64>) -> Self {
+        let name = match parent {
+            Some(parent) => {
+                format!("{STREAM_PREFIX}{id}{INCREMENTAL_INFIX}{parent}{STREAM_SUFFIX}")
+            }
+            None => format!("{STREAM_PREFIX}{id}{STREAM_SUFFIX}"),
+        };
+        Self {
+            path: dir.join(name),
+            id,
+            parent,
+        }
+    }
+
+    /// Parses the file name of a stream file at `path`.
+    fn parse(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let ids = name
+            .strip_prefix(STREAM_PREFIX)?
+            .strip_suffix(STREAM_SUFFIX)?;
+        let (id, parent) = match ids.split_once(INCREMENTAL_INFIX) {
+            Some((id, parent)) => (id.parse().ok()?, Some(parent.parse().ok()?)),
+            None => (ids.parse().ok()?, None),
+        };
+
+        Some(Self { path, id, parent })
+    }
+}
+
+/// Returns all stream files in `dir` ordered by snapshot number.
+///
+/// A missing `dir` contains no streams.
+pub fn stream_files(dir: &Path) -> io::Result<Vec<StreamFile>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut streams = Vec::new();
+    for entry in entries {
+        if let Some(stream) = StreamFile::parse(entry?.path()) {
+            streams.push(stream);
+        }
+    }
+    streams.sort_by_key(|stream| stream.id);
+
+    Ok(streams)
+}
+
+/// Returns the streams to receive in order to restore the snapshot `id`.
+///
+/// The chain starts with a full stream followed by the incremental streams.
+/// Returns [None] if a stream of the chain is missing.
+pub fn stream_chain(streams: &[StreamFile], id: u64) -> Option<Vec<StreamFile>> {
+    let mut chain = Vec::new();
+    let mut next = Some(id);
+    while let Some(id) = next {
+        // a snapshot is only sent once, guard against loops of foreign files anyway
+        if chain.len() > streams.len() {
+            return None;
+        }
+        let stream = streams.iter().find(|stream| stream.id == id)?;
+        chain.push(stream.clone());
+        next = stream.parent;
+    }
+    chain.reverse();
+
+    Some(chain)
+}
+
+/// Returns the snapshot numbers of the streams retained by `cfg`.
+///
+/// Streams are dated by the time they were written. The latest stream is always retained.
+pub(super) fn retained_streams(
+    streams: &[StreamFile],
+    cfg: RetentionConfig,
+) -> io::Result<HashSet<u64>> {
+    let mut dated = Vec::new();
+    for stream in streams {
+        let written = fs::metadata(&stream.path)?.modified()?;
+        dated.push((DateTime::<Local>::from(written).naive_local(), stream.id));
+    }
+    // keep the most recent streams of each kind
+    dated.sort_by(|a, b| b.cmp(a));
+
+    let mut keep: HashSet<_> = streams.last().map(|stream| stream.id).into_iter().collect();
+    let mut retention = Retention::from(cfg);
+    for (date, id) in dated {
+        if retention.retain(date) {
+            keep.insert(id);
+        }
+    }
+
+    Ok(keep)
+}
+
+/// Returns the streams not needed to restore any of the snapshots `keep`.
+pub(super) fn obsolete_streams(streams: &[StreamFile], keep: &HashSet<u64>) -> Vec<StreamFile> {
+    let needed: HashSet<_> = keep
+        .iter()
+        .filter_map(|id| stream_chain(streams, *id))
+        .flatten()
+        .map(|stream| stream.id)
+        .collect();
+
+    streams
+        .iter()
+        .filter(|stream| !needed.contains(&stream.id))
+        .cloned()
+        .collect()
+}
+
+/// Returns the path of the incomplete stream `dest` while it's written.
+fn part_path(dest: &Path) -> PathBuf {
+    let mut part = OsString::from(dest);
+    part.push(PART_SUFFIX);
+    PathBuf::from(part)
+}
+
+/// Removes the incomplete streams left behind by interrupted sends in `dir`.
+pub(super) fn remove_partial(dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        let is_partial = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(STREAM_PREFIX) && name.ends_with(PART_SUFFIX));
+        if is_partial {
+            tracing::warn!(target: "backend::snapper::send", "Removing incomplete stream of interrupted send: {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_status(command: &str, status: ExitStatus) -> io::Result<()> {
+    if !status.success() {
+        return Err(io::Error::other(format!("{command} failed: {status}")));
+    }
+    Ok(())
+}
+
+/// Writes the `btrfs send` stream of the read-only `snapshot` compressed by `zstd` to `dest`.
+///
+/// `btrfs send` is run using `privilege`. If `parent` is given the stream is incremental to it. The stream is written
+/// to a `.part` file first and only moved to `dest` once it's complete.
+pub(super) fn send(
+    snapshot: &Path,
+    parent: Option<&Path>,
+    dest: &Path,
+    privilege: Privilege,
+    progress: &dyn Progress,
+) -> io::Result<()> {
+    let part = part_path(dest);
+
+    let mut send_cmd = privilege.command("btrfs", &[]);
+    send_cmd.args(["send", "-q"]);
+    if let Some(parent) = parent {
+        send_cmd.arg("-p").arg(parent);
+    }
+    send_cmd.arg(snapshot).stdout(Stdio::piped());
+    tracing::trace!(target: "backend::snapper::send", "Running: {send_cmd:?} | zstd -q -f -T0 -o {}", part.display());
+
+    let mut send = send_cmd.spawn()?;
+    let mut zstd = match command::new("zstd")
+        .args(["-q", "-f", "-T0", "-o"])
+        .arg(&part)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(zstd) => zstd,
+        Err(e) => {
+            let _ = send.kill();
+            let _ = send.wait();
+            return Err(e);
+        }
+    };
+
+    let watchdog = Watchdog::start("btrfs", &[send.id(), zstd.id()]);
+    let stdout = send.stdout.take().expect("stdout should be piped");
+    let mut stdin = zstd.stdin.take().expect("stdin should be piped");
+    let copied = io::copy(&mut ProgressReader::new(stdout, progress), &mut stdin);
+    drop(stdin);
+    if copied.is_err() {
+        let _ = send.kill();
+    }
+    let send_status = send.wait();
+    let zstd_status = zstd.wait();
+
+    let written = watchdog
+        .stop()
+        .map_err(io::Error::from)
+        .and(copied)
+        .and_then(|_| check_status("btrfs send", send_status?))
+        .and_then(|()| check_status("zstd", zstd_status?))
+        .and_then(|()| File::open(&part)?.sync_all())
+        .and_then(|()| fs::rename(&part, dest));
+    written.inspect_err(|_| {
+        let _ = fs::remove_file(&part);
+    })
+}
+
+/// Receives the `btrfs send` stream file `stream` compressed by `zstd` into `dir`.
+///
+/// `btrfs receive` is run using `privilege` and creates the snapshot as a read-only
+/// subvolume in `dir`. The parent of an incremental stream has to be received
+/// on the same file system before.
+pub(super) fn receive(stream: &Path, dir: &Path, privilege: Privilege) -> io::Result<()> {
+    let mut zstd_cmd = command::new("zstd");
+    zstd_cmd
+        .args(["-q", "-d", "-c"])
+        .arg(stream)
+        .stdout(Stdio::piped());
+    let mut receive_cmd = privilege.command("btrfs", &[]);
+    receive_cmd.arg("receive").arg(dir).stdin(Stdio::piped());
+    tracing::trace!(target: "backend::snapper::send", "Running: {zstd_cmd:?} | {receive_cmd:?}");
+
+    let mut zstd = zstd_cmd.spawn()?;
+    let mut receive = match receive_cmd.spawn() {
+        Ok(receive) => receive,
+        Err(e) => {
+            let _ = zstd.kill();
+            let _ = zstd.wait();
+            return Err(e);
+        }
+    };
+
+    let watchdog = Watchdog::start("btrfs", &[zstd.id(), receive.id()]);
+    let mut stdout = zstd.stdout.take().expect("stdout should be piped");
+    let mut stdin = receive.stdin.take().expect("stdin should be piped");
+    let copied = io::copy(&mut stdout, &mut stdin);
+    drop(stdin);
+    if copied.is_err() {
+        let _ = zstd.kill();
+    }
+    let zstd_status = zstd.wait();
+    let receive_status = receive.wait();
+
+    watchdog
+        .stop()
+        .map_err(io::Error::from)
+        .and(copied)
+        .and_then(|_| check_status("zstd", zstd_status?))
+        .and_then(|()| check_status("btrfs receive", receive_status?))
+}
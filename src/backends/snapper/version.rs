@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use crate::util::command;
+
+/// First snapper version supporting `--jsonout` on all used subcommands.
+const JSONOUT_SINCE: SnapperVersion = SnapperVersion(0, 8, 10);
+
+/// Version of the installed snapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapperVersion(pub u32, pub u32, pub u32);
+
+impl fmt::Display for SnapperVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+impl SnapperVersion {
+    /// Detect the version of the installed snapper.
+    ///
+    /// The version is detected once using `snapper --version`. If detection
+    /// fails a recent version is assumed.
+    pub fn detect() -> Self {
+        static VERSION: OnceLock<SnapperVersion> = OnceLock::new();
+
+        *VERSION.get_or_init(|| {
+            tracing::trace!(target: "backends::snapper::version", "Running: snapper --version");
+            let version = command::output(Command::new("snapper").arg("--version"), "snapper")
+                .ok()
+                .and_then(|output| Self::parse(&String::from_utf8_lossy(&output.stdout)));
+
+            match version {
+                Some(version) => {
+                    tracing::debug!(target: "backends::snapper::version", "Detected snapper {version}");
+                    version
+                }
+                None => {
+                    tracing::warn!(target: "backends::snapper::version", "Snapper version couldn't be detected, assuming {JSONOUT_SINCE}");
+                    JSONOUT_SINCE
+                }
+            }
+        })
+    }
+
+    /// Parse the output of `snapper --version`, e.g. `snapper 0.10.6`.
+    fn parse(version: &str) -> Option<Self> {
+        let version = version.lines().next()?.strip_prefix("snapper ")?;
+        let mut parts = version.trim().split('.').map(|part| part.parse().ok());
+
+        Some(Self(
+            parts.next()??,
+            parts.next()??,
+            parts.next().flatten().unwrap_or(0),
+        ))
+    }
+
+    /// Whether snapper supports `--jsonout`.
+    pub fn supports_jsonout(&self) -> bool {
+        *self >= JSONOUT_SINCE
+    }
+}
+
+/// Parse a plain table as printed by snapper without `--jsonout`.
+///
+/// Returns one map from column header to value per row. Both ASCII (`|`)
+/// and box-drawing (`│`) column separators are supported.
+pub(super) fn parse_table(table: &str) -> Vec<HashMap<String, String>> {
+    let is_separator = |c: char| c == '|' || c == '│';
+    let is_rule = |line: &str| {
+        line.chars()
+            .all(|c| matches!(c, '-' | '+' | '─' | '┼' | ' '))
+    };
+
+    let mut lines = table.lines().filter(|line| !line.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+    let header: Vec<_> = header.split(is_separator).map(str::trim).collect();
+
+    lines
+        .filter(|line| !is_rule(line))
+        .map(|line| {
+            header
+                .iter()
+                .zip(line.split(is_separator).map(str::trim))
+                .map(|(column, value)| (column.to_string(), value.to_string()))
+                .collect()
+        })
+        .collect()
+}
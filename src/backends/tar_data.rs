@@ -0,0 +1,497 @@
+//! Implements incremental backup of the data directory using [TarData].
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+use flate2::read::MultiGzDecoder;
+
+use crate::backends::{apply_retention, list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{tree_size, unpack_tree, write_zstd_tarball, Excludes};
+use crate::util::artifact::{
+    base_path, discard_artifact, index_path, partition_retained, remove_artifact, write_artifact,
+    ArtifactDir,
+};
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::glob::Glob;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+use super::data::default_excludes;
+
+const TAR_DATA_BACKUP_DEST: &str = "tar-data/";
+const TAR_DATA_PREFIX: &str = "data-";
+const TAR_DATA_SUFFIX: &str = ".tar.zst";
+/// Suffix of [incremental backups](TarDataConfig::full_every).
+const TAR_DATA_INCR_SUFFIX: &str = ".incr.tar.zst";
+
+/// Configuration of [TarData].
+///
+/// ```toml
+/// [tar_data]
+/// full_every = 7
+/// level = 3
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TarDataConfig {
+    /// Archive all files only every this many backups.
+    ///
+    /// The backups in between only contain the files changed since the previous
+    /// backup. `1` archives all files every time.
+    pub full_every: NonZeroU32,
+
+    /// Patterns of the paths not to archive, relative to the data directory.
+    ///
+    /// Defaults to the [DEFAULT_EXCLUDES](super::data::DEFAULT_EXCLUDES) of the [Data](super::Data) backend.
+    pub excludes: Vec<Glob>,
+
+    /// Zstd compression level from `1` (fastest) to `19` (best).
+    pub level: i32,
+}
+
+impl Default for TarDataConfig {
+    fn default() -> Self {
+        Self {
+            full_every: NonZeroU32::new(7).expect("7 should be non-zero"),
+            excludes: default_excludes(),
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+/// Size and times of a regular file as recorded in the [FileIndex].
+///
+/// A file whose stamp differs from the one of the previous backup is archived again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileStamp {
+    /// Size in bytes.
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch.
+    pub mtime: i64,
+    /// Status change time in nanoseconds since the epoch, which catches renames
+    /// and files restored with an old modification time.
+    pub ctime: i64,
+}
+
+impl From<&Metadata> for FileStamp {
+    fn from(metadata: &Metadata) -> Self {
+        let nanos =
+            |secs: i64, nsecs: i64| secs.saturating_mul(1_000_000_000).saturating_add(nsecs);
+        Self {
+            size: metadata.len(),
+            mtime: nanos(metadata.mtime(), metadata.mtime_nsec()),
+            ctime: nanos(metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+/// Regular files of the tree at the time of a [TarData] backup.
+///
+/// It's stored gzip compressed next to the backup, see [index_path].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileIndex {
+    /// Every regular file by its absolute path.
+    pub files: BTreeMap<PathBuf, FileStamp>,
+    /// Regular files of the previous backup which no longer existed.
+    ///
+    /// Restoring an incremental backup removes them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<PathBuf>,
+}
+
+/// The [TarData] backend archives the data directory to zstd compressed tarballs.
+///
+/// It's meant for file systems without snapshots, where the [Snapper](super::Snapper)
+/// backend isn't available. Only every [full_every](TarDataConfig::full_every)-th
+/// backup contains all files, the others only the files changed since the previous
+/// backup according to its [FileIndex].
+#[derive(Debug)]
+pub struct TarData {
+    full_backups: ArtifactDir,
+    incr_backups: ArtifactDir,
+    config: TarDataConfig,
+    retry: RetryConfig,
+}
+
+/// Error of the [TarData] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum TarDataError {
+    /// The data directory couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+    /// Archiving the data directory or writing its index failed.
+    Io(io::Error),
+}
+
+impl TarData {
+    pub fn new(backup_root: &Path) -> Self {
+        let dir = backup_root.join(TAR_DATA_BACKUP_DEST);
+        Self {
+            full_backups: ArtifactDir::new(dir.clone(), TAR_DATA_PREFIX, TAR_DATA_SUFFIX),
+            incr_backups: ArtifactDir::new(dir, TAR_DATA_PREFIX, TAR_DATA_INCR_SUFFIX),
+            config: TarDataConfig::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Archive the data directory as configured by [TarDataConfig].
+    pub fn config(mut self, config: TarDataConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.full_backups = self.full_backups.with_retry(retry.clone());
+        self.incr_backups = self.incr_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.full_backups = self.full_backups.with_clock(clock);
+        self.incr_backups = self.incr_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.full_backups = self.full_backups.with_generation(generation);
+        self.incr_backups = self.incr_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old backups to the cold storage configured by [TieringConfig].
+    ///
+    /// Only done if every backup is full, as incremental backups need the
+    /// backups they're based on next to them.
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.full_backups = self.full_backups.with_cold_tier(
+            tiering.destination.join(TAR_DATA_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Whether incremental backups are created.
+    fn incremental(&self) -> bool {
+        self.config.full_every.get() > 1
+    }
+
+    /// Returns the backup the next backup should be based on along with its index.
+    ///
+    /// Returns [None] if incremental backups are disabled or the next backup has to be full.
+    fn incr_base(&self) -> io::Result<Option<(PathBuf, FileIndex)>> {
+        if !self.incremental() {
+            return Ok(None);
+        }
+        // full backups moved to the cold tier are no longer next to new incremental ones
+        let Some((full, full_date)) = self
+            .full_backups
+            .artifacts()?
+            .into_iter()
+            .find(|(path, _)| path.parent() == Some(self.full_backups.dir()))
+        else {
+            return Ok(None);
+        };
+        let incrs: Vec<_> = self
+            .incr_backups
+            .artifacts()?
+            .into_iter()
+            .filter(|(_, date)| *date > full_date)
+            .collect();
+        if incrs.len() + 1 >= self.config.full_every.get() as usize {
+            return Ok(None);
+        }
+
+        // the artifacts are sorted from the most recent one
+        let base = incrs.into_iter().next().map_or(full, |(path, _)| path);
+        match read_index(&base) {
+            Ok(index) => Ok(Some((base, index))),
+            Err(e) => {
+                tracing::warn!(target: "backend::tar_data", "Index of {} is unreadable, creating a full backup: {e}", base.display());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the data directory and the paths excluded from its archive.
+    fn data_dir(&self, nextcloud: &Nextcloud) -> Result<(PathBuf, Excludes), OccError> {
+        let data_directory = nextcloud.occ().data_directory()?;
+        // don't archive the backups if they're stored in the data directory
+        let mut excludes = Excludes::paths(&[self.full_backups.dir().to_path_buf()]);
+        for glob in &self.config.excludes {
+            excludes = excludes.glob(&data_directory, glob.clone());
+        }
+        Ok((data_directory, excludes))
+    }
+}
+
+/// Reads the [FileIndex] of `backup`.
+pub fn read_index(backup: &Path) -> io::Result<FileIndex> {
+    let reader = MultiGzDecoder::new(File::open(index_path(backup))?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Writes the *new* [FileIndex] of `backup`.
+fn write_index(backup: &Path, index: &FileIndex) -> io::Result<()> {
+    write_artifact(&index_path(backup), |partial| {
+        let mut encoder = CompressionConfig::default().encoder(File::create_new(partial)?);
+        serde_json::to_writer(&mut encoder, index)?;
+        encoder.finish()?.sync_all()
+    })
+}
+
+/// Returns the backup the incremental backup `incr` is based on.
+///
+/// Returns [None] for full backups. The base is expected next to `incr`.
+pub fn incr_base(incr: &Path) -> io::Result<Option<PathBuf>> {
+    if !incr.to_string_lossy().ends_with(TAR_DATA_INCR_SUFFIX) {
+        return Ok(None);
+    }
+    let name = fs::read_to_string(base_path(incr))?;
+    let dir = incr.parent().unwrap_or(Path::new("."));
+    Ok(Some(dir.join(name.trim())))
+}
+
+/// Returns the backups required to restore `backup` in the order they're extracted.
+///
+/// The chain starts with a full backup and ends with `backup`.
+pub fn restore_chain(backup: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut chain = vec![backup.to_path_buf()];
+    while let Some(base) = incr_base(chain.last().expect("chain should not be empty"))? {
+        if chain.contains(&base) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cyclic incremental backups at {}", base.display()),
+            ));
+        }
+        chain.push(base);
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Extracts `backup` along with the backups it's based on into `dest`.
+///
+/// Files deleted between the backups are removed again, so `dest` ends up
+/// with the tree at the time of `backup`.
+pub fn extract(backup: &Path, dest: &Path) -> io::Result<()> {
+    for backup in restore_chain(backup)? {
+        tracing::info!(target: "backend::tar_data", "Extract backup: {}", backup.display());
+        let decoder = zstd::Decoder::new(File::open(&backup)?)?;
+        let mut archive = tar::Archive::new(decoder);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.unpack(dest)?;
+
+        for deleted in read_index(&backup)?.deleted {
+            let path = dest.join(deleted.strip_prefix("/").unwrap_or(&deleted));
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Like [extract], but extracts the archived data directory itself into `data_dir`.
+///
+/// This restores the data directory to another path than it was archived from.
+pub fn extract_data_dir(backup: &Path, data_dir: &Path) -> io::Result<()> {
+    for backup in restore_chain(backup)? {
+        tracing::info!(target: "backend::tar_data", "Extract backup: {}", backup.display());
+        let decoder = zstd::Decoder::new(File::open(&backup)?)?;
+        let Some(root) = unpack_tree(&mut tar::Archive::new(decoder), data_dir)? else {
+            continue;
+        };
+
+        for deleted in read_index(&backup)?.deleted {
+            let Ok(relative) = deleted.strip_prefix(&root) else {
+                continue;
+            };
+            match fs::remove_file(data_dir.join(relative)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Backups the incremental backups among `backups` are based on, directly or indirectly.
+fn incr_bases<'a>(backups: impl IntoIterator<Item = &'a PathBuf>) -> HashSet<PathBuf> {
+    let mut bases = HashSet::new();
+    for backup in backups {
+        match restore_chain(backup) {
+            Ok(chain) => bases.extend(chain.into_iter().filter(|path| path != backup)),
+            Err(e) => {
+                tracing::warn!(target: "backend::tar_data", "Base of {} is unknown: {e}", backup.display());
+            }
+        }
+    }
+    bases
+}
+
+impl Backup for TarData {
+    type Error = TarDataError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let (data_directory, excludes) = self.data_dir(nextcloud)?;
+        let base = self.incr_base()?;
+        let backups = match &base {
+            Some((base, _)) => {
+                tracing::info!(target: "backend::tar_data", "Create incremental backup of Nextcloud data based on: {}", base.display());
+                &self.incr_backups
+            }
+            None => {
+                tracing::info!(target: "backend::tar_data", "Create full backup of Nextcloud data: {}", data_directory.display());
+                &self.full_backups
+            }
+        };
+
+        retry_io(&self.retry, "Creating data backup directory", || {
+            fs::create_dir_all(backups.dir())
+        })?;
+        let backup_file = backups.generate_filename();
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::tar_data", "Backup Nextcloud data to: {}", backup_file.display());
+        let mut index = FileIndex::default();
+        let mut changed = 0_usize;
+        retry_io(&self.retry, "Writing data backup", || {
+            index = FileIndex::default();
+            changed = 0;
+            write_zstd_tarball(
+                &backup_file,
+                std::slice::from_ref(&data_directory),
+                &excludes,
+                self.config.level,
+                dry_run,
+                progress,
+                &mut |path, metadata| {
+                    let stamp = FileStamp::from(metadata);
+                    index.files.insert(path.to_path_buf(), stamp);
+                    let include = base
+                        .as_ref()
+                        .is_none_or(|(_, base)| base.files.get(path) != Some(&stamp));
+                    changed += usize::from(include);
+                    include
+                },
+            )
+        })?;
+        if let Some((_, base)) = &base {
+            index.deleted = base
+                .files
+                .keys()
+                .filter(|path| !index.files.contains_key(*path))
+                .cloned()
+                .collect();
+        }
+        tracing::info!(target: "backend::tar_data", "Archived {changed} of {} files, {} deleted", index.files.len(), index.deleted.len());
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        let write_sidecars = || {
+            if let Some((base, _)) = &base {
+                let name = base.file_name().expect("backup should have a file name");
+                fs::write(base_path(&backup_file), name.as_encoded_bytes())?;
+            }
+            write_index(&backup_file, &index)
+        };
+        if let Err(e) = retry_io(&self.retry, "Writing data backup index", write_sidecars) {
+            let _ = remove_artifact(&backup_file);
+            return Err(e.into());
+        }
+        tracing::info!(target: "backend::tar_data", "Finished backup of Nextcloud data");
+
+        Ok(vec![
+            Artifact::File(backup_file.clone()),
+            Artifact::File(index_path(&backup_file)),
+        ])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        // a full backup may be due
+        let (data_directory, excludes) = self.data_dir(nextcloud)?;
+        let data_size = tree_size(std::slice::from_ref(&data_directory), &excludes)?;
+        Ok(ensure_space(self.full_backups.dir(), data_size)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let mut entries = list_artifacts(&self.full_backups, cfg)?;
+        entries.extend(list_artifacts(&self.incr_backups, cfg)?);
+        apply_retention(&mut entries, *cfg);
+
+        let bases = incr_bases(entries.iter().filter_map(|entry| match &entry.artifact {
+            Artifact::File(path) if entry.pinned || !entry.retained_by.is_empty() => Some(path),
+            _ => None,
+        }));
+        for entry in &mut entries {
+            if let Artifact::File(path) = &entry.artifact {
+                if entry.retained_by.is_empty() && bases.contains(path) {
+                    entry.retained_by.push("base");
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        self.full_backups.remove_partials(dry_run)?;
+        self.incr_backups.remove_partials(dry_run)?;
+        let mut backups = self.full_backups.artifacts()?;
+        backups.extend(self.incr_backups.artifacts()?);
+        backups.sort_by_key(|(_, date)| std::cmp::Reverse(*date));
+        if backups.is_empty() {
+            tracing::debug!(target: "backend::tar_data::retain", "No backups found. Nothing to retain.");
+            return Ok(());
+        }
+
+        let (kept, discarded) = partition_retained(backups, cfg, "backend::tar_data::retain");
+        let bases = incr_bases(&kept);
+        for path in discarded {
+            if bases.contains(&path) {
+                tracing::debug!(target: "backend::tar_data::retain", "Backup retained as base of an incremental backup: {}", path.display());
+                continue;
+            }
+
+            discard_artifact(&path, dry_run, &self.retry, "backend::tar_data::retain");
+        }
+
+        if self.incremental() {
+            // incremental backups need their bases next to them
+            return Ok(());
+        }
+        Ok(self.full_backups.tier(dry_run)?)
+    }
+}
@@ -0,0 +1,152 @@
+//! Implements export of Nextcloud's users and groups using [Users].
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError, UserInfo};
+use crate::util::artifact::{write_artifact, ArtifactDir};
+use crate::util::clock::Clock;
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const USERS_BACKUP_DEST: &str = "users/";
+const USERS_PREFIX: &str = "users-";
+const USERS_SUFFIX: &str = ".json";
+
+/// Users and groups as written by the [Users] backend.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UserExport {
+    /// Accounts of the users by their id, including their groups, quota and storage usage.
+    pub users: BTreeMap<String, UserInfo>,
+    /// Members of the groups by the group id.
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// The [Users] backend exports the users and groups of Nextcloud to JSON.
+///
+/// The users are contained in the database dump as well, but the export is
+/// readable without restoring the database, e.g. for audits or to restore the
+/// files of single users.
+#[derive(Debug)]
+pub struct Users {
+    user_backups: ArtifactDir,
+    retry: RetryConfig,
+}
+
+/// Error of the [Users] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum UsersError {
+    /// The users or groups couldn't be listed.
+    #[display("Listing the users failed: {_0}")]
+    Occ(OccError),
+    /// Writing the export failed.
+    Io(io::Error),
+}
+
+impl Users {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            user_backups: ArtifactDir::new(
+                backup_root.join(USERS_BACKUP_DEST),
+                USERS_PREFIX,
+                USERS_SUFFIX,
+            ),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Retry writing exports on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.user_backups = self.user_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new exports using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.user_backups = self.user_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new exports after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.user_backups = self.user_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old exports to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.user_backups = self.user_backups.with_cold_tier(
+            tiering.destination.join(USERS_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+}
+
+impl Backup for Users {
+    type Error = UsersError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        progress.phase("list", None);
+        let export = UserExport {
+            users: nextcloud.occ().user_list()?,
+            groups: nextcloud.occ().group_list()?,
+        };
+        tracing::info!(target: "backend::users", "Export {} users and {} groups", export.users.len(), export.groups.len());
+        if dry_run {
+            return Ok(Vec::new());
+        }
+
+        retry_io(&self.retry, "Creating user export directory", || {
+            fs::create_dir_all(self.user_backups.dir())
+        })?;
+        let users_backup_file = self.user_backups.generate_filename();
+        tracing::debug!(target: "backend::users", "Export users to: {}", users_backup_file.display());
+        let content = serde_json::to_vec_pretty(&export).map_err(io::Error::from)?;
+        retry_io(&self.retry, "Writing user export", || {
+            write_artifact(&users_backup_file, |partial| fs::write(partial, &content))
+        })?;
+        tracing::info!(target: "backend::users", "Finished export of users");
+
+        Ok(vec![Artifact::File(users_backup_file)])
+    }
+
+    /// The users are listed at once and only meant as a readable copy.
+    fn requires_maintenance(&self) -> bool {
+        false
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.user_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .user_backups
+            .retain(cfg, dry_run, "backend::users::retain")?)
+    }
+}
@@ -0,0 +1,196 @@
+//! Implements backup of Nextcloud's document root using [Webroot].
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::TimeDelta;
+use derive_more::{Display, Error, From};
+
+use crate::backends::{list_artifacts, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::archive::{tree_size, write_tarball, Excludes};
+use crate::util::artifact::ArtifactDir;
+use crate::util::clock::Clock;
+use crate::util::compress::CompressionConfig;
+use crate::util::fs::ensure_space;
+use crate::util::generation::BackupGeneration;
+use crate::util::progress::Progress;
+use crate::util::retention::RetentionConfig;
+use crate::util::retry::{retry_io, RetryConfig};
+use crate::util::tiering::TieringConfig;
+
+const WEBROOT_BACKUP_DEST: &str = "webroot/";
+const WEBROOT_PREFIX: &str = "webroot-";
+const WEBROOT_SUFFIX: &str = ".tar.gz";
+
+/// Configuration of [Webroot].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WebrootConfig {
+    /// Paths not to archive.
+    ///
+    /// Relative paths are resolved against the document root. The data directory
+    /// of Nextcloud is always excluded. `config/config.php` is excluded by default
+    /// as it contains the database password and is backed up masked by [Config](super::Config).
+    pub excludes: Vec<PathBuf>,
+
+    /// Compression of the archive.
+    pub compression: CompressionConfig,
+}
+
+impl Default for WebrootConfig {
+    fn default() -> Self {
+        Self {
+            excludes: vec!["data".into(), "config/config.php".into()],
+            compression: CompressionConfig::default(),
+        }
+    }
+}
+
+/// The [Webroot] backend archives Nextcloud's document root.
+///
+/// This captures the exact installed version including custom themes and
+/// `.htaccess`, so a restore doesn't need to download the release.
+#[derive(Debug)]
+pub struct Webroot {
+    webroot_backups: ArtifactDir,
+    config: WebrootConfig,
+    retry: RetryConfig,
+}
+
+/// Error of the [Webroot] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum WebrootError {
+    /// The data directory couldn't be determined.
+    #[display("Reading the data directory failed: {_0}")]
+    Occ(OccError),
+    /// Archiving the document root failed.
+    Io(io::Error),
+}
+
+impl Webroot {
+    pub fn new(backup_root: &Path) -> Self {
+        Self {
+            webroot_backups: ArtifactDir::new(
+                backup_root.join(WEBROOT_BACKUP_DEST),
+                WEBROOT_PREFIX,
+                WEBROOT_SUFFIX,
+            ),
+            config: WebrootConfig::default(),
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// Archive the document root as configured by [WebrootConfig].
+    pub fn config(mut self, config: WebrootConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Retry writing backups on transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.webroot_backups = self.webroot_backups.with_retry(retry.clone());
+        self.retry = retry;
+        self
+    }
+
+    /// Timestamp new webroot backups using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.webroot_backups = self.webroot_backups.with_clock(clock);
+        self
+    }
+
+    /// Name new webroot backups after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.webroot_backups = self.webroot_backups.with_generation(generation);
+        self
+    }
+
+    /// Move old webroot backups to the cold storage configured by [TieringConfig].
+    pub fn tiering(mut self, tiering: &TieringConfig) -> Self {
+        self.webroot_backups = self.webroot_backups.with_cold_tier(
+            tiering.destination.join(WEBROOT_BACKUP_DEST),
+            TimeDelta::days(tiering.after_days.into()),
+        );
+        self
+    }
+
+    /// Returns the absolute paths excluded from the archive.
+    fn excludes(&self, nextcloud: &Nextcloud) -> Result<Excludes, OccError> {
+        let document_root = nextcloud.document_root();
+        let mut excludes: Vec<_> = self
+            .config
+            .excludes
+            .iter()
+            .map(|exclude| document_root.join(exclude))
+            .collect();
+        excludes.push(nextcloud.occ().data_directory()?);
+        // don't archive the backups if they're stored in the document root
+        excludes.push(self.webroot_backups.dir().to_path_buf());
+
+        Ok(Excludes::paths(&excludes))
+    }
+}
+
+impl Backup for Webroot {
+    type Error = WebrootError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let document_root = vec![nextcloud.document_root().to_path_buf()];
+        let excludes = self.excludes(nextcloud)?;
+        tracing::info!(target: "backend::webroot", "Create backup of Nextcloud document root: {}", document_root[0].display());
+
+        retry_io(&self.retry, "Creating webroot backup directory", || {
+            std::fs::create_dir_all(self.webroot_backups.dir())
+        })?;
+        let webroot_backup_file = self.webroot_backups.generate_filename();
+        progress.phase("archive", None);
+        tracing::debug!(target: "backend::webroot", "Backup Nextcloud document root to: {}", webroot_backup_file.display());
+        retry_io(&self.retry, "Writing webroot backup", || {
+            write_tarball(
+                &webroot_backup_file,
+                &document_root,
+                &excludes,
+                &self.config.compression,
+                dry_run,
+                progress,
+            )
+        })?;
+        tracing::info!(target: "backend::webroot", "Finished backup of Nextcloud document root");
+
+        if dry_run {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Artifact::File(webroot_backup_file)])
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        let document_root = vec![nextcloud.document_root().to_path_buf()];
+        let webroot_size = tree_size(&document_root, &self.excludes(nextcloud)?)?;
+        Ok(ensure_space(self.webroot_backups.dir(), webroot_size)?)
+    }
+
+    fn list(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        Ok(list_artifacts(&self.webroot_backups, cfg)?)
+    }
+
+    fn retention(
+        &self,
+        _nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        Ok(self
+            .webroot_backups
+            .retain(cfg, dry_run, "backend::webroot::retain")?)
+    }
+}
@@ -0,0 +1,496 @@
+//! Implements backup of Nextcloud's data using ZFS snapshots with [Zfs].
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use derive_more::{Display, Error, From};
+
+use super::{apply_retention, Artifact, Backup, BackupEntry};
+use crate::nextcloud::{Nextcloud, OccError};
+use crate::util::artifact::{format_stamp, parse_stamp};
+use crate::util::clock::Clock;
+use crate::util::command::{self, CommandRunner, Watchdog};
+use crate::util::generation::BackupGeneration;
+use crate::util::privilege::Privilege;
+use crate::util::progress::{Progress, ProgressReader};
+use crate::util::retention::{Retention, RetentionConfig};
+
+/// Prefix of the names of the snapshots created by [Zfs].
+const SNAPSHOT_PREFIX: &str = "nc_backup-";
+/// User property pinning a snapshot, i.e. exempting it from retention.
+///
+/// Pin a snapshot using `zfs set nc_backup:pinned=true <snapshot>`.
+pub const ZFS_PIN_PROPERTY: &str = "nc_backup:pinned";
+
+/// Configuration of [Zfs].
+///
+/// ```toml
+/// [zfs]
+/// dataset = "tank/nextcloud"
+///
+/// [zfs.replicate]
+/// target = "backup/nextcloud"
+/// ssh = "root@backup.example.com"
+/// ```
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ZfsConfig {
+    /// Dataset to snapshot.
+    ///
+    /// Defaults to the dataset mounted at or above the data directory.
+    pub dataset: Option<String>,
+
+    /// Replicate every snapshot to another dataset using `zfs send`.
+    pub replicate: Option<ReplicateConfig>,
+}
+
+/// Replication of the snapshots of [Zfs] to another dataset.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ReplicateConfig {
+    /// Dataset receiving the snapshots, e.g. `backup/nextcloud` on another pool.
+    ///
+    /// It's created by the first replication and must not be modified, as
+    /// `zfs receive -F` rolls it back to the latest received snapshot.
+    pub target: String,
+
+    /// Receive on this host by `ssh`, e.g. `root@backup.example.com`.
+    ///
+    /// If unset the snapshots are received on this host.
+    pub ssh: Option<String>,
+}
+
+/// Snapshot of a dataset created by [Zfs].
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ZfsSnapshot {
+    /// Full name, i.e. `<dataset>@nc_backup-<timestamp>` followed by `_<generation>` if any.
+    name: String,
+    /// Creation date as encoded in the name.
+    date: NaiveDateTime,
+    /// Generation of the run as encoded in the name, if any.
+    generation: Option<BackupGeneration>,
+    /// Whether the snapshot is [pinned](ZFS_PIN_PROPERTY).
+    pinned: bool,
+}
+
+impl ZfsSnapshot {
+    /// Name of the snapshot without the dataset.
+    fn short_name(&self) -> &str {
+        self.name
+            .split_once('@')
+            .map_or(self.name.as_str(), |(_, name)| name)
+    }
+}
+
+/// [Zfs]: A backend snapshotting the ZFS dataset hosting the data directory.
+///
+/// Like the [Snapper](super::Snapper) backend the snapshot is taken in maintenance
+/// mode and thus consistent with the database dump. Snapshots can be replicated
+/// incrementally to another pool or host, see [ReplicateConfig].
+#[derive(Debug, Clone)]
+pub struct Zfs {
+    config: ZfsConfig,
+    privilege: Privilege,
+    clock: Clock,
+    generation: Option<BackupGeneration>,
+    runner: Arc<dyn CommandRunner>,
+}
+
+/// Error of the [Zfs] backend.
+#[derive(Debug, Display, Error, From)]
+pub enum ZfsError {
+    /// `zfs` couldn't be run or failed.
+    #[display("zfs failed: {_0}")]
+    Zfs(io::Error),
+    /// The data directory isn't on a ZFS dataset.
+    #[display("Data directory {} isn't on a ZFS dataset, set zfs.dataset or disable the zfs backend", _0.display())]
+    #[from(ignore)]
+    NoDataset(#[error(not(source))] PathBuf),
+    /// Replicating a snapshot failed.
+    #[display("Replicating the snapshot failed: {_0}")]
+    #[from(ignore)]
+    Replicate(io::Error),
+    /// Running commands with root privileges isn't possible without interaction.
+    #[display("Privilege escalation failed: {_0}")]
+    #[from(ignore)]
+    Privilege(io::Error),
+    /// The data directory couldn't be determined.
+    #[display("Querying Nextcloud failed: {_0}")]
+    Occ(OccError),
+}
+
+impl Default for Zfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Zfs {
+    pub fn new() -> Self {
+        Self {
+            config: ZfsConfig::default(),
+            privilege: Privilege::default(),
+            clock: Clock::default(),
+            generation: None,
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Snapshot and replicate as configured by [ZfsConfig].
+    pub fn config(mut self, config: ZfsConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Run `zfs` using `privilege`.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Name new snapshots using the [Clock].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Name new snapshots after the [BackupGeneration] of the run.
+    pub fn generation(mut self, generation: BackupGeneration) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    /// Run `zfs` using `runner`, except for `zfs send` and `zfs receive`.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Run `zfs` with `args` and return its stdout.
+    fn zfs(&self, args: &[&str]) -> io::Result<String> {
+        let mut zfs = self.privilege.command("zfs", &[]);
+        zfs.args(args);
+        self.run(zfs)
+    }
+
+    /// Run `zfs` with `args` where the snapshots are replicated to.
+    fn target_zfs(&self, replicate: &ReplicateConfig, args: &[&str]) -> io::Result<String> {
+        match &replicate.ssh {
+            Some(host) => {
+                let mut ssh = command::new("ssh");
+                ssh.arg(host).arg("zfs").args(args);
+                self.run(ssh)
+            }
+            None => self.zfs(args),
+        }
+    }
+
+    fn run(&self, mut cmd: Command) -> io::Result<String> {
+        tracing::trace!(target: "backend::zfs", "Running: {cmd:?}");
+        let output = self.runner.output(&mut cmd, "zfs")?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{cmd:?} failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The dataset to snapshot for the data directory `data_dir`.
+    ///
+    /// This is the [configured](ZfsConfig::dataset) one or the dataset with the
+    /// longest mountpoint containing `data_dir`.
+    fn dataset(&self, data_dir: &Path) -> Result<String, ZfsError> {
+        if let Some(dataset) = &self.config.dataset {
+            return Ok(dataset.clone());
+        }
+        let datasets = self.zfs(&["list", "-H", "-o", "name,mountpoint", "-t", "filesystem"])?;
+        datasets
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .filter(|(_, mountpoint)| mountpoint.starts_with('/'))
+            .filter(|(_, mountpoint)| data_dir.starts_with(mountpoint))
+            .max_by_key(|(_, mountpoint)| mountpoint.len())
+            .map(|(name, _)| name.to_string())
+            .ok_or_else(|| ZfsError::NoDataset(data_dir.to_path_buf()))
+    }
+
+    /// Snapshots of `dataset` created by this backend, the most recent first.
+    fn snapshots(&self, dataset: &str) -> io::Result<Vec<ZfsSnapshot>> {
+        let columns = format!("name,{ZFS_PIN_PROPERTY}");
+        let output = self.zfs(&[
+            "list", "-H", "-t", "snapshot", "-o", &columns, "-d", "1", dataset,
+        ])?;
+        let mut snapshots: Vec<_> = output
+            .lines()
+            .filter_map(|line| {
+                let (name, pinned) = line.split_once('\t').unwrap_or((line, "-"));
+                let (_, short_name) = name.split_once('@')?;
+                let stamp = short_name.strip_prefix(SNAPSHOT_PREFIX)?;
+                let (date, generation) = parse_stamp(stamp)?;
+                Some(ZfsSnapshot {
+                    name: name.to_string(),
+                    date,
+                    generation,
+                    pinned: pinned == "true",
+                })
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.date));
+        Ok(snapshots)
+    }
+
+    /// Snapshot of `dataset` the next replication is based on.
+    ///
+    /// This is the most recent snapshot of this backend which the target
+    /// already received. Returns [None] if a full stream has to be sent.
+    fn replication_base(
+        &self,
+        replicate: &ReplicateConfig,
+        snapshots: &[ZfsSnapshot],
+        snapshot: &str,
+    ) -> Option<ZfsSnapshot> {
+        let received = match self.target_zfs(
+            replicate,
+            &[
+                "list",
+                "-H",
+                "-t",
+                "snapshot",
+                "-o",
+                "name",
+                "-d",
+                "1",
+                &replicate.target,
+            ],
+        ) {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::info!(target: "backend::zfs", "Sending a full stream, the target {} isn't listable: {e}", replicate.target);
+                return None;
+            }
+        };
+        let received: Vec<_> = received
+            .lines()
+            .filter_map(|name| name.split_once('@'))
+            .map(|(_, short_name)| short_name)
+            .collect();
+        snapshots
+            .iter()
+            .filter(|candidate| candidate.name != snapshot)
+            .find(|candidate| received.contains(&candidate.short_name()))
+            .cloned()
+    }
+
+    /// Send `snapshot` of `dataset` to the target of `replicate`.
+    fn replicate(
+        &self,
+        replicate: &ReplicateConfig,
+        dataset: &str,
+        snapshot: &str,
+        progress: &dyn Progress,
+    ) -> io::Result<()> {
+        let snapshots = self.snapshots(dataset)?;
+        let base = self.replication_base(replicate, &snapshots, snapshot);
+
+        let mut send_cmd = self.privilege.command("zfs", &[]);
+        send_cmd.arg("send");
+        if let Some(base) = &base {
+            send_cmd.arg("-i").arg(format!("@{}", base.short_name()));
+        }
+        send_cmd.arg(snapshot).stdout(Stdio::piped());
+        let mut receive_cmd = match &replicate.ssh {
+            Some(host) => {
+                let mut ssh = command::new("ssh");
+                ssh.arg(host).arg("zfs");
+                ssh
+            }
+            None => self.privilege.command("zfs", &[]),
+        };
+        receive_cmd
+            .args(["receive", "-u", "-F"])
+            .arg(&replicate.target)
+            .stdin(Stdio::piped());
+        match &base {
+            Some(base) => {
+                tracing::info!(target: "backend::zfs", "Replicate {snapshot} incrementally from {} to {}", base.short_name(), replicate.target)
+            }
+            None => {
+                tracing::info!(target: "backend::zfs", "Replicate {snapshot} to {}", replicate.target)
+            }
+        }
+        tracing::trace!(target: "backend::zfs", "Running: {send_cmd:?} | {receive_cmd:?}");
+
+        let mut send = send_cmd.spawn()?;
+        let mut receive = match receive_cmd.spawn() {
+            Ok(receive) => receive,
+            Err(e) => {
+                let _ = send.kill();
+                let _ = send.wait();
+                return Err(e);
+            }
+        };
+
+        let watchdog = Watchdog::start("zfs", &[send.id(), receive.id()]);
+        let stdout = send.stdout.take().expect("stdout should be piped");
+        let mut stdin = receive.stdin.take().expect("stdin should be piped");
+        let copied = io::copy(&mut ProgressReader::new(stdout, progress), &mut stdin);
+        drop(stdin);
+        if copied.is_err() {
+            let _ = send.kill();
+        }
+        let send_status = send.wait()?;
+        let receive_status = receive.wait()?;
+
+        watchdog.stop().map_err(io::Error::from)?;
+        copied?;
+        if !send_status.success() {
+            return Err(io::Error::other(format!("zfs send failed: {send_status}")));
+        }
+        if !receive_status.success() {
+            return Err(io::Error::other(format!(
+                "zfs receive failed: {receive_status}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Backup for Zfs {
+    type Error = ZfsError;
+
+    fn backup(
+        &self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let data_dir = nextcloud.occ().data_directory()?;
+        let dataset = self.dataset(&data_dir)?;
+        let snapshot = format!(
+            "{dataset}@{SNAPSHOT_PREFIX}{}",
+            format_stamp(self.clock.now(), self.generation)
+        );
+
+        progress.phase("snapshot", None);
+        if dry_run {
+            tracing::info!(target: "backend::zfs", "Would create snapshot: {snapshot}");
+            return Ok(Vec::new());
+        }
+        tracing::info!(target: "backend::zfs", "Create snapshot: {snapshot}");
+        self.zfs(&["snapshot", &snapshot])?;
+
+        Ok(vec![Artifact::ZfsSnapshot(snapshot)])
+    }
+
+    /// Replicates the created snapshot outside of maintenance mode.
+    fn finalize(
+        &self,
+        _nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, Self::Error> {
+        let Some(replicate) = &self.config.replicate else {
+            return Ok(artifacts);
+        };
+        for artifact in &artifacts {
+            let Artifact::ZfsSnapshot(snapshot) = artifact else {
+                continue;
+            };
+            let (dataset, _) = snapshot
+                .split_once('@')
+                .expect("snapshot name should contain the dataset");
+            progress.phase("send", None);
+            self.replicate(replicate, dataset, snapshot, progress)
+                .map_err(ZfsError::Replicate)?;
+        }
+
+        Ok(artifacts)
+    }
+
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), Self::Error> {
+        self.privilege.check().map_err(ZfsError::Privilege)?;
+        let data_dir = nextcloud.occ().data_directory()?;
+        self.dataset(&data_dir).map(drop)
+    }
+
+    fn list(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+    ) -> Result<Vec<BackupEntry>, Self::Error> {
+        let data_dir = nextcloud.occ().data_directory()?;
+        let dataset = self.dataset(&data_dir)?;
+        let snapshots = self.snapshots(&dataset)?;
+        let latest = snapshots.first().map(|snapshot| snapshot.name.clone());
+
+        let mut entries: Vec<_> = snapshots
+            .into_iter()
+            .map(|snapshot| BackupEntry {
+                artifact: Artifact::ZfsSnapshot(snapshot.name),
+                date: snapshot.date,
+                size: None,
+                pinned: snapshot.pinned,
+                generation: snapshot.generation,
+                retained_by: Vec::new(),
+            })
+            .collect();
+        apply_retention(&mut entries, *cfg);
+
+        if self.config.replicate.is_some() {
+            for entry in &mut entries {
+                let is_latest = matches!(&entry.artifact, Artifact::ZfsSnapshot(name) if Some(name) == latest.as_ref());
+                if is_latest && entry.retained_by.is_empty() {
+                    entry.retained_by.push("base");
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn retention(
+        &self,
+        nextcloud: &Nextcloud,
+        cfg: &RetentionConfig,
+        dry_run: bool,
+    ) -> Result<(), Self::Error> {
+        let data_dir = nextcloud.occ().data_directory()?;
+        let dataset = self.dataset(&data_dir)?;
+        let snapshots = self.snapshots(&dataset)?;
+        if snapshots.is_empty() {
+            tracing::debug!(target: "backend::zfs::retain", "No snapshots found. Nothing to retain.");
+            return Ok(());
+        }
+
+        let mut retention = Retention::from(*cfg);
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            if snapshot.pinned {
+                tracing::debug!(target: "backend::zfs::retain", "Snapshot pinned: {}", snapshot.name);
+                continue;
+            }
+            if retention.retain(snapshot.date) {
+                tracing::debug!(target: "backend::zfs::retain", "Snapshot retained: {}", snapshot.name);
+                continue;
+            }
+            if i == 0 && self.config.replicate.is_some() {
+                tracing::debug!(target: "backend::zfs::retain", "Snapshot retained as base of the next replication: {}", snapshot.name);
+                continue;
+            }
+
+            tracing::info!(target: "backend::zfs::retain", "Discarding snapshot: {}", snapshot.name);
+            if !dry_run {
+                if let Err(e) = self.zfs(&["destroy", &snapshot.name]) {
+                    tracing::error!(target: "backend::zfs::retain", "Error deleting snapshot: {e}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,23 +1,95 @@
 //! Components for the binary command-line interface.
 
+pub mod progress;
+
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
 
+use chrono::{NaiveDateTime, NaiveTime};
 use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 
-use crate::nextcloud::DEFAULT_INSTALLATION_ROOT;
+use crate::backends::{Artifact, ExportFormat};
+use crate::restore::plan::{Component, RestorePoint};
+use crate::util::command::IoniceClass;
+use crate::util::retention::RetentionConfig;
+
+/// Exit codes of the binary.
+///
+/// If several failures occur the one listed first is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exit {
+    /// Every backend succeeded.
+    Success = 0,
+    /// At least one backend failed.
+    BackendFailed = 1,
+    /// A preflight check failed and no backend was run.
+    Preflight = 2,
+    /// Maintenance mode couldn't be toggled and may be stuck.
+    Maintenance = 3,
+    /// A hook failed.
+    Hook = 4,
+    /// The configuration file couldn't be read.
+    Config = 5,
+    /// The Nextcloud installation couldn't be found.
+    Installation = 6,
+    /// Updating the Nextcloud apps failed.
+    Update = 7,
+    /// The run manifest or the files listed in it failed verification.
+    Verification = 8,
+}
+
+impl From<Exit> for std::process::ExitCode {
+    fn from(exit: Exit) -> Self {
+        (exit as u8).into()
+    }
+}
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  every backend succeeded
+  1  at least one backend failed
+  2  a preflight check failed, no backend was run
+  3  maintenance mode couldn't be toggled and may be stuck
+  4  a hook failed
+  5  the configuration file couldn't be read
+  6  the Nextcloud installation couldn't be found or needs occ upgrade
+  7  updating the Nextcloud apps failed
+  8  the run manifest or the files listed in it failed verification";
 
 /// Main command-line struct.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 pub struct Cli {
     /// Verbosity of the command output.
     #[arg(short, long)]
     pub verbose: Option<LevelFilter>,
 
     /// Directory of the Nextcloud server installation.
-    #[arg(short = 'd', long, default_value = DEFAULT_INSTALLATION_ROOT)]
-    pub document_root: PathBuf,
+    ///
+    /// If omitted common installation roots and web server configurations are searched.
+    #[arg(short = 'd', long)]
+    pub document_root: Option<PathBuf>,
+
+    /// User running `occ` (overrides `occ.user` of the config).
+    ///
+    /// Defaults to the owner of Nextcloud's `config.php`.
+    /// If the user differs from the current one `occ` is run using `sudo`.
+    #[arg(long)]
+    pub php_user: Option<String>,
+
+    /// PHP binary running `occ` (overrides `occ.php` of the config).
+    ///
+    /// By default the `occ` wrapper in `PATH` is used.
+    #[arg(long)]
+    pub php: Option<PathBuf>,
+
+    /// Additional flag passed to the PHP binary, e.g. `-dmemory_limit=512M`.
+    ///
+    /// Can be given multiple times and is added to `occ.php_flags` of the config.
+    #[arg(long, allow_hyphen_values = true)]
+    pub php_flag: Vec<String>,
 
     #[arg(long, short = 'r')]
     /// Root folder used by backup modules to put their data into.
@@ -37,35 +109,142 @@ pub struct Cli {
     /// Path to `nc_backup.toml`
     pub config: PathBuf,
 
-    /// List of enabled backends.
-    #[arg(
-        short = 'b',
-        long,
-        value_delimiter = ',',
-        default_value = "config,maria-db,snapper"
-    )]
-    pub enabled_backends: Vec<Backends>,
+    /// Names of the enabled backends (overrides `backends` of the config).
+    ///
+    /// Built-in backends are `config`, `mariadb`, `mariadb_physical`, `snapper`, `zfs`, `lvm`, `apps`,
+    /// `appdata`, `webroot`, `data`, `tar_data`, `copy_data`, `encryption_keys`, `objectstore` and `users`.
+    /// Defaults to `config,mariadb,snapper`.
+    #[arg(short = 'b', long, value_delimiter = ',')]
+    pub enabled_backends: Option<Vec<String>>,
 
-    /// Simulative run which doesn't alter any files.
+    /// Names of the `[instance.<name>]` sections of the config to run on.
+    ///
+    /// Defaults to all configured instances.
+    #[arg(long, value_delimiter = ',')]
+    pub instance: Vec<String>,
+
+    /// Only archive the directories of these users in the `data` backend (overrides `data.users` of the config).
+    #[arg(long, value_delimiter = ',')]
+    pub user: Vec<String>,
+
+    /// Only dump these database tables (adds to `mariadb.include_tables` of the config).
+    #[arg(long, value_delimiter = ',')]
+    pub db_include_table: Vec<String>,
+
+    /// Dump only the schema of these database tables (adds to `mariadb.exclude_tables` of the config).
+    #[arg(long, value_delimiter = ',')]
+    pub db_exclude_table: Vec<String>,
+
+    /// Dump the full database only every this many backups and store the others as diff against it (sets `mariadb.full_every` of the config).
+    ///
+    /// Requires `zstd`.
+    #[arg(long, value_name = "N")]
+    pub db_full_every: Option<NonZeroU32>,
+
+    /// Connect to the database server at this host instead of Nextcloud's `dbhost` (sets `mariadb.host` of the config).
+    #[arg(long)]
+    pub db_host: Option<String>,
+
+    /// Connect to the database server at this port (sets `mariadb.port` of the config).
+    #[arg(long)]
+    pub db_port: Option<u16>,
+
+    /// Connect to the database server by this Unix socket (sets `mariadb.socket` of the config).
+    #[arg(long)]
+    pub db_socket: Option<PathBuf>,
+
+    /// Test-decompress database dumps after writing them (sets `mariadb.verify` of the config).
+    #[arg(long)]
+    pub verify_dump: bool,
+
+    /// Id of the snapper config to snapshot (sets `snapper.config` of the config).
+    ///
+    /// Defaults to the config of the innermost subvolume containing the data directory.
+    #[arg(long, value_name = "NAME")]
+    pub snapper_config: Option<String>,
+
+    /// Create the snapper config `nextcloud-data` if none covers the data directory (sets `snapper.create_config` of the config).
+    ///
+    /// The data directory has to be a btrfs subvolume.
+    #[arg(long)]
+    pub snapper_create_config: bool,
+
+    /// Directory to write Prometheus metrics of the backup to (overrides `metrics.textfile_dir` of the config).
+    #[arg(long)]
+    pub metrics_dir: Option<PathBuf>,
+
+    /// Skip checking the free space of the backup destinations before the backup.
     #[arg(long)]
+    pub no_preflight: bool,
+
+    /// Keep the Nextcloud online instead of enabling maintenance mode during the backup.
+    ///
+    /// The database is dumped in a single transaction and snapshots are atomic,
+    /// but both may not be consistent with each other.
+    #[arg(long)]
+    pub no_maintenance: bool,
+
+    /// Simulative run which doesn't alter any files.
+    #[arg(long, global = true)]
     pub dry_run: bool,
 
+    /// Use this local time instead of the current one, e.g. `2025-01-01T02:30:00`.
+    ///
+    /// Allows re-running a missed backup under its historical timestamp.
+    /// Snapshots are still dated by snapper itself.
+    #[arg(long)]
+    pub timestamp_override: Option<NaiveDateTime>,
+
+    /// Command run before the backup (overrides `hooks.pre` of the config).
+    ///
+    /// If the command fails no backend is run.
+    #[arg(long)]
+    pub pre_hook: Option<String>,
+
+    /// Command run after the backup (overrides `hooks.post` of the config).
+    #[arg(long)]
+    pub post_hook: Option<String>,
+
+    /// Niceness of the external commands from -20 to 19 (overrides `priority.nice` of the config).
+    #[arg(long, allow_negative_numbers = true)]
+    pub nice: Option<i32>,
+
+    /// I/O scheduling class of the external commands (overrides `priority.ionice_class` of the config).
+    #[arg(long, value_enum)]
+    pub ionice_class: Option<IoniceClass>,
+
+    /// Maximum number of backends run concurrently.
+    ///
+    /// By default all enabled backends run in parallel.
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Don't render progress bars on stderr.
+    ///
+    /// Progress bars are only shown if stderr is a terminal.
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Format of the result printed to stdout.
+    ///
+    /// Logs are always written to stderr.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
     /// Actions to perform.
     #[command(subcommand)]
     pub action: Action,
 }
 
-#[derive(Debug, ValueEnum, Clone, Hash, PartialEq, Eq)]
-/// Available backends.
-pub enum Backends {
-    /// Backup of Nextcloud's `config.php`.
-    Config,
-    /// Backup of Nextcloud's mariadb.
-    MariaDb,
-    /// Incremental backup of Nextcloud's data using Snapper.
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+/// Format of the result printed to stdout.
+pub enum OutputFormat {
+    /// No result is printed, see the logs instead.
+    Text,
+    /// A JSON document with the status, artifacts, errors and timings of every backend.
     ///
-    /// Requires external setup.
-    Snapper,
+    /// The `list` action prints a JSON array of the backups instead.
+    Json,
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -73,8 +252,304 @@ pub enum Backends {
 pub enum Action {
     /// Backup the Nextcloud config, database and data.
     Backup(BackupArgs),
-    /// Retain backups.
-    Retain,
+    /// Apply the retention to the existing backups without running a backup.
+    ///
+    /// Pass `--dry-run` to only log the backups which would be deleted.
+    #[command(visible_alias = "retain")]
+    Prune(PruneArgs),
+    /// Pin backups, exempting them from the retention of every backend.
+    ///
+    /// Files and directories are pinned by a `<backup>.pin` sidecar file, snapper
+    /// snapshots by the userdata `pinned=true` and zfs snapshots by the property
+    /// `nc_backup:pinned=true`.
+    Pin(PinArgs),
+    /// Unpin backups pinned by `pin`, so the retention may discard them again.
+    Unpin(PinArgs),
+    /// List the existing backups of the enabled backends.
+    ///
+    /// Prints a table or, using `--output json`, a JSON array of the backups
+    /// along with the retention categories keeping them.
+    List,
+    /// Check the prerequisites of the enabled backends and suggest fixes.
+    ///
+    /// Exits with the preflight exit code if any check failed.
+    #[command(visible_alias = "status")]
+    Doctor,
+    /// Keep running and backup on a daily schedule.
+    ///
+    /// Missed runs are caught up on start. SIGTERM and SIGINT let a running
+    /// backup finish before shutting down.
+    Daemon(DaemonArgs),
+    /// Run the `occ` commands required after restoring a backup.
+    ///
+    /// Updates the data fingerprint, so sync clients don't overwrite the restored
+    /// data, runs the repair steps and rescans the files if maintenance mode is off.
+    AfterRestore(AfterRestoreArgs),
+    /// Restore a single file or directory of the data directory from a snapper snapshot.
+    ///
+    /// Snapshots gone locally are received from their streams. Files restored to
+    /// the data directory are rescanned by Nextcloud.
+    RestoreFile(RestoreFileArgs),
+    /// Mount the backups of a point in time read-only to inspect them before a restore.
+    ///
+    /// The data directory is mounted or extracted to `<dir>/data` and the database
+    /// dump is loaded into a separate database. Undo it using `--unmount`.
+    Mount(MountArgs),
+    /// Restore the backups of a point in time into the Nextcloud installation.
+    ///
+    /// The config, the database and the data directory are restored from the
+    /// backups listed by the run manifests while maintenance mode is on. The
+    /// database is dropped, created again and loaded from a dump, as described
+    /// by the admin manual. Asks for confirmation unless `--yes` is given.
+    Restore(RestoreArgs),
+    /// Write systemd service and timer units running the backup with the current options.
+    InstallUnits(InstallUnitsArgs),
+    /// Reassemble a deduplicated or differential database dump or prepare a physical backup.
+    ///
+    /// The dump is checked against its checksum while it's written.
+    Extract(ExtractArgs),
+    /// Verify a run manifest and the backups listed in it.
+    ///
+    /// The signature is checked if `manifest.signing` is configured. Backups
+    /// pruned since are reported as missing, altered ones fail the verification.
+    VerifyManifest(VerifyManifestArgs),
+    /// Manage the configuration.
+    #[command(subcommand)]
+    Config(ConfigAction),
+}
+
+#[derive(Debug, Clone, Subcommand)]
+/// Action on the configuration.
+pub enum ConfigAction {
+    /// Print the configuration in the format of another backup tool.
+    ///
+    /// The backup root and the Nextcloud data directory are used as sources.
+    Export {
+        /// Format of the exported configuration.
+        #[arg(long)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Debug, Args, Default, Clone)]
+/// Arguments of pruning, overriding the `retention` of the config.
+pub struct PruneArgs {
+    /// Number of daily backups to keep.
+    #[arg(long)]
+    pub keep_daily: Option<usize>,
+
+    /// Number of weekly backups to keep.
+    #[arg(long)]
+    pub keep_weekly: Option<usize>,
+
+    /// Number of monthly backups to keep.
+    #[arg(long)]
+    pub keep_monthly: Option<usize>,
+
+    /// Number of quarterly backups to keep.
+    #[arg(long)]
+    pub keep_quarterly: Option<usize>,
+
+    /// Number of yearly backups to keep.
+    #[arg(long)]
+    pub keep_yearly: Option<usize>,
+}
+
+impl PruneArgs {
+    /// Returns `cfg` with the counts given on the command line replaced.
+    pub fn retention(&self, mut cfg: RetentionConfig) -> RetentionConfig {
+        let overrides = [
+            (&mut cfg.daily, self.keep_daily),
+            (&mut cfg.weekly, self.keep_weekly),
+            (&mut cfg.monthly, self.keep_monthly),
+            (&mut cfg.quarterly, self.keep_quarterly),
+            (&mut cfg.yearly, self.keep_yearly),
+        ];
+        for (keep, keep_override) in overrides {
+            if keep_override.is_some() {
+                *keep = keep_override;
+            }
+        }
+        cfg
+    }
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of the systemd unit installation.
+pub struct InstallUnitsArgs {
+    /// Directory to write the units to.
+    #[arg(long, default_value = "/etc/systemd/system")]
+    pub dir: PathBuf,
+
+    /// Calendar event of the timer, see `systemd.time(7)`.
+    #[arg(long, default_value = "*-*-* 02:30:00")]
+    pub on_calendar: String,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of reassembling a deduplicated or differential dump.
+pub struct ExtractArgs {
+    /// Manifest of the dump, e.g. `db/database-2025-01-01T02-30-00.sql.chunks`,
+    /// differential dump, e.g. `db/database-2025-01-02T02-30-00.sql.zst`,
+    /// or physical backup, e.g. `db-physical/database-2025-01-01T02-30-00.xbstream.gz`.
+    pub manifest: PathBuf,
+
+    /// Full dump a differential dump is based on, if it was moved away from it.
+    #[arg(long)]
+    pub base: Option<PathBuf>,
+
+    /// File to write the dump to instead of stdout.
+    ///
+    /// A physical backup is extracted and prepared in this new directory, which is required.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of verifying a run manifest.
+pub struct VerifyManifestArgs {
+    /// Run manifest, e.g. `manifests/run-2025-01-01T02-30-00.json`, or the
+    /// generation of its run, e.g. `01JGQ2C4S8ZQK3N7W5R9T0V6XB`.
+    ///
+    /// Generations are looked up in the run manifests of the backup root.
+    pub manifest: PathBuf,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of the steps after a restore.
+pub struct AfterRestoreArgs {
+    /// Run manifest of the restored backup, e.g. `manifests/run-2025-01-01T02-30-00.json`.
+    ///
+    /// If the appdata was backed up without previews, they are regenerated.
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of the restore of a single file or directory.
+pub struct RestoreFileArgs {
+    /// Number of the snapshot to restore from, as listed by `list`.
+    #[arg(long, value_name = "ID")]
+    pub from_snapshot: u64,
+
+    /// File or directory to restore relative to the data directory, e.g. `alice/files/Photos`.
+    #[arg(long)]
+    pub path: PathBuf,
+
+    /// Restore to this path instead of back into the data directory.
+    #[arg(long)]
+    pub target: Option<PathBuf>,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of the restore of the backups.
+pub struct RestoreArgs {
+    /// Restore only these components, defaults to all of them.
+    ///
+    /// Can be given multiple times.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub only: Vec<Component>,
+
+    /// Restore only the database, same as `--only db`.
+    #[arg(long, conflicts_with = "only")]
+    pub db_restore: bool,
+
+    /// Restore the latest backups created at or before this local time, e.g.
+    /// `2025-01-01T02:30:00`, or the backups of a run, e.g. `2025-01-01T02-30-00`
+    /// or its generation `01JGQ2C4S8ZQK3N7W5R9T0V6XB`.
+    ///
+    /// The backups are looked up in the run manifests. Defaults to the latest backups.
+    #[arg(long, value_name = "TIME|RUN|GENERATION")]
+    pub at: Option<RestorePoint>,
+
+    /// Dump to restore instead of the one selected by `--at`.
+    #[arg(long)]
+    pub dump: Option<PathBuf>,
+
+    /// Restore the secrets masked in the config backup from its secrets sidecar
+    /// decrypted using this `age` identity file or `gpg` home directory.
+    ///
+    /// Without it the secrets are taken over from the live config.
+    #[arg(long, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Drop and create the database as this user, e.g. `root`, and grant the
+    /// database user of config.php access again.
+    ///
+    /// Its password is taken from `MYSQL_PWD`. Defaults to the database user of config.php.
+    #[arg(long, value_name = "USER")]
+    pub db_admin_user: Option<String>,
+
+    /// Host the database user is granted access from.
+    #[arg(long, value_name = "HOST", default_value = "localhost")]
+    pub db_grant_host: String,
+
+    /// Don't ask for confirmation before restoring.
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Restore even if the backup isn't of the installed Nextcloud version.
+    ///
+    /// Nextcloud can't be downgraded and a backup of an older version requires
+    /// `occ upgrade` after the restore.
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl RestoreArgs {
+    /// The components to restore in the order of [Component].
+    pub fn components(&self) -> Vec<Component> {
+        if self.db_restore {
+            return vec![Component::Db];
+        }
+        let mut components = match self.only.is_empty() {
+            true => Component::value_variants().to_vec(),
+            false => self.only.clone(),
+        };
+        components.sort();
+        components.dedup();
+        components
+    }
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of mounting the backups of a point in time.
+pub struct MountArgs {
+    /// Directory to mount the backups to.
+    #[arg(long)]
+    pub dir: PathBuf,
+
+    /// Mount the latest backups created at or before this local time, e.g. `2025-01-01T02:30:00`.
+    ///
+    /// Defaults to the latest backups.
+    #[arg(long)]
+    pub at: Option<NaiveDateTime>,
+
+    /// Load the database dump into this database instead of `nc_backup_<timestamp>`.
+    #[arg(long)]
+    pub schema: Option<String>,
+
+    /// Unmount the backups mounted to the directory and drop their database.
+    #[arg(long)]
+    pub unmount: bool,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of the backup daemon.
+pub struct DaemonArgs {
+    /// Local time of the day to backup at, e.g. `03:00`.
+    ///
+    /// Can be given multiple times.
+    #[arg(long, required = true)]
+    pub schedule: Vec<NaiveTime>,
+
+    /// Delay each backup randomly by up to this many minutes.
+    #[arg(long, default_value_t = 0)]
+    pub jitter: u64,
+
+    #[command(flatten)]
+    #[allow(missing_docs)]
+    pub backup: BackupArgs,
 }
 
 #[derive(Debug, Args, Default, Clone)]
@@ -83,4 +558,23 @@ pub struct BackupArgs {
     /// Update nextcloud apps after backup.
     #[arg(long)]
     pub update: bool,
+
+    /// Run `occ maintenance:repair` after backup.
+    #[arg(long)]
+    pub repair: bool,
+
+    /// Run `occ files:scan --all` after backup to bring the file cache in sync.
+    #[arg(long)]
+    pub scan_files: bool,
+}
+
+#[derive(Debug, Args, Clone)]
+/// Arguments of pinning and unpinning backups.
+pub struct PinArgs {
+    /// Backups as printed by `list`, e.g. `db/database-2025-01-01T02-30-00.sql.gz`,
+    /// `snapper:nextcloud:42` or `zfs:tank/nextcloud@nc_backup-2025-01-01T02-30-00`.
+    ///
+    /// Relative paths are resolved against the backup root.
+    #[arg(required = true)]
+    pub backups: Vec<Artifact>,
 }
@@ -0,0 +1,101 @@
+//! Progress bars of the running jobs rendered on stderr.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::util::progress::{Progress, ProgressReporter};
+
+/// Renders a progress bar for every job using [indicatif].
+///
+/// Log lines have to be written by the [log writer](Self::log_writer) to not
+/// tear the bars apart.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressBars {
+    bars: MultiProgress,
+}
+
+impl ProgressBars {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap the writer of the logger to hide the bars while writing to `inner`.
+    pub fn log_writer(&self, inner: Box<dyn Write + Send>) -> Box<dyn Write + Send> {
+        Box::new(LogWriter {
+            bars: self.bars.clone(),
+            inner,
+        })
+    }
+}
+
+impl ProgressReporter for ProgressBars {
+    fn job(&self, name: &str) -> Box<dyn Progress> {
+        let bar = self
+            .bars
+            .add(ProgressBar::new_spinner().with_prefix(name.to_string()));
+        bar.set_style(spinner_style());
+        bar.enable_steady_tick(Duration::from_millis(200));
+        Box::new(JobBar(bar))
+    }
+}
+
+fn spinner_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{spinner} {prefix:12} {msg:10} {binary_bytes} ({binary_bytes_per_sec})",
+    )
+    .expect("progress template should be valid")
+}
+
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{spinner} {prefix:12} {msg:10} [{bar:30}] {binary_bytes}/{binary_total_bytes} ({eta})",
+    )
+    .expect("progress template should be valid")
+    .progress_chars("=> ")
+}
+
+/// [Progress] of a single job.
+struct JobBar(ProgressBar);
+
+impl Progress for JobBar {
+    fn phase(&self, name: &str, total: Option<u64>) {
+        match total {
+            Some(total) => {
+                self.0.set_style(bar_style());
+                self.0.set_length(total);
+            }
+            None => {
+                self.0.set_style(spinner_style());
+                self.0.unset_length();
+            }
+        }
+        self.0.reset();
+        self.0.set_message(name.to_string());
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.0.inc(bytes);
+    }
+
+    fn finish(&self) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// Writer suspending the [ProgressBars] while a log line is written.
+struct LogWriter {
+    bars: MultiProgress,
+    inner: Box<dyn Write + Send>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bars.suspend(|| self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.bars.suspend(|| self.inner.flush())
+    }
+}
@@ -3,6 +3,15 @@
 //! The library tries to follow the [official backup guidelines][nc_backup].
 //! The different backup modules are located in the [`backends`] module.
 //!
+//! # Logging
+//!
+//! Events are emitted using [`tracing`]. Every job of the
+//! [BackupRunner](runner::BackupRunner) runs in a `backend` span and every
+//! external command in a `command` span, events carry structured fields like
+//! `snapshot_id` or `duration_secs`. Without a `tracing` subscriber the events
+//! are forwarded to the [`log`](https://docs.rs/log) crate, so `env_logger` and
+//! other loggers keep working.
+//!
 //! [nc]: https://nextcloud.com/
 //! [nc_backup]: https://docs.nextcloud.com/server/latest/admin_manual/maintenance/backup.html
 
@@ -11,4 +20,7 @@
 pub mod backends;
 pub mod cli;
 pub mod nextcloud;
+pub mod report;
+pub mod restore;
+pub mod runner;
 pub mod util;
@@ -1,152 +1,1409 @@
-use std::collections::HashSet;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
-use nc_backup_lib::backends::{BackendsConfig, Backup, Config, MariaDb};
-use nc_backup_lib::cli::{Action, Backends, BackupArgs, Cli};
+use nc_backup_lib::backends::config::{CONFIG_PREFIX, CONFIG_SUFFIX};
+use nc_backup_lib::backends::registry::{NamedBackend, DEFAULT_BACKENDS};
+use nc_backup_lib::backends::snapper::SnapperBackupError;
+use nc_backup_lib::backends::{mariadb, mariadb_physical, MariaDbError};
+use nc_backup_lib::backends::{
+    AppdataMode, Artifact, BackendContext, BackendEntry, BackendRegistry, BackendsConfig,
+    BackupEntry, PinError, Pinner, RegistryError,
+};
+use nc_backup_lib::cli::progress::ProgressBars;
+use nc_backup_lib::cli::{
+    Action, BackupArgs, Cli, ConfigAction, DaemonArgs, Exit, ExtractArgs, MountArgs, OutputFormat,
+    RestoreArgs, VerifyManifestArgs,
+};
 
+use chrono::Local;
 use clap::Parser;
-use nc_backup_lib::nextcloud::Nextcloud;
+use derive_more::{Display, Error, From};
+use nc_backup_lib::nextcloud::{Nextcloud, NextcloudError, OccBuilder, OccError};
+use nc_backup_lib::report::email::LogTail;
+use nc_backup_lib::report::manifest::{self, ManifestConfig, ManifestError, RunManifest};
+use nc_backup_lib::report::{metrics, RunSummary};
+use nc_backup_lib::restore::config::ConfigRestore;
+use nc_backup_lib::restore::data::{DataRestore, DataRestoreError};
+use nc_backup_lib::restore::database::DatabaseRestore;
+use nc_backup_lib::restore::mount::{Generation, Mount, MountError};
+use nc_backup_lib::restore::plan::{Component, PlanError, RestorePlan, RestorePoint};
+use nc_backup_lib::restore::version::{self, RestoreVersions, VersionError};
+use nc_backup_lib::runner::doctor::{self, Check, CheckStatus};
+use nc_backup_lib::runner::schedule::{self, Schedule};
+use nc_backup_lib::runner::window::{Deferral, WindowConfig};
+use nc_backup_lib::runner::{BackupLayout, BackupRunner, Job, LayoutError, RunReport, RunnerError};
+use nc_backup_lib::util::checksum::HashingReader;
+use nc_backup_lib::util::chunkstore::{ChunkStore, Manifest};
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command;
+use nc_backup_lib::util::generation::BackupGeneration;
+use nc_backup_lib::util::mask::Masker;
+use nc_backup_lib::util::progress::{LogProgress, ProgressReporter};
+use nc_backup_lib::util::sign::Signing;
+use nc_backup_lib::util::systemd;
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// Error aborting [run].
+#[derive(Debug, Display, Error, From)]
+enum Error {
+    /// The config file couldn't be read.
+    #[display("Reading the config file failed: {_0}")]
+    ReadConfig(io::Error),
+    /// The config file isn't valid.
+    #[display("Parsing the config file failed: {_0}")]
+    ParseConfig(toml::de::Error),
+    /// The Nextcloud installation couldn't be set up.
+    #[display("{_0}")]
+    Installation(NextcloudError),
+    /// A backup couldn't be pinned or unpinned.
+    #[display("Pinning the backup failed: {_0}")]
+    Pin(PinError),
+    /// The systemd units couldn't be written.
+    #[display("Installing the systemd units failed: {_0}")]
+    #[from(ignore)]
+    InstallUnits(io::Error),
+    /// A deduplicated dump couldn't be reassembled.
+    #[display("Extracting the dump failed: {_0}")]
+    #[from(ignore)]
+    Extract(io::Error),
+    /// The `occ` commands after a restore failed.
+    #[display("Running the occ commands after the restore failed: {_0}")]
+    #[from(ignore)]
+    AfterRestore(OccError),
+    /// A file couldn't be restored from a snapshot.
+    #[display("Restoring from the snapshot failed: {_0}")]
+    #[from(ignore)]
+    RestoreFile(SnapperBackupError),
+    /// The backups couldn't be mounted or unmounted.
+    #[display("Mounting the backups failed: {_0}")]
+    Mount(MountError),
+    /// The run manifests listing the backups to restore couldn't be read.
+    #[display("Reading the run manifests failed: {_0}")]
+    #[from(ignore)]
+    Catalog(io::Error),
+    /// A component to restore has no backup.
+    #[display("{_0}")]
+    Plan(PlanError),
+    /// The installation to restore couldn't be queried.
+    #[display("Querying the installation failed: {_0}")]
+    #[from(ignore)]
+    RestoreOcc(OccError),
+    /// Maintenance mode couldn't be toggled around the restore.
+    #[display("Toggling maintenance mode failed: {_0}")]
+    #[from(ignore)]
+    Maintenance(OccError),
+    /// The config couldn't be restored.
+    #[display("Restoring the config failed: {_0}")]
+    #[from(ignore)]
+    RestoreConfig(io::Error),
+    /// The data directory couldn't be restored.
+    #[display("Restoring the data directory failed: {_0}")]
+    RestoreData(DataRestoreError),
+    /// The database couldn't be restored.
+    #[display("Restoring the database failed: {_0}")]
+    #[from(ignore)]
+    RestoreDatabase(MariaDbError),
+    /// The backup isn't of the installed Nextcloud version.
+    #[display("Restore refused: {_0}, pass --force to restore anyway")]
+    Version(VersionError),
+    /// The restore wasn't confirmed.
+    #[display("Restore not confirmed, pass --yes to skip the confirmation")]
+    NotConfirmed,
+    /// The enabled backends couldn't be set up.
+    #[display("{_0}")]
+    Backends(RegistryError),
+    /// The run of the backends was aborted.
+    #[display("Backup aborted: {_0}")]
+    Runner(RunnerError),
+    /// The backup root of the instance couldn't be determined.
+    #[display("{_0}")]
+    Layout(LayoutError),
+    /// `--instance` names an instance missing from the config.
+    #[display("Instance {_0} isn't configured")]
+    #[from(ignore)]
+    UnknownInstance(#[error(not(source))] String),
+    /// The action requires a single instance but several are configured.
+    #[display("Several instances are configured, select one using --instance")]
+    AmbiguousInstance,
+    /// The run manifest couldn't be verified.
+    #[display("Verifying the run manifest failed: {_0}")]
+    Manifest(ManifestError),
+    /// The backup may not start yet, see `[window]`.
+    #[display("Backup refused: {_0}")]
+    Deferred(Deferral),
+}
+
+impl Error {
+    /// Exit code reported for the error.
+    fn exit(&self) -> Exit {
+        match self {
+            Error::ReadConfig(..)
+            | Error::ParseConfig(..)
+            | Error::InstallUnits(..)
+            | Error::Backends(..)
+            | Error::UnknownInstance(..)
+            | Error::AmbiguousInstance
+            | Error::Plan(..)
+            | Error::NotConfirmed => Exit::Config,
+            Error::Installation(..)
+            | Error::Layout(..)
+            | Error::AfterRestore(..)
+            | Error::RestoreOcc(..)
+            | Error::Runner(RunnerError::Status(..)) => Exit::Installation,
+            Error::Pin(..) => Exit::BackendFailed,
+            Error::Runner(RunnerError::Preflight { .. })
+            | Error::Deferred(..)
+            | Error::Version(..) => Exit::Preflight,
+            Error::Runner(RunnerError::Maintenance(..)) | Error::Maintenance(..) => {
+                Exit::Maintenance
+            }
+            Error::Runner(RunnerError::Hook(..)) => Exit::Hook,
+            Error::Extract(..)
+            | Error::RestoreFile(..)
+            | Error::Mount(..)
+            | Error::RestoreConfig(..)
+            | Error::RestoreData(..)
+            | Error::RestoreDatabase(..) => Exit::BackendFailed,
+            Error::Manifest(..) | Error::Catalog(..) => Exit::Verification,
+        }
+    }
+}
 
 fn main() -> ExitCode {
     let cli = Cli::parse();
-    let enabled_backends: HashSet<_> = cli.enabled_backends.into_iter().collect();
 
     // init logger
+    let log_tail = LogTail::new(100);
     let mut env_logger = env_logger::builder();
     if let Some(level) = cli.verbose {
         env_logger.filter_level(level);
     }
-    env_logger.try_init().expect("env_logger should not fail");
+    let progress = (!cli.no_progress && io::stderr().is_terminal() && !systemd::is_journald())
+        .then(ProgressBars::new);
+    let log_writer = match &progress {
+        Some(progress) => progress.log_writer(log_tail.writer()),
+        None => log_tail.writer(),
+    };
+    env_logger.target(env_logger::Target::Pipe(log_writer));
+    if systemd::is_journald() {
+        // the journal adds timestamps itself and parses the syslog priority prefix
+        env_logger.format(|buf, record| {
+            let priority = match record.level() {
+                log::Level::Error => 3,
+                log::Level::Warn => 4,
+                log::Level::Info => 6,
+                log::Level::Debug | log::Level::Trace => 7,
+            };
+            writeln!(buf, "<{priority}>{}: {}", record.target(), record.args())
+        });
+    }
+    if let Err(e) = env_logger.try_init() {
+        eprintln!("Initializing the logger failed: {e}");
+    }
+    systemd::spawn_watchdog();
+
+    match run(cli, &log_tail, progress.as_ref()) {
+        Ok(exit) => exit.into(),
+        Err(e) => {
+            tracing::error!("{e}");
+            if let Error::Installation(..) = e {
+                tracing::error!(
+                    "Use --document-root or installation_root of the instance to point to your Nextcloud installation"
+                );
+            }
+            e.exit().into()
+        }
+    }
+}
+
+/// Load the config file at `path`.
+///
+/// If it doesn't exist yet the default config is written to `path`.
+fn load_config(path: &std::path::Path) -> Result<BackendsConfig, Error> {
+    match std::fs::read(path) {
+        Ok(config_str) => Ok(toml::from_slice(&config_str)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            tracing::debug!(
+                "Writing default config to {} because it doesn't exist yet",
+                path.display()
+            );
+            let default_config = BackendsConfig::default();
+            let written = toml::to_string_pretty(&default_config)
+                .map_err(io::Error::other)
+                .and_then(|config_str| std::fs::write(path, config_str));
+            if let Err(e) = written {
+                tracing::warn!("Writing default config to {} failed {e}", path.display());
+            }
+
+            Ok(default_config)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Run the requested action and return the exit code to report.
+///
+/// Failures of individual backends are reported by the returned [Exit] code.
+///
+/// # Errors
+///
+/// Fails if the run couldn't be started or had to be aborted.
+fn run(cli: Cli, log_tail: &LogTail, progress: Option<&ProgressBars>) -> Result<Exit, Error> {
+    if let Action::InstallUnits(args) = &cli.action {
+        let mut unit_args = vec![
+            "--backup-root".to_string(),
+            cli.backup_root.display().to_string(),
+            "--config".to_string(),
+            cli.config.display().to_string(),
+        ];
+        if let Some(document_root) = &cli.document_root {
+            unit_args.push("--document-root".to_string());
+            unit_args.push(document_root.display().to_string());
+        }
+        systemd::install_units(&args.dir, &unit_args, &args.on_calendar)
+            .map_err(Error::InstallUnits)?;
+        tracing::info!(
+            "Enable the backup with: systemctl daemon-reload && systemctl enable --now {}",
+            systemd::TIMER_UNIT
+        );
+        return Ok(Exit::Success);
+    }
+    if let Action::Extract(args) = &cli.action {
+        extract(args).map_err(Error::Extract)?;
+        return Ok(Exit::Success);
+    }
+
+    let mut backends_config = load_config(&cli.config)?;
+    command::set_timeouts(backends_config.timeouts.clone());
+    let priority = &mut backends_config.priority;
+    priority.nice = cli.nice.or(priority.nice);
+    priority.ionice_class = cli.ionice_class.or(priority.ionice_class);
+    command::set_priority(*priority);
+    // the backup root of other layouts and instances requires the installation
+    if let Action::VerifyManifest(args) = &cli.action {
+        if backends_config.instance.is_empty() && backends_config.layout == BackupLayout::Flat {
+            return verify_manifest(args, &cli.backup_root, &backends_config);
+        }
+    }
+    let mariadb_config = &mut backends_config.mariadb;
+    mariadb_config
+        .include_tables
+        .extend(cli.db_include_table.iter().cloned());
+    mariadb_config
+        .exclude_tables
+        .extend(cli.db_exclude_table.iter().cloned());
+    mariadb_config.full_every = cli.db_full_every.or(mariadb_config.full_every);
+    mariadb_config.host = cli.db_host.clone().or(mariadb_config.host.take());
+    mariadb_config.port = cli.db_port.or(mariadb_config.port);
+    mariadb_config.socket = cli.db_socket.clone().or(mariadb_config.socket.take());
+    if !cli.user.is_empty() {
+        backends_config.data.users.clone_from(&cli.user);
+    }
+    mariadb_config.verify |= cli.verify_dump;
+    if let Some(config_id) = &cli.snapper_config {
+        backends_config.snapper = backends_config.snapper.config_id(config_id.clone());
+    }
+    if cli.snapper_create_config {
+        backends_config.snapper = backends_config.snapper.create_config(true);
+    }
+
+    if cli.dry_run {
+        tracing::warn!("Running in dry-run mode");
+    }
+
+    let mut occ = backends_config.occ.clone();
+    if let Some(php_user) = &cli.php_user {
+        occ = occ.user(php_user.clone());
+    }
+    if let Some(php) = &cli.php {
+        occ = occ.php(php.clone());
+    }
+    for flag in &cli.php_flag {
+        occ = occ.php_flag(flag.clone());
+    }
+    let occ = occ.retry(backends_config.retry.policy("occ"));
+    let parallel = backends_config.parallel_instances;
+    let window = backends_config.window.clone();
+    let mut instances = if backends_config.instance.is_empty() {
+        let mut nextcloud = Nextcloud::builder().occ(occ);
+        if let Some(document_root) = &cli.document_root {
+            nextcloud = nextcloud.installation_root(document_root.clone());
+        }
+        let nextcloud = nextcloud.build()?;
+        let backup_root = backends_config
+            .layout
+            .backup_root(&cli.backup_root, &nextcloud)?;
+        vec![Instance {
+            name: None,
+            nextcloud,
+            backup_root,
+            config: backends_config,
+        }]
+    } else {
+        configured_instances(&cli, &backends_config, &occ)?
+    };
+    for instance in &mut instances {
+        if let Some(tiering) = &mut instance.config.tiering {
+            *tiering = tiering.nested(&cli.backup_root, &instance.backup_root);
+        }
+    }
+
+    match &cli.action {
+        Action::Config(ConfigAction::Export { format }) => {
+            let instance = single_instance(&instances)?;
+            let mut sources = vec![instance.backup_root.clone()];
+            match instance.nextcloud.occ().data_directory() {
+                Ok(data_directory) => sources.push(data_directory),
+                Err(e) => tracing::warn!("Data directory omitted from export: {e}"),
+            }
+            print!("{}", instance.config.export(*format, &sources));
+            Ok(Exit::Success)
+        }
+        Action::Daemon(args) => daemon(
+            &cli,
+            args,
+            &instances,
+            parallel,
+            window.as_ref(),
+            log_tail,
+            progress,
+        ),
+        Action::InstallUnits(..) => unreachable!("units are installed early"),
+        Action::Extract(..) => unreachable!("dumps are extracted early"),
+        Action::VerifyManifest(args) => {
+            let instance = single_instance(&instances)?;
+            verify_manifest(args, &instance.backup_root, &instance.config)
+        }
+        Action::List => for_each_instance(&instances, false, |instance| list(&cli, instance)),
+        Action::Pin(args) => pin(&cli, &args.backups, true, single_instance(&instances)?),
+        Action::Unpin(args) => pin(&cli, &args.backups, false, single_instance(&instances)?),
+        Action::Doctor => for_each_instance(&instances, false, |instance| diagnose(&cli, instance)),
+        Action::AfterRestore(args) => {
+            let regenerate_previews = match &args.manifest {
+                Some(path) => RunManifest::read(path)?
+                    .appdata
+                    .is_some_and(AppdataMode::lacks_previews),
+                None => false,
+            };
+            single_instance(&instances)?
+                .nextcloud
+                .after_restore(cli.dry_run, regenerate_previews)
+                .map_err(Error::AfterRestore)?;
+            Ok(Exit::Success)
+        }
+        Action::RestoreFile(args) => {
+            let instance = single_instance(&instances)?;
+            let reporter: &dyn ProgressReporter = match progress {
+                Some(progress) => progress,
+                None => &LogProgress,
+            };
+            let restore_progress = reporter.job("restore");
+            let restored = instance
+                .config
+                .snapper
+                .clone()
+                .streams_root(&instance.backup_root)
+                .privilege(instance.config.privilege)
+                .build()
+                .and_then(|snapper| {
+                    snapper.restore_path(
+                        &instance.nextcloud,
+                        args.from_snapshot,
+                        &args.path,
+                        args.target.as_deref(),
+                        cli.dry_run,
+                        restore_progress.as_ref(),
+                    )
+                })
+                .map_err(Error::RestoreFile)?;
+            restore_progress.finish();
+            tracing::info!("Restored {}", restored.display());
+            Ok(Exit::Success)
+        }
+        Action::Mount(args) => mount(&cli, args, single_instance(&instances)?, progress),
+        Action::Restore(args) => restore(&cli, args, single_instance(&instances)?, progress),
+        action => {
+            if let (Action::Backup(..), Some(window)) = (action, &window) {
+                window.wait(false, &AtomicBool::new(false))?;
+            }
+            for_each_instance(&instances, parallel, |instance| {
+                backup(&cli, action, instance, log_tail, progress)
+            })
+        }
+    }
+}
+
+/// Nextcloud instance an action is run on.
+struct Instance {
+    /// Name of its `[instance.<name>]` section, [None] for the instance of the command line.
+    name: Option<String>,
+    nextcloud: Nextcloud,
+    backup_root: PathBuf,
+    /// Config with the overrides of the instance applied.
+    config: BackendsConfig,
+}
+
+/// Set up the instances of the config selected by `--instance`.
+fn configured_instances(
+    cli: &Cli,
+    backends_config: &BackendsConfig,
+    occ: &OccBuilder,
+) -> Result<Vec<Instance>, Error> {
+    if let Some(unknown) = cli
+        .instance
+        .iter()
+        .find(|name| !backends_config.instance.contains_key(*name))
+    {
+        return Err(Error::UnknownInstance(unknown.clone()));
+    }
+
+    let mut instances = Vec::new();
+    for (name, instance) in &backends_config.instance {
+        if !cli.instance.is_empty() && !cli.instance.contains(name) {
+            continue;
+        }
+        let nextcloud = Nextcloud::builder()
+            .occ(instance.occ(occ))
+            .installation_root(instance.installation_root.clone())
+            .build()?;
+        let config = instance.apply(backends_config);
+        let backup_root = config
+            .layout
+            .backup_root(&instance.backup_root(name, &cli.backup_root), &nextcloud)?;
+        instances.push(Instance {
+            name: Some(name.clone()),
+            nextcloud,
+            backup_root,
+            config,
+        });
+    }
 
-    let backends_config: BackendsConfig = match std::fs::read(&cli.config) {
-        Ok(config_str) => match toml::from_slice(&config_str) {
+    Ok(instances)
+}
+
+/// The only instance of `instances`, for actions which can't be run on several.
+fn single_instance(instances: &[Instance]) -> Result<&Instance, Error> {
+    match instances {
+        [instance] => Ok(instance),
+        _ => Err(Error::AmbiguousInstance),
+    }
+}
+
+/// Run `f` on every instance, in parallel if `parallel` is set.
+///
+/// The error of a single instance is returned as is. Of several instances the
+/// errors are logged and the exit code of the worst outcome is returned.
+fn for_each_instance(
+    instances: &[Instance],
+    parallel: bool,
+    f: impl Fn(&Instance) -> Result<Exit, Error> + Sync,
+) -> Result<Exit, Error> {
+    if let [instance] = instances {
+        return f(instance);
+    }
+
+    let run = |instance: &Instance| {
+        let name = instance.name.as_deref().unwrap_or_default();
+        let _span = tracing::info_span!("instance", instance = name).entered();
+        tracing::info!("Starting instance: {name}");
+        let exit = match f(instance) {
+            Ok(exit) => exit,
             Err(e) => {
-                log::error!("Reading the config file failed: {e}");
-                return ExitCode::from(255);
+                tracing::error!("Instance {name} failed: {e}");
+                e.exit()
             }
-            Ok(cfg) => cfg,
+        };
+        tracing::info!("Finished instance {name}: {exit:?}");
+        exit
+    };
+    let exits: Vec<_> = if parallel {
+        thread::scope(|scope| {
+            let runs: Vec<_> = instances
+                .iter()
+                .map(|instance| scope.spawn(|| run(instance)))
+                .collect();
+            runs.into_iter()
+                .map(|run| run.join().expect("instance should not panic"))
+                .collect()
+        })
+    } else {
+        instances.iter().map(run).collect()
+    };
+
+    Ok(exits
+        .into_iter()
+        .filter(|exit| *exit != Exit::Success)
+        .min_by_key(|exit| *exit as u8)
+        .unwrap_or(Exit::Success))
+}
+
+/// Verify the run manifest of `args` and the backups listed in it.
+///
+/// A generation is looked up in the catalog of `backup_root`.
+fn verify_manifest(
+    args: &VerifyManifestArgs,
+    backup_root: &Path,
+    backends_config: &BackendsConfig,
+) -> Result<Exit, Error> {
+    let signing = manifest_signing(backends_config);
+    if signing.is_none() {
+        tracing::warn!("No manifest signing configured, the signature isn't checked");
+    }
+    let generation = args
+        .manifest
+        .to_str()
+        .and_then(|manifest| manifest.parse::<BackupGeneration>().ok());
+    let path = match generation {
+        Some(generation) => match manifest::by_generation(backup_root, generation) {
+            Ok(Some((path, _))) => path,
+            Ok(None) => {
+                tracing::error!("No run manifest of generation {generation} found");
+                return Ok(Exit::Verification);
+            }
+            Err(e) => return Err(Error::Catalog(e)),
         },
-        Err(e) => {
-            if std::fs::exists(&cli.config).is_ok_and(|b| !b) {
-                log::debug!(
-                    "Writing default config to {} because it doesn't exist yet",
-                    cli.config.display()
-                );
-                let default_config = BackendsConfig::default();
-                let config_str = toml::to_string_pretty(&default_config)
-                    .expect("default config should be serializable");
-                if let Err(e) = std::fs::write(&cli.config, config_str) {
-                    log::warn!(
-                        "Writing default config to {} failed {e}",
-                        cli.config.display(),
-                    );
+        None => args.manifest.clone(),
+    };
+    tracing::info!("Verify run manifest: {}", path.display());
+    let verification = match &backends_config.tiering {
+        Some(tiering) => manifest::verify_tiered(&path, signing, backup_root, tiering)?,
+        None => manifest::verify(&path, signing)?,
+    };
+    tracing::info!(
+        "{} backups verified, {} missing, {} altered",
+        verification.verified.len(),
+        verification.missing.len(),
+        verification.altered.len()
+    );
+
+    match verification.success() {
+        true => Ok(Exit::Success),
+        false => Ok(Exit::Verification),
+    }
+}
+
+/// Reassemble the deduplicated or differential dump of `args` and check it against its checksum.
+///
+/// Physical backups are extracted into the output directory and prepared.
+fn extract(args: &ExtractArgs) -> io::Result<()> {
+    if args.manifest.to_string_lossy().ends_with(".xbstream.gz") {
+        let Some(output) = &args.output else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a physical backup requires --output",
+            ));
+        };
+        return mariadb_physical::restore(&args.manifest, output).map_err(io::Error::other);
+    }
+    if args.manifest.to_string_lossy().ends_with(".sql.zst") {
+        return mariadb::restore_diff(&args.manifest, args.base.as_deref(), args.output.as_deref())
+            .map_err(io::Error::other);
+    }
+    let manifest = Manifest::read(&args.manifest)?;
+    let store = ChunkStore::beside(&args.manifest);
+    let mut reader = HashingReader::new(store.reader(manifest.clone()));
+    match &args.output {
+        Some(output) => {
+            let mut file = std::fs::File::create_new(output)?;
+            io::copy(&mut reader, &mut file)?;
+            file.sync_all()?;
+        }
+        None => {
+            io::copy(&mut reader, &mut io::stdout().lock())?;
+        }
+    }
+
+    if reader.hex_digest() != manifest.hex_digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "dump doesn't match its checksum",
+        ));
+    }
+    Ok(())
+}
+
+/// Run [backup] of the `instances` on the schedule of `args` until SIGTERM or SIGINT is received.
+///
+/// Every run waits for the backup `window` to open and the system to calm down.
+fn daemon(
+    cli: &Cli,
+    args: &DaemonArgs,
+    instances: &[Instance],
+    parallel: bool,
+    window: Option<&WindowConfig>,
+    log_tail: &LogTail,
+    progress: Option<&ProgressBars>,
+) -> Result<Exit, Error> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        if let Err(e) = signal_hook::flag::register(signal, Arc::clone(&shutdown)) {
+            tracing::warn!("Registering the shutdown handler failed: {e}");
+        }
+    }
+
+    let schedule =
+        Schedule::new(args.schedule.clone()).jitter(Duration::from_secs(args.jitter * 60));
+    let last_run_file = cli.backup_root.join(schedule::LAST_RUN_FILE);
+    let action = Action::Backup(args.backup.clone());
+    systemd::ready();
+
+    loop {
+        let now = Local::now().naive_local();
+        if schedule.missed(schedule::read_last_run(&last_run_file), now) {
+            tracing::info!("Catching up on missed backup");
+        } else if !schedule.wait(&shutdown) {
+            break;
+        }
+        match window.map(|window| window.wait(true, &shutdown)) {
+            Some(Err(Deferral::Shutdown)) => break,
+            Some(Err(e)) => {
+                // record the skipped run, so it isn't caught up on right away
+                tracing::warn!("Skipping scheduled backup: {e}");
+                if !cli.dry_run {
+                    if let Err(e) =
+                        schedule::write_last_run(&last_run_file, Local::now().naive_local())
+                    {
+                        tracing::warn!("Recording the last run failed: {e}");
+                    }
                 }
+                continue;
+            }
+            Some(Ok(())) | None => {}
+        }
+
+        let started = Local::now().naive_local();
+        let exit = for_each_instance(instances, parallel, |instance| {
+            backup(cli, &action, instance, log_tail, progress)
+        });
+        match exit {
+            Ok(Exit::Success) => tracing::info!("Scheduled backup finished"),
+            Ok(exit) => tracing::warn!("Scheduled backup finished with {exit:?}"),
+            Err(e) => tracing::error!("{e}"),
+        }
+        if !cli.dry_run {
+            if let Err(e) = schedule::write_last_run(&last_run_file, started) {
+                tracing::warn!("Recording the last run failed: {e}");
+            }
+        }
+
+        if shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    tracing::info!("Shutting down");
+    systemd::stopping();
+    Ok(Exit::Success)
+}
+
+/// Write the run manifest of `report` of the `instance` as configured by `config`.
+///
+/// Returns the path of the manifest.
+fn write_manifest(
+    cli: &Cli,
+    instance: &Instance,
+    report: &RunReport,
+    generation: Option<BackupGeneration>,
+    config: &ManifestConfig,
+) -> io::Result<PathBuf> {
+    let mut run_manifest = RunManifest::new(report, clock(cli).now())?;
+    run_manifest.generation = generation;
+    run_manifest.nextcloud_version = instance
+        .nextcloud
+        .occ()
+        .status()
+        .map(|status| status.version)
+        .inspect_err(|e| tracing::warn!("Nextcloud version omitted from the run manifest: {e}"))
+        .ok();
+    run_manifest.apps = instance
+        .nextcloud
+        .occ()
+        .app_list()
+        .inspect_err(|e| tracing::warn!("Apps omitted from the run manifest: {e}"))
+        .ok();
+    let appdata = report
+        .jobs
+        .iter()
+        .any(|job| job.name == "appdata" && job.result.is_ok());
+    run_manifest.appdata = appdata.then_some(instance.config.appdata.mode);
+    let snapshots = report
+        .jobs
+        .iter()
+        .filter(|job| job.name == "snapper" && job.result.is_ok())
+        .flat_map(|job| job.artifacts.iter().cloned())
+        .collect::<Vec<_>>();
+    if !snapshots.is_empty() {
+        run_manifest.changes = instance
+            .config
+            .snapper
+            .clone()
+            .privilege(instance.config.privilege)
+            .build()
+            .and_then(|snapper| snapper.changes(&snapshots))
+            .inspect_err(|e| tracing::warn!("Changes omitted from the run manifest: {e}"))
+            .unwrap_or_default();
+    }
+
+    manifest::write(&run_manifest, &instance.backup_root, config)
+}
+
+/// Clock dating the backups, see `--timestamp-override`.
+fn clock(cli: &Cli) -> Clock {
+    match cli.timestamp_override {
+        Some(timestamp) => Clock::Fixed(timestamp),
+        None => Clock::System,
+    }
+}
+
+/// Signing of the run manifests if configured.
+fn manifest_signing(backends_config: &BackendsConfig) -> Option<&Signing> {
+    backends_config
+        .manifest
+        .as_ref()
+        .and_then(|manifest| manifest.signing.as_ref())
+}
+
+/// Create the backends of `instance` enabled by the CLI or the config file.
+///
+/// New backups are named after the `generation` of the run if given.
+fn create_backends(
+    cli: &Cli,
+    instance: &Instance,
+    generation: Option<BackupGeneration>,
+) -> Result<Vec<NamedBackend>, Error> {
+    let backends_config = &instance.config;
+    if let Some(timestamp) = cli.timestamp_override {
+        tracing::warn!("Using {timestamp} as current time");
+    }
+    let clock = clock(cli);
+
+    let enabled_backends = match (&cli.enabled_backends, &backends_config.backends) {
+        (Some(backends), _) | (None, Some(backends)) => backends.clone(),
+        (None, None) => DEFAULT_BACKENDS.iter().map(ToString::to_string).collect(),
+    };
+    let mut ctx = BackendContext::new(&instance.backup_root, backends_config).clock(clock);
+    if let Some(generation) = generation {
+        ctx = ctx.generation(generation);
+    }
+    Ok(BackendRegistry::default().create_all(&enabled_backends, &ctx)?)
+}
 
-                default_config
-            } else {
-                log::error!("Reading the config file failed: {e}");
-                return ExitCode::from(255);
+/// Print the existing backups of the enabled backends.
+///
+/// Backends failing to list their backups are skipped and reported by [Exit::BackendFailed].
+fn list(cli: &Cli, instance: &Instance) -> Result<Exit, Error> {
+    let mut exit = Exit::Success;
+    let mut backups = Vec::new();
+    for (name, backend) in create_backends(cli, instance, None)? {
+        match backend.list(&instance.nextcloud, &instance.config.retention) {
+            Ok(entries) => backups.extend(entries.into_iter().map(|entry| BackendEntry {
+                backend: name.clone(),
+                entry,
+            })),
+            Err(e) => {
+                tracing::error!("Listing the backups of {name} failed: {e}");
+                exit = Exit::BackendFailed;
             }
         }
+    }
+
+    match cli.output {
+        OutputFormat::Text => print_backups(&backups),
+        OutputFormat::Json => match serde_json::to_string_pretty(&backups) {
+            Ok(json) => println!("{json}"),
+            Err(e) => tracing::error!("Serializing the backups failed: {e}"),
+        },
+    }
+
+    Ok(exit)
+}
+
+/// The backups of all enabled backends, skipping backends failing to list theirs.
+fn all_backups(cli: &Cli, instance: &Instance) -> Result<Vec<BackendEntry>, Error> {
+    let mut backups = Vec::new();
+    for (name, backend) in create_backends(cli, instance, None)? {
+        match backend.list(&instance.nextcloud, &instance.config.retention) {
+            Ok(entries) => backups.extend(entries.into_iter().map(|entry| BackendEntry {
+                backend: name.clone(),
+                entry,
+            })),
+            Err(e) => tracing::warn!("Listing the backups of {name} failed: {e}"),
+        }
+    }
+    Ok(backups)
+}
+
+/// Mount the backups selected by `args` read-only or unmount them.
+fn mount(
+    cli: &Cli,
+    args: &MountArgs,
+    instance: &Instance,
+    progress: Option<&ProgressBars>,
+) -> Result<Exit, Error> {
+    let mounter = Mount::new(&args.dir).privilege(instance.config.privilege);
+    let db = match instance.nextcloud.occ().db_config() {
+        Ok(db) if db.dbtype == "mysql" => Some(instance.config.mariadb.connection(db)),
+        Ok(db) => {
+            tracing::warn!(
+                "Database type {} isn't supported, skipping the database",
+                db.dbtype
+            );
+            None
+        }
+        Err(e) => {
+            tracing::warn!("Skipping the database: {e}");
+            None
+        }
     };
+    if args.unmount {
+        mounter.unmount(db.as_ref())?;
+        return Ok(Exit::Success);
+    }
 
-    let dry_run = cli.dry_run;
-    if dry_run {
-        log::warn!("Running in dry-run mode");
+    let at = args.at.unwrap_or_else(|| clock(cli).now());
+    let generation = Generation::select(&all_backups(cli, instance)?, at)?;
+    for backup in generation.data.iter().chain(&generation.database) {
+        tracing::info!(
+            "Selected {} backup of {}: {}",
+            backup.backend,
+            backup.entry.date,
+            backup.entry.artifact
+        );
     }
 
-    let nextcloud = Nextcloud::new(cli.document_root)
-        .expect("Nextcloud should be installed in {cli.document_root}");
+    let reporter: &dyn ProgressReporter = match progress {
+        Some(progress) => progress,
+        None => &LogProgress,
+    };
+    let mount_progress = reporter.job("mount");
+    let state = mounter.mount(
+        &generation,
+        db.as_ref(),
+        args.schema.clone(),
+        cli.dry_run,
+        mount_progress.as_ref(),
+    )?;
+    mount_progress.finish();
+    if let Some(data) = &state.data {
+        tracing::info!("Browse the data directory at {}", data.display());
+    }
+    if let Some(schema) = &state.schema {
+        tracing::info!("Inspect the database {schema}");
+    }
 
-    // FIXME: handle incomplete backups due to terminating signal
+    Ok(Exit::Success)
+}
 
-    nextcloud
-        .occ()
-        .enable_maintenance()
-        .expect("maintenance should be enableable");
+/// Restore the backups selected by `args` into the installation of `instance`.
+///
+/// The components are restored while maintenance mode is on.
+fn restore(
+    cli: &Cli,
+    args: &RestoreArgs,
+    instance: &Instance,
+    progress: Option<&ProgressBars>,
+) -> Result<Exit, Error> {
+    let occ = instance.nextcloud.occ();
+    let point = args
+        .at
+        .unwrap_or_else(|| RestorePoint::Time(clock(cli).now()));
+    let catalog = manifest::catalog(&instance.backup_root).map_err(Error::Catalog)?;
+    // all components are restored by default, as far as they were backed up
+    let explicit = args.db_restore || !args.only.is_empty();
+    let components: Vec<_> = args
+        .components()
+        .into_iter()
+        .filter(|component| *component != Component::Db || args.dump.is_none())
+        .filter(|component| {
+            explicit
+                || RestorePlan::select(&catalog, point, &[*component])
+                    .inspect_err(|e| tracing::warn!("Skipping: {e}"))
+                    .is_ok()
+        })
+        .collect();
+    let mut plan = RestorePlan::select(&catalog, point, &components)?;
+    if args.components().contains(&Component::Db) {
+        plan.database = plan.database.or_else(|| args.dump.clone());
+    }
+    if plan == RestorePlan::default() {
+        tracing::error!("No backups found at {point}");
+        return Ok(Exit::BackendFailed);
+    }
 
-    // spawn threads for different components (Snapper, Config, MariaDB)
+    if let Some(file) = plan.database.as_ref().or(plan.config.as_ref()) {
+        let versions = restore_versions(instance, file)?;
+        match versions.check() {
+            Ok(()) => {}
+            Err(e) if args.force => tracing::warn!("{e}, restoring anyway"),
+            Err(e) => return Err(Error::Version(e)),
+        }
+    }
 
-    let snapper = enabled_backends.get(&Backends::Snapper).map(|_| {
-        let nextcloud = nextcloud.clone();
-        let backend_snapper = backends_config.snapper;
+    if let Some(tiering) = &instance.config.tiering {
+        plan = plan.locate(&instance.backup_root, tiering);
+    }
 
-        match cli.action {
-            Action::Backup(..) => {
-                thread::spawn(move || backend_snapper.backup(&nextcloud, dry_run))
+    let db = match plan.database {
+        Some(_) => Some(
+            occ.db_config()
+                .map(|db| instance.config.mariadb.connection(db))
+                .map_err(Error::RestoreOcc)?,
+        ),
+        None => None,
+    };
+    if let Some(db) = db.as_ref().filter(|db| db.dbtype != "mysql") {
+        let unsupported = MariaDbError::UnsupportedDatabase(db.dbtype.clone());
+        return Err(Error::RestoreDatabase(unsupported));
+    }
+    let data_dir = match plan.data.is_empty() {
+        true => None,
+        false => Some(occ.data_directory().map_err(Error::RestoreOcc)?),
+    };
+    let config_dir = instance.nextcloud.document_root().join("config");
+    if let Some(config) = &plan.config {
+        tracing::info!("Restore {} from {}", config_dir.display(), config.display());
+    }
+    if let (Some(dump), Some(db)) = (&plan.database, &db) {
+        tracing::info!("Replace the database {} by {}", db.name, dump.display());
+    }
+    if let Some(data_dir) = &data_dir {
+        for artifact in &plan.data {
+            tracing::info!("Restore {} from {artifact}", data_dir.display());
+        }
+    }
+    let question = format!("Restore the backups of {point} listed above?");
+    if !cli.dry_run && !args.yes && !confirm(&question) {
+        return Err(Error::NotConfirmed);
+    }
+
+    let reporter: &dyn ProgressReporter = match progress {
+        Some(progress) => progress,
+        None => &LogProgress,
+    };
+    let restore_progress = reporter.job("restore");
+    let maintenance = match cli.dry_run {
+        true => None,
+        false => Some(occ.maintenance_guard().map_err(Error::Maintenance)?),
+    };
+    if let Some(config) = &plan.config {
+        let masker = Masker::new(&instance.config.config)
+            .map_err(|e| Error::RestoreConfig(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        let mut restorer = ConfigRestore::new(masker);
+        match (&instance.config.config.secrets, &args.identity) {
+            (Some(encryption), Some(identity)) => {
+                restorer = restorer.secrets(encryption.clone(), identity.clone());
+            }
+            (None, Some(_)) => {
+                tracing::warn!("No config.secrets configured, ignoring --identity");
             }
-            Action::Retain => thread::spawn(move || {
-                backend_snapper.retention(&nextcloud, &backends_config.retention, dry_run)
-            }),
+            (_, None) => {}
         }
+        restorer
+            .restore(config, &config_dir, cli.dry_run)
+            .map_err(Error::RestoreConfig)?;
+    }
+    if let (Some(dump), Some(db)) = (&plan.database, &db) {
+        let mut restorer = DatabaseRestore::new().grant_host(args.db_grant_host.clone());
+        if let Some(user) = &args.db_admin_user {
+            restorer = restorer.admin_user(user.clone());
+        }
+        restorer
+            .restore(dump, db, cli.dry_run, restore_progress.as_ref())
+            .map_err(Error::RestoreDatabase)?;
+    }
+    if let Some(data_dir) = &data_dir {
+        DataRestore::new()
+            .privilege(instance.config.privilege)
+            .restore(&plan.data, data_dir, cli.dry_run, restore_progress.as_ref())?;
+    }
+    if let Some(maintenance) = maintenance {
+        maintenance.disable().map_err(Error::Maintenance)?;
+    }
+    restore_progress.finish();
+    tracing::info!("Run `nc_backup after-restore` to update the data fingerprint");
+
+    Ok(Exit::Success)
+}
+
+/// Versions of Nextcloud installed and recorded for the backup `file` by its run manifest.
+fn restore_versions(instance: &Instance, file: &Path) -> Result<RestoreVersions, Error> {
+    let installed = instance
+        .nextcloud
+        .occ()
+        .status()
+        .map_err(Error::RestoreOcc)?
+        .version;
+    let file = std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf());
+    let found = manifest::find(&instance.backup_root, &file)
+        .inspect_err(|e| tracing::warn!("Searching the run manifests failed: {e}"))
+        .ok()
+        .flatten();
+    let Some((path, run_manifest)) = found else {
+        tracing::warn!("No run manifest lists {}", file.display());
+        return Ok(RestoreVersions {
+            installed,
+            ..Default::default()
+        });
+    };
+    tracing::debug!("{} is listed by {}", file.display(), path.display());
+    let config_backup = run_manifest.files.iter().find(|listed| {
+        let name = listed
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        name.starts_with(CONFIG_PREFIX) && name.ends_with(CONFIG_SUFFIX)
+    });
+    let config = config_backup.and_then(|listed| {
+        let path = match &instance.config.tiering {
+            Some(tiering) => tiering.locate(&instance.backup_root, &listed.path),
+            None => listed.path.clone(),
+        };
+        version::config_version(&path)
+            .inspect_err(|e| tracing::warn!("Reading {} failed: {e}", path.display()))
+            .ok()
+            .flatten()
     });
 
-    let config = enabled_backends.get(&Backends::Config).map(|_| {
-        let nextcloud = nextcloud.clone();
-        let backend_config = Config::new(&cli.backup_root);
-        match cli.action {
-            Action::Backup(..) => thread::spawn(move || backend_config.backup(&nextcloud, dry_run)),
-            Action::Retain => thread::spawn(move || {
-                backend_config.retention(&nextcloud, &backends_config.retention, dry_run)
-            }),
+    Ok(RestoreVersions {
+        installed,
+        manifest: run_manifest.nextcloud_version,
+        config,
+    })
+}
+
+/// Ask `question` on the terminal, false unless answered with yes or if stdin isn't a terminal.
+fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Pin the `backups` of `instance` or, if `pinned` is `false`, unpin them.
+fn pin(cli: &Cli, backups: &[Artifact], pinned: bool, instance: &Instance) -> Result<Exit, Error> {
+    let pinner = Pinner::new().privilege(instance.config.privilege);
+    for backup in backups {
+        let backup = match backup {
+            Artifact::File(path) => Artifact::File(instance.backup_root.join(path)),
+            Artifact::Directory(path) => Artifact::Directory(instance.backup_root.join(path)),
+            backup => backup.clone(),
+        };
+        pinner.set(&backup, pinned, cli.dry_run)?;
+    }
+    Ok(Exit::Success)
+}
+
+/// Check the prerequisites of the backup and print the results.
+fn diagnose(cli: &Cli, instance: &Instance) -> Result<Exit, Error> {
+    let Instance {
+        nextcloud,
+        config: backends_config,
+        ..
+    } = instance;
+    let mut checks = vec![
+        doctor::check_nextcloud(nextcloud),
+        doctor::check_version(nextcloud),
+    ];
+    checks.extend(doctor::check_backup_root(&instance.backup_root));
+    if let Some(signing) = manifest_signing(backends_config) {
+        checks.push(doctor::check_signing(signing));
+    }
+    let backends = create_backends(cli, instance, None)?;
+    checks.push(doctor::check_encryption(
+        nextcloud,
+        backends.iter().any(|(name, _)| name == "encryption_keys"),
+    ));
+    for (name, backend) in backends {
+        checks.extend(doctor::check_backend(
+            &name,
+            backend.as_ref(),
+            nextcloud,
+            &backends_config.retention,
+        ));
+    }
+
+    match cli.output {
+        OutputFormat::Text => {
+            for Check {
+                name,
+                status,
+                detail,
+                hint,
+            } in &checks
+            {
+                println!("[{:^4}] {name}: {detail}", status.to_string());
+                if let Some(hint) = hint {
+                    println!("       {hint}");
+                }
+            }
         }
-    });
+        OutputFormat::Json => match serde_json::to_string_pretty(&checks) {
+            Ok(json) => println!("{json}"),
+            Err(e) => tracing::error!("Serializing the checks failed: {e}"),
+        },
+    }
+
+    if checks
+        .iter()
+        .any(|check| check.status == CheckStatus::Failed)
+    {
+        Ok(Exit::Preflight)
+    } else {
+        Ok(Exit::Success)
+    }
+}
+
+/// Print `backups` as table to stdout.
+fn print_backups(backups: &[BackendEntry]) {
+    let now = Local::now().naive_local();
+    println!(
+        "{:<12} {:<19} {:>6} {:>10} {:<24} BACKUP",
+        "BACKEND", "DATE", "AGE", "SIZE", "RETENTION"
+    );
+    for BackendEntry { backend, entry } in backups {
+        let BackupEntry {
+            artifact,
+            date,
+            size,
+            pinned,
+            generation: _,
+            retained_by,
+        } = entry;
+        let age = now - *date;
+        let age = match (age.num_days(), age.num_hours()) {
+            (0, hours) => format!("{hours}h"),
+            (days, _) => format!("{days}d"),
+        };
+        let size = size.map(format_size).unwrap_or_else(|| "-".to_string());
+        let retention = match (pinned, retained_by.is_empty()) {
+            (true, _) => "pinned".to_string(),
+            (false, true) => "discard".to_string(),
+            (false, false) => retained_by.join(","),
+        };
+        println!(
+            "{backend:<12} {:<19} {age:>6} {size:>10} {retention:<24} {artifact}",
+            date.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+}
+
+/// Format `bytes` using binary prefixes.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
 
-    let mariadb = enabled_backends.get(&Backends::MariaDb).map(|_| {
-        let nextcloud = nextcloud.clone();
-        let backend_mariadb = MariaDb::new(&cli.backup_root);
-        match cli.action {
-            Action::Backup(..) => {
-                thread::spawn(move || backend_mariadb.backup(&nextcloud, dry_run))
+/// Run the backends for the backup or prune `action` once.
+///
+/// Failures of individual backends are reported by the returned [Exit] code.
+///
+/// # Errors
+///
+/// Fails if the run had to be aborted.
+fn backup(
+    cli: &Cli,
+    action: &Action,
+    instance: &Instance,
+    log_tail: &LogTail,
+    progress: Option<&ProgressBars>,
+) -> Result<Exit, Error> {
+    // FIXME: handle incomplete backups due to terminating signal
+
+    let Instance {
+        nextcloud,
+        config: backends_config,
+        ..
+    } = instance;
+    let dry_run = cli.dry_run;
+    let generation =
+        matches!(action, Action::Backup(..)).then(|| BackupGeneration::new(clock(cli)));
+    if let Some(generation) = generation {
+        tracing::info!("Backup generation {generation}");
+    }
+    let backends = create_backends(cli, instance, generation)?;
+    if matches!(action, Action::Backup(..)) {
+        warn_unprotected_keys(nextcloud, &backends);
+    }
+
+    let mut run_hooks = backends_config.hooks.run.clone();
+    if cli.pre_hook.is_some() {
+        run_hooks.pre.clone_from(&cli.pre_hook);
+    }
+    if cli.post_hook.is_some() {
+        run_hooks.post.clone_from(&cli.post_hook);
+    }
+
+    let mut runner = BackupRunner::new(nextcloud.clone())
+        .hooks(run_hooks)
+        .concurrency(cli.jobs)
+        .preflight(!cli.no_preflight)
+        .maintenance(!cli.no_maintenance)
+        .dry_run(dry_run);
+    runner = match progress {
+        Some(progress) => runner.progress(Box::new(progress.clone())),
+        None => runner.progress(Box::new(LogProgress)),
+    };
+    for (name, backend) in backends {
+        let mut job = match action {
+            Action::Backup(..) => Job::backup(&name, backend),
+            Action::Prune(args) => {
+                Job::retention(&name, backend, args.retention(backends_config.retention))
+            }
+            Action::Daemon(..)
+            | Action::InstallUnits(..)
+            | Action::Extract(..)
+            | Action::VerifyManifest(..)
+            | Action::Config(..)
+            | Action::List
+            | Action::Pin(..)
+            | Action::Unpin(..)
+            | Action::Doctor
+            | Action::AfterRestore(..)
+            | Action::RestoreFile(..)
+            | Action::Mount(..)
+            | Action::Restore(..) => {
+                unreachable!("only backup and prune are run once")
             }
-            Action::Retain => thread::spawn(move || {
-                backend_mariadb.retention(&nextcloud, &backends_config.retention, dry_run)
-            }),
+        };
+        if let Some(hooks) = backends_config.hooks.backends.get(&name) {
+            job = job.hooks(hooks.clone());
         }
-    });
+        runner = runner.job(job);
+    }
 
-    // wait for completion of modules
-    let mut exit_code = 0;
+    let webhook = backends_config
+        .webhook
+        .as_ref()
+        .filter(|_| !dry_run && matches!(action, Action::Backup(..)));
+    if let Some(webhook) = webhook {
+        webhook.start();
+    }
 
-    if let Some(snapper) = snapper {
-        let snapper_res = snapper.join().expect("no panic in backend snapper");
-        if let Err(e) = snapper_res {
-            log::error!(target: "backend::snapper", "Fatal error: {e}");
-            exit_code += 1 << 1;
+    let report_summary = |mut summary: RunSummary| {
+        summary.instance.clone_from(&instance.name);
+        print_summary(cli.output, &summary);
+        if let Some(webhook) = webhook {
+            webhook.finish(&summary);
+        }
+        if let Some(email) = backends_config.email.as_ref().filter(|_| !dry_run) {
+            if let Err(e) = email.send(&summary, log_tail) {
+                tracing::error!("Sending the email report failed: {e}");
+            }
+        }
+    };
+
+    let report = match runner.run() {
+        Ok(report) => report,
+        Err(e) => {
+            report_summary(RunSummary::aborted(&e));
+            return Err(e.into());
+        }
+    };
+    report_summary(RunSummary::from(&report));
+
+    let manifest = backends_config
+        .manifest
+        .as_ref()
+        .filter(|_| !dry_run && matches!(action, Action::Backup(..)));
+    if let Some(config) = manifest {
+        match write_manifest(cli, instance, &report, generation, config) {
+            Ok(path) => tracing::info!("Wrote run manifest: {}", path.display()),
+            Err(e) => tracing::error!("Writing the run manifest failed: {e}"),
         }
     }
 
-    if let Some(config) = config {
-        let config_res = config.join().expect("no panic in backend config");
-        if let Err(e) = config_res {
-            log::error!(target: "backend::config", "Fatal error: {e}");
-            exit_code += 1 << 2;
+    let metrics_dir = cli.metrics_dir.as_ref().or(backends_config
+        .metrics
+        .as_ref()
+        .map(|metrics| &metrics.textfile_dir));
+    if let (Action::Backup(..), Some(metrics_dir), false) = (action, metrics_dir, dry_run) {
+        if let Err(e) = metrics::write(&report, metrics_dir, instance.name.as_deref()) {
+            tracing::error!("Writing metrics failed: {e}");
         }
     }
 
-    if let Some(mariadb) = mariadb {
-        let mariadb_res = mariadb.join().expect("no panic in backend mariadb");
-        if let Err(e) = mariadb_res {
-            log::error!(target: "backend::mariadb", "Fatal error: {e}");
-            exit_code += 1 << 3;
+    let mut exit = if report.failed().next().is_some() {
+        Exit::BackendFailed
+    } else if report.hook_error.is_some() {
+        Exit::Hook
+    } else {
+        Exit::Success
+    };
+
+    if let Action::Backup(args) = action {
+        if args.repair {
+            if dry_run {
+                tracing::info!("Would run the repair steps");
+            } else if let Err(e) = nextcloud.occ().repair() {
+                tracing::error!("Running the repair steps failed: {e}");
+            }
+        }
+        if args.scan_files {
+            if dry_run {
+                tracing::info!("Would rescan the files of all users");
+            } else if let Err(e) = nextcloud.occ().scan_files() {
+                tracing::error!("Rescanning the files failed: {e}");
+            }
         }
     }
 
-    if let Action::Backup(BackupArgs { update: true, .. }) = cli.action {
+    if let Action::Backup(BackupArgs { update: true, .. }) = action {
         if let Err(e) = nextcloud.occ().update_apps(dry_run) {
-            log::error!(target: "apps", "Updating the Nextcloud apps failed: {e}");
-            exit_code += 1;
+            tracing::error!(target: "apps", "Updating the Nextcloud apps failed: {e}");
+            if exit == Exit::Success {
+                exit = Exit::Update;
+            }
         }
     }
 
-    nextcloud
-        .occ()
-        .disable_maintenance()
-        .expect("maintenance should be disableable");
+    Ok(exit)
+}
 
-    if exit_code != 0 {
-        return ExitCode::from(exit_code);
+/// Warn if server-side encryption is enabled but its keys aren't backed up.
+///
+/// The encrypted files can't be restored without the keys.
+fn warn_unprotected_keys(nextcloud: &Nextcloud, backends: &[NamedBackend]) {
+    if backends.iter().any(|(name, _)| name == "encryption_keys") {
+        return;
+    }
+    match nextcloud.occ().encryption_enabled() {
+        Ok(true) => tracing::warn!(
+            "Server-side encryption is enabled but the encryption_keys backend isn't. \
+             WITHOUT THE KEYS THE BACKUP OF THE USER FILES CAN'T BE DECRYPTED!"
+        ),
+        Ok(false) => {}
+        Err(e) => tracing::debug!("Querying the encryption status failed: {e}"),
+    }
+}
+
+/// Print the `summary` of the run to stdout in the requested `format`.
+fn print_summary(format: OutputFormat, summary: &RunSummary) {
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => match serde_json::to_string_pretty(summary) {
+            Ok(json) => println!("{json}"),
+            Err(e) => tracing::error!("Serializing the summary failed: {e}"),
+        },
     }
-    ExitCode::SUCCESS
 }
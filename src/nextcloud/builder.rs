@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use super::{discover, InstallationChecks, Nextcloud, NextcloudError, OccBuilder};
+
+/// Options on how to access a [Nextcloud] installation.
+///
+/// Created by [Nextcloud::builder].
+///
+/// # Example
+///
+/// ```no_run
+/// # use nc_backup_lib::nextcloud::{Nextcloud, OccBuilder, DEFAULT_INSTALLATION_ROOT};
+/// let nc = Nextcloud::builder()
+///     .installation_root(DEFAULT_INSTALLATION_ROOT.into())
+///     .occ(OccBuilder::default().php("php8.2".into()))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct NextcloudBuilder {
+    installation_root: Option<PathBuf>,
+    occ: OccBuilder,
+}
+
+impl NextcloudBuilder {
+    /// Use the installation in `installation_root` instead of discovering it.
+    pub fn installation_root(mut self, installation_root: PathBuf) -> Self {
+        self.installation_root = Some(installation_root);
+        self
+    }
+
+    /// Invoke `occ` as configured by `occ`.
+    pub fn occ(mut self, occ: OccBuilder) -> Self {
+        self.occ = occ;
+        self
+    }
+
+    /// Create the [Nextcloud] instance.
+    ///
+    /// Without [installation root](Self::installation_root) the installation is
+    /// discovered like by [Nextcloud::discover].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the installation root isn't a directory containing Nextcloud's `occ`
+    /// and `config/config.php` or if no installation could be discovered.
+    pub fn build(&self) -> Result<Nextcloud, NextcloudError> {
+        if let Some(installation_root) = &self.installation_root {
+            return self.build_at(installation_root.clone());
+        }
+
+        let candidates = discover::candidates();
+        for candidate in &candidates {
+            tracing::trace!(target: "nextcloud::discover", "Probing {}", candidate.display());
+            if let Ok(nextcloud) = self.build_at(candidate.clone()) {
+                tracing::info!(target: "nextcloud::discover", "Discovered Nextcloud installation in {}", candidate.display());
+                return Ok(nextcloud);
+            }
+        }
+
+        Err(NextcloudError::NotDiscovered { candidates })
+    }
+
+    /// Create the [Nextcloud] instance of the installation in `installation_root`.
+    fn build_at(&self, installation_root: PathBuf) -> Result<Nextcloud, NextcloudError> {
+        // TODO: Handle io::Error
+        let occ_path = installation_root.join("occ");
+        let config_path = installation_root.join("config/config.php");
+        let checks = vec![
+            (installation_root.clone(), installation_root.is_dir()),
+            (occ_path.clone(), occ_path.is_file()),
+            (config_path.clone(), config_path.is_file()),
+        ];
+        if checks.iter().any(|(_, found)| !found) {
+            return Err(NextcloudError::InstalltionNotFound {
+                root: installation_root,
+                checks: InstallationChecks(checks),
+            });
+        }
+        tracing::debug!(target: "nextcloud", "Found Nextcloud installation in {}", installation_root.display());
+
+        let occ = self.occ.build(&installation_root)?;
+
+        Ok(Nextcloud {
+            occ,
+            document_root: installation_root,
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+/// Installation roots of common distributions and packages.
+pub const COMMON_INSTALLATION_ROOTS: &[&str] = &[
+    // Debian/Ubuntu
+    "/var/www/nextcloud",
+    // official docker image
+    "/var/www/html",
+    // Arch Linux
+    "/usr/share/webapps/nextcloud",
+    // snap
+    "/snap/nextcloud/current/htdocs",
+    "/srv/http/nextcloud",
+    "/srv/www/htdocs/nextcloud",
+];
+
+/// Configuration files and directories of web servers possibly referencing Nextcloud.
+const WEB_SERVER_CONFIGS: &[&str] = &[
+    "/etc/apache2/sites-enabled",
+    "/etc/apache2/conf-enabled",
+    "/etc/httpd/conf.d",
+    "/etc/httpd/conf/extra",
+    "/etc/nginx/sites-enabled",
+    "/etc/nginx/conf.d",
+    "/etc/nginx/nginx.conf",
+];
+
+/// Collect candidates for the installation root of Nextcloud.
+///
+/// The [COMMON_INSTALLATION_ROOTS] are followed by the document roots found in
+/// the configurations of Apache and nginx.
+pub(super) fn candidates() -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = COMMON_INSTALLATION_ROOTS
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+    for config in WEB_SERVER_CONFIGS.iter().map(Path::new) {
+        let files = if config.is_dir() {
+            let Ok(entries) = fs::read_dir(config) else {
+                continue;
+            };
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .collect()
+        } else {
+            vec![config.to_path_buf()]
+        };
+
+        for file in files {
+            for root in document_roots(&file) {
+                if !candidates.contains(&root) {
+                    candidates.push(root);
+                }
+            }
+        }
+    }
+
+    candidates
+}
+
+/// Parse the document roots and aliases of a web server configuration `file`.
+fn document_roots(file: &Path) -> Vec<PathBuf> {
+    let Ok(config) = fs::read_to_string(file) else {
+        return Vec::new();
+    };
+    tracing::trace!(target: "nextcloud::discover", "Searching document roots in {}", file.display());
+
+    // Apache: `DocumentRoot /path` or `Alias /nextcloud "/path/"`
+    // nginx: `root /path;`
+    let re = Regex::new(r#"(?m)^\s*(?:DocumentRoot|Alias\s+\S+|root)\s+"?([^"\s;]+)"?"#).unwrap();
+    re.captures_iter(&config)
+        .map(|captures| PathBuf::from(&captures[1]))
+        .collect()
+}
@@ -0,0 +1,56 @@
+use super::{Occ, OccError};
+
+/// Maintenance mode enabled for the lifetime of the guard.
+///
+/// Created by [Occ::maintenance_guard]. Maintenance mode is disabled again
+/// once the guard is dropped, even if the thread is unwinding from a panic.
+/// Use [disable](Self::disable) to handle errors on leaving maintenance mode.
+///
+/// # Example
+///
+/// ```no_run
+/// # use nc_backup_lib::nextcloud::{DEFAULT_INSTALLATION_ROOT, Nextcloud};
+/// let nc = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
+/// let maintenance = nc.occ().maintenance_guard().unwrap();
+/// // backup in a consistent state
+/// maintenance.disable().unwrap();
+/// ```
+#[must_use = "maintenance mode is disabled again once the guard is dropped"]
+#[derive(Debug)]
+pub struct MaintenanceGuard<'a> {
+    occ: &'a Occ,
+    enabled: bool,
+}
+
+impl<'a> MaintenanceGuard<'a> {
+    pub(super) fn enable(occ: &'a Occ) -> Result<Self, OccError> {
+        // created first to also revert a partially enabled maintenance mode
+        let guard = Self { occ, enabled: true };
+        occ.enable_maintenance()?;
+        Ok(guard)
+    }
+
+    /// Disable maintenance mode.
+    pub fn disable(mut self) -> Result<(), OccError> {
+        self.enabled = false;
+        self.occ.disable_maintenance()
+    }
+}
+
+impl Drop for MaintenanceGuard<'_> {
+    fn drop(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if std::thread::panicking() {
+            tracing::error!(target: "nextcloud::maintenance", "Panicked in maintenance mode, disabling it");
+        } else {
+            tracing::warn!(target: "nextcloud::maintenance", "Maintenance guard dropped, disabling maintenance mode");
+        }
+        // never panic in drop as it aborts the process while unwinding
+        if let Err(e) = self.occ.disable_maintenance() {
+            tracing::error!(target: "nextcloud::maintenance", "Disabling maintenance mode failed: {e}");
+        }
+    }
+}
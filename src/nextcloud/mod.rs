@@ -3,12 +3,22 @@
 //! [Nextcloud] is the access point for managing your Nextcloud installation.
 //! Additionally [Occ] exposes some of the commands of Nextcloud's command-line interface.
 
+mod builder;
+mod discover;
+mod maintenance;
 mod occ;
+pub mod redis;
+mod status;
 
 use derive_more::{Display, Error, From};
+use std::fmt;
 use std::path::{Path, PathBuf};
 
-pub use occ::{Occ, OccError, OccPathError};
+pub use builder::NextcloudBuilder;
+pub use discover::COMMON_INSTALLATION_ROOTS;
+pub use maintenance::MaintenanceGuard;
+pub use occ::{AppList, DbConfig, Occ, OccBuilder, OccError, OccPathError, UserInfo};
+pub use status::{NextcloudStatus, StatusError, TESTED_MAJOR_VERSIONS};
 
 /// Default location of the `nextcloud/` folder of a Nextcloud installation on Ubuntu Linux.
 pub const DEFAULT_INSTALLATION_ROOT: &str = "/var/www/nextcloud/";
@@ -20,41 +30,91 @@ pub struct Nextcloud {
     document_root: PathBuf,
 }
 
+/// Paths checked on locating a Nextcloud installation and whether they were found.
+#[derive(Debug)]
+pub struct InstallationChecks(pub Vec<(PathBuf, bool)>);
+
+impl fmt::Display for InstallationChecks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks: Vec<_> = self
+            .0
+            .iter()
+            .map(|(path, found)| {
+                let found = if *found { "found" } else { "missing" };
+                format!("{} ({found})", path.display())
+            })
+            .collect();
+        write!(f, "{}", checks.join(", "))
+    }
+}
+
 #[derive(Display, Debug, Error, From)]
 /// Errors possible on creating a [Nextcloud] instance.
 pub enum NextcloudError {
     /// The installation folder of Nextcloud couldn't be located.
-    #[display("Nextcloud installation directory couldn't be located: {_0:#?}")]
-    InstalltionNotFound(#[error(ignore)] PathBuf),
+    #[display("Nextcloud installation couldn't be located in {}, checked: {checks}", root.display())]
+    InstalltionNotFound {
+        /// Installation root which was checked.
+        #[error(not(source))]
+        root: PathBuf,
+        /// Paths checked below the installation root.
+        #[error(not(source))]
+        checks: InstallationChecks,
+    },
+    /// No Nextcloud installation could be discovered.
+    #[display("No Nextcloud installation found in any of: {}", candidates.iter().map(|c| c.display().to_string()).collect::<Vec<_>>().join(", "))]
+    NotDiscovered {
+        /// Installation roots which were probed.
+        #[error(not(source))]
+        candidates: Vec<PathBuf>,
+    },
     /// Nextcloud's command-line interface couldn't be located.
     #[from]
     Occ(OccPathError),
 }
 
 impl Nextcloud {
-    /// Create a new [Nextcloud] instance.
+    /// Configure a new [Nextcloud] instance, see [NextcloudBuilder].
+    pub fn builder() -> NextcloudBuilder {
+        NextcloudBuilder::default()
+    }
+
+    /// Create a new [Nextcloud] instance of the installation in `installation_root`.
     ///
-    /// You can use the [DEFAULT_INSTALLATION_ROOT] if your Nextcloud installation is
+    /// Shorthand for the [builder](Self::builder) using the default [OccBuilder]. You can use the [DEFAULT_INSTALLATION_ROOT] if your Nextcloud installation is
     /// deployed in `/var/www/nextcloud` as on Ubuntu Linux.
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
     /// let nc = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into());
     /// assert!(nc.is_ok());
     /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails if `installation_root` isn't a directory containing Nextcloud's `occ`
+    /// and `config/config.php`. The error lists every checked path.
     pub fn new(installation_root: PathBuf) -> Result<Nextcloud, NextcloudError> {
-        // TODO: Handle io::Error
-        if !installation_root.is_dir() {
-            return Err(NextcloudError::InstalltionNotFound(installation_root));
-        }
-
-        let occ = Occ;
+        Self::builder().installation_root(installation_root).build()
+    }
 
-        Ok(Self {
-            occ,
-            document_root: installation_root,
-        })
+    /// Discover the Nextcloud installation of this host.
+    ///
+    /// Probes the [COMMON_INSTALLATION_ROOTS] of various distributions and the
+    /// document roots configured for Apache and nginx. The first directory
+    /// containing a Nextcloud installation is used.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use nc_backup_lib::nextcloud::Nextcloud;
+    /// let nc = Nextcloud::discover().unwrap();
+    /// println!("Nextcloud is installed in {}", nc.document_root().display());
+    /// ```
+    pub fn discover() -> Result<Nextcloud, NextcloudError> {
+        Self::builder().build()
     }
 
     /// Get the root document folder of the Nextcloud installation.
@@ -64,7 +124,8 @@ impl Nextcloud {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
     /// let nc = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
     /// assert_eq!(nc.document_root().to_str(), Some("/var/www/nextcloud"));
     /// ```
@@ -76,7 +137,8 @@ impl Nextcloud {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
     /// let nc = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
     /// assert_eq!(nc.config().to_str(), Some("/var/www/nextcloud/config/config.php"));
     /// ```
@@ -84,8 +146,66 @@ impl Nextcloud {
         self.document_root().join("config/config.php")
     }
 
+    /// Invoke `occ` as configured by the [OccBuilder].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use nc_backup_lib::nextcloud::{Nextcloud, OccBuilder, DEFAULT_INSTALLATION_ROOT};
+    /// let occ = OccBuilder::default()
+    ///     .php("php8.2".into())
+    ///     .php_flag("-dmemory_limit=512M".into());
+    /// let nc = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into())
+    ///     .unwrap()
+    ///     .with_occ(&occ)
+    ///     .unwrap();
+    /// ```
+    pub fn with_occ(mut self, occ: &OccBuilder) -> Result<Self, NextcloudError> {
+        self.occ = occ.build(&self.document_root)?;
+        Ok(self)
+    }
+
     /// The command-line interface of the Nextcloud instance.
     pub fn occ(&self) -> &Occ {
         &self.occ
     }
+
+    /// Run the `occ` commands required after the instance was restored from a backup.
+    ///
+    /// The data fingerprint is updated and the repair steps are run. If
+    /// maintenance mode is off, the files are rescanned as well. Previews missing
+    /// from the backup are regenerated if `regenerate_previews` is set and the
+    /// `previewgenerator` app is enabled.
+    pub fn after_restore(&self, dry_run: bool, regenerate_previews: bool) -> Result<(), OccError> {
+        let occ = self.occ();
+        let scan = !occ.maintenance()?;
+        if dry_run {
+            tracing::info!(target: "nextcloud", "Would update the data fingerprint, repair and rescan: {scan}, regenerate previews: {regenerate_previews}");
+            return Ok(());
+        }
+
+        tracing::info!(target: "nextcloud", "Updating the data fingerprint");
+        occ.data_fingerprint()?;
+        tracing::info!(target: "nextcloud", "Running the repair steps");
+        occ.repair()?;
+        if scan {
+            tracing::info!(target: "nextcloud", "Rescanning the files of all users");
+            occ.scan_files()?;
+        } else {
+            tracing::warn!(target: "nextcloud", "Maintenance mode is on, disable it and run occ files:scan --all");
+        }
+        if !regenerate_previews {
+            return Ok(());
+        }
+        if !occ.app_list()?.enabled.contains_key("previewgenerator") {
+            tracing::info!(target: "nextcloud", "Previews weren't backed up and are generated when first viewed");
+        } else if scan {
+            tracing::info!(target: "nextcloud", "Generating the previews, which weren't backed up");
+            occ.generate_previews()?;
+        } else {
+            tracing::warn!(target: "nextcloud", "Previews weren't backed up, run occ preview:generate-all once maintenance mode is off");
+        }
+
+        Ok(())
+    }
 }
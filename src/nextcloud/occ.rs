@@ -1,8 +1,18 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
 use std::io;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 
 use derive_more::{Display, Error, From};
+use serde::de::DeserializeOwned;
+
+use super::{MaintenanceGuard, NextcloudStatus};
+use crate::util::command::{self, CommandRunner};
+use crate::util::retry::{is_transient, retry, RetryConfig};
 
 /// Error on determining the validity of the [Occ] path.
 #[derive(Debug, Display, Error, From)]
@@ -30,31 +40,382 @@ pub enum OccError {
         error: String,
     },
 
+    /// Maintenance mode wasn't toggled although occ reported success.
+    #[display("Maintenance mode didn't become {expected}")]
+    MaintenanceNotToggled {
+        /// Expected state of the maintenance mode.
+        #[error(ignore)]
+        expected: &'static str,
+    },
+
+    /// Output of an [Occ] command isn't valid JSON of the expected type.
+    #[display("Occ returned unexpected JSON: {_0}")]
+    #[from]
+    InvalidJson(serde_json::Error),
+
     /// Generic [io::Error] on command execution.
     #[from]
     IoError(io::Error),
 }
 
+impl OccError {
+    /// Returns whether the error is likely transient, e.g. due to a lost database connection.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::OccCommandFailed { error, .. } => TRANSIENT_FAILURES
+                .iter()
+                .any(|failure| error.contains(failure)),
+            Self::IoError(e) => is_transient(e),
+            _ => false,
+        }
+    }
+}
+
+/// Messages of failed `occ` commands which likely succeed on retry.
+const TRANSIENT_FAILURES: &[&str] = &[
+    "SQLSTATE[HY000] [2002]", // can't connect to the database server
+    "SQLSTATE[HY000] [2006]", // server has gone away
+    "SQLSTATE[40001]",        // deadlock
+    "SQLSTATE[HY000]: General error: 1205", // lock wait timeout
+];
+
 type Result<T> = std::result::Result<T, OccError>;
 
+/// Connection settings of Nextcloud's database.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DbConfig {
+    /// Type of the database, e.g. `mysql`, `pgsql` or `sqlite3`.
+    pub dbtype: String,
+    /// Host of the database server.
+    ///
+    /// [None] if connected by [socket](Self::socket) only.
+    pub host: Option<String>,
+    /// Port of the database server.
+    pub port: Option<u16>,
+    /// Name of the database.
+    pub name: String,
+    /// User connecting to the database.
+    pub user: String,
+    /// Password of the [user](Self::user).
+    pub password: Option<String>,
+    /// Unix socket of the database server.
+    pub socket: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for DbConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DbConfig")
+            .field("dbtype", &self.dbtype)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("name", &self.name)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+/// Database settings as stored in Nextcloud's `config.php`.
+#[derive(serde::Deserialize)]
+struct SystemDbConfig {
+    #[serde(default = "default_dbtype")]
+    dbtype: String,
+    #[serde(default)]
+    dbhost: String,
+    #[serde(default)]
+    dbport: serde_json::Value,
+    #[serde(default)]
+    dbname: String,
+    #[serde(default)]
+    dbuser: String,
+    #[serde(default)]
+    dbpassword: String,
+}
+
+fn default_dbtype() -> String {
+    "sqlite3".to_string()
+}
+
+impl From<SystemDbConfig> for DbConfig {
+    fn from(config: SystemDbConfig) -> Self {
+        let mut host = Some(config.dbhost).filter(|host| !host.is_empty());
+        let mut port = match config.dbport {
+            serde_json::Value::Number(port) => port.as_u64().and_then(|p| p.try_into().ok()),
+            serde_json::Value::String(port) => port.parse().ok(),
+            _ => None,
+        };
+        let mut socket = None;
+
+        // `dbhost` may be `host`, `host:port`, `host:/path/to/socket` or `[ipv6]:port`
+        if let Some(dbhost) = host.take() {
+            let (name, suffix) = match dbhost.strip_prefix('[') {
+                Some(ipv6) => match ipv6.split_once(']') {
+                    Some((name, rest)) => (name.to_string(), rest.strip_prefix(':')),
+                    None => (dbhost.clone(), None),
+                },
+                None => match dbhost.split_once(':') {
+                    Some((name, rest)) => (name.to_string(), Some(rest)),
+                    None => (dbhost.clone(), None),
+                },
+            };
+            match suffix {
+                Some(path) if path.starts_with('/') => socket = Some(PathBuf::from(path)),
+                Some(p) => port = port.or(p.parse().ok()),
+                None => {}
+            }
+            host = Some(name).filter(|name| !name.is_empty());
+        }
+
+        Self {
+            dbtype: config.dbtype,
+            host,
+            port,
+            name: config.dbname,
+            user: config.dbuser,
+            password: Some(config.dbpassword).filter(|pw| !pw.is_empty()),
+            socket,
+        }
+    }
+}
+
+/// Installed apps and their versions as listed by [Occ::app_list].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct AppList {
+    /// Versions of the enabled apps by their id.
+    #[serde(default, deserialize_with = "php_map")]
+    pub enabled: BTreeMap<String, String>,
+    /// Versions of the installed but disabled apps by their id.
+    #[serde(default, deserialize_with = "php_map")]
+    pub disabled: BTreeMap<String, String>,
+}
+
+/// Account of a user as listed by [Occ::user_list].
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct UserInfo {
+    /// Name of the user shown in the web interface.
+    #[serde(default)]
+    pub display_name: String,
+    /// Email address of the user.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Whether the user may log in.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Groups the user is a member of.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Quota of the user, e.g. `none` or `10 GB`.
+    #[serde(default)]
+    pub quota: Option<String>,
+    /// Time of the last login.
+    #[serde(default)]
+    pub last_seen: Option<String>,
+    /// Further details depending on the Nextcloud version, e.g. `storage` and `backend`.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_json::Value>,
+}
+
+/// Deserialize a map, which PHP encodes as `[]` if it's empty.
+fn php_map<'de, D, V>(deserializer: D) -> std::result::Result<BTreeMap<String, V>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    V: serde::Deserialize<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum PhpMap<V> {
+        Map(BTreeMap<String, V>),
+        Empty([(); 0]),
+    }
+
+    match serde::Deserialize::deserialize(deserializer)? {
+        PhpMap::Map(map) => Ok(map),
+        PhpMap::Empty([]) => Ok(BTreeMap::new()),
+    }
+}
+
+/// Number of entries requested per page by [Occ::list_paged], which is the default of `occ`.
+const LIST_PAGE_SIZE: usize = 500;
+
+/// Options on how to invoke [Occ].
+///
+/// By default the `occ` wrapper found in `PATH` is run as owner of Nextcloud's `config.php`.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct OccBuilder {
+    /// User running `occ` using `sudo`.
+    ///
+    /// Defaults to the owner of `config.php`.
+    /// Numeric user ids can be passed as `#<uid>`.
+    pub user: Option<String>,
+
+    /// PHP binary running the `occ` of the installation, e.g. `php8.2`.
+    ///
+    /// If unset the `occ` wrapper in `PATH` is used.
+    pub php: Option<PathBuf>,
+
+    /// Additional flags passed to the PHP binary, e.g. `-d memory_limit=512M`.
+    pub php_flags: Vec<String>,
+
+    /// Additional environment variables of `occ`.
+    pub env: BTreeMap<String, String>,
+
+    /// Retrying of `occ` commands failing due to transient errors.
+    ///
+    /// Set from the `occ` [policy](RetryConfig::policy) of `[retry]`.
+    #[serde(skip)]
+    pub retry: RetryConfig,
+
+    /// Runs the `occ` commands, defaults to the [SystemRunner](command::SystemRunner).
+    #[serde(skip)]
+    pub runner: Option<Arc<dyn CommandRunner>>,
+}
+
+impl OccBuilder {
+    /// Run `occ` as `user`.
+    pub fn user(mut self, user: String) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    /// Run `occ` using the PHP binary `php`.
+    pub fn php(mut self, php: PathBuf) -> Self {
+        self.php = Some(php);
+        self
+    }
+
+    /// Pass `flag` to the PHP binary.
+    pub fn php_flag(mut self, flag: String) -> Self {
+        self.php_flags.push(flag);
+        self
+    }
+
+    /// Retry commands failing due to transient errors as configured by [RetryConfig].
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Run the commands using `runner`, e.g. a [ScriptedRunner](command::ScriptedRunner) in tests.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Set the environment variable `key` to `value`.
+    pub fn env(mut self, key: String, value: String) -> Self {
+        self.env.insert(key, value);
+        self
+    }
+
+    /// Build the [Occ] of the installation in `installation_root`.
+    ///
+    /// If no user is configured and the current user doesn't own `config.php`
+    /// `occ` is run as its owner.
+    pub fn build(&self, installation_root: &Path) -> std::result::Result<Occ, OccPathError> {
+        let occ_path = installation_root.join("occ");
+        if !occ_path.is_file() {
+            return Err(OccPathError::PathNotFound(occ_path));
+        }
+
+        let run_as = match &self.user {
+            Some(user) => Some(user.clone()),
+            None => {
+                let config = installation_root.join("config/config.php");
+                let owner = fs::metadata(&config)?.uid();
+                // the proc filesystem entries of a process belong to its effective user
+                let current_user = fs::metadata("/proc/self")?.uid();
+
+                (owner != current_user).then(|| {
+                    tracing::debug!(target: "nextcloud::occ", "Running occ as owner of {}: uid {owner}", config.display());
+                    format!("#{owner}")
+                })
+            }
+        };
+
+        Ok(Occ {
+            run_as,
+            php: self.php.clone(),
+            php_flags: self.php_flags.clone(),
+            env: self.env.clone(),
+            occ_path,
+            retry: self.retry.clone(),
+            runner: self.runner.clone().unwrap_or_else(command::system_runner),
+        })
+    }
+}
+
 /// Access to the command-line interface of Nextcloud.
+///
+/// Nextcloud refuses to run `occ` as a different user than the owner of its `config.php`.
+/// Therefore `occ` can be run as a different user using `sudo`.
+///
+/// Created by an [OccBuilder].
 #[derive(Debug, Clone)]
-pub struct Occ;
+pub struct Occ {
+    run_as: Option<String>,
+    php: Option<PathBuf>,
+    php_flags: Vec<String>,
+    env: BTreeMap<String, String>,
+    occ_path: PathBuf,
+    retry: RetryConfig,
+    runner: Arc<dyn CommandRunner>,
+}
 
 impl Occ {
+    /// Arguments invoking `occ` with the configured user, PHP binary and environment.
+    fn invocation(&self) -> Vec<OsString> {
+        let mut invocation: Vec<OsString> = Vec::new();
+        if let Some(user) = &self.run_as {
+            invocation.extend(["sudo", "-n", "-u", user].map(OsString::from));
+            // sudo resets the environment
+            if !self.env.is_empty() {
+                invocation.push("env".into());
+                invocation.extend(self.env.iter().map(|(k, v)| format!("{k}={v}").into()));
+            }
+        }
+        match &self.php {
+            Some(php) => {
+                invocation.push(php.into());
+                invocation.extend(self.php_flags.iter().map(OsString::from));
+                invocation.push(self.occ_path.clone().into());
+            }
+            None => invocation.push("occ".into()),
+        }
+
+        invocation
+    }
+
     fn execute_command(&self, command: &str, args: &[&str]) -> Result<String> {
-        log::trace!(
+        let invocation = self.invocation();
+        tracing::trace!(
             target: "nextcloud::occ",
-            "Running: occ --no-warnings {} {}",
+            "Running: {} --no-warnings {} {}",
+            invocation.iter().map(|arg| arg.to_string_lossy()).collect::<Vec<_>>().join(" "),
             command,
             args.join(" ")
         );
-        let mut occ_command = Command::new("occ");
+        retry(
+            &self.retry,
+            &format!("occ {command}"),
+            OccError::is_transient,
+            || self.run(&invocation, command, args),
+        )
+    }
+
+    /// Run the `occ` `command` with `args` once using `invocation`.
+    fn run(&self, invocation: &[OsString], command: &str, args: &[&str]) -> Result<String> {
+        let mut occ_command = Command::new(&invocation[0]);
+        occ_command.args(&invocation[1..]);
+        if self.run_as.is_none() {
+            occ_command.envs(&self.env);
+        }
         occ_command
             .arg("--no-warnings") // suppress maintenance mode is enabled warning
             .arg(command)
             .args(args);
-        let occ_output = occ_command.output()?;
+        let occ_output = self.runner.output(&mut occ_command, "occ")?;
 
         let stdout = String::from_utf8_lossy(&occ_output.stdout);
         let stderr = String::from_utf8_lossy(&occ_output.stderr);
@@ -68,12 +429,30 @@ impl Occ {
 
         // relay stderr
         if !stderr.is_empty() {
-            log::warn!(target: "nextcloud::occ", "{stderr}");
+            tracing::warn!(target: "nextcloud::occ", "{stderr}");
         }
 
         Ok(stdout.trim_end().into())
     }
 
+    /// Returns the version and state of the installation.
+    pub fn status(&self) -> Result<NextcloudStatus> {
+        let json = self.execute_command("status", &["--output=json"])?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Returns whether server-side encryption is enabled.
+    pub fn encryption_enabled(&self) -> Result<bool> {
+        #[derive(serde::Deserialize)]
+        struct EncryptionStatus {
+            enabled: bool,
+        }
+
+        let json = self.execute_command("encryption:status", &["--output=json"])?;
+        let status: EncryptionStatus = serde_json::from_str(&json)?;
+        Ok(status.enabled)
+    }
+
     /// Returns whether maintenance mode is enabled.
     pub fn maintenance(&self) -> Result<bool> {
         let msg = self.execute_command("maintenance:mode", &[])?;
@@ -81,11 +460,17 @@ impl Occ {
     }
 
     /// Enable the maintenance mode.
+    ///
+    /// Prefer [maintenance_guard](Self::maintenance_guard) to not leave it enabled by accident.
     pub fn enable_maintenance(&self) -> Result<()> {
         let _ = self.execute_command("maintenance:mode", &["--on"])?;
 
-        assert!(self.maintenance()?, "maintenance should be enabled");
-        log::debug!(target: "occ", "Maintenance Mode enabled.");
+        if !self.maintenance()? {
+            return Err(OccError::MaintenanceNotToggled {
+                expected: "enabled",
+            });
+        }
+        tracing::debug!(target: "occ", "Maintenance Mode enabled.");
 
         Ok(())
     }
@@ -94,17 +479,46 @@ impl Occ {
     pub fn disable_maintenance(&self) -> Result<()> {
         let _ = self.execute_command("maintenance:mode", &["--off"])?;
 
-        assert!(!self.maintenance()?, "maintenance should be disabled");
-        log::debug!(target: "occ", "Maintenance Mode disabled.");
+        if self.maintenance()? {
+            return Err(OccError::MaintenanceNotToggled {
+                expected: "disabled",
+            });
+        }
+        tracing::debug!(target: "occ", "Maintenance Mode disabled.");
 
         Ok(())
     }
 
+    /// Enable the maintenance mode until the returned [MaintenanceGuard] is dropped.
+    pub fn maintenance_guard(&self) -> Result<MaintenanceGuard<'_>> {
+        MaintenanceGuard::enable(self)
+    }
+
+    /// Returns the value of the system config `keys` deserialized as `T`.
+    ///
+    /// Nested values are accessed by multiple keys, e.g. `["trusted_domains", "0"]`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
+    /// let nc = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
+    /// let trusted_domains: Vec<String> = nc
+    ///     .occ()
+    ///     .config_system_get_json(&["trusted_domains"])
+    ///     .unwrap();
+    /// ```
+    pub fn config_system_get_json<T: DeserializeOwned>(&self, keys: &[&str]) -> Result<T> {
+        let mut args = keys.to_vec();
+        args.push("--output=json");
+        let json = self.execute_command("config:system:get", &args)?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
     /// Returns a path to the data directory of Nextcloud.
     pub fn data_directory(&self) -> Result<PathBuf> {
-        let data_directory: PathBuf = self
-            .execute_command("config:system:get", &["datadirectory"])?
-            .into();
+        let data_directory: PathBuf = self.config_system_get_json(&["datadirectory"])?;
         assert!(
             data_directory.is_dir(),
             "nextcloud data directory should be an accesible directory"
@@ -113,14 +527,95 @@ impl Occ {
         Ok(data_directory)
     }
 
+    /// Returns the app directories configured by `apps_paths`.
+    ///
+    /// Fails if `apps_paths` isn't set, in which case only the `apps/`
+    /// directory of the installation is used.
+    pub fn apps_paths(&self) -> Result<Vec<PathBuf>> {
+        #[derive(serde::Deserialize)]
+        struct AppsPath {
+            path: PathBuf,
+        }
+
+        let apps_paths: Vec<AppsPath> = self.config_system_get_json(&["apps_paths"])?;
+        Ok(apps_paths.into_iter().map(|apps| apps.path).collect())
+    }
+
+    /// Returns the `instanceid` identifying the installation.
+    pub fn instance_id(&self) -> Result<String> {
+        self.config_system_get_json(&["instanceid"])
+    }
+
+    /// Returns the installed apps along with their versions.
+    pub fn app_list(&self) -> Result<AppList> {
+        let json = self.execute_command("app:list", &["--output=json"])?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Returns the accounts of all users including their groups, quota and storage usage.
+    pub fn user_list(&self) -> Result<BTreeMap<String, UserInfo>> {
+        self.list_paged("user:list", &["--info"])
+    }
+
+    /// Returns the members of every group by the group id.
+    pub fn group_list(&self) -> Result<BTreeMap<String, Vec<String>>> {
+        self.list_paged("group:list", &[])
+    }
+
+    /// Run the listing `command` with `args` page by page until every entry is listed.
+    fn list_paged<V: DeserializeOwned>(
+        &self,
+        command: &str,
+        args: &[&str],
+    ) -> Result<BTreeMap<String, V>> {
+        let mut entries = BTreeMap::new();
+        loop {
+            let limit = format!("--limit={LIST_PAGE_SIZE}");
+            let offset = format!("--offset={}", entries.len());
+            let mut page_args = args.to_vec();
+            page_args.extend([limit.as_str(), offset.as_str(), "--output=json"]);
+            let json = self.execute_command(command, &page_args)?;
+            let page: BTreeMap<String, V> =
+                php_map(serde_json::from_str::<serde_json::Value>(&json)?)?;
+
+            let len = page.len();
+            entries.extend(page);
+            if len < LIST_PAGE_SIZE {
+                return Ok(entries);
+            }
+        }
+    }
+
+    /// Generates the missing previews of all files using the `previewgenerator` app.
+    pub fn generate_previews(&self) -> Result<()> {
+        let _ = self.execute_command("preview:generate-all", &[])?;
+        Ok(())
+    }
+
     /// Returns the name of the database.
     pub fn db_name(&self) -> Result<String> {
-        self.execute_command("config:system:get", &["dbname"])
+        self.config_system_get_json(&["dbname"])
     }
 
     /// Returns the database user.
     pub fn db_user(&self) -> Result<String> {
-        self.execute_command("config:system:get", &["dbuser"])
+        self.config_system_get_json(&["dbuser"])
+    }
+
+    /// Returns the connection settings of the database.
+    ///
+    /// The settings include the database password.
+    pub fn db_config(&self) -> Result<DbConfig> {
+        #[derive(serde::Deserialize)]
+        struct ConfigList {
+            system: SystemDbConfig,
+        }
+
+        let json =
+            self.execute_command("config:list", &["system", "--private", "--output=json"])?;
+        let config: ConfigList = serde_json::from_str(&json)?;
+
+        Ok(config.system.into())
     }
 
     /// Updates all apps.
@@ -134,7 +629,49 @@ impl Occ {
 
         let update_log = self.execute_command("app:update", &opts)?;
         for line in update_log.lines() {
-            log::info!(target: "nextcloud::occ", "Update Apps: {line}");
+            tracing::info!(target: "nextcloud::occ", "Update Apps: {line}");
+        }
+
+        Ok(())
+    }
+
+    /// Run the repair steps of Nextcloud.
+    pub fn repair(&self) -> Result<()> {
+        let repair_log = self.execute_command("maintenance:repair", &[])?;
+        for line in repair_log.lines() {
+            tracing::info!(target: "nextcloud::occ", "Repair: {line}");
+        }
+
+        Ok(())
+    }
+
+    /// Update the data fingerprint, telling the sync clients that the data was restored.
+    pub fn data_fingerprint(&self) -> Result<()> {
+        let _ = self.execute_command("maintenance:data-fingerprint", &[])?;
+
+        Ok(())
+    }
+
+    /// Rescan the files of all users to bring the file cache in sync with the data directory.
+    ///
+    /// Fails in maintenance mode.
+    pub fn scan_files(&self) -> Result<()> {
+        let scan_log = self.execute_command("files:scan", &["--all"])?;
+        for line in scan_log.lines() {
+            tracing::info!(target: "nextcloud::occ", "Scan files: {line}");
+        }
+
+        Ok(())
+    }
+
+    /// Rescan the files below `path`, e.g. `/alice/files/Photos`.
+    ///
+    /// The path is relative to the data directory and starts with the user.
+    pub fn scan_path(&self, path: &Path) -> Result<()> {
+        let path = format!("--path=/{}", path.display());
+        let scan_log = self.execute_command("files:scan", &[&path])?;
+        for line in scan_log.lines() {
+            tracing::info!(target: "nextcloud::occ", "Scan files: {line}");
         }
 
         Ok(())
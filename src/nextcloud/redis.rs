@@ -0,0 +1,150 @@
+//! Access to the Redis server used by Nextcloud for transactional file locking.
+
+use std::fmt;
+use std::io;
+use std::process::Command;
+
+use derive_more::{Display, Error, From};
+
+use super::{Occ, OccError};
+use crate::util::command;
+
+/// Error on running a [Redis] command.
+#[derive(Debug, Display, Error, From)]
+pub enum RedisError {
+    /// `redis-cli` couldn't be run.
+    #[display("Running redis-cli failed: {_0}")]
+    Io(io::Error),
+    /// The Redis server answered with an error.
+    #[display("Redis command failed: {_0}")]
+    #[from(ignore)]
+    Failed(#[error(ignore)] String),
+}
+
+/// Handling of Nextcloud's Redis locking state before a snapshot is taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisAction {
+    /// Persist the Redis database in the background using `BGSAVE`.
+    BgSave,
+    /// Drop all keys of Nextcloud's Redis database using `FLUSHDB`.
+    ///
+    /// This clears stale file locks along with cached data, which Nextcloud rebuilds.
+    Flush,
+}
+
+/// Connection settings of the Redis server from the `redis` system config.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Redis {
+    /// Host name or path to the unix socket of the Redis server.
+    pub host: String,
+    /// Port of the Redis server.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Index of the Redis database used by Nextcloud.
+    #[serde(default)]
+    pub dbindex: Option<u32>,
+    /// User to authenticate as.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Password of the [user](Self::user).
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for Redis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Redis")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("dbindex", &self.dbindex)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl Redis {
+    /// Returns the Redis server used for file locking by Nextcloud.
+    ///
+    /// Returns [None] if `memcache.locking` isn't set to Redis.
+    pub fn locking(occ: &Occ) -> Result<Option<Self>, OccError> {
+        let locking: String = match occ.config_system_get_json(&["memcache.locking"]) {
+            Ok(locking) => locking,
+            Err(OccError::OccCommandFailed { .. }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !locking.ends_with("Redis") {
+            tracing::debug!(target: "nextcloud::redis", "File locking uses {locking}");
+            return Ok(None);
+        }
+
+        occ.config_system_get_json(&["redis"]).map(Some)
+    }
+
+    /// Run the Redis command `args` using `redis-cli` and return its reply.
+    pub fn command(&self, args: &[&str]) -> Result<String, RedisError> {
+        let mut cmd = Command::new("redis-cli");
+        if self.host.starts_with('/') {
+            cmd.args(["-s", &self.host]);
+        } else {
+            cmd.args(["-h", &self.host]);
+            if let Some(port) = self.port.filter(|port| *port != 0) {
+                cmd.args(["-p", &port.to_string()]);
+            }
+        }
+        if let Some(dbindex) = self.dbindex {
+            cmd.args(["-n", &dbindex.to_string()]);
+        }
+        if let Some(user) = &self.user {
+            cmd.args(["--user", user]);
+        }
+        // keep the password out of the process list
+        if let Some(password) = self
+            .password
+            .as_ref()
+            .filter(|password| !password.is_empty())
+        {
+            cmd.env("REDISCLI_AUTH", password);
+        }
+        cmd.args(args);
+
+        tracing::trace!(target: "nextcloud::redis", "Running: redis-cli {}", args.join(" "));
+        let output = command::output(&mut cmd, "redis-cli")?;
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+        // redis-cli reports errors of the server on stdout
+        let is_error = ["ERR", "NOAUTH", "WRONGPASS", "NOPERM", "(error)"]
+            .iter()
+            .any(|prefix| stdout.starts_with(prefix));
+        if !output.status.success() || is_error {
+            return Err(RedisError::Failed(if stderr.is_empty() {
+                stdout
+            } else {
+                stderr
+            }));
+        }
+
+        Ok(stdout)
+    }
+
+    /// Perform the [RedisAction].
+    ///
+    /// On a dry run the server is only pinged.
+    pub fn apply(&self, action: RedisAction, dry_run: bool) -> Result<(), RedisError> {
+        if dry_run {
+            self.command(&["PING"])?;
+            tracing::info!(target: "nextcloud::redis", "Would perform {action:?} on Redis");
+            return Ok(());
+        }
+
+        let reply = match action {
+            RedisAction::BgSave => self.command(&["BGSAVE"])?,
+            RedisAction::Flush => self.command(&["FLUSHDB"])?,
+        };
+        tracing::info!(target: "nextcloud::redis", "Redis {action:?}: {reply}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+//! State of the Nextcloud installation as reported by `occ status`.
+
+use std::ops::RangeInclusive;
+
+use derive_more::{Display, Error};
+
+/// Major versions of Nextcloud the backup was tested with.
+pub const TESTED_MAJOR_VERSIONS: RangeInclusive<u32> = 27..=32;
+
+/// Output of `occ status`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NextcloudStatus {
+    /// Whether the installation was completed.
+    pub installed: bool,
+    /// Full version, e.g. `30.0.4.1`.
+    pub version: String,
+    /// Human readable version, e.g. `30.0.4`.
+    #[serde(rename = "versionstring")]
+    pub version_string: String,
+    /// Edition, empty for the community edition.
+    #[serde(default)]
+    pub edition: String,
+    /// Whether maintenance mode is enabled.
+    pub maintenance: bool,
+    /// Whether `occ upgrade` has to be run after updating the code.
+    #[serde(default)]
+    pub needs_db_upgrade: bool,
+}
+
+/// The installation isn't in a state to be backed up.
+#[derive(Debug, Display, Error)]
+pub enum StatusError {
+    /// The installation wasn't completed.
+    #[display("Nextcloud isn't installed")]
+    NotInstalled,
+    /// The code was updated but the database wasn't migrated yet.
+    #[display("Nextcloud {_0} needs a database upgrade, run occ upgrade first")]
+    NeedsDbUpgrade(#[error(ignore)] String),
+}
+
+impl NextcloudStatus {
+    /// Major version of Nextcloud if the version is valid.
+    pub fn major_version(&self) -> Option<u32> {
+        self.version.split('.').next()?.parse().ok()
+    }
+
+    /// Checks that the installation can be backed up consistently.
+    ///
+    /// Versions outside of [TESTED_MAJOR_VERSIONS] are only warned about.
+    pub fn check(&self) -> Result<(), StatusError> {
+        if !self.installed {
+            return Err(StatusError::NotInstalled);
+        }
+        if self.needs_db_upgrade {
+            return Err(StatusError::NeedsDbUpgrade(self.version_string.clone()));
+        }
+        match self.major_version() {
+            Some(major) if TESTED_MAJOR_VERSIONS.contains(&major) => {}
+            _ => tracing::warn!(
+                target: "nextcloud",
+                "Nextcloud {} wasn't tested, tested are the major versions {} to {}",
+                self.version_string,
+                TESTED_MAJOR_VERSIONS.start(),
+                TESTED_MAJOR_VERSIONS.end()
+            ),
+        }
+
+        Ok(())
+    }
+}
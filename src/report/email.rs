@@ -0,0 +1,252 @@
+//! Email report of a run sent via SMTP.
+//!
+//! Mails are sent using `curl`. The report contains the [RunSummary] and the
+//! last lines of the log captured by a [LogTail].
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use chrono::Local;
+
+use super::summary::RunSummary;
+use crate::util::command::{TimedOut, Watchdog};
+
+/// Encryption of the connection to the SMTP server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Unencrypted connection (default port 25).
+    None,
+    /// Upgrade the connection using STARTTLS (default port 587).
+    #[default]
+    StartTls,
+    /// Implicit TLS (default port 465).
+    Tls,
+}
+
+/// Configure sending of email reports.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct EmailConfig {
+    /// Host of the SMTP server.
+    pub host: String,
+
+    /// Port of the SMTP server.
+    ///
+    /// Defaults to the port matching [tls](Self::tls).
+    pub port: Option<u16>,
+
+    /// Encryption of the connection.
+    #[serde(default)]
+    pub tls: SmtpTls,
+
+    /// User authenticating at the SMTP server.
+    pub username: Option<String>,
+
+    /// Password of the [username](Self::username).
+    pub password: Option<String>,
+
+    /// Sender address.
+    pub from: String,
+
+    /// Recipient addresses.
+    pub to: Vec<String>,
+
+    /// Send a report after every run instead of only after failed ones.
+    #[serde(default)]
+    pub always: bool,
+}
+
+/// Error on sending an email report.
+#[derive(Debug, derive_more::Display, derive_more::Error, derive_more::From)]
+pub enum EmailError {
+    /// `curl` couldn't be run.
+    #[display("curl couldn't be run: {_0}")]
+    Io(io::Error),
+    /// `curl` failed to deliver the mail.
+    #[display("curl failed with {_0}")]
+    #[from(ignore)]
+    Failed(#[error(ignore)] std::process::ExitStatus),
+    /// `curl` didn't finish in time.
+    TimedOut(TimedOut),
+}
+
+impl EmailConfig {
+    /// Send the report of the run if it failed or [always](Self::always) is set.
+    pub fn send(&self, summary: &RunSummary, log: &LogTail) -> Result<(), EmailError> {
+        if summary.success && !self.always {
+            return Ok(());
+        }
+
+        let (scheme, default_port) = match self.tls {
+            SmtpTls::None => ("smtp", 25),
+            SmtpTls::StartTls => ("smtp", 587),
+            SmtpTls::Tls => ("smtps", 465),
+        };
+        let url = format!(
+            "{scheme}://{}:{}",
+            self.host,
+            self.port.unwrap_or(default_port)
+        );
+
+        let message_path =
+            std::env::temp_dir().join(format!("nc_backup-report-{}.eml", std::process::id()));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&message_path)?
+            .write_all(self.message(summary, log).as_bytes())?;
+
+        tracing::debug!(target: "report::email", "Running: curl -sS --connect-timeout 10 -m 60 --url {url} --upload-file {}", message_path.display());
+        let mut curl = Command::new("curl");
+        // an unreachable server mustn't delay the end of the run
+        curl.args(["-sS", "--connect-timeout", "10", "-m", "60", "-K", "-"])
+            .args(["--url", &url, "--mail-from", &self.from])
+            .arg("--upload-file")
+            .arg(&message_path)
+            .stdin(Stdio::piped());
+        for to in &self.to {
+            curl.arg("--mail-rcpt").arg(to);
+        }
+        if self.tls == SmtpTls::StartTls {
+            curl.arg("--ssl-reqd");
+        }
+
+        let mut watchdog = None;
+        let status = curl.spawn().and_then(|mut child| {
+            watchdog = Some(Watchdog::start("curl", &[child.id()]));
+            let mut stdin = child.stdin.take().expect("stdin should be untaken");
+            // keep the credentials out of the process list
+            if let Some(username) = &self.username {
+                let password = self.password.as_deref().unwrap_or_default();
+                writeln!(
+                    stdin,
+                    "user = \"{}:{}\"",
+                    username.replace('\\', "\\\\").replace('"', "\\\""),
+                    password.replace('\\', "\\\\").replace('"', "\\\"")
+                )?;
+            }
+            drop(stdin);
+            child.wait()
+        });
+        let _ = fs::remove_file(&message_path);
+
+        if let Some(watchdog) = watchdog {
+            watchdog.stop()?;
+        }
+        let status = status?;
+        if !status.success() {
+            return Err(EmailError::Failed(status));
+        }
+
+        Ok(())
+    }
+
+    /// Render the mail of the report.
+    fn message(&self, summary: &RunSummary, log: &LogTail) -> String {
+        let outcome = if summary.success {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        let backup = match &summary.instance {
+            Some(instance) => format!("Backup of {instance}"),
+            None => "Backup".to_string(),
+        };
+
+        let mut message = String::new();
+        writeln!(message, "From: {}\r", self.from).unwrap();
+        writeln!(message, "To: {}\r", self.to.join(", ")).unwrap();
+        writeln!(message, "Subject: [nc_backup] {backup} {outcome}\r").unwrap();
+        writeln!(message, "Date: {}\r", Local::now().to_rfc2822()).unwrap();
+        writeln!(message, "Content-Type: text/plain; charset=utf-8\r").unwrap();
+        writeln!(message, "\r").unwrap();
+
+        writeln!(message, "{backup} {outcome}.\r").unwrap();
+        if let Some(error) = &summary.error {
+            writeln!(message, "Error: {error}\r").unwrap();
+        }
+        writeln!(message, "\r").unwrap();
+        for job in &summary.jobs {
+            let status = if job.success { "ok" } else { "FAILED" };
+            writeln!(
+                message,
+                "{}: {status} after {:.1}s\r",
+                job.name, job.duration_secs
+            )
+            .unwrap();
+            if let Some(error) = &job.error {
+                writeln!(message, "  {error}\r").unwrap();
+            }
+            for artifact in &job.artifacts {
+                writeln!(message, "  {artifact}\r").unwrap();
+            }
+        }
+
+        writeln!(message, "\r\nLast log lines:\r").unwrap();
+        for line in log.lines() {
+            writeln!(message, "{line}\r").unwrap();
+        }
+
+        message
+    }
+}
+
+/// Captures the last lines of the log while passing it through to stderr.
+///
+/// Use [LogTail::writer] as target of the logger.
+#[derive(Clone, Debug)]
+pub struct LogTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogTail {
+    /// Capture the last `capacity` lines of the log.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Writer capturing everything written to it before forwarding it to stderr.
+    pub fn writer(&self) -> Box<dyn Write + Send> {
+        Box::new(self.clone())
+    }
+
+    /// The captured lines.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines
+            .lock()
+            .expect("log tail should not be poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Write for LogTail {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut lines = self.lines.lock().expect("log tail should not be poisoned");
+        for line in String::from_utf8_lossy(buf).lines() {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+        drop(lines);
+
+        io::stderr().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -0,0 +1,275 @@
+//! Manifest of the files created by a run.
+//!
+//! The manifest lists the SHA-256 of every file as stored, so a restore can
+//! [verify] that backups kept on third-party storage weren't altered. If
+//! [signing](ManifestConfig::signing) is configured the manifest itself is
+//! signed, otherwise it only detects corruption.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+use derive_more::{Display, Error, From};
+
+use crate::backends::snapper::SnapshotDiff;
+use crate::backends::{AppdataMode, Artifact};
+use crate::nextcloud::AppList;
+use crate::runner::RunReport;
+use crate::util::artifact::{write_artifact, ARTIFACT_TS};
+use crate::util::checksum::HashingReader;
+use crate::util::generation::BackupGeneration;
+use crate::util::sign::Signing;
+use crate::util::tiering::TieringConfig;
+
+/// Directory in the backup root the manifests are written to.
+pub const MANIFEST_DEST: &str = "manifests/";
+
+/// Configure writing of run manifests.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ManifestConfig {
+    /// Sign every manifest, e.g. `[manifest.signing]` with `tool = "gpg"` and the `key`.
+    pub signing: Option<Signing>,
+}
+
+/// File listed in a [RunManifest].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ManifestFile {
+    /// Path of the file when it was created.
+    pub path: PathBuf,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file as stored.
+    pub sha256: String,
+}
+
+/// Files created by a run.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct RunManifest {
+    /// Time of the run.
+    pub created: NaiveDateTime,
+    /// Generation of the run, embedded into the names of the files and the snapshots it created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<BackupGeneration>,
+    /// Files created by the successful jobs.
+    pub files: Vec<ManifestFile>,
+    /// Other artifacts created by the successful jobs, e.g. snapshots.
+    pub artifacts: Vec<String>,
+    /// Version of Nextcloud at the time of the run, e.g. `30.0.4.1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nextcloud_version: Option<String>,
+    /// Apps installed at the time of the run, so a restore onto a fresh
+    /// installation can enable the same apps at the same versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apps: Option<AppList>,
+    /// What the `appdata` backend archived, if it was run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub appdata: Option<AppdataMode>,
+    /// Files changed since the previous snapshots, if [enabled](crate::backends::SnapperBuilder::diff).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<SnapshotDiff>,
+}
+
+/// Error of [verify].
+#[derive(Debug, Display, Error, From)]
+pub enum ManifestError {
+    /// The manifest couldn't be read.
+    #[display("Reading the manifest failed: {_0}")]
+    Io(io::Error),
+    /// The manifest isn't valid.
+    #[display("Parsing the manifest failed: {_0}")]
+    Parse(serde_json::Error),
+    /// The signature of the manifest is missing or invalid.
+    #[display("Signature of the manifest is invalid: {_0}")]
+    #[from(ignore)]
+    Signature(io::Error),
+}
+
+/// Outcome of [verify].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Files matching the manifest.
+    pub verified: Vec<PathBuf>,
+    /// Files which no longer exist, e.g. because they were pruned.
+    pub missing: Vec<PathBuf>,
+    /// Files whose size or SHA-256 differ from the manifest.
+    pub altered: Vec<PathBuf>,
+}
+
+impl Verification {
+    /// Whether none of the existing files were altered.
+    pub fn success(&self) -> bool {
+        self.altered.is_empty()
+    }
+}
+
+/// SHA-256 and size of the file `path` as stored.
+fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    let size = io::copy(&mut reader, &mut io::sink())?;
+    Ok((reader.hex_digest(), size))
+}
+
+impl RunManifest {
+    /// Create the manifest of the successful jobs of `report` run at `created`.
+    pub fn new(report: &RunReport, created: NaiveDateTime) -> io::Result<Self> {
+        let mut manifest = Self {
+            created,
+            generation: None,
+            files: Vec::new(),
+            artifacts: Vec::new(),
+            nextcloud_version: None,
+            apps: None,
+            appdata: None,
+            changes: Vec::new(),
+        };
+        let artifacts = report
+            .jobs
+            .iter()
+            .filter(|job| job.result.is_ok())
+            .flat_map(|job| &job.artifacts);
+        for artifact in artifacts {
+            match artifact {
+                Artifact::File(path) => {
+                    let (sha256, size) = hash_file(path)?;
+                    manifest.files.push(ManifestFile {
+                        path: path.clone(),
+                        size,
+                        sha256,
+                    });
+                }
+                artifact => manifest.artifacts.push(artifact.to_string()),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Read the manifest `path` without verifying it.
+    pub fn read(path: &Path) -> Result<Self, ManifestError> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// Write the `manifest` to the [MANIFEST_DEST] of `backup_root` and sign it.
+///
+/// Returns the path of the manifest.
+pub fn write(
+    manifest: &RunManifest,
+    backup_root: &Path,
+    config: &ManifestConfig,
+) -> io::Result<PathBuf> {
+    let dir = backup_root.join(MANIFEST_DEST);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("run-{}.json", manifest.created.format(ARTIFACT_TS)));
+    tracing::debug!(target: "report::manifest", "Write run manifest to: {}", path.display());
+
+    let content = serde_json::to_vec_pretty(manifest)?;
+    write_artifact(&path, |partial| fs::write(partial, &content))?;
+    if let Some(signing) = &config.signing {
+        let signature = signing.sign(&path)?;
+        tracing::debug!(target: "report::manifest", "Signed run manifest: {}", signature.display());
+    }
+
+    Ok(path)
+}
+
+/// Read the run manifests in the [MANIFEST_DEST] of `backup_root` along with their paths.
+///
+/// The manifests are sorted by the time of their run. Unreadable manifests are skipped.
+pub fn catalog(backup_root: &Path) -> io::Result<Vec<(PathBuf, RunManifest)>> {
+    let dir = backup_root.join(MANIFEST_DEST);
+    let entries = match fs::read_dir(&dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        entries => entries?,
+    };
+    let mut manifests = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match RunManifest::read(&path) {
+            Ok(manifest) => manifests.push((path, manifest)),
+            Err(e) => {
+                tracing::warn!(target: "report::manifest", "Skipping {}: {e}", path.display())
+            }
+        }
+    }
+    manifests.sort_by_key(|(_, manifest)| manifest.created);
+
+    Ok(manifests)
+}
+
+/// Find the run manifest in the [catalog] of `backup_root` listing `file`.
+///
+/// Returns the path and the content of the manifest.
+pub fn find(backup_root: &Path, file: &Path) -> io::Result<Option<(PathBuf, RunManifest)>> {
+    Ok(catalog(backup_root)?
+        .into_iter()
+        .find(|(_, manifest)| manifest.files.iter().any(|listed| listed.path == file)))
+}
+
+/// Find the run manifest of the run `generation` in the [catalog] of `backup_root`.
+///
+/// Returns the path and the content of the manifest.
+pub fn by_generation(
+    backup_root: &Path,
+    generation: BackupGeneration,
+) -> io::Result<Option<(PathBuf, RunManifest)>> {
+    Ok(catalog(backup_root)?
+        .into_iter()
+        .find(|(_, manifest)| manifest.generation == Some(generation)))
+}
+
+/// Verify the signature of the manifest `path` and the files listed in it.
+///
+/// The signature is only checked if `signing` is given.
+pub fn verify(path: &Path, signing: Option<&Signing>) -> Result<Verification, ManifestError> {
+    verify_located(path, signing, |file| file.to_path_buf())
+}
+
+/// Verify the manifest `path` like [verify], also finding the files of
+/// `backup_root` which were moved to the cold storage of `tiering`.
+pub fn verify_tiered(
+    path: &Path,
+    signing: Option<&Signing>,
+    backup_root: &Path,
+    tiering: &TieringConfig,
+) -> Result<Verification, ManifestError> {
+    verify_located(path, signing, |file| tiering.locate(backup_root, file))
+}
+
+/// Verify the manifest `path` hashing the listed files at the path returned by `locate`.
+fn verify_located(
+    path: &Path,
+    signing: Option<&Signing>,
+    locate: impl Fn(&Path) -> PathBuf,
+) -> Result<Verification, ManifestError> {
+    if let Some(signing) = signing {
+        signing.verify(path).map_err(ManifestError::Signature)?;
+        tracing::info!(target: "report::manifest", "Valid {} signature of: {}", signing.tool(), path.display());
+    }
+    let manifest = RunManifest::read(path)?;
+
+    let mut verification = Verification::default();
+    for file in manifest.files {
+        match hash_file(&locate(&file.path)) {
+            Ok(stored) if stored == (file.sha256, file.size) => {
+                verification.verified.push(file.path)
+            }
+            Ok(_) => {
+                tracing::error!(target: "report::manifest", "Altered: {}", file.path.display());
+                verification.altered.push(file.path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                tracing::warn!(target: "report::manifest", "Missing: {}", file.path.display());
+                verification.missing.push(file.path);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(verification)
+}
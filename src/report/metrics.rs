@@ -0,0 +1,174 @@
+//! Metrics of a run in the Prometheus text format.
+//!
+//! The metrics are written to a textfile picked up by the textfile collector
+//! of the [node_exporter](https://github.com/prometheus/node_exporter).
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backends::Artifact;
+use crate::runner::RunReport;
+use crate::util::fs::write_atomic;
+
+/// Name of the textfile written to the [MetricsConfig::textfile_dir].
+pub const METRICS_FILE: &str = "nc_backup.prom";
+
+/// Configure writing of metrics.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct MetricsConfig {
+    /// Directory of the node_exporter textfile collector, e.g. `/var/lib/node_exporter`.
+    pub textfile_dir: PathBuf,
+}
+
+/// Render the metrics of the `report` in the Prometheus text format.
+pub fn render(report: &RunReport) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# HELP nc_backup_last_run_timestamp_seconds Time the last run finished."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE nc_backup_last_run_timestamp_seconds gauge").unwrap();
+    writeln!(out, "nc_backup_last_run_timestamp_seconds {timestamp}").unwrap();
+
+    writeln!(
+        out,
+        "# HELP nc_backup_run_success Whether every backend and hook of the last run succeeded."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE nc_backup_run_success gauge").unwrap();
+    writeln!(out, "nc_backup_run_success {}", u8::from(report.success())).unwrap();
+
+    if let Some(status) = &report.nextcloud {
+        writeln!(
+            out,
+            "# HELP nc_backup_nextcloud_info Version of Nextcloud backed up in the last run."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE nc_backup_nextcloud_info gauge").unwrap();
+        writeln!(
+            out,
+            "nc_backup_nextcloud_info{{version=\"{}\"}} 1",
+            status.version
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP nc_backup_success Whether the backend succeeded in the last run."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE nc_backup_success gauge").unwrap();
+    for job in &report.jobs {
+        writeln!(
+            out,
+            "nc_backup_success{{backend=\"{}\"}} {}",
+            job.name,
+            u8::from(job.result.is_ok())
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP nc_backup_duration_seconds Duration of the backend in the last run."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE nc_backup_duration_seconds gauge").unwrap();
+    for job in &report.jobs {
+        writeln!(
+            out,
+            "nc_backup_duration_seconds{{backend=\"{}\"}} {:.3}",
+            job.name,
+            job.duration.as_secs_f64()
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP nc_backup_artifact_bytes Size of the files created by the backend in the last run."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE nc_backup_artifact_bytes gauge").unwrap();
+    for job in &report.jobs {
+        let bytes: u64 = job
+            .artifacts
+            .iter()
+            .filter_map(|artifact| match artifact {
+                Artifact::File(path) => fs::metadata(path).ok().map(|m| m.len()),
+                _ => None,
+            })
+            .sum();
+        writeln!(
+            out,
+            "nc_backup_artifact_bytes{{backend=\"{}\"}} {bytes}",
+            job.name
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP nc_backup_snapshot_id Id of the snapshot created in the last run."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE nc_backup_snapshot_id gauge").unwrap();
+    for job in &report.jobs {
+        for artifact in &job.artifacts {
+            if let Artifact::Snapshot { config, id } = artifact {
+                writeln!(
+                    out,
+                    "nc_backup_snapshot_id{{backend=\"{}\",config=\"{config}\"}} {id}",
+                    job.name
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    out
+}
+
+/// Adds the label `instance` to every sample of the rendered `metrics`.
+fn label_instance(metrics: &str, instance: &str) -> String {
+    let label = format!("instance=\"{instance}\"");
+    let mut out = String::with_capacity(metrics.len());
+    for line in metrics.lines() {
+        let labelled = match line.find(['{', ' ']) {
+            _ if line.starts_with('#') => line.to_string(),
+            Some(idx) if line[idx..].starts_with('{') => {
+                format!("{}{{{label},{}", &line[..idx], &line[idx + 1..])
+            }
+            Some(idx) => format!("{}{{{label}}}{}", &line[..idx], &line[idx..]),
+            None => line.to_string(),
+        };
+        writeln!(out, "{labelled}").unwrap();
+    }
+    out
+}
+
+/// Atomically write the metrics of the `report` to the [METRICS_FILE] in `textfile_dir`.
+///
+/// The metrics of an `instance` are written to a file of its own and labelled by its name.
+pub fn write(report: &RunReport, textfile_dir: &Path, instance: Option<&str>) -> io::Result<()> {
+    let (path, metrics) = match instance {
+        Some(instance) => (
+            textfile_dir.join(format!("nc_backup-{instance}.prom")),
+            label_instance(&render(report), instance),
+        ),
+        None => (textfile_dir.join(METRICS_FILE), render(report)),
+    };
+    tracing::debug!(target: "report::metrics", "Writing metrics to {}", path.display());
+
+    write_atomic(&path, metrics.as_bytes())
+}
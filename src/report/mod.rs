@@ -0,0 +1,16 @@
+//! Reporting of the outcome of a run to external systems.
+//!
+//! - [email]: Mail via SMTP.
+//! - [manifest]: Signed list of the files created by a run.
+//! - [metrics]: Prometheus textfile for the node_exporter.
+//! - [webhook]: Healthchecks.io style pings and JSON webhooks.
+//!
+//! Reporters consume the serializable [RunSummary] of a run.
+
+pub mod email;
+pub mod manifest;
+pub mod metrics;
+pub mod summary;
+pub mod webhook;
+
+pub use summary::{JobSummary, RunSummary};
@@ -0,0 +1,73 @@
+//! Serializable summary of a [RunReport].
+
+use crate::runner::{JobReport, RunReport};
+
+/// Summary of a single [JobReport].
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct JobSummary {
+    /// Name of the job.
+    pub name: String,
+    /// Whether the job succeeded.
+    pub success: bool,
+    /// Duration of the job in seconds.
+    pub duration_secs: f64,
+    /// Created artifacts.
+    pub artifacts: Vec<String>,
+    /// Error of the failed job.
+    pub error: Option<String>,
+}
+
+impl From<&JobReport> for JobSummary {
+    fn from(job: &JobReport) -> Self {
+        Self {
+            name: job.name.clone(),
+            success: job.result.is_ok(),
+            duration_secs: job.duration.as_secs_f64(),
+            artifacts: job.artifacts.iter().map(ToString::to_string).collect(),
+            error: job.result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+/// Summary of a [RunReport] or an aborted run.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct RunSummary {
+    /// Name of the instance if [several](crate::backends::BackendsConfig::instance) are backed up.
+    pub instance: Option<String>,
+    /// Whether every job and hook succeeded.
+    pub success: bool,
+    /// Summaries of every job.
+    pub jobs: Vec<JobSummary>,
+    /// Error aborting the run or of the post hook of the run.
+    pub error: Option<String>,
+    /// Version of Nextcloud at the start of the run if known.
+    pub nextcloud_version: Option<String>,
+}
+
+impl From<&RunReport> for RunSummary {
+    fn from(report: &RunReport) -> Self {
+        Self {
+            instance: None,
+            success: report.success(),
+            jobs: report.jobs.iter().map(JobSummary::from).collect(),
+            error: report.hook_error.as_ref().map(ToString::to_string),
+            nextcloud_version: report
+                .nextcloud
+                .as_ref()
+                .map(|status| status.version.clone()),
+        }
+    }
+}
+
+impl RunSummary {
+    /// Summary of a run aborted by `error` before any job completed.
+    pub fn aborted(error: impl ToString) -> Self {
+        Self {
+            instance: None,
+            success: false,
+            jobs: Vec::new(),
+            error: Some(error.to_string()),
+            nextcloud_version: None,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Pinging of Healthchecks.io style URLs and generic JSON webhooks.
+//!
+//! Requests are sent using `curl`. Failing requests are logged but never fail the run.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use super::summary::RunSummary;
+
+/// Configure URLs notified about a run.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// URL pinged before the run, e.g. `https://hc-ping.com/<uuid>/start`.
+    pub start_url: Option<String>,
+
+    /// URL pinged after a successful run, e.g. `https://hc-ping.com/<uuid>`.
+    pub success_url: Option<String>,
+
+    /// URL pinged after a failed run, e.g. `https://hc-ping.com/<uuid>/fail`.
+    pub failure_url: Option<String>,
+
+    /// URL the [RunSummary] is posted to as JSON after every run.
+    pub summary_url: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Ping the start URL.
+    pub fn start(&self) {
+        if let Some(url) = &self.start_url {
+            request(url, None);
+        }
+    }
+
+    /// Ping the success or failure URL and post the `summary`.
+    pub fn finish(&self, summary: &RunSummary) {
+        let url = if summary.success {
+            &self.success_url
+        } else {
+            &self.failure_url
+        };
+        if let Some(url) = url {
+            request(url, None);
+        }
+
+        if let Some(url) = &self.summary_url {
+            let payload = serde_json::to_vec(summary).expect("summary should be serializable");
+            request(url, Some(&payload));
+        }
+    }
+}
+
+/// Send a request to `url` posting the JSON `payload` if any.
+fn request(url: &str, payload: Option<&[u8]>) {
+    tracing::debug!(target: "report::webhook", "Running: curl -fsS -m 10 --retry 3 {url}");
+    let mut curl = Command::new("curl");
+    curl.args(["-fsS", "-m", "10", "--retry", "3", "-o", "/dev/null"])
+        .arg(url)
+        .stdin(Stdio::piped());
+    if payload.is_some() {
+        curl.args([
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+        ]);
+    }
+
+    let result = curl.spawn().and_then(|mut child| {
+        let mut stdin = child.stdin.take().expect("stdin should be untaken");
+        if let Some(payload) = payload {
+            stdin.write_all(payload)?;
+        }
+        drop(stdin);
+        child.wait()
+    });
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            tracing::warn!(target: "report::webhook", "Request to {url} failed with {status}")
+        }
+        Err(e) => {
+            tracing::warn!(target: "report::webhook", "Request to {url} couldn't be sent: {e}")
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Restoring the `config/` directory from a backup of the [Config](crate::backends::Config) backend.
+//!
+//! The backups mask the secrets of the config files. Given the identity to
+//! decrypt it, the masked values are restored from the [secrets sidecar](secrets_path)
+//! of the backup. Without sidecar or identity they are taken over from the live
+//! config files instead.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::backends::MaskedSecrets;
+use crate::util::artifact::secrets_path;
+use crate::util::encrypt::Encryption;
+use crate::util::mask::{unmask, Masker};
+
+/// Restore of the `config/` directory from a config backup.
+#[derive(Debug, Clone)]
+pub struct ConfigRestore {
+    masker: Masker,
+    secrets: Option<(Encryption, PathBuf)>,
+}
+
+impl ConfigRestore {
+    /// Create a restore of config backups whose secrets were masked by `masker`.
+    pub fn new(masker: Masker) -> Self {
+        Self {
+            masker,
+            secrets: None,
+        }
+    }
+
+    /// Restore the masked secrets from the secrets sidecar encrypted using `encryption`.
+    ///
+    /// The sidecar is decrypted using `identity`, see [Encryption::decrypt].
+    pub fn secrets(mut self, encryption: Encryption, identity: PathBuf) -> Self {
+        self.secrets = Some((encryption, identity));
+        self
+    }
+
+    /// Restore the files of the config backup `config_backup` to `config_dir`.
+    ///
+    /// Masked secrets are restored from the secrets sidecar of `config_backup`
+    /// if [configured](Self::secrets) and present. Otherwise they are replaced by
+    /// the values of the files in `config_dir`, secrets missing there stay masked
+    /// and are logged. Files added to `config_dir` are owned by its owner.
+    /// Returns the restored files.
+    pub fn restore(
+        &self,
+        config_backup: &Path,
+        config_dir: &Path,
+        dry_run: bool,
+    ) -> io::Result<Vec<PathBuf>> {
+        let owner = fs::metadata(config_dir)?;
+        let secrets = match dry_run {
+            true => None,
+            false => self.read_secrets(config_backup)?,
+        };
+        let mut restored = Vec::new();
+        let mut archive = tar::Archive::new(MultiGzDecoder::new(File::open(config_backup)?));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let archived = entry.path()?.into_owned();
+            let Some(name) = config_file_name(&archived) else {
+                continue;
+            };
+            let dest = config_dir.join(name);
+            if dry_run {
+                tracing::info!(target: "restore::config", "Would restore {}", dest.display());
+                restored.push(dest);
+                continue;
+            }
+
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            if dest.extension().is_some_and(|ext| ext == "php") {
+                let content_str = String::from_utf8_lossy(&content);
+                content = match &secrets {
+                    Some(secrets) => {
+                        let entries = secrets
+                            .get(archived.to_string_lossy().as_ref())
+                            .map_or(&[][..], Vec::as_slice);
+                        unmask(&content_str, entries)
+                    }
+                    None => unmask_with_live(&content_str, &dest, &self.masker)?,
+                }
+                .into_bytes();
+            }
+            tracing::info!(target: "restore::config", "Restore {}", dest.display());
+            let existed = fs::exists(&dest)?;
+            fs::write(&dest, content)?;
+            if !existed {
+                chown(&dest, Some(owner.uid()), Some(owner.gid()))?;
+            }
+            restored.push(dest);
+        }
+
+        Ok(restored)
+    }
+
+    /// The secrets of the sidecar of `config_backup`, if configured and present.
+    fn read_secrets(&self, config_backup: &Path) -> io::Result<Option<MaskedSecrets>> {
+        let Some((encryption, identity)) = &self.secrets else {
+            return Ok(None);
+        };
+        let secrets_file = secrets_path(config_backup, encryption.tool());
+        if !fs::exists(&secrets_file)? {
+            tracing::warn!(target: "restore::config", "No secrets sidecar {} found, taking secrets from the live config", secrets_file.display());
+            return Ok(None);
+        }
+
+        tracing::debug!(target: "restore::config", "Decrypt masked secrets of: {}", secrets_file.display());
+        let plaintext = encryption.decrypt(&secrets_file, identity)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+}
+
+/// Restore the files of the config backup `config_backup` to `config_dir`.
+///
+/// Same as [ConfigRestore::restore] without secrets sidecar, the masked secrets
+/// are taken over from the live files.
+pub fn restore_config(
+    config_backup: &Path,
+    config_dir: &Path,
+    masker: &Masker,
+    dry_run: bool,
+) -> io::Result<Vec<PathBuf>> {
+    ConfigRestore::new(masker.clone()).restore(config_backup, config_dir, dry_run)
+}
+
+/// The name of the archived file `path` in the `config/` directory.
+fn config_file_name(path: &Path) -> Option<PathBuf> {
+    let components: Vec<_> = path.components().collect();
+    let [.., Component::Normal(dir), Component::Normal(name)] = components[..] else {
+        return None;
+    };
+    (dir == "config").then(|| PathBuf::from(name))
+}
+
+/// Replace the secrets of `content` masked by `masker` by the ones of the live file `dest`.
+fn unmask_with_live(content: &str, dest: &Path, masker: &Masker) -> io::Result<String> {
+    let live = match fs::read_to_string(dest) {
+        Ok(live) => masker.mask(&live).entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let masked = masker.mask(content).entries;
+    for entry in masked
+        .iter()
+        .filter(|entry| !live.iter().any(|live| live.key == entry.key))
+    {
+        tracing::warn!(target: "restore::config", "{} of {} stays masked, restore it from the secrets sidecar", entry.key, dest.display());
+    }
+
+    Ok(unmask(content, &live))
+}
@@ -0,0 +1,166 @@
+//! Restoring the data directory from its backups using [DataRestore].
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use derive_more::{Display, Error, From};
+use flate2::read::MultiGzDecoder;
+
+use crate::backends::snapper::{SnapperConfig, SnapperConfigError};
+use crate::backends::{tar_data, Artifact};
+use crate::util::archive::{chown_tree, copy_tree, unpack_tree, Excludes};
+use crate::util::command::{self, CommandRunner};
+use crate::util::privilege::Privilege;
+use crate::util::progress::Progress;
+
+/// Error of [DataRestore].
+#[derive(Debug, Display, Error, From)]
+pub enum DataRestoreError {
+    /// The snapshot to restore couldn't be found.
+    #[display("Finding the snapshot failed: {_0}")]
+    Snapper(SnapperConfigError),
+    /// The snapshot to restore doesn't exist anymore.
+    #[display("Snapshot {_1} of snapper config {_0} not found")]
+    #[from(ignore)]
+    SnapshotNotFound(#[error(not(source))] String, #[error(not(source))] u64),
+    /// The backup can't be restored by nc_backup.
+    #[display("Restoring {_0} isn't supported, restore it by hand")]
+    #[from(ignore)]
+    Unsupported(#[error(not(source))] String),
+    /// Copying or extracting the data failed.
+    Io(io::Error),
+}
+
+/// Restores the data directory from its snapshots, copies or tarballs.
+///
+/// Snapshots and copies are copied back into the data directory, replacing files
+/// of the same name. Tarballs are extracted into the data directory, which may differ
+/// from the one they were archived from.
+/// Files added since the backup are kept, except for incremental tarballs of the
+/// [TarData](crate::backends::TarData) backend, which remove them again.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use nc_backup_lib::backends::Artifact;
+/// # use nc_backup_lib::restore::data::DataRestore;
+/// # use nc_backup_lib::util::progress::NoProgress;
+/// let snapshot = Artifact::Snapshot {
+///     config: "nextcloud".into(),
+///     id: 42,
+/// };
+/// DataRestore::new()
+///     .restore(&[snapshot], Path::new("/srv/nextcloud/data"), false, &NoProgress)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct DataRestore {
+    privilege: Privilege,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl Default for DataRestore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DataRestore {
+    /// Create a restore of the data directory using the default privilege and command runner.
+    pub fn new() -> Self {
+        Self {
+            privilege: Privilege::default(),
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Run `snapper` using `privilege`.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Run the external commands using `runner`.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Restore the data directory `data_dir` from the backups `artifacts`.
+    ///
+    /// Of snapshots only the data directory is restored, even if their snapper config
+    /// covers a parent subvolume. Nested subvolumes below the data directory are
+    /// restored along with it.
+    /// The restored files are owned by the owner of `data_dir`.
+    pub fn restore(
+        &self,
+        artifacts: &[Artifact],
+        data_dir: &Path,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<(), DataRestoreError> {
+        for artifact in artifacts {
+            if dry_run {
+                tracing::info!(target: "restore::data", "Would restore {artifact} to {}", data_dir.display());
+                continue;
+            }
+            tracing::info!(target: "restore::data", "Restore {artifact} to {}", data_dir.display());
+            progress.phase("restore", None);
+            match artifact {
+                Artifact::Snapshot { config, id } => {
+                    let (snapshot, subvolume) = self.snapshot(config, *id)?;
+                    let Ok(relative) = data_dir.strip_prefix(&subvolume) else {
+                        return Err(DataRestoreError::Io(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "{} isn't located in {}",
+                                data_dir.display(),
+                                subvolume.display()
+                            ),
+                        )));
+                    };
+                    let source = snapshot.join(relative);
+                    copy_tree(
+                        &source,
+                        data_dir,
+                        None,
+                        &Excludes::default(),
+                        false,
+                        progress,
+                    )?;
+                }
+                Artifact::Directory(dir) => {
+                    copy_tree(dir, data_dir, None, &Excludes::default(), false, progress)?;
+                }
+                Artifact::File(tarball) if tarball.to_string_lossy().ends_with(".tar.zst") => {
+                    tar_data::extract_data_dir(tarball, data_dir)?;
+                }
+                Artifact::File(tarball) if tarball.to_string_lossy().ends_with(".tar.gz") => {
+                    let decoder = MultiGzDecoder::new(File::open(tarball)?);
+                    unpack_tree(&mut tar::Archive::new(decoder), data_dir)?;
+                }
+                artifact => return Err(DataRestoreError::Unsupported(artifact.to_string())),
+            }
+        }
+        if dry_run {
+            return Ok(());
+        }
+
+        let owner = fs::metadata(data_dir)?;
+        chown_tree(data_dir, owner.uid(), owner.gid())?;
+        Ok(())
+    }
+
+    /// The path of the snapshot `id` of the snapper config `config` and its subvolume.
+    fn snapshot(&self, config: &str, id: u64) -> Result<(PathBuf, PathBuf), DataRestoreError> {
+        let not_found = || DataRestoreError::SnapshotNotFound(config.to_string(), id);
+        let cfg = SnapperConfig::config_by_id(config, self.privilege, self.runner.clone())?
+            .ok_or_else(not_found)?;
+        let snapshot = cfg.snapshot(id)?.ok_or_else(not_found)?;
+        Ok((snapshot.snapshot_path(), cfg.subvolume().to_path_buf()))
+    }
+}
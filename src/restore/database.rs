@@ -0,0 +1,152 @@
+//! Restoring the database from a dump using [DatabaseRestore].
+//!
+//! Follows the restore of the [admin manual]: the database is dropped and created
+//! again, the dump is loaded into it and the database user of Nextcloud is granted
+//! access to it.
+//!
+//! [admin manual]: https://docs.nextcloud.com/server/latest/admin_manual/maintenance/restore.html
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::backends::mariadb::{self, quote_identifier, quote_string};
+use crate::backends::MariaDbError;
+use crate::nextcloud::DbConfig;
+use crate::util::command::{self, CommandRunner};
+use crate::util::progress::{NoProgress, Progress};
+
+/// Restores Nextcloud's MySQL/MariaDB database from a dump of the
+/// [MariaDb](crate::backends::MariaDb) backend.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
+/// # use nc_backup_lib::restore::database::DatabaseRestore;
+/// # use nc_backup_lib::util::progress::NoProgress;
+/// let nextcloud = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
+/// let occ = nextcloud.occ();
+/// let db = occ.db_config().unwrap();
+/// let maintenance = occ.maintenance_guard().unwrap();
+/// DatabaseRestore::new()
+///     .admin_user("root".into())
+///     .restore(
+///         Path::new("/nextcloud/backup/db/database-2025-01-01T02-30-00.sql.gz"),
+///         &db,
+///         false,
+///         &NoProgress,
+///     )
+///     .unwrap();
+/// maintenance.disable().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct DatabaseRestore {
+    admin_user: Option<String>,
+    grant_host: String,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl Default for DatabaseRestore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DatabaseRestore {
+    /// Create a restore loading the dump as the database user of Nextcloud, granting access from `localhost`.
+    pub fn new() -> Self {
+        Self {
+            admin_user: None,
+            grant_host: "localhost".into(),
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Drop and create the database as `user`, e.g. `root`.
+    ///
+    /// Its password is taken from `MYSQL_PWD` if set. Defaults to the database
+    /// user of Nextcloud, which then has to be allowed to drop and create its database.
+    pub fn admin_user(mut self, user: String) -> Self {
+        self.admin_user = Some(user);
+        self
+    }
+
+    /// Grant the database user of Nextcloud access from `host`, defaults to `localhost`.
+    pub fn grant_host(mut self, host: String) -> Self {
+        self.grant_host = host;
+        self
+    }
+
+    /// Run the database client using `runner`, except for loading the dump.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Replace the database `db` by the contents of `dump`.
+    ///
+    /// Nextcloud should be in maintenance mode while the database is restored. If
+    /// an [admin user](Self::admin_user) is set, the database user and password of
+    /// `db` are granted access to the database again.
+    pub fn restore(
+        &self,
+        dump: &Path,
+        db: &DbConfig,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<(), MariaDbError> {
+        if db.dbtype != "mysql" {
+            return Err(MariaDbError::UnsupportedDatabase(db.dbtype.clone()));
+        }
+        let admin = match &self.admin_user {
+            Some(user) => DbConfig {
+                user: user.clone(),
+                password: None,
+                ..db.clone()
+            },
+            None => db.clone(),
+        };
+        let grant = admin.user != db.user;
+        if dry_run {
+            tracing::info!(target: "restore::database", "Would drop and create database {} as {}", db.name, admin.user);
+            if grant {
+                tracing::info!(target: "restore::database", "Would grant {}@{} access to {}", db.user, self.grant_host, db.name);
+            }
+            tracing::info!(target: "restore::database", "Would load {} into {}", dump.display(), db.name);
+            return Ok(());
+        }
+
+        let runner = self.runner.as_ref();
+        tracing::info!(target: "restore::database", "Drop and create database {} as {}", db.name, admin.user);
+        mariadb::drop_database(runner, &admin)?;
+        mariadb::create_database(runner, &admin)?;
+        if grant {
+            tracing::info!(target: "restore::database", "Grant {}@{} access to {}", db.user, self.grant_host, db.name);
+            // piped to keep the password out of the process list
+            mariadb::run_sql(runner, self.grant_sql(db).as_bytes(), &admin, &NoProgress)?;
+        }
+        tracing::info!(target: "restore::database", "Load {} into {}", dump.display(), db.name);
+        mariadb::load_dump(runner, dump, &admin, progress)?;
+        tracing::info!(target: "restore::database", "Restored database {}", db.name);
+
+        Ok(())
+    }
+
+    /// Statements granting the user of `db` access to its database like the installer of Nextcloud.
+    fn grant_sql(&self, db: &DbConfig) -> String {
+        let account = format!(
+            "{}@{}",
+            quote_string(&db.user),
+            quote_string(&self.grant_host)
+        );
+        let password = quote_string(db.password.as_deref().unwrap_or_default());
+        format!(
+            "CREATE USER IF NOT EXISTS {account} IDENTIFIED BY {password};\n\
+             ALTER USER {account} IDENTIFIED BY {password};\n\
+             GRANT ALL PRIVILEGES ON {}.* TO {account};\n\
+             FLUSH PRIVILEGES;\n",
+            quote_identifier(&db.name)
+        )
+    }
+}
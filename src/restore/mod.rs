@@ -0,0 +1,15 @@
+//! Restoring the backups created by the [backends](crate::backends).
+//!
+//! - [config]: Restore the `config/` directory, keeping the live secrets.
+//! - [data]: Restore the data directory from its snapshots, copies or tarballs.
+//! - [database]: Replace the database by a dump.
+//! - [mount]: Browse the backups of a point in time read-only.
+//! - [plan]: Select the backups to restore from the run manifests.
+//! - [version]: Refuse restores across Nextcloud versions.
+
+pub mod config;
+pub mod data;
+pub mod database;
+pub mod mount;
+pub mod plan;
+pub mod version;
@@ -0,0 +1,375 @@
+//! Browsing the backups of a point in time read-only using [Mount].
+//!
+//! Before committing to a restore, admins can inspect the data directory and the
+//! database as they were at the time of a backup. The [Generation] of backups to
+//! inspect is selected among the backups listed by the backends.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use derive_more::{Display, Error, From};
+use flate2::read::MultiGzDecoder;
+
+use crate::backends::snapper::{SnapperConfig, SnapperConfigError};
+use crate::backends::{mariadb, tar_data, Artifact, BackendEntry, MariaDbError};
+use crate::nextcloud::DbConfig;
+use crate::util::archive::unpack_tree;
+use crate::util::command::{self, CommandRunner};
+use crate::util::privilege::Privilege;
+use crate::util::progress::Progress;
+
+/// Backends whose backups contain the data directory.
+const DATA_BACKENDS: &[&str] = &["snapper", "copy_data", "tar_data", "lvm", "data"];
+/// Backends whose backups contain the database.
+const DATABASE_BACKENDS: &[&str] = &["mariadb"];
+/// File in the mount directory recording what was mounted.
+const STATE_FILE: &str = ".nc_backup-mount.json";
+/// Directory in the mount directory the data is browsable at.
+const DATA_DIR: &str = "data";
+
+/// Backups of the data directory and the database of a point in time.
+#[derive(Debug, Clone, Default)]
+pub struct Generation {
+    /// Backup of the data directory.
+    pub data: Option<BackendEntry>,
+    /// Dump of the database.
+    pub database: Option<BackendEntry>,
+}
+
+impl Generation {
+    /// Select the latest backups of the data directory and the database among
+    /// `backups` created at or before `at`.
+    ///
+    /// Only backups [Mount] is able to mount are considered, e.g. no streams
+    /// of snapshots or physical database backups. If the run which created the
+    /// backup of the data directory also dumped the database, its dump is
+    /// selected even if a later one exists.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [MountError::NoBackup] if neither is found.
+    pub fn select(backups: &[BackendEntry], at: NaiveDateTime) -> Result<Self, MountError> {
+        let candidates = |backends: &'static [&str], mountable: fn(&Artifact) -> bool| {
+            backups
+                .iter()
+                .filter(move |backup| backends.contains(&backup.backend.as_str()))
+                .filter(move |backup| backup.entry.date <= at && mountable(&backup.entry.artifact))
+        };
+        let data = candidates(DATA_BACKENDS, is_data).max_by_key(|backup| backup.entry.date);
+        let same_run = candidates(DATABASE_BACKENDS, is_dump).find(|backup| {
+            backup.entry.generation.is_some()
+                && backup.entry.generation == data.and_then(|data| data.entry.generation)
+        });
+        let database = same_run.or_else(|| {
+            candidates(DATABASE_BACKENDS, is_dump).max_by_key(|backup| backup.entry.date)
+        });
+        let generation = Self {
+            data: data.cloned(),
+            database: database.cloned(),
+        };
+        if generation.data.is_none() && generation.database.is_none() {
+            return Err(MountError::NoBackup(at));
+        }
+
+        Ok(generation)
+    }
+}
+
+/// Whether the data directory in `artifact` can be mounted.
+pub(crate) fn is_data(artifact: &Artifact) -> bool {
+    match artifact {
+        Artifact::Snapshot { .. } | Artifact::Directory(..) => true,
+        Artifact::File(path) => {
+            let name = path.to_string_lossy();
+            name.ends_with(".tar.gz") || name.ends_with(".tar.zst")
+        }
+        Artifact::ZfsSnapshot(..) => false,
+    }
+}
+
+/// Whether `artifact` is a logical database dump.
+pub(crate) fn is_dump(artifact: &Artifact) -> bool {
+    let Artifact::File(path) = artifact else {
+        return false;
+    };
+    let name = path.to_string_lossy();
+    [".sql.gz", ".sql.chunks", ".sql.zst"]
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+}
+
+/// What [Mount::mount] mounted, recorded in the mount directory for [Mount::unmount].
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MountState {
+    /// Directory the data is browsable at.
+    pub data: Option<PathBuf>,
+    /// Whether the data is bind mounted rather than extracted.
+    pub bind: bool,
+    /// Database the dump was loaded into.
+    pub schema: Option<String>,
+}
+
+/// Error of [Mount].
+#[derive(Debug, Display, Error, From)]
+pub enum MountError {
+    /// No backup was created at or before the selected time.
+    #[display("No backup of the data directory or the database found at or before {_0}")]
+    #[from(ignore)]
+    NoBackup(#[error(not(source))] NaiveDateTime),
+    /// A backup is already mounted in the directory.
+    #[display("A backup is already mounted at {}, unmount it first", _0.display())]
+    #[from(ignore)]
+    AlreadyMounted(#[error(not(source))] PathBuf),
+    /// No backup is mounted in the directory.
+    #[display("No backup is mounted at {}", _0.display())]
+    #[from(ignore)]
+    NotMounted(#[error(not(source))] PathBuf),
+    /// The snapshot to mount couldn't be found.
+    #[display("Finding the snapshot failed: {_0}")]
+    Snapper(SnapperConfigError),
+    /// The snapshot to mount doesn't exist anymore.
+    #[display("Snapshot {_1} of snapper config {_0} not found")]
+    #[from(ignore)]
+    SnapshotNotFound(#[error(not(source))] String, #[error(not(source))] u64),
+    /// The dump couldn't be loaded or the database couldn't be dropped.
+    #[display("Database failed: {_0}")]
+    MariaDb(MariaDbError),
+    /// Mounting or extracting the data failed.
+    Io(io::Error),
+}
+
+/// Mounts a [Generation] of backups read-only into a directory.
+///
+/// Snapshots and copies of the data directory are bind mounted read-only to
+/// `<dir>/data`, tarballs are extracted there instead. The database dump is
+/// loaded into a separate database, so the live database stays untouched.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use nc_backup_lib::restore::mount::{Generation, Mount};
+/// # use nc_backup_lib::util::progress::NoProgress;
+/// # let backups = Vec::new();
+/// # let at = chrono::Local::now().naive_local();
+/// let generation = Generation::select(&backups, at).unwrap();
+/// let state = Mount::new(Path::new("/mnt/nc_backup"))
+///     .mount(&generation, None, None, false, &NoProgress)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Mount {
+    dir: PathBuf,
+    privilege: Privilege,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl Mount {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            privilege: Privilege::default(),
+            runner: command::system_runner(),
+        }
+    }
+
+    /// Run `mount`, `umount` and `snapper` using `privilege`.
+    pub fn privilege(mut self, privilege: Privilege) -> Self {
+        self.privilege = privilege;
+        self
+    }
+
+    /// Run the external commands using `runner`, except for loading the dump.
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE)
+    }
+
+    /// Mount the backups of `generation`.
+    ///
+    /// The database dump is loaded into the new database `schema` on the server
+    /// of `db`, which defaults to `nc_backup_<timestamp of the dump>`. Without
+    /// `db` the dump isn't loaded.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [MountError::AlreadyMounted] if a backup is still mounted.
+    /// Whatever was mounted before an error is undone.
+    pub fn mount(
+        &self,
+        generation: &Generation,
+        db: Option<&DbConfig>,
+        schema: Option<String>,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<MountState, MountError> {
+        if fs::exists(self.state_path())? {
+            return Err(MountError::AlreadyMounted(self.dir.clone()));
+        }
+
+        let mut state = MountState::default();
+        if let Some(data) = &generation.data {
+            let dest = self.dir.join(DATA_DIR);
+            if dry_run {
+                tracing::info!(target: "restore::mount", "Would mount {} to {}", data.entry.artifact, dest.display());
+            } else {
+                fs::create_dir_all(&dest)?;
+                state.bind = self
+                    .mount_data(&data.entry.artifact, &dest, progress)
+                    .inspect_err(|_| {
+                        let _ = fs::remove_dir_all(&dest);
+                    })?;
+                state.data = Some(dest);
+                self.write_state(&state)?;
+            }
+        }
+
+        let dump = generation.database.as_ref().zip(db);
+        if let Some((dump, db)) = dump {
+            let Artifact::File(path) = &dump.entry.artifact else {
+                unreachable!("database backups should be dumps");
+            };
+            let db = DbConfig {
+                name: schema.unwrap_or_else(|| {
+                    format!("nc_backup_{}", dump.entry.date.format("%Y%m%d%H%M%S"))
+                }),
+                ..db.clone()
+            };
+            if dry_run {
+                tracing::info!(target: "restore::mount", "Would load {} into database {}", path.display(), db.name);
+            } else {
+                tracing::info!(target: "restore::mount", "Load {} into database {}", path.display(), db.name);
+                let runner = self.runner.as_ref();
+                let loaded = mariadb::create_database(runner, &db).and_then(|()| {
+                    mariadb::load_dump(runner, path, &db, progress).inspect_err(|_| {
+                        let _ = mariadb::drop_database(runner, &db);
+                    })
+                });
+                if let Err(e) = loaded {
+                    if state.data.is_some() {
+                        if let Err(e) = self.unmount(None) {
+                            tracing::error!(target: "restore::mount", "Undoing the mount failed: {e}");
+                        }
+                    }
+                    return Err(e.into());
+                }
+                state.schema = Some(db.name);
+                self.write_state(&state)?;
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Make the data directory of `artifact` browsable at `dest`.
+    ///
+    /// Returns whether it was bind mounted.
+    fn mount_data(
+        &self,
+        artifact: &Artifact,
+        dest: &Path,
+        progress: &dyn Progress,
+    ) -> Result<bool, MountError> {
+        let source = match artifact {
+            Artifact::Snapshot { config, id } => {
+                let cfg = SnapperConfig::config_by_id(config, self.privilege, self.runner.clone())?
+                    .ok_or_else(|| MountError::SnapshotNotFound(config.clone(), *id))?;
+                cfg.snapshot(*id)?
+                    .ok_or_else(|| MountError::SnapshotNotFound(config.clone(), *id))?
+                    .snapshot_path()
+            }
+            Artifact::Directory(dir) => dir.clone(),
+            Artifact::File(tarball) => {
+                tracing::info!(target: "restore::mount", "Extract {} to {}", tarball.display(), dest.display());
+                progress.phase("extract", None);
+                if tarball.to_string_lossy().ends_with(".tar.zst") {
+                    tar_data::extract(tarball, dest)?;
+                } else {
+                    let decoder = MultiGzDecoder::new(File::open(tarball)?);
+                    unpack_tree(&mut tar::Archive::new(decoder), dest)?;
+                }
+                return Ok(false);
+            }
+            Artifact::ZfsSnapshot(..) => unreachable!("zfs snapshots aren't selected"),
+        };
+
+        tracing::info!(target: "restore::mount", "Mount {} read-only to {}", source.display(), dest.display());
+        let mut mount = self.privilege.command("mount", &[]);
+        mount.args(["--bind", "-o", "ro"]).arg(&source).arg(dest);
+        self.run(mount)?;
+        Ok(true)
+    }
+
+    /// Undo the [mount](Self::mount) in the directory.
+    ///
+    /// The database the dump was loaded into is dropped on the server of `db`.
+    /// Without `db` it's kept.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [MountError::NotMounted] if no backup is mounted.
+    pub fn unmount(&self, db: Option<&DbConfig>) -> Result<(), MountError> {
+        let state: MountState = match fs::read(self.state_path()) {
+            Ok(state) => serde_json::from_slice(&state).map_err(io::Error::from)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(MountError::NotMounted(self.dir.clone()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(data) = &state.data {
+            if state.bind {
+                tracing::info!(target: "restore::mount", "Unmount {}", data.display());
+                let mut umount = self.privilege.command("umount", &[]);
+                umount.arg(data);
+                self.run(umount)?;
+                fs::remove_dir(data)?;
+            } else {
+                tracing::info!(target: "restore::mount", "Remove extracted {}", data.display());
+                fs::remove_dir_all(data)?;
+            }
+        }
+        match (&state.schema, db) {
+            (Some(schema), Some(db)) => {
+                tracing::info!(target: "restore::mount", "Drop database {schema}");
+                let db = DbConfig {
+                    name: schema.clone(),
+                    ..db.clone()
+                };
+                mariadb::drop_database(self.runner.as_ref(), &db)?;
+            }
+            (Some(schema), None) => {
+                tracing::warn!(target: "restore::mount", "Keeping database {schema}, drop it manually")
+            }
+            (None, _) => {}
+        }
+
+        fs::remove_file(self.state_path())?;
+        Ok(())
+    }
+
+    fn write_state(&self, state: &MountState) -> io::Result<()> {
+        let state = serde_json::to_vec_pretty(state)?;
+        fs::write(self.state_path(), state)
+    }
+
+    fn run(&self, mut cmd: std::process::Command) -> io::Result<()> {
+        tracing::trace!(target: "restore::mount", "Running: {cmd:?}");
+        let output = self.runner.output(&mut cmd, "mount")?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{cmd:?} failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
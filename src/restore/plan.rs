@@ -0,0 +1,230 @@
+//! Selecting the backups to restore from the run manifests using [RestorePlan].
+//!
+//! The [catalog](crate::report::manifest::catalog) of run manifests lists the
+//! backups of every backend by run, so the backups of the config, the database
+//! and the data directory of a point in time can be found even if they were
+//! created by different runs. Restoring a [generation](BackupGeneration) uses
+//! the backups of that run only.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use clap::ValueEnum;
+use derive_more::{Display, Error};
+
+use super::mount::{is_data, is_dump};
+use crate::backends::Artifact;
+use crate::report::manifest::RunManifest;
+use crate::util::artifact::ARTIFACT_TS;
+use crate::util::generation::BackupGeneration;
+use crate::util::tiering::TieringConfig;
+
+/// Part of the installation restored separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Display, ValueEnum)]
+pub enum Component {
+    /// The database, from a dump of the `mariadb` backend.
+    #[display("db")]
+    Db,
+    /// The `config/` directory, from a backup of the `config` backend.
+    #[display("config")]
+    Config,
+    /// The data directory, from a snapshot, copy or tarball.
+    #[display("data")]
+    Data,
+}
+
+/// Point in time to restore.
+///
+/// Parsed from a local time like `2025-01-01T02:30:00`, the id of a run like
+/// `2025-01-01T02-30-00`, as in the name of its run manifest, or the generation
+/// of a run like `01JGQ2C4S8ZQK3N7W5R9T0V6XB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// The latest backups created at or before the time.
+    Time(NaiveDateTime),
+    /// The backups of the run started at the time.
+    Run(NaiveDateTime),
+    /// The backups of the run of the generation.
+    Generation(BackupGeneration),
+}
+
+impl FromStr for RestorePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(time) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S") {
+            return Ok(Self::Time(time));
+        }
+        if let Ok(generation) = s.parse() {
+            return Ok(Self::Generation(generation));
+        }
+        let id = s.strip_prefix("run-").unwrap_or(s);
+        NaiveDateTime::parse_from_str(id, ARTIFACT_TS)
+            .map(Self::Run)
+            .map_err(|_| {
+                format!("{s} is neither a time like 2025-01-01T02:30:00, a run like 2025-01-01T02-30-00 nor a generation like 01JGQ2C4S8ZQK3N7W5R9T0V6XB")
+            })
+    }
+}
+
+impl fmt::Display for RestorePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Time(time) => write!(f, "{}", time.format("%Y-%m-%dT%H:%M:%S")),
+            Self::Run(created) => write!(f, "run {}", created.format(ARTIFACT_TS)),
+            Self::Generation(generation) => write!(f, "generation {generation}"),
+        }
+    }
+}
+
+impl RestorePoint {
+    /// Whether the run of `manifest` is a candidate for the point.
+    fn matches(&self, manifest: &RunManifest) -> bool {
+        match self {
+            Self::Time(time) => manifest.created <= *time,
+            Self::Run(run) => manifest.created == *run,
+            Self::Generation(generation) => manifest.generation == Some(*generation),
+        }
+    }
+}
+
+/// A [Component] has no backup at the [RestorePoint].
+#[derive(Debug, Display, Error)]
+#[display("No backup of the {component} found at {point}")]
+pub struct PlanError {
+    /// Component lacking a backup.
+    #[error(not(source))]
+    pub component: Component,
+    /// Point in time searched.
+    #[error(not(source))]
+    pub point: RestorePoint,
+}
+
+/// Backups to restore per [Component].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestorePlan {
+    /// Dump of the database.
+    pub database: Option<PathBuf>,
+    /// Backup of the `config/` directory.
+    pub config: Option<PathBuf>,
+    /// Backups of the data directory, e.g. the snapshots of the data directory
+    /// and the subvolumes nested in it.
+    pub data: Vec<Artifact>,
+}
+
+impl RestorePlan {
+    /// Select the backups of `components` at `point` among the run manifests of `catalog`.
+    ///
+    /// Every component is restored from the latest run at `point` that backed it up.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a component has no backup at `point`.
+    pub fn select(
+        catalog: &[(PathBuf, RunManifest)],
+        point: RestorePoint,
+        components: &[Component],
+    ) -> Result<Self, PlanError> {
+        let mut plan = Self::default();
+        for &component in components {
+            let artifacts = catalog
+                .iter()
+                .rev()
+                .filter(|(_, manifest)| point.matches(manifest))
+                .map(|(path, manifest)| (path, artifacts_of(manifest, component)))
+                .find(|(_, artifacts)| !artifacts.is_empty());
+            let Some((manifest, mut artifacts)) = artifacts else {
+                return Err(PlanError { component, point });
+            };
+            tracing::debug!(target: "restore::plan", "Restore the {component} from {}", manifest.display());
+            match component {
+                Component::Db => plan.database = file(artifacts.swap_remove(0)),
+                Component::Config => plan.config = file(artifacts.swap_remove(0)),
+                Component::Data => plan.data = artifacts,
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Locate the backups of the plan which were moved to the cold storage of `tiering`.
+    ///
+    /// The run manifests list the backups at their original path in `backup_root`.
+    pub fn locate(mut self, backup_root: &Path, tiering: &TieringConfig) -> Self {
+        let locate = |path: &mut PathBuf| *path = tiering.locate(backup_root, path);
+        let files = self.database.iter_mut().chain(&mut self.config);
+        let data = self.data.iter_mut().filter_map(|artifact| match artifact {
+            Artifact::File(path) | Artifact::Directory(path) => Some(path),
+            _ => None,
+        });
+        files.chain(data).for_each(locate);
+        self
+    }
+}
+
+/// The path of the file `artifact`.
+fn file(artifact: Artifact) -> Option<PathBuf> {
+    match artifact {
+        Artifact::File(path) => Some(path),
+        _ => None,
+    }
+}
+
+/// The backups of `component` listed by `manifest`.
+///
+/// Of the data directory either all snapshots or a single copy or tarball is returned.
+fn artifacts_of(manifest: &RunManifest, component: Component) -> Vec<Artifact> {
+    let files = manifest
+        .files
+        .iter()
+        .map(|listed| Artifact::File(listed.path.clone()));
+    match component {
+        Component::Db => files
+            .filter(|artifact| is_dump(artifact) && has_prefix(artifact, "database-"))
+            .take(1)
+            .collect(),
+        Component::Config => files
+            .filter(|artifact| {
+                has_prefix(artifact, "config-") && artifact.to_string().ends_with(".tar.gz")
+            })
+            .take(1)
+            .collect(),
+        Component::Data => {
+            let others = manifest
+                .artifacts
+                .iter()
+                .filter_map(|s| s.parse::<Artifact>().ok())
+                .filter(|artifact| match artifact {
+                    Artifact::File(_) => false,
+                    Artifact::Directory(_) => has_prefix(artifact, "data-"),
+                    _ => true,
+                });
+            let mut data: Vec<_> = files
+                .filter(|artifact| has_prefix(artifact, "data-"))
+                .chain(others)
+                .filter(is_data)
+                .collect();
+            if data
+                .iter()
+                .any(|artifact| matches!(artifact, Artifact::Snapshot { .. }))
+            {
+                data.retain(|artifact| matches!(artifact, Artifact::Snapshot { .. }));
+            } else {
+                data.truncate(1);
+            }
+            data
+        }
+    }
+}
+
+/// Whether the name of the file or directory `artifact` starts with `prefix`.
+fn has_prefix(artifact: &Artifact, prefix: &str) -> bool {
+    let path: &Path = match artifact {
+        Artifact::File(path) | Artifact::Directory(path) => path,
+        _ => return false,
+    };
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(prefix))
+}
@@ -0,0 +1,135 @@
+//! Safety checks of the Nextcloud version before a restore using [RestoreVersions].
+//!
+//! Nextcloud can't be downgraded, so a backup of a newer version mustn't be
+//! restored onto an older installation. A backup of an older version requires
+//! `occ upgrade` after the restore.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use derive_more::{Display, Error};
+use flate2::read::MultiGzDecoder;
+use regex::Regex;
+
+/// `version` entry of `config.php` as written by `var_export`.
+const CONFIG_VERSION: &str = r#"['"]version['"]\s*=>\s*['"]([0-9.]+)['"]"#;
+
+/// Mismatch of the versions of a restore found by [RestoreVersions::check].
+#[derive(Debug, Display, Error)]
+pub enum VersionError {
+    /// Neither the manifest nor the config backup records the version.
+    #[display("The Nextcloud version of the backup is unknown")]
+    Unknown,
+    /// The manifest and the config backup record different versions.
+    #[display("The run manifest records Nextcloud {manifest} but the config backup {config}")]
+    Inconsistent {
+        /// Version recorded in the run manifest.
+        #[error(not(source))]
+        manifest: String,
+        /// Version of the backed up `config.php`.
+        #[error(not(source))]
+        config: String,
+    },
+    /// The backup is of a newer version than the installed one.
+    #[display(
+        "The backup of Nextcloud {backup} can't be restored onto the older Nextcloud {installed}"
+    )]
+    Downgrade {
+        /// Version of the backup.
+        #[error(not(source))]
+        backup: String,
+        /// Version installed.
+        #[error(not(source))]
+        installed: String,
+    },
+    /// The backup is of an older version than the installed one.
+    #[display("The backup of Nextcloud {backup} is older than the installed Nextcloud {installed}, run occ upgrade after restoring it")]
+    Upgrade {
+        /// Version of the backup.
+        #[error(not(source))]
+        backup: String,
+        /// Version installed.
+        #[error(not(source))]
+        installed: String,
+    },
+}
+
+/// Versions of Nextcloud involved in a restore.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RestoreVersions {
+    /// Version installed, e.g. `30.0.4.1`.
+    pub installed: String,
+    /// Version recorded in the [run manifest](crate::report::manifest::RunManifest) of the backup.
+    pub manifest: Option<String>,
+    /// `version` of the backed up `config.php`, see [config_version].
+    pub config: Option<String>,
+}
+
+impl RestoreVersions {
+    /// Version of the backup, preferring the one of `config.php`.
+    pub fn backup(&self) -> Option<&str> {
+        self.config.as_deref().or(self.manifest.as_deref())
+    }
+
+    /// Check that the backup is of the installed version.
+    ///
+    /// Versions are compared numerically, so `30.0.4` equals `30.0.4.0`.
+    pub fn check(&self) -> Result<(), VersionError> {
+        if let (Some(manifest), Some(config)) = (&self.manifest, &self.config) {
+            if compare(manifest, config) != Ordering::Equal {
+                return Err(VersionError::Inconsistent {
+                    manifest: manifest.clone(),
+                    config: config.clone(),
+                });
+            }
+        }
+        let Some(backup) = self.backup() else {
+            return Err(VersionError::Unknown);
+        };
+        let (backup, installed) = (backup.to_string(), self.installed.clone());
+        match compare(&backup, &installed) {
+            Ordering::Equal => Ok(()),
+            Ordering::Greater => Err(VersionError::Downgrade { backup, installed }),
+            Ordering::Less => Err(VersionError::Upgrade { backup, installed }),
+        }
+    }
+}
+
+/// Compares the dotted versions `a` and `b` numerically, ignoring trailing zeros.
+fn compare(a: &str, b: &str) -> Ordering {
+    let parts = |version: &str| {
+        let mut parts: Vec<u64> = version
+            .split('.')
+            .map(|part| part.trim().parse().unwrap_or(0))
+            .collect();
+        while parts.last() == Some(&0) {
+            parts.pop();
+        }
+        parts
+    };
+    parts(a).cmp(&parts(b))
+}
+
+/// Reads the `version` of `config.php` in the backup `config_backup` of the
+/// [Config](crate::backends::Config) backend.
+///
+/// Returns [None] if the backup lacks `config.php` or its version.
+pub fn config_version(config_backup: &Path) -> io::Result<Option<String>> {
+    let regex = Regex::new(CONFIG_VERSION).expect("regex should be valid");
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(File::open(config_backup)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.path()?.ends_with("config/config.php") {
+            continue;
+        }
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        return Ok(regex
+            .captures(&content)
+            .map(|captures| captures[1].to_string()));
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,257 @@
+//! Diagnostics of the environment a backup runs in.
+//!
+//! Every [Check] reports its [CheckStatus] along with a hint how to resolve
+//! the problem. Backends are diagnosed by their [preflight](DynBackup::preflight)
+//! check and their latest existing backup.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use derive_more::Display;
+
+use crate::backends::{
+    BackupError, DynBackup, MariaDbError, MariaDbPhysicalError, SnapperBackupError,
+};
+use crate::nextcloud::{Nextcloud, TESTED_MAJOR_VERSIONS};
+use crate::util::fs::{available_space, SPACE_HEADROOM};
+use crate::util::retention::RetentionConfig;
+use crate::util::sign::Signing;
+
+/// Outcome of a [Check].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    /// Nothing to do.
+    #[display("ok")]
+    Ok,
+    /// The backup works but something should be looked at.
+    #[display("warn")]
+    Warning,
+    /// The backup is going to fail.
+    #[display("fail")]
+    Failed,
+}
+
+/// Result of diagnosing a single prerequisite.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Check {
+    /// What was checked.
+    pub name: String,
+    /// Outcome of the check.
+    pub status: CheckStatus,
+    /// Details on the outcome.
+    pub detail: String,
+    /// How to resolve a warning or failure.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Check that `occ` works and maintenance mode isn't left enabled.
+pub fn check_nextcloud(nextcloud: &Nextcloud) -> Check {
+    match nextcloud.occ().maintenance() {
+        Ok(false) => Check::new("occ", CheckStatus::Ok, "maintenance mode is off"),
+        Ok(true) => Check::new("occ", CheckStatus::Warning, "maintenance mode is on")
+            .hint("If no backup is running, a previous run may have left it on: occ maintenance:mode --off"),
+        Err(e) => Check::new("occ", CheckStatus::Failed, e.to_string())
+            .hint("Make sure occ runs, see --php-user, --php and the occ section of the config"),
+    }
+}
+
+/// Check that Nextcloud is installed, up to date and of a tested version.
+pub fn check_version(nextcloud: &Nextcloud) -> Check {
+    let status = match nextcloud.occ().status() {
+        Ok(status) => status,
+        Err(e) => return Check::new("version", CheckStatus::Failed, e.to_string()),
+    };
+    let version = format!("Nextcloud {}", status.version_string);
+    if let Err(e) = status.check() {
+        return Check::new("version", CheckStatus::Failed, e.to_string());
+    }
+    match status.major_version() {
+        Some(major) if TESTED_MAJOR_VERSIONS.contains(&major) => {
+            Check::new("version", CheckStatus::Ok, version)
+        }
+        _ => Check::new(
+            "version",
+            CheckStatus::Warning,
+            format!("{version} wasn't tested"),
+        )
+        .hint(format!(
+            "Tested are the major versions {} to {}, check the backups carefully",
+            TESTED_MAJOR_VERSIONS.start(),
+            TESTED_MAJOR_VERSIONS.end()
+        )),
+    }
+}
+
+/// Check that the keys are backed up if server-side encryption is enabled.
+pub fn check_encryption(nextcloud: &Nextcloud, keys_backed_up: bool) -> Check {
+    match nextcloud.occ().encryption_enabled() {
+        Ok(false) => Check::new("encryption", CheckStatus::Ok, "server-side encryption is off"),
+        Ok(true) if keys_backed_up => Check::new(
+            "encryption",
+            CheckStatus::Ok,
+            "server-side encryption is on, keys are backed up",
+        ),
+        Ok(true) => Check::new(
+            "encryption",
+            CheckStatus::Failed,
+            "server-side encryption is on but the keys aren't backed up",
+        )
+        .hint("Enable the encryption_keys backend, the user files can't be decrypted without the keys"),
+        Err(e) => Check::new("encryption", CheckStatus::Warning, e.to_string()),
+    }
+}
+
+/// Check that the backup root is writable and has free space left.
+pub fn check_backup_root(backup_root: &Path) -> Vec<Check> {
+    let writable = fs::create_dir_all(backup_root).and_then(|()| {
+        let probe = backup_root.join(".nc_backup_probe");
+        fs::write(&probe, b"")?;
+        fs::remove_file(&probe)
+    });
+    let writable = match writable {
+        Ok(()) => Check::new(
+            "backup root",
+            CheckStatus::Ok,
+            format!("{} is writable", backup_root.display()),
+        ),
+        Err(e) => Check::new(
+            "backup root",
+            CheckStatus::Failed,
+            format!("{} isn't writable: {e}", backup_root.display()),
+        )
+        .hint("Create the backup root and make it writable by the user running the backup"),
+    };
+
+    let space = match available_space(backup_root) {
+        Ok(available) if available < SPACE_HEADROOM => Check::new(
+            "free space",
+            CheckStatus::Warning,
+            format!("{} MiB available", available / 1024 / 1024),
+        )
+        .hint("Free up space or prune old backups: nc_backup prune"),
+        Ok(available) => Check::new(
+            "free space",
+            CheckStatus::Ok,
+            format!("{} MiB available", available / 1024 / 1024),
+        ),
+        Err(e) => Check::new("free space", CheckStatus::Failed, e.to_string()),
+    };
+
+    vec![writable, space]
+}
+
+/// Check that the run manifests can be signed by `signing`.
+pub fn check_signing(signing: &Signing) -> Check {
+    match signing.check() {
+        Ok(()) => Check::new(
+            "manifest signing",
+            CheckStatus::Ok,
+            format!("signing using {}", signing.tool()),
+        ),
+        Err(e) => {
+            Check::new("manifest signing", CheckStatus::Failed, e.to_string()).hint(match signing {
+                Signing::Minisign { .. } => {
+                    "Install minisign and create a key without password: minisign -G -W"
+                }
+                Signing::Gpg { .. } => "Install gpg and import the signing key",
+            })
+        }
+    }
+}
+
+/// Check the backend `name` by its preflight check and its latest backup.
+pub fn check_backend(
+    name: &str,
+    backend: &dyn DynBackup,
+    nextcloud: &Nextcloud,
+    retention: &RetentionConfig,
+) -> Vec<Check> {
+    let preflight = match backend.preflight(nextcloud) {
+        Ok(()) => Check::new(name, CheckStatus::Ok, "preflight check passed"),
+        Err(e) => {
+            let check = Check::new(name, CheckStatus::Failed, e.to_string());
+            match hint(&e) {
+                Some(hint) => check.hint(hint),
+                None => check,
+            }
+        }
+    };
+
+    let latest = match backend.list(nextcloud, retention) {
+        Ok(entries) => match entries.iter().map(|entry| entry.date).max() {
+            Some(date) => Check::new(
+                name,
+                CheckStatus::Ok,
+                format!("latest backup from {}", date.format("%Y-%m-%d %H:%M:%S")),
+            ),
+            None => Check::new(name, CheckStatus::Warning, "no backup found")
+                .hint("Run a backup: nc_backup backup"),
+        },
+        Err(e) => Check::new(
+            name,
+            CheckStatus::Failed,
+            format!("listing backups failed: {e}"),
+        ),
+    };
+
+    vec![preflight, latest]
+}
+
+/// Returns how to resolve `error` of a backend if known.
+pub fn hint(error: &BackupError) -> Option<&'static str> {
+    match error {
+        BackupError::Snapper(SnapperBackupError::SnapperConfigNotFound(..)) => {
+            Some("Create a snapper config of the data directory or pass --snapper-create-config")
+        }
+        BackupError::Snapper(
+            SnapperBackupError::NotBtrfs(..) | SnapperBackupError::NotSubvolume(..),
+        ) => Some("Snapper requires the data directory to be a btrfs subvolume, otherwise disable the snapper backend"),
+        BackupError::Snapper(SnapperBackupError::Privilege(..)) => {
+            Some("Permit running snapper and btrfs without password, e.g. by a NOPASSWD sudoers rule, or set ALLOW_USERS in the snapper config")
+        }
+        BackupError::Snapper(
+            SnapperBackupError::SnapperConfig(..) | SnapperBackupError::ListSnapshotsFailed(..),
+        ) => Some("Make sure snapper is installed and permits the user, see the privilege key of the config"),
+        BackupError::MariaDb(MariaDbError::NoDumpClient) => {
+            Some("Install the MariaDB client providing mariadb-dump")
+        }
+        BackupError::MariaDbPhysical(MariaDbPhysicalError::NoMariabackup) => {
+            Some("Install mariabackup on the host of the database server and run nc_backup there")
+        }
+        BackupError::MariaDb(MariaDbError::Unreachable { .. })
+        | BackupError::MariaDbPhysical(MariaDbPhysicalError::MariaDb(
+            MariaDbError::Unreachable { .. },
+        )) => {
+            Some("Make sure the database server accepts connections from this host or set mariadb.host, mariadb.port or mariadb.socket of the config")
+        }
+        BackupError::MariaDb(MariaDbError::UnsupportedDatabase(..)) => {
+            Some("Only MySQL and MariaDB are supported, disable the mariadb backend")
+        }
+        BackupError::MariaDb(MariaDbError::Io(e))
+        | BackupError::Snapper(SnapperBackupError::Preflight(e))
+        | BackupError::Config(e)
+            if e.kind() == io::ErrorKind::StorageFull =>
+        {
+            Some("Free up space or prune old backups: nc_backup prune")
+        }
+        _ => None,
+    }
+}
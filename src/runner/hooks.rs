@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::io;
+use std::process::{Command, ExitStatus};
+
+use derive_more::{Display, Error};
+
+use crate::util::command;
+
+/// Error on running a hook.
+#[derive(Debug, Display, Error)]
+pub enum HookError {
+    /// Hook couldn't be spawned.
+    #[display("Hook {command:?} couldn't be run: {source}")]
+    NotRun {
+        /// Command of the hook.
+        #[error(not(source))]
+        command: String,
+        /// Cause of the failure.
+        source: io::Error,
+    },
+    /// Hook exited unsuccessfully.
+    #[display("Hook {command:?} failed with {status}")]
+    Failed {
+        /// Command of the hook.
+        #[error(ignore)]
+        command: String,
+        /// Exit status of the hook.
+        #[error(ignore)]
+        status: ExitStatus,
+    },
+}
+
+/// Commands to run before and after a task.
+///
+/// The commands are run by `sh -c`. Details about the task are exposed to
+/// the commands through the following environment variables:
+///
+/// - `NC_BACKUP_PHASE`: `pre` or `post`.
+/// - `NC_BACKUP_DRY_RUN`: `1` on a dry run, `0` otherwise.
+/// - `NC_BACKUP_BACKEND`: Name of the backend (only for backend hooks).
+/// - `NC_BACKUP_STATUS`: `success` or `failure` (only `post`).
+/// - `NC_BACKUP_DURATION`: Duration of the task in seconds (only `post`).
+/// - `NC_BACKUP_ARTIFACTS`: Newline separated list of created artifacts (only `post`).
+/// - `NC_BACKUP_ERROR`: Error message of the failed task (only `post`).
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Hooks {
+    /// Command run before the task.
+    ///
+    /// If the command fails the task is not run.
+    pub pre: Option<String>,
+
+    /// Command run after the task regardless of its success.
+    pub post: Option<String>,
+}
+
+/// Configuration of hooks around the whole run and around each backend.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Hooks run before and after the whole run.
+    #[serde(flatten)]
+    pub run: Hooks,
+
+    /// Hooks run before and after the backend with the given name.
+    pub backends: HashMap<String, Hooks>,
+}
+
+/// Run the `command` of a hook with the additional environment `envs`.
+pub(super) fn run_hook(command: &str, envs: &[(&str, String)]) -> Result<(), HookError> {
+    tracing::debug!(target: "runner::hooks", "Running hook: sh -c {command:?}");
+
+    let mut sh = Command::new("sh");
+    sh.arg("-c")
+        .arg(command)
+        .envs(envs.iter().map(|(k, v)| (k, v)));
+    let status = command::status(&mut sh, "hook").map_err(|source| HookError::NotRun {
+        command: command.to_string(),
+        source,
+    })?;
+    if !status.success() {
+        return Err(HookError::Failed {
+            command: command.to_string(),
+            status,
+        });
+    }
+
+    Ok(())
+}
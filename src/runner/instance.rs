@@ -0,0 +1,137 @@
+//! Backing up several Nextcloud instances in one run.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use derive_more::{Display, Error, From};
+
+use crate::backends::BackendsConfig;
+use crate::nextcloud::{Nextcloud, OccBuilder, OccError};
+use crate::report::email::EmailConfig;
+use crate::report::webhook::WebhookConfig;
+use crate::util::retention::RetentionConfig;
+
+/// Nextcloud instance backed up along with others, configured by an `[instance.<name>]` section.
+///
+/// ```toml
+/// [instance.cloud1]
+/// installation_root = "/var/www/cloud1"
+/// backends = ["config", "mariadb"]
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct InstanceConfig {
+    /// Installation root of the instance, e.g. `/var/www/cloud1`.
+    pub installation_root: PathBuf,
+
+    /// Directory to put the backups of the instance into.
+    ///
+    /// Defaults to the directory named after the instance in the backup root.
+    pub backup_root: Option<PathBuf>,
+
+    /// Names of the backends to run, overriding `backends`.
+    pub backends: Option<Vec<String>>,
+
+    /// Retention of the backups, overriding `retention`.
+    pub retention: Option<RetentionConfig>,
+
+    /// Ping URLs of the instance, overriding `webhook`.
+    pub webhook: Option<WebhookConfig>,
+
+    /// Email reports of the instance, overriding `email`.
+    pub email: Option<EmailConfig>,
+}
+
+impl InstanceConfig {
+    /// Backup root of the instance `name` within the common `backup_root`.
+    pub fn backup_root(&self, name: &str, backup_root: &Path) -> PathBuf {
+        self.backup_root
+            .clone()
+            .unwrap_or_else(|| backup_root.join(name))
+    }
+
+    /// Returns `occ` running the `occ` of the instance's installation root.
+    ///
+    /// The `occ` wrapper in `PATH` belongs to a single installation, so unless a PHP
+    /// binary is configured `php` runs `<installation_root>/occ`.
+    pub fn occ(&self, occ: &OccBuilder) -> OccBuilder {
+        match occ.php {
+            Some(_) => occ.clone(),
+            None => occ.clone().php("php".into()),
+        }
+    }
+
+    /// Returns `config` with the overrides of the instance applied.
+    pub fn apply(&self, config: &BackendsConfig) -> BackendsConfig {
+        let mut config = config.clone();
+        if self.backends.is_some() {
+            config.backends.clone_from(&self.backends);
+        }
+        if let Some(retention) = self.retention {
+            config.retention = retention;
+        }
+        if self.webhook.is_some() {
+            config.webhook.clone_from(&self.webhook);
+        }
+        if self.email.is_some() {
+            config.email.clone_from(&self.email);
+        }
+        config
+    }
+}
+
+/// Layout of the backups in the backup root.
+///
+/// Nesting the backups keeps those of several servers pushing to shared storage
+/// apart, so the retention of one server doesn't touch the backups of another.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupLayout {
+    /// The directories of the backends are in the backup root itself.
+    #[default]
+    Flat,
+    /// The directories of the backends are in `<backup root>/<instanceid>/`.
+    ///
+    /// The `instanceid` is read from Nextcloud's config.
+    InstanceId,
+    /// The directories of the backends are in `<backup root>/<hostname>/`.
+    Hostname,
+}
+
+/// Error determining the backup root of a [BackupLayout].
+#[derive(Debug, Display, Error, From)]
+pub enum LayoutError {
+    /// The `instanceid` couldn't be read.
+    #[display("Reading the instanceid failed: {_0}")]
+    InstanceId(OccError),
+    /// The hostname couldn't be read.
+    #[display("Reading the hostname failed: {_0}")]
+    Hostname(io::Error),
+    /// The name isn't usable as directory name.
+    #[display("{_0:?} isn't a valid directory name")]
+    #[from(ignore)]
+    InvalidName(#[error(not(source))] String),
+}
+
+impl BackupLayout {
+    /// Directory in `backup_root` the backends of `nextcloud` put their backups into.
+    pub fn backup_root(
+        self,
+        backup_root: &Path,
+        nextcloud: &Nextcloud,
+    ) -> Result<PathBuf, LayoutError> {
+        let name = match self {
+            Self::Flat => return Ok(backup_root.to_path_buf()),
+            Self::InstanceId => nextcloud.occ().instance_id()?,
+            Self::Hostname => fs::read_to_string("/proc/sys/kernel/hostname")?
+                .trim()
+                .to_string(),
+        };
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(LayoutError::InvalidName(name));
+        }
+        tracing::debug!(target: "runner::instance", "Nesting backups in: {name}");
+
+        Ok(backup_root.join(name))
+    }
+}
@@ -0,0 +1,688 @@
+//! Orchestration of backup jobs.
+//!
+//! The [BackupRunner] executes a set of [Job]s concurrently and aggregates their
+//! results in a [RunReport]. Maintenance mode of the [Nextcloud] instance is only
+//! enabled if at least one of the jobs requires it.
+//!
+//! A run has two phases. First the jobs requiring maintenance mode create their
+//! backups, e.g. snapshots and database dumps. Once maintenance mode is disabled,
+//! these backups are [finalized](DynBackup::finalize), e.g. compressed or sent, and
+//! all other jobs are run.
+//!
+//! Arbitrary commands can be run before and after the whole run and around each
+//! job using [Hooks].
+
+pub mod doctor;
+mod hooks;
+pub mod instance;
+pub mod schedule;
+pub mod window;
+
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use derive_more::{Display, Error, From};
+
+use crate::backends::{Artifact, BackupError, DynBackup};
+use crate::nextcloud::{Nextcloud, NextcloudStatus, OccError, StatusError};
+use crate::util::progress::{NoProgress, Progress, ProgressReporter};
+use crate::util::retention::RetentionConfig;
+use crate::util::systemd;
+
+pub use hooks::{HookError, Hooks, HooksConfig};
+pub use instance::{BackupLayout, InstanceConfig, LayoutError};
+
+/// Work performed by a [Job].
+trait Task: Send {
+    /// See [DynBackup::preflight].
+    fn preflight(&self, _nextcloud: &Nextcloud) -> Result<(), BackupError> {
+        Ok(())
+    }
+
+    /// Run the task reporting to `progress` and return the created [Artifact]s.
+    fn run(
+        &mut self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError>;
+
+    /// See [DynBackup::finalize].
+    fn finalize(
+        &self,
+        _nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        _dry_run: bool,
+        _progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError> {
+        Ok(artifacts)
+    }
+}
+
+/// [Task] running an arbitrary closure once.
+struct FnTask<F>(Option<F>);
+
+impl<F> Task for FnTask<F>
+where
+    F: FnOnce(&Nextcloud, bool) -> Result<Vec<Artifact>, BackupError> + Send,
+{
+    fn run(
+        &mut self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        _progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError> {
+        let task = self.0.take().expect("task should only be run once");
+        task(nextcloud, dry_run)
+    }
+}
+
+/// [Task] performing a backup.
+struct BackupTask(Box<dyn DynBackup>);
+
+impl Task for BackupTask {
+    fn preflight(&self, nextcloud: &Nextcloud) -> Result<(), BackupError> {
+        self.0.preflight(nextcloud)
+    }
+
+    fn run(
+        &mut self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError> {
+        self.0.backup(nextcloud, dry_run, progress)
+    }
+
+    fn finalize(
+        &self,
+        nextcloud: &Nextcloud,
+        artifacts: Vec<Artifact>,
+        dry_run: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<Artifact>, BackupError> {
+        self.0.finalize(nextcloud, artifacts, dry_run, progress)
+    }
+}
+
+/// State of a [Job] whose [Task] was run but not finalized yet.
+struct Staged {
+    /// Span of the job, entered again on finalizing.
+    span: tracing::Span,
+    progress: Box<dyn Progress>,
+    start: Instant,
+    result: Result<Vec<Artifact>, BackupError>,
+}
+
+/// A unit of work executed by the [BackupRunner].
+pub struct Job {
+    name: String,
+    requires_maintenance: bool,
+    hooks: Hooks,
+    task: Box<dyn Task>,
+    staged: Option<Staged>,
+}
+
+impl Job {
+    /// Create a new [Job] running an arbitrary `task`.
+    ///
+    /// The task receives the [Nextcloud] instance and whether this is a dry run.
+    pub fn new<F>(name: impl Into<String>, requires_maintenance: bool, task: F) -> Self
+    where
+        F: FnOnce(&Nextcloud, bool) -> Result<Vec<Artifact>, BackupError> + Send + 'static,
+    {
+        Self {
+            name: name.into(),
+            requires_maintenance,
+            hooks: Hooks::default(),
+            task: Box::new(FnTask(Some(task))),
+            staged: None,
+        }
+    }
+
+    /// Create a [Job] performing a [backup](DynBackup::backup) using `backend`.
+    ///
+    /// Backups are run in maintenance mode to obtain a consistent state
+    /// if the `backend` [requires](DynBackup::requires_maintenance) it.
+    pub fn backup(name: impl Into<String>, backend: Box<dyn DynBackup>) -> Self {
+        Self {
+            name: name.into(),
+            requires_maintenance: backend.requires_maintenance(),
+            hooks: Hooks::default(),
+            task: Box::new(BackupTask(backend)),
+            staged: None,
+        }
+    }
+
+    /// Create a [Job] applying the [RetentionConfig] to the backups of `backend`.
+    pub fn retention(
+        name: impl Into<String>,
+        backend: Box<dyn DynBackup>,
+        cfg: RetentionConfig,
+    ) -> Self {
+        Self::new(name, false, move |nextcloud, dry_run| {
+            backend.retention(nextcloud, &cfg, dry_run)?;
+            Ok(Vec::new())
+        })
+    }
+
+    /// Run the [Hooks] before and after the job.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Name of the job.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the job has to be run in maintenance mode.
+    pub fn requires_maintenance(&self) -> bool {
+        self.requires_maintenance
+    }
+
+    /// Run the pre hook and the task of the job without finalizing it.
+    fn stage(&mut self, nextcloud: &Nextcloud, dry_run: bool, progress: &dyn ProgressReporter) {
+        let name = &self.name;
+        let span = tracing::info_span!("backend", backend = %name);
+        let entered = span.enter();
+        tracing::info!(target: "runner", "Starting job: {name}");
+        systemd::status(&format!("Running job: {name}"));
+        let progress = progress.job(name);
+        let start = Instant::now();
+        let pre_hook = self.hooks.pre.as_deref().map_or(Ok(()), |pre| {
+            hooks::run_hook(
+                pre,
+                &[
+                    ("NC_BACKUP_PHASE", "pre".into()),
+                    ("NC_BACKUP_DRY_RUN", u8::from(dry_run).to_string()),
+                    ("NC_BACKUP_BACKEND", name.clone()),
+                ],
+            )
+        });
+        let result = pre_hook
+            .map_err(BackupError::from)
+            .and_then(|()| self.task.run(nextcloud, dry_run, progress.as_ref()));
+
+        drop(entered);
+        self.staged = Some(Staged {
+            span,
+            progress,
+            start,
+            result,
+        });
+    }
+
+    /// Run the job to completion, finalizing the task and running the post hook.
+    ///
+    /// The task is only run if it wasn't [staged](Self::stage) before.
+    fn run(
+        mut self,
+        nextcloud: &Nextcloud,
+        dry_run: bool,
+        progress: &dyn ProgressReporter,
+    ) -> JobReport {
+        if self.staged.is_none() {
+            self.stage(nextcloud, dry_run, progress);
+        }
+        let Self {
+            name,
+            hooks,
+            task,
+            staged,
+            ..
+        } = self;
+        let Staged {
+            span,
+            progress,
+            start,
+            result,
+        } = staged.expect("job should be staged");
+        let _entered = span.enter();
+
+        let result = result.and_then(|artifacts| {
+            tracing::debug!(target: "runner", "Finalizing job: {name}");
+            task.finalize(nextcloud, artifacts, dry_run, progress.as_ref())
+        });
+        let (artifacts, mut result) = match result {
+            Ok(artifacts) => (artifacts, Ok(())),
+            Err(e) => (Vec::new(), Err(e)),
+        };
+        let duration = start.elapsed();
+        progress.finish();
+
+        if let Some(post) = &hooks.post {
+            let error = result.as_ref().err().map(ToString::to_string);
+            let mut envs = post_hook_envs(dry_run, duration, &artifacts, error);
+            envs.push(("NC_BACKUP_BACKEND", name.clone()));
+
+            if let Err(e) = hooks::run_hook(post, &envs) {
+                if result.is_ok() {
+                    result = Err(e.into());
+                } else {
+                    tracing::error!(target: "runner", "Post hook of job {name} failed: {e}");
+                }
+            }
+        }
+
+        match &result {
+            Ok(()) => tracing::info!(
+                target: "runner",
+                duration_secs = duration.as_secs_f64(),
+                artifacts = artifacts.len(),
+                "Finished job {name} in {duration:.2?}"
+            ),
+            Err(e) => tracing::error!(
+                target: "runner",
+                duration_secs = duration.as_secs_f64(),
+                "Job {name} failed after {duration:.2?}: {e}"
+            ),
+        }
+
+        JobReport {
+            name,
+            duration,
+            artifacts,
+            result,
+        }
+    }
+}
+
+impl std::fmt::Debug for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Job")
+            .field("name", &self.name)
+            .field("requires_maintenance", &self.requires_maintenance)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Environment of a post hook.
+fn post_hook_envs(
+    dry_run: bool,
+    duration: Duration,
+    artifacts: &[Artifact],
+    error: Option<String>,
+) -> Vec<(&'static str, String)> {
+    let status = if error.is_none() {
+        "success"
+    } else {
+        "failure"
+    };
+    let artifacts = artifacts
+        .iter()
+        .map(Artifact::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut envs = vec![
+        ("NC_BACKUP_PHASE", "post".into()),
+        ("NC_BACKUP_DRY_RUN", u8::from(dry_run).to_string()),
+        ("NC_BACKUP_STATUS", status.into()),
+        (
+            "NC_BACKUP_DURATION",
+            format!("{:.3}", duration.as_secs_f64()),
+        ),
+        ("NC_BACKUP_ARTIFACTS", artifacts),
+    ];
+    if let Some(error) = error {
+        envs.push(("NC_BACKUP_ERROR", error));
+    }
+
+    envs
+}
+
+/// Outcome of a single [Job].
+#[derive(Debug)]
+pub struct JobReport {
+    /// Name of the job.
+    pub name: String,
+    /// Time it took to run the job.
+    pub duration: Duration,
+    /// Artifacts created by the job.
+    pub artifacts: Vec<Artifact>,
+    /// Result of the job.
+    pub result: Result<(), BackupError>,
+}
+
+/// Outcome of all [Job]s run by the [BackupRunner].
+///
+/// The reports are in the order the jobs were added to the runner.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// Reports of every job.
+    pub jobs: Vec<JobReport>,
+    /// Error of the post hook of the run.
+    pub hook_error: Option<HookError>,
+    /// Version and state of Nextcloud at the start of the run if known.
+    pub nextcloud: Option<NextcloudStatus>,
+}
+
+impl RunReport {
+    /// Returns whether every job and the post hook of the run succeeded.
+    pub fn success(&self) -> bool {
+        self.hook_error.is_none() && self.jobs.iter().all(|job| job.result.is_ok())
+    }
+
+    /// Iterate over the reports of the failed jobs.
+    pub fn failed(&self) -> impl Iterator<Item = &JobReport> {
+        self.jobs.iter().filter(|job| job.result.is_err())
+    }
+}
+
+/// Runs [Job]s against a [Nextcloud] instance.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use nc_backup_lib::backends::{Config, MariaDb};
+/// # use nc_backup_lib::nextcloud::{Nextcloud, DEFAULT_INSTALLATION_ROOT};
+/// # use nc_backup_lib::runner::{BackupRunner, Job};
+/// let nextcloud = Nextcloud::new(DEFAULT_INSTALLATION_ROOT.into()).unwrap();
+/// let backup_root = Path::new("/nextcloud/backup");
+///
+/// let report = BackupRunner::new(nextcloud)
+///     .job(Job::backup("config", Box::new(Config::new(backup_root))))
+///     .job(Job::backup("mariadb", Box::new(MariaDb::new(backup_root))))
+///     .run()
+///     .unwrap();
+/// assert!(report.success());
+/// ```
+pub struct BackupRunner {
+    nextcloud: Nextcloud,
+    jobs: Vec<Job>,
+    hooks: Hooks,
+    concurrency: Option<NonZeroUsize>,
+    dry_run: bool,
+    preflight: bool,
+    maintenance: bool,
+    progress: Box<dyn ProgressReporter>,
+}
+
+impl std::fmt::Debug for BackupRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackupRunner")
+            .field("nextcloud", &self.nextcloud)
+            .field("jobs", &self.jobs)
+            .field("hooks", &self.hooks)
+            .field("concurrency", &self.concurrency)
+            .field("dry_run", &self.dry_run)
+            .field("preflight", &self.preflight)
+            .field("maintenance", &self.maintenance)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Error aborting the run of a [BackupRunner].
+#[derive(Debug, Display, Error, From)]
+pub enum RunnerError {
+    /// Toggling the maintenance mode failed.
+    #[display("Toggling maintenance mode failed: {_0}")]
+    Maintenance(OccError),
+    /// The pre hook of the run failed.
+    #[display("Pre hook failed: {_0}")]
+    Hook(HookError),
+    /// Nextcloud isn't in a state to be backed up.
+    #[display("Nextcloud can't be backed up: {_0}")]
+    Status(StatusError),
+    /// The preflight check of a job failed.
+    #[display("Preflight check of {job} failed: {source}")]
+    #[from(ignore)]
+    Preflight {
+        /// Name of the failed job.
+        #[error(not(source))]
+        job: String,
+        /// Cause of the failure.
+        source: BackupError,
+    },
+}
+
+impl BackupRunner {
+    /// Create a new [BackupRunner] without any jobs.
+    pub fn new(nextcloud: Nextcloud) -> Self {
+        Self {
+            nextcloud,
+            jobs: Vec::new(),
+            hooks: Hooks::default(),
+            concurrency: None,
+            dry_run: false,
+            preflight: true,
+            maintenance: true,
+            progress: Box::new(NoProgress),
+        }
+    }
+
+    /// Run the [Hooks] before and after the whole run.
+    ///
+    /// The pre hook is run before maintenance mode is enabled
+    /// and the post hook after it was disabled again.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Add a [Job] to the runner.
+    pub fn job(mut self, job: Job) -> Self {
+        self.jobs.push(job);
+        self
+    }
+
+    /// Limit the number of concurrently running jobs.
+    ///
+    /// If [None] all jobs are run in parallel.
+    pub fn concurrency(mut self, concurrency: Option<NonZeroUsize>) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Run all jobs in dry-run mode.
+    ///
+    /// See [Backup::backup](crate::backends::Backup::backup) for the guarantees of a dry run.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Run the [preflight checks](DynBackup::preflight) of all jobs before the run.
+    ///
+    /// Enabled by default.
+    pub fn preflight(mut self, preflight: bool) -> Self {
+        self.preflight = preflight;
+        self
+    }
+
+    /// Enable maintenance mode for jobs [requiring](Job::requires_maintenance) it.
+    ///
+    /// Enabled by default. If disabled the instance stays online during the
+    /// run and the backups may be inconsistent with each other.
+    pub fn maintenance(mut self, maintenance: bool) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Report the progress of every job to the [ProgressReporter].
+    ///
+    /// By default the progress is discarded.
+    pub fn progress(mut self, progress: Box<dyn ProgressReporter>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Run all jobs and wait for their completion.
+    ///
+    /// Maintenance mode is enabled while the jobs [requiring](Job::requires_maintenance)
+    /// it are run. It's disabled before they are [finalized](DynBackup::finalize)
+    /// and the remaining jobs are run.
+    ///
+    /// Once the pre hook succeeded the post hook is always run, with
+    /// `NC_BACKUP_ERROR` set if the run was aborted.
+    ///
+    /// # Errors
+    ///
+    /// Errors of the individual jobs are collected in the [RunReport].
+    /// Only failing to toggle the maintenance mode, a failing pre hook or a
+    /// failing preflight check aborts the run.
+    pub fn run(mut self) -> Result<RunReport, RunnerError> {
+        let hooks = std::mem::take(&mut self.hooks);
+        let start = Instant::now();
+        if let Some(pre) = &hooks.pre {
+            hooks::run_hook(
+                pre,
+                &[
+                    ("NC_BACKUP_PHASE", "pre".into()),
+                    ("NC_BACKUP_DRY_RUN", u8::from(self.dry_run).to_string()),
+                ],
+            )?;
+        }
+
+        let dry_run = self.dry_run;
+        let report = self.run_jobs();
+        let Some(post) = &hooks.post else {
+            return report;
+        };
+        let (artifacts, error) = match &report {
+            Ok(report) => {
+                let artifacts: Vec<_> = report
+                    .jobs
+                    .iter()
+                    .flat_map(|job| job.artifacts.iter().cloned())
+                    .collect();
+                let failed: Vec<_> = report.failed().map(|job| job.name.as_str()).collect();
+                let error =
+                    (!failed.is_empty()).then(|| format!("Failed jobs: {}", failed.join(", ")));
+                (artifacts, error)
+            }
+            Err(e) => (Vec::new(), Some(e.to_string())),
+        };
+        let envs = post_hook_envs(dry_run, start.elapsed(), &artifacts, error);
+
+        let hook_result = hooks::run_hook(post, &envs);
+        if let Err(e) = &hook_result {
+            tracing::error!(target: "runner", "Post hook failed: {e}");
+        }
+        let mut report = report?;
+        report.hook_error = hook_result.err();
+        Ok(report)
+    }
+
+    /// Run the preflight checks and the jobs, toggling the maintenance mode.
+    fn run_jobs(self) -> Result<RunReport, RunnerError> {
+        let Self {
+            nextcloud,
+            jobs,
+            hooks: _,
+            concurrency,
+            dry_run,
+            preflight,
+            maintenance,
+            progress,
+        } = self;
+
+        let status = match nextcloud.occ().status() {
+            Ok(status) => {
+                tracing::info!(target: "runner", "Nextcloud {}", status.version_string);
+                Some(status)
+            }
+            Err(e) => {
+                tracing::warn!(target: "runner", "Querying the Nextcloud status failed: {e}");
+                None
+            }
+        };
+
+        // also without preflight checks, backing up a broken installation is pointless
+        if let Some(status) = &status {
+            status.check()?;
+        }
+        if preflight {
+            for job in &jobs {
+                tracing::debug!(target: "runner", "Running preflight check of job: {}", job.name);
+                job.task
+                    .preflight(&nextcloud)
+                    .map_err(|source| RunnerError::Preflight {
+                        job: job.name.clone(),
+                        source,
+                    })?;
+            }
+        }
+
+        let requires_maintenance = jobs.iter().any(Job::requires_maintenance);
+        if requires_maintenance && !maintenance {
+            tracing::warn!(
+                target: "runner",
+                "Running without maintenance mode, the backups may be inconsistent with each other"
+            );
+        }
+        let maintenance = (requires_maintenance && maintenance)
+            .then(|| nextcloud.occ().maintenance_guard())
+            .transpose()?;
+
+        let progress = progress.as_ref();
+        let jobs = run_parallel(jobs, concurrency, |mut job| {
+            if job.requires_maintenance {
+                job.stage(&nextcloud, dry_run, progress);
+            }
+            job
+        });
+
+        if let Some(maintenance) = maintenance {
+            maintenance.disable()?;
+        }
+
+        let jobs = run_parallel(jobs, concurrency, |job| {
+            job.run(&nextcloud, dry_run, progress)
+        });
+
+        Ok(RunReport {
+            jobs,
+            hook_error: None,
+            nextcloud: status,
+        })
+    }
+}
+
+/// Applies `f` to all `jobs` using at most `concurrency` threads.
+///
+/// The results are in the order of the `jobs`.
+fn run_parallel<T: Send, R: Send>(
+    jobs: Vec<T>,
+    concurrency: Option<NonZeroUsize>,
+    f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    let workers = concurrency.map_or(jobs.len(), NonZeroUsize::get);
+    let workers = workers.min(jobs.len());
+    tracing::debug!(target: "runner", "Running {} jobs with {workers} workers", jobs.len());
+
+    let queue: Mutex<VecDeque<_>> = Mutex::new(jobs.into_iter().enumerate().collect());
+    let reports = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = queue
+                    .lock()
+                    .expect("job queue should not be poisoned")
+                    .pop_front();
+                let Some((idx, job)) = next else {
+                    break;
+                };
+
+                let report = f(job);
+                reports
+                    .lock()
+                    .expect("job reports should not be poisoned")
+                    .push((idx, report));
+            });
+        }
+    });
+
+    let mut reports = reports
+        .into_inner()
+        .expect("job reports should not be poisoned");
+    reports.sort_by_key(|(idx, _)| *idx);
+    reports.into_iter().map(|(_, report)| report).collect()
+}
@@ -0,0 +1,135 @@
+//! Recurring runs at fixed times of the day.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime, NaiveTime, TimeDelta};
+
+use crate::util::artifact::ARTIFACT_TS;
+use crate::util::fs::write_atomic;
+use crate::util::systemd;
+
+/// Name of the file in the backup root recording the start of the last scheduled run.
+pub const LAST_RUN_FILE: &str = ".nc_backup_last_run";
+
+/// Interval in which a waiting [Schedule] checks for a requested shutdown.
+const SHUTDOWN_POLL: Duration = Duration::from_secs(1);
+
+/// Daily times at which runs are due.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    times: Vec<NaiveTime>,
+    jitter: Duration,
+}
+
+impl Schedule {
+    /// Create a [Schedule] running daily at each of the `times`.
+    pub fn new(mut times: Vec<NaiveTime>) -> Self {
+        times.sort();
+        times.dedup();
+        Self {
+            times,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Delay each run randomly by up to `jitter`.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The first scheduled time after `after`.
+    ///
+    /// Returns [None] if no times are scheduled.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = after.date();
+        let tomorrow = today.succ_opt()?;
+        self.times
+            .iter()
+            .map(|time| today.and_time(*time))
+            .chain(self.times.iter().map(|time| tomorrow.and_time(*time)))
+            .find(|next| *next > after)
+    }
+
+    /// The last scheduled time at or before `before`.
+    ///
+    /// Returns [None] if no times are scheduled.
+    pub fn last_before(&self, before: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = before.date();
+        let yesterday = today.pred_opt()?;
+        self.times
+            .iter()
+            .rev()
+            .map(|time| today.and_time(*time))
+            .chain(
+                self.times
+                    .iter()
+                    .rev()
+                    .map(|time| yesterday.and_time(*time)),
+            )
+            .find(|last| *last <= before)
+    }
+
+    /// Whether a scheduled run was missed since `last_run`.
+    ///
+    /// If there was no previous run nothing was missed.
+    pub fn missed(&self, last_run: Option<NaiveDateTime>, now: NaiveDateTime) -> bool {
+        match (last_run, self.last_before(now)) {
+            (Some(last_run), Some(due)) => last_run < due,
+            _ => false,
+        }
+    }
+
+    /// Block until the next run is due.
+    ///
+    /// Returns `false` without waiting any further once `shutdown` is set.
+    pub fn wait(&self, shutdown: &AtomicBool) -> bool {
+        let now = Local::now().naive_local();
+        let Some(next) = self.next_after(now) else {
+            return false;
+        };
+        let next = next + TimeDelta::from_std(self.random_jitter()).unwrap_or_default();
+        tracing::info!(target: "runner::schedule", "Next run at {next}");
+        systemd::status(&format!("Next run at {next}"));
+
+        while Local::now().naive_local() < next {
+            if shutdown.load(Ordering::Relaxed) {
+                return false;
+            }
+            thread::sleep(SHUTDOWN_POLL);
+        }
+
+        !shutdown.load(Ordering::Relaxed)
+    }
+
+    fn random_jitter(&self) -> Duration {
+        let max = self.jitter.as_secs();
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        let random = RandomState::new().build_hasher().finish();
+        Duration::from_secs(random % (max + 1))
+    }
+}
+
+/// Read the start of the last scheduled run from `path`.
+///
+/// Returns [None] if it was never recorded or can't be read.
+pub fn read_last_run(path: &Path) -> Option<NaiveDateTime> {
+    let last_run = std::fs::read_to_string(path).ok()?;
+    NaiveDateTime::parse_from_str(last_run.trim(), ARTIFACT_TS).ok()
+}
+
+/// Record `last_run` as the start of the last scheduled run at `path`.
+pub fn write_last_run(path: &Path, last_run: NaiveDateTime) -> io::Result<()> {
+    write_atomic(
+        path,
+        format!("{}\n", last_run.format(ARTIFACT_TS)).as_bytes(),
+    )
+}
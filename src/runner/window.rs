@@ -0,0 +1,226 @@
+//! Deferring runs outside a time window or while the system is busy.
+
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveDateTime, NaiveTime};
+use derive_more::{Display, Error};
+
+use crate::util::systemd;
+
+/// Interval in which a deferred run checks for a requested shutdown.
+const SHUTDOWN_POLL: Duration = Duration::from_secs(1);
+
+/// Configuration of when backups may start.
+///
+/// ```toml
+/// [window]
+/// start = "01:00:00"
+/// end = "06:00:00"
+/// max_load = 4.0
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct WindowConfig {
+    /// Local time of the day the window opens, e.g. `01:00:00`.
+    pub start: Option<NaiveTime>,
+
+    /// Local time of the day the window closes, e.g. `06:00:00`.
+    ///
+    /// The window spans midnight if it closes before it opens.
+    pub end: Option<NaiveTime>,
+
+    /// Defer the backup while the 1 minute load average exceeds this value.
+    pub max_load: Option<f64>,
+
+    /// Defer the backup while the share of time tasks stalled on I/O over the
+    /// last 10 seconds exceeds this percentage, see `/proc/pressure/io`.
+    pub max_io_pressure: Option<f64>,
+
+    /// Seconds between rechecking the load and I/O pressure of a deferred backup.
+    pub recheck_secs: u64,
+
+    /// Give up on a backup deferred by the load or I/O pressure after this many minutes.
+    ///
+    /// By default the backup waits as long as the window is open.
+    pub max_defer_mins: Option<u64>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            start: None,
+            end: None,
+            max_load: None,
+            max_io_pressure: None,
+            recheck_secs: 300,
+            max_defer_mins: None,
+        }
+    }
+}
+
+/// Reason a backup can't start yet.
+#[derive(Debug, Clone, Display, Error, PartialEq)]
+pub enum Deferral {
+    /// The time window is closed.
+    #[display("outside the backup window, which opens at {opens}")]
+    OutsideWindow {
+        /// Next time the window opens.
+        #[error(not(source))]
+        opens: NaiveDateTime,
+    },
+    /// The load average is too high.
+    #[display("load average {load:.2} exceeds {max:.2}")]
+    Load {
+        /// Current 1 minute load average.
+        load: f64,
+        /// Configured maximum.
+        max: f64,
+    },
+    /// The I/O pressure is too high.
+    #[display("I/O pressure {pressure:.2}% exceeds {max:.2}%")]
+    IoPressure {
+        /// Current share of time stalled on I/O in percent.
+        pressure: f64,
+        /// Configured maximum.
+        max: f64,
+    },
+    /// Shutdown was requested while waiting.
+    #[display("shutdown requested")]
+    Shutdown,
+}
+
+impl WindowConfig {
+    /// The next time the window opens if it's closed at `now`.
+    pub fn opens_after(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = self.start.unwrap_or(NaiveTime::MIN);
+        let time = now.time();
+        let open = match self.end {
+            None => time >= start,
+            Some(end) if start <= end => start <= time && time < end,
+            // spans midnight
+            Some(end) => time >= start || time < end,
+        };
+        if open || self.start.is_none() {
+            return None;
+        }
+
+        let today = now.date().and_time(start);
+        match today > now {
+            true => Some(today),
+            false => now
+                .date()
+                .succ_opt()
+                .map(|tomorrow| tomorrow.and_time(start)),
+        }
+    }
+
+    /// Check whether a backup may start at `now`.
+    ///
+    /// Load and I/O pressure which can't be read are ignored.
+    pub fn check(&self, now: NaiveDateTime) -> Result<(), Deferral> {
+        if let Some(opens) = self.opens_after(now) {
+            return Err(Deferral::OutsideWindow { opens });
+        }
+        if let Some(max) = self.max_load {
+            match load_average() {
+                Ok(load) if load > max => return Err(Deferral::Load { load, max }),
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(target: "runner::window", "Reading the load average failed: {e}")
+                }
+            }
+        }
+        if let Some(max) = self.max_io_pressure {
+            match io_pressure() {
+                Ok(pressure) if pressure > max => {
+                    return Err(Deferral::IoPressure { pressure, max })
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(target: "runner::window", "Reading the I/O pressure failed: {e}")
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block until a backup may start.
+    ///
+    /// Outside the window the backup is refused unless `wait_for_window` is set,
+    /// in which case it waits for the window to open. While the load or I/O pressure
+    /// is too high the check is repeated every [recheck_secs](Self::recheck_secs).
+    ///
+    /// # Errors
+    ///
+    /// Returns the reason the backup was refused or [Deferral::Shutdown] once
+    /// `shutdown` is set.
+    pub fn wait(&self, wait_for_window: bool, shutdown: &AtomicBool) -> Result<(), Deferral> {
+        let deadline = self
+            .max_defer_mins
+            .map(|mins| Instant::now() + Duration::from_secs(mins * 60));
+        loop {
+            let now = Local::now().naive_local();
+            let deferral = match self.check(now) {
+                Ok(()) => return Ok(()),
+                Err(deferral) => deferral,
+            };
+            let recheck = match &deferral {
+                Deferral::OutsideWindow { opens } if wait_for_window => {
+                    (*opens - now).to_std().unwrap_or_default()
+                }
+                Deferral::OutsideWindow { .. } => return Err(deferral),
+                _ if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    return Err(deferral)
+                }
+                _ => Duration::from_secs(self.recheck_secs),
+            };
+
+            tracing::info!(target: "runner::window", "Deferring the backup by {recheck:?}: {deferral}");
+            systemd::status(&format!("Backup deferred: {deferral}"));
+            if !sleep(recheck, shutdown) {
+                return Err(Deferral::Shutdown);
+            }
+        }
+    }
+}
+
+/// Sleep for `duration` unless `shutdown` is set, returning whether it wasn't.
+fn sleep(duration: Duration, shutdown: &AtomicBool) -> bool {
+    let until = Instant::now() + duration;
+    while Instant::now() < until {
+        if shutdown.load(Ordering::Relaxed) {
+            return false;
+        }
+        thread::sleep(SHUTDOWN_POLL.min(until - Instant::now()));
+    }
+    !shutdown.load(Ordering::Relaxed)
+}
+
+/// The 1 minute load average of the system.
+fn load_average() -> io::Result<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg")?;
+    loadavg
+        .split_whitespace()
+        .next()
+        .and_then(|load| load.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/loadavg"))
+}
+
+/// Share of the last 10 seconds in which some tasks stalled on I/O in percent.
+fn io_pressure() -> io::Result<f64> {
+    let pressure = fs::read_to_string("/proc/pressure/io")?;
+    pressure
+        .lines()
+        .find_map(|line| line.strip_prefix("some "))
+        .and_then(|line| {
+            line.split_whitespace()
+                .find_map(|field| field.strip_prefix("avg10="))
+        })
+        .and_then(|avg| avg.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/pressure/io"))
+}
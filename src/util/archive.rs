@@ -0,0 +1,492 @@
+//! Compressed tarballs and copies of directory trees.
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+use super::artifact::write_artifact;
+use super::checksum::HashingReader;
+use super::compress::CompressionConfig;
+use super::glob::Glob;
+use super::progress::Progress;
+use flate2::read::MultiGzDecoder;
+
+/// Paths skipped along with their contents when walking directory trees.
+#[derive(Clone, Debug, Default)]
+pub struct Excludes {
+    paths: Vec<PathBuf>,
+    globs: Vec<(PathBuf, Glob)>,
+}
+
+impl Excludes {
+    /// Skip the `paths`.
+    pub fn paths(paths: &[PathBuf]) -> Self {
+        Self {
+            paths: paths.to_vec(),
+            globs: Vec::new(),
+        }
+    }
+
+    /// Skip the paths below `base` whose path relative to `base` matches `glob`.
+    pub fn glob(mut self, base: &Path, glob: Glob) -> Self {
+        self.globs.push((base.to_path_buf(), glob));
+        self
+    }
+
+    /// Whether `path` is skipped.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.paths.iter().any(|exclude| path.starts_with(exclude))
+            || self.globs.iter().any(|(base, glob)| {
+                path.strip_prefix(base)
+                    .is_ok_and(|relative| glob.matches(relative))
+            })
+    }
+}
+
+/// Walks the directory trees of `sources` skipping `excludes`.
+///
+/// `visit` is called with every path and its (not followed) metadata,
+/// directories before their contents.
+fn walk(
+    sources: &[PathBuf],
+    excludes: &Excludes,
+    visit: &mut dyn FnMut(&Path, &Metadata) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut pending: Vec<PathBuf> = sources.iter().rev().cloned().collect();
+    while let Some(path) = pending.pop() {
+        if excludes.matches(&path) {
+            tracing::debug!(target: "util::archive", "Excluded: {}", path.display());
+            continue;
+        }
+
+        let metadata = fs::symlink_metadata(&path)?;
+        visit(&path, &metadata)?;
+
+        if metadata.is_dir() {
+            let mut entries = fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort_unstable_by(|a, b| b.cmp(a));
+            pending.extend(entries);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the total size in bytes of the files below `sources` skipping `excludes`.
+pub fn tree_size(sources: &[PathBuf], excludes: &Excludes) -> io::Result<u64> {
+    let mut size = 0;
+    walk(sources, excludes, &mut |_, metadata| {
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+        Ok(())
+    })?;
+    Ok(size)
+}
+
+/// Writes the directory trees of `sources` to the *new* gzip compressed tarball `dest`.
+///
+/// Like `tar` the absolute paths without the leading `/` are used as names.
+/// Paths matching `excludes` are skipped along with their contents. Symbolic links
+/// are archived as links and sockets are skipped.
+///
+/// The tarball is gzip compressed as configured by `compression`.
+/// On a dry run the trees are only traversed and `dest` isn't created.
+/// The size of every archived file is reported to `progress`.
+///
+/// # Errors
+///
+/// Fails if `dest` already exists. The tarball is [written](write_artifact)
+/// to a partial file first, so `dest` only appears once it's complete.
+pub fn write_tarball(
+    dest: &Path,
+    sources: &[PathBuf],
+    excludes: &Excludes,
+    compression: &CompressionConfig,
+    dry_run: bool,
+    progress: &dyn Progress,
+) -> io::Result<()> {
+    write_tarball_with(
+        dest,
+        sources,
+        excludes,
+        compression,
+        dry_run,
+        progress,
+        &mut |_| Ok(None),
+    )
+}
+
+/// Like [write_tarball], but archives the content returned by `replace` instead of the file.
+///
+/// `replace` is called with the path of every regular file and returns [None]
+/// to archive the file unchanged. The replaced content keeps the metadata of the file.
+/// On a dry run `replace` is called as well.
+pub fn write_tarball_with(
+    dest: &Path,
+    sources: &[PathBuf],
+    excludes: &Excludes,
+    compression: &CompressionConfig,
+    dry_run: bool,
+    progress: &dyn Progress,
+    replace: &mut dyn FnMut(&Path) -> io::Result<Option<Vec<u8>>>,
+) -> io::Result<()> {
+    if dry_run {
+        return walk(sources, excludes, &mut |path, metadata| {
+            if metadata.is_file() {
+                replace(path)?;
+                progress.advance(metadata.len());
+            }
+            Ok(())
+        });
+    }
+
+    write_artifact(dest, |partial| {
+        let file = File::create_new(partial)?;
+        let mut builder = tar::Builder::new(compression.encoder(file));
+        builder.follow_symlinks(false);
+
+        walk(sources, excludes, &mut |path, metadata| {
+            let content = match metadata.is_file() {
+                true => replace(path)?,
+                false => None,
+            };
+            append(&mut builder, path, path, metadata, content)?;
+            if metadata.is_file() {
+                progress.advance(metadata.len());
+            }
+            Ok(())
+        })?;
+
+        builder.into_inner()?.finish()?.sync_all()
+    })
+}
+
+/// Like [write_tarball], but archives the tree of `source` as if it was located at `archive_as`.
+///
+/// This archives a mounted snapshot under the path of the directory it's a snapshot of.
+/// `excludes` match the paths below `source`.
+pub fn write_tarball_as(
+    dest: &Path,
+    source: &Path,
+    archive_as: &Path,
+    excludes: &Excludes,
+    compression: &CompressionConfig,
+    dry_run: bool,
+    progress: &dyn Progress,
+) -> io::Result<()> {
+    let sources = [source.to_path_buf()];
+    if dry_run {
+        return walk(&sources, excludes, &mut |_, metadata| {
+            if metadata.is_file() {
+                progress.advance(metadata.len());
+            }
+            Ok(())
+        });
+    }
+
+    write_artifact(dest, |partial| {
+        let file = File::create_new(partial)?;
+        let mut builder = tar::Builder::new(compression.encoder(file));
+        builder.follow_symlinks(false);
+
+        walk(&sources, excludes, &mut |path, metadata| {
+            let relative = path.strip_prefix(source).unwrap_or(path);
+            append(
+                &mut builder,
+                path,
+                &archive_as.join(relative),
+                metadata,
+                None,
+            )?;
+            if metadata.is_file() {
+                progress.advance(metadata.len());
+            }
+            Ok(())
+        })?;
+
+        builder.into_inner()?.finish()?.sync_all()
+    })
+}
+
+/// Writes the directory trees of `sources` to the *new* zstd compressed tarball `dest`,
+/// archiving only the regular files `include` returns `true` for.
+///
+/// `include` is called with every regular file and its metadata, also on a dry run.
+/// Directories, symbolic links and other files are always archived, so a tarball
+/// of the changed files still recreates the whole tree. Otherwise like [write_tarball]
+/// compressed at the zstd `level`.
+pub fn write_zstd_tarball(
+    dest: &Path,
+    sources: &[PathBuf],
+    excludes: &Excludes,
+    level: i32,
+    dry_run: bool,
+    progress: &dyn Progress,
+    include: &mut dyn FnMut(&Path, &Metadata) -> bool,
+) -> io::Result<()> {
+    if dry_run {
+        return walk(sources, excludes, &mut |path, metadata| {
+            if metadata.is_file() && include(path, metadata) {
+                progress.advance(metadata.len());
+            }
+            Ok(())
+        });
+    }
+
+    write_artifact(dest, |partial| {
+        let file = File::create_new(partial)?;
+        let mut builder = tar::Builder::new(zstd::Encoder::new(file, level)?);
+        builder.follow_symlinks(false);
+
+        walk(sources, excludes, &mut |path, metadata| {
+            if metadata.is_file() {
+                if !include(path, metadata) {
+                    return Ok(());
+                }
+                progress.advance(metadata.len());
+            }
+            append(&mut builder, path, path, metadata, None)
+        })?;
+
+        builder.into_inner()?.finish()?.sync_all()
+    })
+}
+
+/// Appends `path` as `name` to `builder`, or the `content` instead of the file if given.
+///
+/// Like `tar` the leading `/` of `name` is removed. Sockets are skipped.
+fn append<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    metadata: &Metadata,
+    content: Option<Vec<u8>>,
+) -> io::Result<()> {
+    if metadata.file_type().is_socket() {
+        tracing::debug!(target: "util::archive", "Skipped socket: {}", path.display());
+        return Ok(());
+    }
+    let name = name.strip_prefix("/").unwrap_or(name);
+    match content {
+        Some(content) => {
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(metadata);
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, name, content.as_slice())
+        }
+        None => builder.append_path_with_name(path, name),
+    }
+}
+
+/// Summary of reading back a tarball by [read_tarball].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarballSummary {
+    /// Number of regular files in the tarball.
+    pub files: usize,
+    /// Hex encoded SHA-256 of the uncompressed tarball.
+    pub hex_digest: String,
+}
+
+/// Reads the gzip compressed tarball `path` completely.
+///
+/// This detects truncated or corrupted tarballs.
+pub fn read_tarball(path: &Path) -> io::Result<TarballSummary> {
+    let mut reader = HashingReader::new(MultiGzDecoder::new(File::open(path)?));
+    let mut files = 0;
+    {
+        let mut archive = tar::Archive::new(&mut reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() {
+                files += 1;
+            }
+            io::copy(&mut entry, &mut io::sink())?;
+        }
+    }
+    // consume the padding after the end of the archive
+    io::copy(&mut reader, &mut io::sink())?;
+
+    Ok(TarballSummary {
+        files,
+        hex_digest: reader.hex_digest(),
+    })
+}
+
+/// Extracts the tree archived by [write_tarball] or [write_tarball_as] from `archive` into `dest`.
+///
+/// Unlike [tar::Archive::unpack] the names are taken relative to the archived directory,
+/// the first entry of the tarball, so the tree can be extracted to another path than it
+/// was archived from. Fails with [io::ErrorKind::InvalidData] if the first entry isn't a
+/// directory or any other entry is outside of it. Returns the absolute path of the archived
+/// directory, or `None` if the tarball is empty.
+pub fn unpack_tree<R: io::Read>(
+    archive: &mut tar::Archive<R>,
+    dest: &Path,
+) -> io::Result<Option<PathBuf>> {
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    let mut root: Option<PathBuf> = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let relative = match &root {
+            Some(root) => match name.strip_prefix(root) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("entry {} outside of {}", name.display(), root.display()),
+                    ));
+                }
+            },
+            None if !entry.header().entry_type().is_dir() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("first entry {} isn't a directory", name.display()),
+                ));
+            }
+            None => {
+                root = Some(name);
+                PathBuf::new()
+            }
+        };
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid entry {}", relative.display()),
+            ));
+        }
+
+        let path = dest.join(&relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&path)?;
+    }
+    Ok(root.map(|root| Path::new("/").join(root)))
+}
+
+/// Returns the number of regular files below `sources` skipping `excludes`.
+pub fn count_files(sources: &[PathBuf], excludes: &Excludes) -> io::Result<usize> {
+    let mut files = 0;
+    walk(sources, excludes, &mut |_, metadata| {
+        if metadata.is_file() {
+            files += 1;
+        }
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+/// Bytes copied and hard linked by [copy_tree].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    /// Size of the files copied.
+    pub copied: u64,
+    /// Size of the unchanged files hard linked to the previous copy.
+    pub linked: u64,
+}
+
+/// Copies the directory tree or file `source` to `dest`, hard linking the files
+/// unchanged since the copy `previous`.
+///
+/// A file is unchanged if the file at the same path below `previous` has the same
+/// size and modification time. Other files are copied keeping their permissions
+/// and modification time, so the next copy can link them in turn. Symbolic links
+/// are recreated, while paths matching `excludes` and special files are skipped.
+/// Files and links already in `dest` are replaced, other contents of `dest` are kept.
+///
+/// On a dry run `dest` isn't created. The size of every file is reported to `progress`.
+pub fn copy_tree(
+    source: &Path,
+    dest: &Path,
+    previous: Option<&Path>,
+    excludes: &Excludes,
+    dry_run: bool,
+    progress: &dyn Progress,
+) -> io::Result<CopyStats> {
+    let mut stats = CopyStats::default();
+    let mut dirs = Vec::new();
+    walk(&[source.to_path_buf()], excludes, &mut |path, metadata| {
+        let relative = path.strip_prefix(source).unwrap_or(path);
+        // joining an empty path would append a separator to a file
+        let target = if relative.as_os_str().is_empty() {
+            dest.to_path_buf()
+        } else {
+            dest.join(relative)
+        };
+        if metadata.is_dir() {
+            if !dry_run {
+                fs::create_dir_all(&target)?;
+                dirs.push((target, metadata.permissions()));
+            }
+        } else if metadata.is_file() {
+            let unchanged = previous
+                .map(|previous| previous.join(relative))
+                .filter(|linked| {
+                    fs::symlink_metadata(linked).is_ok_and(|linked| {
+                        linked.is_file()
+                            && linked.len() == metadata.len()
+                            && linked.modified().ok() == metadata.modified().ok()
+                    })
+                });
+            match unchanged {
+                Some(linked) => {
+                    if !dry_run {
+                        fs::hard_link(linked, &target)?;
+                    }
+                    stats.linked += metadata.len();
+                }
+                None => {
+                    if !dry_run {
+                        fs::copy(path, &target)?;
+                        File::open(&target)?.set_modified(metadata.modified()?)?;
+                    }
+                    stats.copied += metadata.len();
+                }
+            }
+            progress.advance(metadata.len());
+        } else if metadata.is_symlink() {
+            if !dry_run {
+                if fs::symlink_metadata(&target).is_ok_and(|existing| existing.is_symlink()) {
+                    fs::remove_file(&target)?;
+                }
+                std::os::unix::fs::symlink(fs::read_link(path)?, &target)?;
+            }
+        } else {
+            tracing::debug!(target: "util::archive", "Skipping special file: {}", path.display());
+        }
+        Ok(())
+    })?;
+
+    // restrict the directories once their contents are written
+    for (dir, permissions) in dirs.into_iter().rev() {
+        fs::set_permissions(dir, permissions)?;
+    }
+
+    Ok(stats)
+}
+
+/// Changes the owner of the directory tree or file `root` to `uid` and `gid`.
+///
+/// Paths already owned by them are left alone, so this succeeds without root
+/// privileges if nothing has to be changed. Symbolic links aren't followed.
+pub fn chown_tree(root: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    walk(
+        &[root.to_path_buf()],
+        &Excludes::default(),
+        &mut |path, metadata| {
+            if metadata.uid() != uid || metadata.gid() != gid {
+                std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+            }
+            Ok(())
+        },
+    )
+}
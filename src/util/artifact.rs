@@ -0,0 +1,471 @@
+//! Timestamped file artifacts created by the backends.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, TimeDelta};
+
+use crate::util::checksum::checksum_path;
+use crate::util::clock::Clock;
+use crate::util::encrypt;
+use crate::util::fs::move_file;
+use crate::util::generation::BackupGeneration;
+use crate::util::retention::{Retention, RetentionConfig};
+use crate::util::retry::{retry_io, RetryConfig};
+
+/// Format of the timestamp embedded into the file name of every artifact.
+pub const ARTIFACT_TS: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// Stamp of the name of an artifact, its [ARTIFACT_TS] timestamp followed by the
+/// [BackupGeneration] of its run if any, e.g. `2025-01-01T02-30-00_01JGQ2C4S8ZQK3N7W5R9T0V6XB`.
+pub fn format_stamp(timestamp: NaiveDateTime, generation: Option<BackupGeneration>) -> String {
+    match generation {
+        Some(generation) => format!("{}_{generation}", timestamp.format(ARTIFACT_TS)),
+        None => timestamp.format(ARTIFACT_TS).to_string(),
+    }
+}
+
+/// Parse the timestamp and the generation of a [stamp](format_stamp).
+///
+/// Stamps of artifacts created before generations were introduced lack the generation.
+pub fn parse_stamp(stamp: &str) -> Option<(NaiveDateTime, Option<BackupGeneration>)> {
+    let (timestamp, generation) = match stamp.split_once('_') {
+        Some((timestamp, generation)) => (timestamp, Some(generation.parse().ok()?)),
+        None => (stamp, None),
+    };
+    let timestamp = NaiveDateTime::parse_from_str(timestamp, ARTIFACT_TS).ok()?;
+    Some((timestamp, generation))
+}
+
+/// Suffix of the sidecar file pinning an artifact.
+pub const PIN_SUFFIX: &str = ".pin";
+
+/// Path of the sidecar file pinning `artifact`.
+///
+/// Pinned artifacts are exempt from retention. To pin an artifact simply create
+/// the sidecar file, e.g. `touch database-2025-01-01T02-30-00.sql.gz.pin`.
+pub fn pin_path(artifact: &Path) -> PathBuf {
+    let mut pin_path = artifact.as_os_str().to_owned();
+    pin_path.push(PIN_SUFFIX);
+    pin_path.into()
+}
+
+/// Returns whether `artifact` is [pinned](pin_path).
+pub fn is_pinned(artifact: &Path) -> bool {
+    pin_path(artifact).is_file()
+}
+
+/// Suffix of artifacts while they are written.
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Time after which an unmodified [partial](partial_path) artifact is considered left over.
+const PARTIAL_STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Path `artifact` is written to until it's complete.
+///
+/// Readers thus never observe a truncated artifact, e.g. after a crash.
+pub fn partial_path(artifact: &Path) -> PathBuf {
+    let mut partial_path = artifact.as_os_str().to_owned();
+    partial_path.push(PARTIAL_SUFFIX);
+    partial_path.into()
+}
+
+/// Moves the completely written [partial](partial_path) file of `artifact` into place.
+///
+/// If moving fails the partial file is removed.
+pub fn complete_partial(artifact: &Path) -> io::Result<()> {
+    let partial = partial_path(artifact);
+    let complete = || {
+        if fs::exists(artifact)? {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        File::open(&partial)?.sync_all()?;
+        fs::rename(&partial, artifact)
+    };
+
+    complete().inspect_err(|_| {
+        let _ = fs::remove_file(&partial);
+    })
+}
+
+/// Writes the *new* `artifact` using `write` to its [partial](partial_path) file first.
+///
+/// `write` is called with the path of the partial file. Once it succeeded the
+/// file is [moved into place](complete_partial), otherwise it's removed.
+/// A partial file left over by an interrupted write is replaced.
+pub fn write_artifact<T, E: From<io::Error>>(
+    artifact: &Path,
+    write: impl FnOnce(&Path) -> Result<T, E>,
+) -> Result<T, E> {
+    if fs::exists(artifact)? {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+    }
+    let partial = partial_path(artifact);
+    match fs::remove_file(&partial) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let written = write(&partial).inspect_err(|_| {
+        let _ = fs::remove_file(&partial);
+    })?;
+    complete_partial(artifact)?;
+
+    Ok(written)
+}
+
+/// Suffix of the sidecar file holding the secrets masked in an artifact.
+pub const SECRETS_SUFFIX: &str = ".secrets";
+
+/// Path of the sidecar file holding the secrets masked in `artifact` encrypted by `tool`.
+pub fn secrets_path(artifact: &Path, tool: &str) -> PathBuf {
+    let mut secrets_path = artifact.as_os_str().to_owned();
+    secrets_path.push(format!("{SECRETS_SUFFIX}.{tool}"));
+    secrets_path.into()
+}
+
+/// Suffix of the sidecar file listing the files archived in an artifact.
+pub const INDEX_SUFFIX: &str = ".index.gz";
+
+/// Path of the sidecar file listing the files archived in `artifact`.
+pub fn index_path(artifact: &Path) -> PathBuf {
+    let mut index_path = artifact.as_os_str().to_owned();
+    index_path.push(INDEX_SUFFIX);
+    index_path.into()
+}
+
+/// Suffix of the sidecar file naming the artifact an incremental or differential one is based on.
+pub const BASE_SUFFIX: &str = ".base";
+
+/// Path of the sidecar file naming the artifact the incremental or differential `artifact` is based on.
+pub fn base_path(artifact: &Path) -> PathBuf {
+    let mut base_path = artifact.as_os_str().to_owned();
+    base_path.push(BASE_SUFFIX);
+    base_path.into()
+}
+
+/// Sidecar files of `artifact` which are removed and tiered along with it.
+fn sidecars(artifact: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    [
+        checksum_path(artifact),
+        index_path(artifact),
+        base_path(artifact),
+    ]
+    .into_iter()
+    .chain(
+        encrypt::TOOLS
+            .iter()
+            .map(|tool| secrets_path(artifact, tool)),
+    )
+}
+
+/// Moves `src` to `dst` unless an earlier attempt did so already.
+fn move_once(src: &Path, dst: &Path) -> io::Result<()> {
+    if !fs::exists(src)? {
+        return Ok(());
+    }
+    if fs::exists(dst)? {
+        // duplicated to another file system, but removing `src` failed
+        tracing::debug!(target: "util::artifact", "Already moved: {}", src.display());
+        return fs::remove_file(src);
+    }
+    move_file(src, dst)
+}
+
+/// Remove `artifact` along with its [checksum](checksum_path), [index](index_path) and [secrets](secrets_path) sidecars.
+pub fn remove_artifact(artifact: &Path) -> io::Result<()> {
+    fs::remove_file(artifact)?;
+    for sidecar in sidecars(artifact) {
+        match fs::remove_file(sidecar) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Split `artifacts` into the ones kept by the retention `cfg` and the discarded ones.
+///
+/// The `artifacts` have to be sorted from the most recent to the oldest one.
+/// [Pinned](is_pinned) artifacts are always kept. The decisions are logged
+/// under `target`, e.g. `backend::apps::retain`.
+pub fn partition_retained(
+    artifacts: Vec<(PathBuf, NaiveDateTime)>,
+    cfg: &RetentionConfig,
+    target: &str,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    // tracing requires constant targets, so the events are passed to `log` directly
+    let mut retention = Retention::from(*cfg);
+    let mut kept = Vec::new();
+    let mut discarded = Vec::new();
+    for (path, date) in artifacts {
+        if is_pinned(&path) {
+            log::debug!(target: target, "Backup pinned: {}", path.display());
+            kept.push(path);
+        } else if retention.retain(date) {
+            log::debug!(target: target, "Backup retained: {}", path.display());
+            kept.push(path);
+        } else {
+            discarded.push(path);
+        }
+    }
+
+    (kept, discarded)
+}
+
+/// Remove the discarded `artifact` along with its sidecars, retried as configured by `retry`.
+///
+/// Failing to remove it is only logged under `target`, so the retention goes on.
+pub fn discard_artifact(artifact: &Path, dry_run: bool, retry: &RetryConfig, target: &str) {
+    log::info!(target: target, "Discarding backup: {}", artifact.display());
+    if dry_run {
+        return;
+    }
+    if let Err(e) = retry_io(retry, "Deleting backup", || remove_artifact(artifact)) {
+        log::error!(target: target, "Unable to delete backup: {e}");
+    }
+}
+
+/// A directory of artifacts named `<prefix><stamp><suffix>`, see [format_stamp].
+///
+/// Optionally artifacts can be moved to a cold tier once they reached a
+/// certain age. Artifacts in the cold tier are still managed by the [ArtifactDir].
+///
+/// File system operations are retried on transient errors as configured by the [RetryConfig].
+#[derive(Debug, Clone)]
+pub struct ArtifactDir {
+    dir: PathBuf,
+    prefix: &'static str,
+    suffix: &'static str,
+    cold_tier: Option<ColdTier>,
+    retry: RetryConfig,
+    clock: Clock,
+    generation: Option<BackupGeneration>,
+}
+
+#[derive(Debug, Clone)]
+struct ColdTier {
+    dir: PathBuf,
+    after: TimeDelta,
+}
+
+impl ArtifactDir {
+    /// Create a new [ArtifactDir] located at `dir`.
+    pub fn new(dir: PathBuf, prefix: &'static str, suffix: &'static str) -> Self {
+        Self {
+            dir,
+            prefix,
+            suffix,
+            cold_tier: None,
+            retry: RetryConfig::default(),
+            clock: Clock::default(),
+            generation: None,
+        }
+    }
+
+    /// Timestamp new artifacts and determine their age using `clock`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Embed `generation` into the names of new artifacts.
+    pub fn with_generation(mut self, generation: BackupGeneration) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    /// Retry file system operations as configured by `retry`.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Move artifacts older than `after` to `cold_dir` on [tiering](Self::tier).
+    pub fn with_cold_tier(mut self, cold_dir: PathBuf, after: TimeDelta) -> Self {
+        self.cold_tier = Some(ColdTier {
+            dir: cold_dir,
+            after,
+        });
+        self
+    }
+
+    /// Directory new artifacts are created in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Generate the path of a new artifact timestamped with the current time of the [Clock].
+    ///
+    /// The [generation](Self::with_generation) follows the timestamp if set.
+    pub fn generate_filename(&self) -> PathBuf {
+        let stamp = format_stamp(self.clock.now(), self.generation);
+
+        let path = self
+            .dir
+            .join(format!("{}{stamp}{}", self.prefix, self.suffix));
+        assert!(!path.exists(), "artifact should not exist prior");
+
+        path
+    }
+
+    /// Parse the creation date of the artifact from its `file_name`.
+    pub fn parse_timestamp(&self, file_name: &str) -> Option<NaiveDateTime> {
+        self.parse_stamp(file_name).map(|(timestamp, _)| timestamp)
+    }
+
+    /// Parse the generation of the artifact from its `file_name`.
+    ///
+    /// Returns [None] if it's no artifact of this directory or lacks a generation.
+    pub fn parse_generation(&self, file_name: &str) -> Option<BackupGeneration> {
+        self.parse_stamp(file_name)
+            .and_then(|(_, generation)| generation)
+    }
+
+    fn parse_stamp(&self, file_name: &str) -> Option<(NaiveDateTime, Option<BackupGeneration>)> {
+        let stamp = file_name
+            .strip_prefix(self.prefix)?
+            .strip_suffix(self.suffix)?;
+        parse_stamp(stamp)
+    }
+
+    /// Collect all artifacts created so far along with their creation date.
+    ///
+    /// This includes artifacts which were moved to the cold tier.
+    /// The artifacts are sorted from the most recent to the oldest one.
+    pub fn artifacts(&self) -> io::Result<Vec<(PathBuf, NaiveDateTime)>> {
+        let mut artifacts = self.artifacts_in(&self.dir)?;
+        if let Some(cold_tier) = &self.cold_tier {
+            artifacts.extend(self.artifacts_in(&cold_tier.dir)?);
+        }
+        artifacts.sort_by(|(_, ts_1), (_, ts_2)| ts_1.cmp(ts_2).reverse());
+
+        Ok(artifacts)
+    }
+
+    fn artifacts_in(&self, dir: &Path) -> io::Result<Vec<(PathBuf, NaiveDateTime)>> {
+        if !retry_io(&self.retry, "Checking artifact directory", || {
+            fs::exists(dir)
+        })? {
+            return Ok(Vec::new());
+        }
+
+        Ok(
+            retry_io(&self.retry, "Listing artifacts", || fs::read_dir(dir))?
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let Ok(file_name) = entry.file_name().into_string() else {
+                        return None;
+                    };
+                    let timestamp = self.parse_timestamp(&file_name)?;
+                    Some((entry.path(), timestamp))
+                })
+                .collect(),
+        )
+    }
+
+    /// Remove the [partial](partial_path) artifacts left over by interrupted writes.
+    ///
+    /// Partial artifacts modified recently may still be written and are kept.
+    pub fn remove_partials(&self, dry_run: bool) -> io::Result<()> {
+        if !retry_io(&self.retry, "Checking artifact directory", || {
+            fs::exists(&self.dir)
+        })? {
+            return Ok(());
+        }
+
+        for entry in retry_io(&self.retry, "Listing artifacts", || fs::read_dir(&self.dir))? {
+            let entry = entry?;
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // partial artifacts or partial secrets sidecars of artifacts
+            let is_partial = file_name.strip_suffix(PARTIAL_SUFFIX).is_some_and(|name| {
+                let artifact = encrypt::TOOLS
+                    .iter()
+                    .find_map(|tool| name.strip_suffix(&format!("{SECRETS_SUFFIX}.{tool}")))
+                    .unwrap_or(name);
+                self.parse_timestamp(artifact).is_some()
+            });
+            let stale = entry
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age >= PARTIAL_STALE_AFTER);
+            if !is_partial || !stale {
+                continue;
+            }
+
+            let path = entry.path();
+            tracing::warn!(target: "util::artifact", "Removing incomplete artifact of interrupted write: {}", path.display());
+            if !dry_run {
+                retry_io(&self.retry, "Removing incomplete artifact", || {
+                    fs::remove_file(&path)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Discard the artifacts not kept by the retention `cfg` and [tier](Self::tier) the others.
+    ///
+    /// Partial artifacts of interrupted writes are removed beforehand. The
+    /// decisions are logged under `target`, see [partition_retained].
+    pub fn retain(&self, cfg: &RetentionConfig, dry_run: bool, target: &str) -> io::Result<()> {
+        self.remove_partials(dry_run)?;
+        let artifacts = self.artifacts()?;
+        if artifacts.is_empty() {
+            log::debug!(target: target, "No backups found. Nothing to retain.");
+            return Ok(());
+        }
+
+        let (_, discarded) = partition_retained(artifacts, cfg, target);
+        for artifact in discarded {
+            discard_artifact(&artifact, dry_run, &self.retry, target);
+        }
+
+        self.tier(dry_run)
+    }
+
+    /// Move all artifacts which reached the configured age to the cold tier.
+    ///
+    /// Does nothing if no cold tier is configured.
+    pub fn tier(&self, dry_run: bool) -> io::Result<()> {
+        let Some(cold_tier) = &self.cold_tier else {
+            return Ok(());
+        };
+
+        let cutoff = self.clock.now() - cold_tier.after;
+        for (path, date) in self.artifacts_in(&self.dir)? {
+            if date > cutoff {
+                continue;
+            }
+
+            let cold_path = cold_tier
+                .dir
+                .join(path.file_name().expect("artifact should have a file name"));
+            tracing::info!(target: "util::artifact", "Move artifact to cold tier: {}", cold_path.display());
+            if !dry_run {
+                // every file is moved once, so a retry continues where the last attempt failed
+                retry_io(&self.retry, "Moving artifact to cold tier", || {
+                    fs::create_dir_all(&cold_tier.dir)?;
+                    move_once(&path, &cold_path)?;
+                    let pin = (pin_path(&path), pin_path(&cold_path));
+                    for (file, cold_file) in [pin]
+                        .into_iter()
+                        .chain(sidecars(&path).zip(sidecars(&cold_path)))
+                    {
+                        move_once(&file, &cold_file)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+//! SHA-256 checksums of artifacts computed while they are written.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Suffix of the sidecar file holding the checksum of an artifact.
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// Path of the sidecar file holding the checksum of `artifact`.
+pub fn checksum_path(artifact: &Path) -> PathBuf {
+    let mut checksum_path = artifact.as_os_str().to_owned();
+    checksum_path.push(CHECKSUM_SUFFIX);
+    checksum_path.into()
+}
+
+/// Reader computing the SHA-256 of everything read through it.
+#[derive(Debug)]
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// Hash everything read from `inner`.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hex encoded SHA-256 of everything read so far.
+    pub fn hex_digest(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Write the `hex_digest` of the *uncompressed* content of `artifact` to its sidecar file.
+///
+/// The sidecar is in the format of `sha256sum` reading from stdin, so the
+/// artifact can be verified by e.g. `zcat <artifact> | sha256sum -c <artifact>.sha256`.
+pub fn write_checksum(artifact: &Path, hex_digest: &str) -> io::Result<()> {
+    fs::write(checksum_path(artifact), format!("{hex_digest}  -\n"))
+}
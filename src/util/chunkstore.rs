@@ -0,0 +1,333 @@
+//! Deduplicating store of content-defined chunks.
+//!
+//! Streams written to a [ChunkStore] are split into chunks at boundaries
+//! determined by their content using a gear hash. Unchanged parts of a stream
+//! therefore result in the same chunks, even if data was inserted before them.
+//! Every chunk is stored once, compressed, named by its SHA-256. A manifest
+//! lists the chunks of a stream in order.
+//!
+//! Chunks no longer referenced by any manifest are removed by [ChunkStore::gc].
+//! Recently written chunks are kept, as they may belong to a stream whose manifest
+//! isn't written yet.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+
+use super::checksum::HashingReader;
+
+/// Directory of the chunk store next to the manifests referencing it.
+pub const CHUNKS_DIR: &str = "chunks";
+
+/// First line of every manifest.
+const MANIFEST_HEADER: &str = "nc_backup chunks 1";
+/// Extension of chunks being written.
+const PARTIAL_EXTENSION: &str = "partial";
+/// Time after which an unreferenced chunk is removed by [ChunkStore::gc].
+///
+/// Chunks are touched whenever they are stored, so those of a stream still being
+/// written are kept even if they were stored before.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Chunks are never smaller than this except at the end of a stream.
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// Chunks are cut at the latest after this many bytes.
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+/// A boundary is found on average every 64 KiB after the minimum size.
+const BOUNDARY_MASK: u64 = 0xffff << 48;
+
+/// Random values of the gear hash for every byte.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 with a fixed seed, the chunk boundaries must never change
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6e63_5f62_6163_6b75;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Returns the length of the first chunk of `data`.
+///
+/// If `data` is shorter than the maximum chunk size it's treated as the end of the stream.
+fn chunk_boundary(data: &[u8]) -> usize {
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    if end <= MIN_CHUNK_SIZE {
+        return end;
+    }
+
+    let mut hash: u64 = 0;
+    for (i, &byte) in data[..end].iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+        if i >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Chunks of a stream stored in a [ChunkStore].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Size of the stream in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 of the stream.
+    pub hex_digest: String,
+    /// Hex encoded SHA-256 of every chunk in order.
+    pub chunks: Vec<String>,
+}
+
+impl Manifest {
+    /// Read the manifest at `path`.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {msg}", path.display()),
+            )
+        };
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        if lines.next().transpose()?.as_deref() != Some(MANIFEST_HEADER) {
+            return Err(invalid("not a chunk manifest"));
+        }
+        let mut field = |name: &str| -> io::Result<String> {
+            let line = lines.next().transpose()?.unwrap_or_default();
+            line.strip_prefix(name)
+                .and_then(|value| value.strip_prefix(' '))
+                .map(str::to_string)
+                .ok_or_else(|| invalid(&format!("{name} is missing")))
+        };
+        let size = field("size")?
+            .parse()
+            .map_err(|_| invalid("size is invalid"))?;
+        let hex_digest = field("sha256")?;
+        let chunks = lines.collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            size,
+            hex_digest,
+            chunks,
+        })
+    }
+
+    /// Write the manifest to the new file `path`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create_new(path)?);
+        writeln!(writer, "{MANIFEST_HEADER}")?;
+        writeln!(writer, "size {}", self.size)?;
+        writeln!(writer, "sha256 {}", self.hex_digest)?;
+        for chunk in &self.chunks {
+            writeln!(writer, "{chunk}")?;
+        }
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()
+    }
+}
+
+/// Content-defined chunk store located in a directory.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    /// Create a [ChunkStore] located at `dir`.
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The chunk store of the manifest `manifest`, located in [CHUNKS_DIR] next to it.
+    pub fn beside(manifest: &Path) -> Self {
+        let dir = manifest.parent().unwrap_or(Path::new("."));
+        Self::new(dir.join(CHUNKS_DIR))
+    }
+
+    /// Directory of the chunk store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn chunk_path(&self, chunk: &str) -> PathBuf {
+        self.dir.join(&chunk[..2.min(chunk.len())]).join(chunk)
+    }
+
+    /// Store the chunk `data` unless already stored and return its name.
+    fn store_chunk(&self, data: &[u8]) -> io::Result<String> {
+        let chunk = hex(&Sha256::digest(data));
+        let path = self.chunk_path(&chunk);
+        if path.is_file() {
+            // protect the chunk from gc until the manifest references it
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(SystemTime::now())?;
+            return Ok(chunk);
+        }
+
+        fs::create_dir_all(path.parent().expect("chunk should be in a directory"))?;
+        let partial = path.with_extension(PARTIAL_EXTENSION);
+        let write = || {
+            let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?.sync_all()?;
+            fs::rename(&partial, &path)
+        };
+        write().inspect_err(|_| {
+            let _ = fs::remove_file(&partial);
+        })?;
+
+        Ok(chunk)
+    }
+
+    /// Split everything from `reader` into chunks, store them and return the [Manifest].
+    ///
+    /// Only chunks not yet in the store are written.
+    pub fn write(&self, reader: impl Read) -> io::Result<Manifest> {
+        let mut reader = HashingReader::new(reader);
+        let mut buf = Vec::with_capacity(2 * MAX_CHUNK_SIZE);
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        let mut eof = false;
+        loop {
+            while !eof && buf.len() < MAX_CHUNK_SIZE {
+                let read = (&mut reader)
+                    .take((2 * MAX_CHUNK_SIZE - buf.len()) as u64)
+                    .read_to_end(&mut buf)?;
+                eof = read == 0;
+            }
+            if buf.is_empty() {
+                break;
+            }
+
+            let len = chunk_boundary(&buf);
+            chunks.push(self.store_chunk(&buf[..len])?);
+            size += len as u64;
+            buf.drain(..len);
+        }
+        tracing::debug!(target: "util::chunkstore", "Stored {size} bytes in {} chunks", chunks.len());
+
+        Ok(Manifest {
+            size,
+            hex_digest: reader.hex_digest(),
+            chunks,
+        })
+    }
+
+    /// Read the stream of the `manifest` from the store.
+    ///
+    /// The content of every chunk is checked against its name.
+    pub fn reader(&self, manifest: Manifest) -> ChunkReader {
+        ChunkReader {
+            store: self.clone(),
+            chunks: manifest.chunks.into_iter(),
+            current: io::Cursor::new(Vec::new()),
+        }
+    }
+
+    /// Remove all chunks not referenced by any of the `manifests`.
+    ///
+    /// Chunks being written and those stored within the last day are kept, as the
+    /// manifest of a stream is written after its chunks. Returns the number of removed chunks. On a dry run the chunks are only counted.
+    pub fn gc<'a>(
+        &self,
+        manifests: impl IntoIterator<Item = &'a Path>,
+        dry_run: bool,
+    ) -> io::Result<usize> {
+        let mut referenced = HashSet::new();
+        for manifest in manifests {
+            referenced.extend(Manifest::read(manifest)?.chunks);
+        }
+
+        let prefixes = match fs::read_dir(&self.dir) {
+            Ok(prefixes) => prefixes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut removed = 0;
+        for prefix in prefixes {
+            let prefix = prefix?.path();
+            if !prefix.is_dir() {
+                continue;
+            }
+            for chunk in fs::read_dir(&prefix)? {
+                let chunk = chunk?.path();
+                let name = chunk.file_name().and_then(|name| name.to_str());
+                if name.is_some_and(|name| referenced.contains(name))
+                    || chunk
+                        .extension()
+                        .is_some_and(|ext| ext == PARTIAL_EXTENSION)
+                {
+                    continue;
+                }
+                // modified in the future counts as recent
+                let age = fs::metadata(&chunk)?.modified()?.elapsed();
+                if age.map_or(true, |age| age < GC_GRACE_PERIOD) {
+                    tracing::trace!(target: "util::chunkstore", "Keeping recent chunk: {}", chunk.display());
+                    continue;
+                }
+                tracing::trace!(target: "util::chunkstore", "Removing unreferenced chunk: {}", chunk.display());
+                if !dry_run {
+                    fs::remove_file(&chunk)?;
+                }
+                removed += 1;
+            }
+        }
+        tracing::debug!(target: "util::chunkstore", "Removed {removed} unreferenced chunks");
+
+        Ok(removed)
+    }
+}
+
+/// Reader concatenating the chunks of a [Manifest] created by [ChunkStore::reader].
+#[derive(Debug)]
+pub struct ChunkReader {
+    store: ChunkStore,
+    chunks: std::vec::IntoIter<String>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let Some(chunk) = self.chunks.next() else {
+                return Ok(0);
+            };
+
+            let path = self.store.chunk_path(&chunk);
+            let mut data = Vec::new();
+            GzDecoder::new(File::open(&path)?).read_to_end(&mut data)?;
+            if hex(&Sha256::digest(&data)) != chunk {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk {} is corrupted", path.display()),
+                ));
+            }
+            self.current = io::Cursor::new(data);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! Source of the current time.
+//!
+//! Instead of querying the system time directly, timestamps of new artifacts
+//! and age based decisions use a [Clock]. This allows re-running a missed
+//! backup under its historical timestamp.
+
+use chrono::{Local, NaiveDateTime};
+
+/// Source of the current local time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// The local time of the system.
+    #[default]
+    System,
+    /// A fixed point in time.
+    Fixed(NaiveDateTime),
+}
+
+impl Clock {
+    /// The current local time.
+    pub fn now(&self) -> NaiveDateTime {
+        match self {
+            Self::System => Local::now().naive_local(),
+            Self::Fixed(now) => *now,
+        }
+    }
+}
@@ -0,0 +1,527 @@
+//! Timeouts and priority of external commands.
+//!
+//! A hung external command, e.g. a wedged `btrfs receive`, would block the
+//! whole run forever. Commands are therefore run by [output] or [status], or
+//! watched by a [Watchdog], which kill them once their timeout configured by
+//! [TimeoutConfig] passed. The hang then surfaces as [TimedOut] error.
+//!
+//! [Occ](crate::nextcloud::Occ), [SnapperConfig](crate::backends::snapper::SnapperConfig)
+//! and [MariaDb](crate::backends::mariadb::MariaDb) run their commands by a
+//! [CommandRunner]. Tests replace the [SystemRunner] by a [ScriptedRunner]
+//! answering the commands with prepared output, so the backends can be tested
+//! without root, btrfs or a Nextcloud installation.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use derive_more::{Display, Error};
+use rustix::process::{kill_process, Pid, Signal};
+
+/// Time a killed command is given to terminate before it's killed forcefully.
+const KILL_GRACE: Duration = Duration::from_secs(10);
+/// Bytes of the output of a timed out command that are logged.
+const PARTIAL_OUTPUT_LEN: usize = 4096;
+
+/// Timeouts configured by [set_timeouts].
+static TIMEOUTS: OnceLock<TimeoutConfig> = OnceLock::new();
+/// Priority configured by [set_priority].
+static PRIORITY: OnceLock<PriorityConfig> = OnceLock::new();
+
+/// Timeouts of external commands.
+///
+/// ```toml
+/// [timeouts]
+/// default_secs = 3600
+/// commands = { btrfs = 86400, occ = 600 }
+/// ```
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Seconds after which a command not listed in `commands` is killed.
+    ///
+    /// Commands run without timeout if unset.
+    pub default_secs: Option<u64>,
+
+    /// Seconds after which the command is killed by its name, e.g. `occ`,
+    /// `snapper`, `btrfs`, `zstd`, `mariadb-dump`, `redis-cli` or `aws`.
+    ///
+    /// `0` disables the timeout of the command.
+    pub commands: BTreeMap<String, u64>,
+}
+
+impl TimeoutConfig {
+    /// Timeout of the command `name`.
+    pub fn timeout(&self, name: &str) -> Option<Duration> {
+        self.commands
+            .get(name)
+            .copied()
+            .or(self.default_secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+/// Use the timeouts of `config` for all commands run from now on.
+///
+/// Only the first call takes effect.
+pub fn set_timeouts(config: TimeoutConfig) {
+    if TIMEOUTS.set(config).is_err() {
+        tracing::debug!(target: "util::command", "Timeouts already set, ignoring new ones");
+    }
+}
+
+/// Timeout of the command `name` as configured by [set_timeouts].
+pub fn timeout(name: &str) -> Option<Duration> {
+    TIMEOUTS.get().and_then(|config| config.timeout(name))
+}
+
+/// Scheduling class of the I/O of a command, see `ionice(1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoniceClass {
+    /// Gets the disk first, requires root.
+    Realtime,
+    /// Shares the disk according to the level.
+    BestEffort,
+    /// Only gets the disk if no other process needs it.
+    Idle,
+}
+
+impl IoniceClass {
+    /// Number of the class understood by `ionice -c`.
+    fn number(self) -> u8 {
+        match self {
+            Self::Realtime => 1,
+            Self::BestEffort => 2,
+            Self::Idle => 3,
+        }
+    }
+}
+
+/// CPU and I/O priority of external commands.
+///
+/// ```toml
+/// [priority]
+/// nice = 10
+/// ionice_class = "idle"
+/// ```
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PriorityConfig {
+    /// Niceness of the commands from -20 (highest priority) to 19 (lowest priority).
+    pub nice: Option<i32>,
+
+    /// I/O scheduling class of the commands.
+    pub ionice_class: Option<IoniceClass>,
+
+    /// Level of the I/O scheduling class from 0 (highest priority) to 7 (lowest priority).
+    ///
+    /// Only used by the `realtime` and `best-effort` classes.
+    pub ionice_level: Option<u8>,
+}
+
+/// Use the priority of `config` for all commands [created](new) from now on.
+///
+/// Only the first call takes effect.
+pub fn set_priority(config: PriorityConfig) {
+    if PRIORITY.set(config).is_err() {
+        tracing::debug!(target: "util::command", "Priority already set, ignoring new one");
+    }
+}
+
+/// Prepare a [Command] running `program` with the priority configured by [set_priority].
+///
+/// The program is run by `nice` and `ionice`, so the priority is set before it's
+/// executed and inherited by the processes it forks, e.g. by `sudo`. A priority
+/// the user isn't permitted to set is skipped, the program is run anyway.
+pub fn new(program: impl AsRef<OsStr>) -> Command {
+    let Some(config) = PRIORITY.get() else {
+        return Command::new(program);
+    };
+    let mut prefix: Vec<String> = Vec::new();
+    if let Some(nice) = config.nice {
+        prefix.extend(["nice".into(), "-n".into(), nice.to_string()]);
+    }
+    if let Some(class) = config.ionice_class {
+        prefix.extend([
+            "ionice".into(),
+            "-t".into(),
+            "-c".into(),
+            class.number().to_string(),
+        ]);
+        if let Some(level) = config.ionice_level {
+            prefix.extend(["-n".into(), level.to_string()]);
+        }
+    }
+    let Some((first, args)) = prefix.split_first() else {
+        return Command::new(program);
+    };
+    let mut cmd = Command::new(first);
+    cmd.args(args).arg(program);
+    cmd
+}
+
+/// The command `command` was killed as it didn't finish in time.
+#[derive(Debug, Display, Error, Clone, PartialEq, Eq)]
+#[display("{command} didn't finish within {}s", after.as_secs())]
+pub struct TimedOut {
+    /// Name of the command.
+    pub command: String,
+    /// Timeout the command exceeded.
+    pub after: Duration,
+}
+
+impl From<TimedOut> for io::Error {
+    fn from(e: TimedOut) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// Returns the [TimedOut] error wrapped in `e`, if any.
+pub fn timed_out(e: &io::Error) -> Option<&TimedOut> {
+    e.get_ref()?.downcast_ref()
+}
+
+/// Kills processes which didn't finish within the timeout of their command.
+///
+/// The processes are watched until the [Watchdog] is [stopped](Self::stop) or dropped.
+#[derive(Debug)]
+pub struct Watchdog {
+    command: String,
+    after: Option<Duration>,
+    cancel: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl Watchdog {
+    /// Watch the processes `pids` of the command `name`.
+    ///
+    /// Does nothing if no timeout is configured for `name`.
+    pub fn start(name: &str, pids: &[u32]) -> Self {
+        let after = timeout(name);
+        let mut watchdog = Self {
+            command: name.to_string(),
+            after,
+            cancel: None,
+            thread: None,
+        };
+        let Some(after) = after else {
+            return watchdog;
+        };
+
+        let (cancel, cancelled) = mpsc::channel();
+        let command = watchdog.command.clone();
+        let pids: Vec<_> = pids
+            .iter()
+            .filter_map(|pid| Pid::from_raw(*pid as i32))
+            .collect();
+        watchdog.cancel = Some(cancel);
+        watchdog.thread = Some(thread::spawn(move || {
+            if cancelled.recv_timeout(after) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            tracing::error!(target: "util::command", "{command} didn't finish within {}s, terminating it", after.as_secs());
+            kill(&pids, Signal::TERM);
+            if cancelled.recv_timeout(KILL_GRACE) == Err(RecvTimeoutError::Timeout) {
+                tracing::error!(target: "util::command", "{command} didn't terminate, killing it");
+                kill(&pids, Signal::KILL);
+            }
+            true
+        }));
+
+        watchdog
+    }
+
+    /// Stop watching the processes, which have to be waited for already.
+    ///
+    /// Returns [TimedOut] if they were killed.
+    pub fn stop(mut self) -> Result<(), TimedOut> {
+        match self.cancel() {
+            true => Err(TimedOut {
+                command: self.command.clone(),
+                after: self.after.unwrap_or_default(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Stop the watching thread and return whether it killed the processes.
+    fn cancel(&mut self) -> bool {
+        drop(self.cancel.take());
+        self.thread
+            .take()
+            .is_some_and(|thread| thread.join().unwrap_or(false))
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Send `signal` to the processes `pids` and all their descendants.
+///
+/// Descendants, e.g. spawned by `sh` or `sudo`, would otherwise keep running
+/// and keep the pipes of the command open.
+fn kill(pids: &[Pid], signal: Signal) {
+    let mut targets = pids.to_vec();
+    let mut i = 0;
+    let parents = parent_pids();
+    while i < targets.len() {
+        let parent = targets[i];
+        targets.extend(
+            parents
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+
+    for pid in targets {
+        if let Err(e) = kill_process(pid, signal) {
+            tracing::warn!(target: "util::command", "Unable to signal process {pid}: {e}");
+        }
+    }
+}
+
+/// Returns every running process along with its parent according to `/proc`.
+fn parent_pids() -> Vec<(Pid, Pid)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(entry.path().join("stat")).ok()?;
+            // the name in parentheses may contain spaces, the parent follows the state
+            let ppid = stat
+                .rsplit_once(')')?
+                .1
+                .split_whitespace()
+                .nth(1)?
+                .parse()
+                .ok()?;
+            Some((Pid::from_raw(pid)?, Pid::from_raw(ppid)?))
+        })
+        .collect()
+}
+
+/// Log the output a timed out `command` produced so far.
+fn log_partial_output(command: &str, stream: &str, output: &[u8]) {
+    if output.is_empty() {
+        return;
+    }
+    let partial = &output[output.len().saturating_sub(PARTIAL_OUTPUT_LEN)..];
+    tracing::warn!(target: "util::command", "{stream} of {command} before the timeout: {}", String::from_utf8_lossy(partial).trim_end());
+}
+
+/// Like [Command::output] but kills the command `name` after its [timeout].
+///
+/// The output produced until the timeout is logged.
+pub fn output(cmd: &mut Command, name: &str) -> io::Result<Output> {
+    let _span = tracing::debug_span!("command", command = name).entered();
+    let start = Instant::now();
+    let child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let watchdog = Watchdog::start(name, &[child.id()]);
+    let output = child.wait_with_output();
+    if let Err(e) = watchdog.stop() {
+        if let Ok(output) = &output {
+            log_partial_output(name, "stdout", &output.stdout);
+            log_partial_output(name, "stderr", &output.stderr);
+        }
+        return Err(e.into());
+    }
+    if let Ok(output) = &output {
+        log_finished(start, output.status);
+    }
+
+    output
+}
+
+/// Like [Command::status] but kills the command `name` after its [timeout].
+pub fn status(cmd: &mut Command, name: &str) -> io::Result<ExitStatus> {
+    let _span = tracing::debug_span!("command", command = name).entered();
+    let start = Instant::now();
+    let mut child = cmd.spawn()?;
+    let watchdog = Watchdog::start(name, &[child.id()]);
+    let status = child.wait();
+    watchdog.stop()?;
+    if let Ok(status) = &status {
+        log_finished(start, *status);
+    }
+
+    status
+}
+
+/// Log the exit of the command of the current span started at `start`.
+fn log_finished(start: Instant, status: ExitStatus) {
+    tracing::debug!(
+        target: "util::command",
+        duration_ms = start.elapsed().as_millis() as u64,
+        exit_code = status.code(),
+        "Command exited with {status}"
+    );
+}
+
+/// Runs external commands to completion.
+pub trait CommandRunner: fmt::Debug + Send + Sync {
+    /// Run the command `name` capturing its output, see [output].
+    fn output(&self, cmd: &mut Command, name: &str) -> io::Result<Output>;
+}
+
+/// Runs the commands on this host using [output].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, cmd: &mut Command, name: &str) -> io::Result<Output> {
+        output(cmd, name)
+    }
+}
+
+/// The [SystemRunner] shared by the users of a [CommandRunner] by default.
+pub fn system_runner() -> Arc<dyn CommandRunner> {
+    Arc::new(SystemRunner)
+}
+
+/// Arguments of `cmd` including the program.
+fn command_line(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Prepared answer of a [ScriptedRunner].
+#[derive(Debug)]
+struct Step {
+    args: Vec<String>,
+    answer: io::Result<Output>,
+}
+
+/// [CommandRunner] answering commands in the order they were scripted.
+///
+/// Every command has to contain the arguments of the next scripted answer,
+/// otherwise it fails with [io::ErrorKind::InvalidInput]. Commands not scripted
+/// at all fail with [io::ErrorKind::NotFound].
+///
+/// # Example
+///
+/// ```
+/// # use std::process::Command;
+/// # use nc_backup_lib::util::command::{CommandRunner, ScriptedRunner};
+/// let runner = ScriptedRunner::new();
+/// runner.expect(&["maintenance:mode"], "Maintenance mode is currently disabled");
+///
+/// let output = runner
+///     .output(Command::new("occ").arg("maintenance:mode"), "occ")
+///     .unwrap();
+/// assert_eq!(output.stdout, b"Maintenance mode is currently disabled");
+/// assert!(runner.finished());
+/// ```
+#[derive(Debug, Default)]
+pub struct ScriptedRunner {
+    script: Mutex<VecDeque<Step>>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+impl ScriptedRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, args: &[&str], answer: io::Result<Output>) -> &Self {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .push_back(Step {
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                answer,
+            });
+        self
+    }
+
+    /// Answer the next command containing `args` successfully with `stdout`.
+    pub fn expect(&self, args: &[&str], stdout: &str) -> &Self {
+        self.expect_exit(args, 0, stdout, "")
+    }
+
+    /// Answer the next command containing `args` with the exit `code` and the output.
+    pub fn expect_exit(&self, args: &[&str], code: i32, stdout: &str, stderr: &str) -> &Self {
+        self.push(
+            args,
+            Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.into(),
+                stderr: stderr.into(),
+            }),
+        )
+    }
+
+    /// Fail the next command containing `args` to run with `error`.
+    pub fn expect_error(&self, args: &[&str], error: io::ErrorKind) -> &Self {
+        self.push(args, Err(error.into()))
+    }
+
+    /// The command lines run so far, each starting with the program.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls
+            .lock()
+            .expect("calls should not be poisoned")
+            .clone()
+    }
+
+    /// Returns whether every scripted command was run.
+    pub fn finished(&self) -> bool {
+        self.script
+            .lock()
+            .expect("script should not be poisoned")
+            .is_empty()
+    }
+}
+
+impl CommandRunner for ScriptedRunner {
+    fn output(&self, cmd: &mut Command, name: &str) -> io::Result<Output> {
+        let line = command_line(cmd);
+        self.calls
+            .lock()
+            .expect("calls should not be poisoned")
+            .push(line.clone());
+
+        let step = self
+            .script
+            .lock()
+            .expect("script should not be poisoned")
+            .pop_front();
+        let Some(step) = step else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} wasn't scripted: {line:?}"),
+            ));
+        };
+        if !step.args.iter().all(|arg| line.contains(arg)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} was expected with {:?}: {line:?}", step.args),
+            ));
+        }
+        step.answer
+    }
+}
@@ -0,0 +1,186 @@
+//! Gzip compression using multiple threads.
+//!
+//! Like `pigz` the input is split into blocks which are compressed in parallel
+//! as independent gzip members. The concatenated members form a valid gzip file,
+//! which has to be read using a multi-member decoder like
+//! [MultiGzDecoder](flate2::read::MultiGzDecoder).
+
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::thread;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Size of the blocks compressed independently by [ParallelGzEncoder].
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Compression of an archive or dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Number of threads compressing in parallel.
+    ///
+    /// `0` uses one thread per core. Defaults to `1`, which writes a single
+    /// gzip member like `gzip` does.
+    pub threads: usize,
+
+    /// Gzip compression level from `0` (none) to `9` (best).
+    pub level: u32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            level: Compression::default().level(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Number of threads to compress with.
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        }
+    }
+
+    /// Create a gzip encoder writing to `writer` as configured.
+    pub fn encoder<W: Write>(&self, writer: W) -> GzWriter<W> {
+        let level = Compression::new(self.level.min(9));
+        match self.threads() {
+            1 => GzWriter::Single(GzEncoder::new(writer, level)),
+            threads => GzWriter::Parallel(ParallelGzEncoder::new(writer, level, threads)),
+        }
+    }
+}
+
+/// Gzip encoder created by [CompressionConfig::encoder].
+#[derive(Debug)]
+pub enum GzWriter<W: Write> {
+    /// Compress using the calling thread.
+    Single(GzEncoder<W>),
+    /// Compress using multiple threads.
+    Parallel(ParallelGzEncoder<W>),
+}
+
+impl<W: Write> GzWriter<W> {
+    /// Write the remaining data and return the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Single(encoder) => encoder.finish(),
+            Self::Parallel(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for GzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Single(encoder) => encoder.write(buf),
+            Self::Parallel(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Single(encoder) => encoder.flush(),
+            Self::Parallel(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Gzip encoder compressing blocks of the input on multiple threads.
+///
+/// Every block becomes an independent gzip member. [flush](Write::flush) only
+/// flushes the underlying writer, the buffered input is written on [finish](Self::finish).
+#[derive(Debug)]
+pub struct ParallelGzEncoder<W: Write> {
+    writer: Option<W>,
+    level: Compression,
+    threads: usize,
+    pending: Vec<u8>,
+    empty: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(writer: W, level: Compression, threads: usize) -> Self {
+        let threads = threads.max(1);
+        Self {
+            writer: Some(writer),
+            level,
+            threads,
+            pending: Vec::with_capacity(threads * BLOCK_SIZE),
+            empty: true,
+        }
+    }
+
+    /// Compress the pending input in parallel and write it.
+    fn compress_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let level = self.level;
+        let members: Vec<io::Result<Vec<u8>>> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .pending
+                .chunks(BLOCK_SIZE)
+                .map(|block| {
+                    scope.spawn(move || {
+                        let mut encoder = GzEncoder::new(Vec::with_capacity(block.len()), level);
+                        encoder.write_all(block)?;
+                        encoder.finish()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("compression thread panicked"))
+                .collect()
+        });
+        self.pending.clear();
+        self.empty = false;
+
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("encoder should not be finished");
+        for member in members {
+            writer.write_all(&member?)?;
+        }
+        Ok(())
+    }
+
+    /// Write the remaining data and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress_pending()?;
+        let mut writer = self.writer.take().expect("encoder should not be finished");
+        if self.empty {
+            // an empty file isn't valid gzip
+            GzEncoder::new(&mut writer, self.level).finish()?;
+        }
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let capacity = self.threads * BLOCK_SIZE;
+        let written = buf.len().min(capacity - self.pending.len());
+        self.pending.extend_from_slice(&buf[..written]);
+        if self.pending.len() == capacity {
+            self.compress_pending()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Encryption of small files for recipients using `age` or `gpg` and their decryption.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::util::command;
+
+/// Tools the files can be encrypted with, see [Encryption::tool].
+pub const TOOLS: &[&str] = &["age", "gpg"];
+
+/// Encryption of files for one or more recipients.
+///
+/// ```toml
+/// tool = "age"
+/// recipients = ["age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"]
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "tool", rename_all = "lowercase")]
+pub enum Encryption {
+    /// Encrypt using `age` for its recipients, e.g. `age1…` or SSH public keys.
+    Age {
+        /// Recipients able to decrypt the files.
+        recipients: Vec<String>,
+    },
+    /// Encrypt using `gpg` for its recipients, e.g. key IDs of the keyring.
+    Gpg {
+        /// Recipients able to decrypt the files.
+        recipients: Vec<String>,
+    },
+}
+
+impl Encryption {
+    /// Name of the tool, also used as extension of the encrypted files.
+    pub fn tool(&self) -> &'static str {
+        match self {
+            Self::Age { .. } => "age",
+            Self::Gpg { .. } => "gpg",
+        }
+    }
+
+    fn recipients(&self) -> &[String] {
+        match self {
+            Self::Age { recipients } | Self::Gpg { recipients } => recipients,
+        }
+    }
+
+    /// Checks the tool is installed and recipients are configured.
+    pub fn check(&self) -> io::Result<()> {
+        if self.recipients().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no recipients to encrypt for using {}", self.tool()),
+            ));
+        }
+
+        tracing::trace!(target: "util::encrypt", "Running: {} --version", self.tool());
+        let output = command::output(Command::new(self.tool()).arg("--version"), self.tool())?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} --version failed with {}",
+                self.tool(),
+                output.status
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts `plaintext` into the *new* file `dest`.
+    ///
+    /// If encrypting fails the incomplete `dest` is removed.
+    pub fn encrypt(&self, plaintext: &[u8], dest: &Path) -> io::Result<()> {
+        self.check()?;
+        if fs::exists(dest)? {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+
+        let mut encrypt = Command::new(self.tool());
+        match self {
+            Self::Age { recipients } => {
+                for recipient in recipients {
+                    encrypt.arg("-r").arg(recipient);
+                }
+            }
+            Self::Gpg { recipients } => {
+                encrypt.args(["--batch", "--trust-model", "always", "--encrypt"]);
+                for recipient in recipients {
+                    encrypt.arg("--recipient").arg(recipient);
+                }
+            }
+        }
+        encrypt.arg("--output").arg(dest);
+        tracing::trace!(target: "util::encrypt", "Running: {encrypt:?}");
+
+        let mut process = encrypt
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let written = process
+            .stdin
+            .take()
+            .expect("stdin should be piped")
+            .write_all(plaintext);
+        let output = process.wait_with_output()?;
+
+        let result = written.and_then(|()| match output.status.success() {
+            true => Ok(()),
+            false => Err(io::Error::other(format!(
+                "{} failed with {}: {}",
+                self.tool(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        });
+        result.inspect_err(|_| {
+            let _ = fs::remove_file(dest);
+        })
+    }
+
+    /// Decrypts the file `source` using `identity` and returns its plaintext.
+    ///
+    /// `identity` is the identity file of `age`, e.g. `~/.config/age/key.txt`,
+    /// or the home directory of the `gpg` keyring holding the secret key.
+    pub fn decrypt(&self, source: &Path, identity: &Path) -> io::Result<Vec<u8>> {
+        let mut decrypt = Command::new(self.tool());
+        match self {
+            Self::Age { .. } => decrypt.arg("--decrypt").arg("--identity").arg(identity),
+            Self::Gpg { .. } => decrypt
+                .arg("--homedir")
+                .arg(identity)
+                .args(["--batch", "--decrypt"]),
+        };
+        decrypt.arg(source);
+        tracing::trace!(target: "util::encrypt", "Running: {decrypt:?}");
+
+        let output = command::output(&mut decrypt, self.tool())?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} failed with {}: {}",
+                self.tool(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+}
@@ -0,0 +1,154 @@
+//! File system helpers shared by the backends.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Duplicates the file at `src` to the *new* file `dst`.
+///
+/// If `src` and `dst` reside on the same btrfs or XFS file system `dst` is created
+/// as a reflink using the `FICLONE` ioctl: the data blocks are shared between both
+/// files and no data is copied at all. Otherwise the data is copied in the kernel
+/// by `copy_file_range(2)`, falling back to [`io::copy`] if that isn't supported
+/// either, e.g. across file systems on older kernels.
+///
+/// Returns the number of bytes duplicated.
+///
+/// # Errors
+///
+/// Fails if `dst` already exists to save you from overwriting existing backups.
+/// If the duplication fails midway the incomplete `dst` is removed.
+pub fn duplicate(src: &Path, dst: &Path) -> io::Result<u64> {
+    tracing::trace!(target: "util::fs", "Duplicate {} to {}", src.display(), dst.display());
+
+    let mut src_file = File::open(src)?;
+    let mut dst_file = File::create_new(dst)?;
+    let mut copy = || {
+        let bytes = match rustix::fs::ioctl_ficlone(&dst_file, &src_file) {
+            Ok(()) => {
+                tracing::debug!(target: "util::fs", "Reflinked {}", dst.display());
+                src_file.metadata()?.len()
+            }
+            Err(e) => {
+                tracing::debug!(target: "util::fs", "Reflinking {} failed: {e}", dst.display());
+                copy_file(&mut src_file, &mut dst_file, dst)?
+            }
+        };
+        dst_file.set_permissions(src_file.metadata()?.permissions())?;
+        dst_file.sync_all()?;
+        Ok(bytes)
+    };
+
+    copy().inspect_err(|_| {
+        let _ = std::fs::remove_file(dst);
+    })
+}
+
+/// Copies `src` to `dst` using `copy_file_range(2)` or, if unsupported, [`io::copy`].
+fn copy_file(src: &mut File, dst: &mut File, path: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    loop {
+        match rustix::fs::copy_file_range(&*src, None, &*dst, None, 1 << 30) {
+            Ok(0) => break,
+            Ok(copied) => bytes += copied as u64,
+            // nothing written yet, so `io::copy` can start over
+            Err(e) if bytes == 0 => {
+                tracing::debug!(target: "util::fs", "copy_file_range to {} failed: {e}", path.display());
+                let bytes = io::copy(src, dst)?;
+                tracing::debug!(target: "util::fs", "Copied {}", path.display());
+                return Ok(bytes);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    tracing::debug!(target: "util::fs", "Copied {} in the kernel", path.display());
+    Ok(bytes)
+}
+
+/// Moves the file at `src` to the *new* file `dst`.
+///
+/// If both paths reside on different file systems the file is
+/// [duplicated](duplicate) and `src` removed afterwards.
+pub fn move_file(src: &Path, dst: &Path) -> io::Result<()> {
+    if std::fs::exists(dst)? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dst.display()),
+        ));
+    }
+
+    match std::fs::rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            duplicate(src, dst)?;
+            std::fs::remove_file(src)
+        }
+        res => res,
+    }
+}
+
+/// Free space kept on top of the estimated size of a backup.
+pub const SPACE_HEADROOM: u64 = 512 * 1024 * 1024;
+
+/// Returns the bytes available to unprivileged users on the file system of `path`.
+///
+/// If `path` doesn't exist yet the closest existing ancestor is queried.
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("/"));
+    let stat = rustix::fs::statvfs(existing)?;
+
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// Ensures `required` bytes plus [SPACE_HEADROOM] are available at `path`.
+///
+/// # Errors
+///
+/// Fails with [io::ErrorKind::StorageFull] if there isn't enough space.
+pub fn ensure_space(path: &Path, required: u64) -> io::Result<()> {
+    let available = available_space(path)?;
+    let required = required.saturating_add(SPACE_HEADROOM);
+    tracing::debug!(
+        target: "util::fs",
+        "{} bytes available at {}, {required} bytes required",
+        available,
+        path.display()
+    );
+
+    if available < required {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "{} requires {} MiB but only {} MiB are available",
+                path.display(),
+                required.div_ceil(1024 * 1024),
+                available / (1024 * 1024)
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Atomically replaces the file at `path` with `contents`.
+///
+/// The contents are written to a temporary file next to `path` which is
+/// renamed to `path` afterwards. Readers thus never observe a partial file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = std::path::PathBuf::from(tmp_path);
+
+    let write = || {
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    };
+
+    write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })
+}
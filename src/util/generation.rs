@@ -0,0 +1,98 @@
+//! Ids tying the backups of a single run together.
+//!
+//! Every backup run is assigned a [BackupGeneration]. It's embedded into the
+//! names of the files, the userdata of the snapshots and the run manifest, so
+//! the backups of the config, the database and the data directory created by
+//! the same run can be found without comparing their timestamps.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+
+use chrono::Local;
+use derive_more::{Display, Error};
+
+use crate::util::clock::Clock;
+
+/// Crockford's base32 alphabet used by ULIDs.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Length of a ULID in characters.
+pub const GENERATION_LEN: usize = 26;
+
+/// Id of the backups created by a run, a [ULID](https://github.com/ulid/spec).
+///
+/// The first 48 bits are the milliseconds since the Unix epoch, so ids sort by
+/// the time of their run, followed by 80 random bits.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct BackupGeneration(u128);
+
+/// The string isn't a valid [BackupGeneration].
+#[derive(Debug, Display, Error)]
+#[display("Invalid generation {_0:?}, expected a ULID like 01JGQ2C4S8ZQK3N7W5R9T0V6XB")]
+pub struct ParseGenerationError(#[error(not(source))] String);
+
+impl BackupGeneration {
+    /// Create a new generation started at the current time of `clock`.
+    pub fn new(clock: Clock) -> Self {
+        let now = clock.now();
+        let millis = now.and_local_timezone(Local).earliest().map_or_else(
+            || now.and_utc().timestamp_millis(),
+            |now| now.timestamp_millis(),
+        );
+        let random = || RandomState::new().build_hasher().finish() as u128;
+        let random = (random() << 64 | random()) & ((1 << 80) - 1);
+        Self((millis.max(0) as u128 & ((1 << 48) - 1)) << 80 | random)
+    }
+
+    /// Milliseconds since the Unix epoch the generation was started at.
+    pub fn timestamp_ms(&self) -> u64 {
+        (self.0 >> 80) as u64
+    }
+}
+
+impl fmt::Display for BackupGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut encoded = [0; GENERATION_LEN];
+        for (i, c) in encoded.iter_mut().enumerate() {
+            let shift = 5 * (GENERATION_LEN - 1 - i);
+            *c = ALPHABET[(self.0 >> shift) as usize & 0x1f];
+        }
+        f.write_str(std::str::from_utf8(&encoded).expect("alphabet should be ASCII"))
+    }
+}
+
+impl FromStr for BackupGeneration {
+    type Err = ParseGenerationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseGenerationError(s.to_string());
+        // the first character only holds the 3 highest bits
+        if s.len() != GENERATION_LEN || !s.starts_with(|c: char| ('0'..='7').contains(&c)) {
+            return Err(invalid());
+        }
+        s.bytes().try_fold(Self(0), |id, c| {
+            let c = c.to_ascii_uppercase();
+            let value = ALPHABET.iter().position(|&a| a == c).ok_or_else(invalid)?;
+            Ok(Self(id.0 << 5 | value as u128))
+        })
+    }
+}
+
+impl TryFrom<String> for BackupGeneration {
+    type Error = ParseGenerationError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BackupGeneration> for String {
+    fn from(generation: BackupGeneration) -> Self {
+        generation.to_string()
+    }
+}
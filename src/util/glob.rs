@@ -0,0 +1,91 @@
+//! Shell-style patterns matching relative paths.
+
+use std::fmt;
+use std::path::Path;
+
+use regex::Regex;
+
+/// Pattern matching relative paths like a shell glob.
+///
+/// `*` matches any characters within a path component, `**` any characters
+/// including `/` and `?` a single character other than `/`. The pattern has to
+/// match the whole path.
+///
+/// # Example
+///
+/// ```
+/// # use std::path::Path;
+/// # use nc_backup_lib::util::glob::Glob;
+/// let glob = Glob::new("*/cache/**").unwrap();
+/// assert!(glob.matches(Path::new("alice/cache/thumbnails/1.png")));
+/// assert!(!glob.matches(Path::new("alice/files/cache")));
+/// ```
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Glob {
+    /// Compile the `pattern`.
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&regex)?,
+        })
+    }
+
+    /// Whether the relative `path` matches the pattern.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.regex.is_match(&path.to_string_lossy())
+    }
+
+    /// The pattern as given.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Glob").field(&self.pattern).finish()
+    }
+}
+
+impl PartialEq for Glob {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl Eq for Glob {}
+
+impl TryFrom<String> for Glob {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Self::new(&pattern)
+    }
+}
+
+impl From<Glob> for String {
+    fn from(glob: Glob) -> Self {
+        glob.pattern
+    }
+}
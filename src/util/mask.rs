@@ -0,0 +1,210 @@
+//! Masking of secrets in Nextcloud's PHP config files.
+//!
+//! Nextcloud writes its config using PHP's `var_export`, one entry per line.
+//! Entries are identified by their key path, the keys of the nested arrays
+//! joined by `.`, e.g. `objectstore.arguments.secret`. The values of entries
+//! whose key path matches a [rule](MaskingConfig::rules) are replaced by their
+//! uppercased key, e.g. `'dbpassword' => 'DBPASSWORD',`.
+
+use regex::Regex;
+
+use crate::util::encrypt::Encryption;
+
+/// Key paths masked by default, they aren't needed to restore a backup.
+pub const DEFAULT_RULES: &[&str] = &[
+    "dbpassword",
+    "mail_smtppassword",
+    r"redis(\.cluster)?\.password",
+    r"objectstore(_multibucket)?\.arguments\.secret",
+];
+
+/// Key paths identifying the instance, masked if [instance_secrets](MaskingConfig::instance_secrets) is set.
+///
+/// Without `secret` server-side encrypted files and stored credentials can't be decrypted.
+pub const INSTANCE_SECRET_RULES: &[&str] = &["secret", "passwordsalt", "instanceid"];
+
+/// Configuration of masking secrets.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MaskingConfig {
+    /// Mask secrets at all.
+    ///
+    /// Disable to keep exact copies, which can be restored as is.
+    pub mask: bool,
+
+    /// Also mask the secrets identifying the instance, see [INSTANCE_SECRET_RULES].
+    pub instance_secrets: bool,
+
+    /// Regular expressions of further key paths to mask, e.g. `mail_smtpname`.
+    ///
+    /// The expressions have to match the whole key path.
+    pub rules: Vec<String>,
+
+    /// Write the masked secrets to a sidecar of every backup encrypted for the recipients.
+    ///
+    /// Allows you to restore the config unmasked, e.g. `[config.secrets]` with
+    /// `tool = "age"` and the `recipients`.
+    pub secrets: Option<Encryption>,
+}
+
+impl Default for MaskingConfig {
+    fn default() -> Self {
+        Self {
+            mask: true,
+            instance_secrets: false,
+            rules: Vec::new(),
+            secrets: None,
+        }
+    }
+}
+
+/// Masks the secrets of PHP config files as configured by [MaskingConfig].
+#[derive(Debug, Clone)]
+pub struct Masker {
+    rules: Vec<Regex>,
+}
+
+/// Entry masked by [Masker::mask].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MaskedEntry {
+    /// Key path of the entry.
+    pub key: String,
+    /// Original value as PHP expression, e.g. `'secret'`.
+    pub value: String,
+}
+
+/// Result of [Masker::mask].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Masked {
+    /// The config with the secrets masked.
+    pub content: String,
+    /// The masked entries along with their original value.
+    pub entries: Vec<MaskedEntry>,
+}
+
+impl Masker {
+    /// Create the masker of `config`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a rule isn't a valid regular expression.
+    pub fn new(config: &MaskingConfig) -> Result<Self, regex::Error> {
+        if !config.mask {
+            return Ok(Self { rules: Vec::new() });
+        }
+
+        let instance_rules = match config.instance_secrets {
+            true => INSTANCE_SECRET_RULES,
+            false => &[],
+        };
+        let rules = DEFAULT_RULES
+            .iter()
+            .chain(instance_rules)
+            .copied()
+            .chain(config.rules.iter().map(String::as_str))
+            .map(|rule| Regex::new(&format!("^(?:{rule})$")))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Whether any secrets are masked.
+    pub fn enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Masks the secrets of the PHP config `content`.
+    pub fn mask(&self, content: &str) -> Masked {
+        let mut entries = Vec::new();
+        let content = rewrite(content, |key_path, key, value| {
+            if !self.rules.iter().any(|rule| rule.is_match(key_path)) {
+                return None;
+            }
+            tracing::trace!(target: "util::mask", "Masked {key_path}");
+            entries.push(MaskedEntry {
+                key: key_path.to_string(),
+                value: value.to_string(),
+            });
+            Some(format!("'{}'", key.to_uppercase()))
+        });
+
+        Masked { content, entries }
+    }
+}
+
+/// Restores the original values of the masked `entries` in the PHP config `content`.
+pub fn unmask(content: &str, entries: &[MaskedEntry]) -> String {
+    rewrite(content, |key_path, _, _| {
+        entries
+            .iter()
+            .find(|entry| entry.key == key_path)
+            .map(|entry| entry.value.clone())
+    })
+}
+
+/// Replaces the values of the entries of the PHP config `content` by the ones returned by `replace`.
+///
+/// `replace` is called with the key path, the key and the value of every entry
+/// not opening an array and returns [None] to keep the value.
+fn rewrite(content: &str, mut replace: impl FnMut(&str, &str, &str) -> Option<String>) -> String {
+    let entry = Regex::new(r#"^(\s*(?:'([^']*)'|"([^"]*)"|(\d+))\s*=>)\s*(.*?)\s*$"#).unwrap();
+
+    let mut rewritten = String::with_capacity(content.len());
+    // keys of the enclosing arrays, the outermost one has none
+    let mut path: Vec<Option<String>> = Vec::new();
+    // key of an entry whose array starts on the next line
+    let mut pending = None;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        let mut output = None;
+        if let Some(captures) = entry.captures(line) {
+            let key = captures
+                .get(2)
+                .or(captures.get(3))
+                .or(captures.get(4))
+                .map_or("", |key| key.as_str());
+            let value = &captures[5];
+            if value.is_empty() {
+                pending = Some(key.to_string());
+            } else if opens_array(value) {
+                path.push(Some(key.to_string()));
+            } else {
+                let (value, comma) = match value.strip_suffix(',') {
+                    Some(value) => (value.trim_end(), ","),
+                    None => (value, ""),
+                };
+                if let Some(replaced) = replace(&key_path(&path, key), key, value) {
+                    output = Some(format!("{} {replaced}{comma}", &captures[1]));
+                }
+            }
+        } else if opens_array(trimmed) {
+            path.push(pending.take());
+        } else if trimmed.starts_with(')') || trimmed.starts_with(']') {
+            path.pop();
+        }
+
+        rewritten.push_str(output.as_deref().unwrap_or(line));
+        rewritten.push('\n');
+    }
+
+    rewritten
+}
+
+/// Whether the (end of a) line `value` opens an array continued on the next lines.
+fn opens_array(value: &str) -> bool {
+    let value = value.trim_end();
+    value.ends_with('[')
+        || value
+            .strip_suffix('(')
+            .is_some_and(|value| value.trim_end().ends_with("array"))
+}
+
+/// Joins the keys of the enclosing arrays `path` and `key` by `.`.
+fn key_path(path: &[Option<String>], key: &str) -> String {
+    path.iter()
+        .flatten()
+        .map(String::as_str)
+        .chain([key])
+        .collect::<Vec<_>>()
+        .join(".")
+}
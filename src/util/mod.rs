@@ -1 +1,19 @@
+pub mod archive;
+pub mod artifact;
+pub mod checksum;
+pub mod chunkstore;
+pub mod clock;
+pub mod command;
+pub mod compress;
+pub mod encrypt;
+pub mod fs;
+pub mod generation;
+pub mod glob;
+pub mod mask;
+pub mod privilege;
+pub mod progress;
 pub mod retention;
+pub mod retry;
+pub mod sign;
+pub mod systemd;
+pub mod tiering;
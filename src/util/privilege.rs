@@ -0,0 +1,95 @@
+//! Running commands requiring root privileges.
+//!
+//! Managing snapshots requires root privileges. If `nc_backup` doesn't run as
+//! root, the commands are run using the configured [Privilege] escalation.
+
+use std::ffi::OsStr;
+use std::io;
+use std::process::{Command, Stdio};
+
+use crate::util::command;
+
+/// How commands requiring root privileges are run.
+///
+/// If the process already runs as root, commands are always run directly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    /// Run commands directly, e.g. because snapper permits the user through `ALLOW_USERS`.
+    #[default]
+    Direct,
+    /// Run commands using `sudo -n`.
+    Sudo,
+    /// Run commands using `doas -n`.
+    Doas,
+    /// Run commands using `pkexec` without an interactive agent.
+    Pkexec,
+}
+
+impl Privilege {
+    /// The escalation command and its flag forbidding interactive prompts.
+    ///
+    /// Returns [None] if the command is run directly.
+    fn escalation(self) -> Option<[&'static str; 2]> {
+        if rustix::process::geteuid().is_root() {
+            return None;
+        }
+
+        match self {
+            Privilege::Direct => None,
+            Privilege::Sudo => Some(["sudo", "-n"]),
+            Privilege::Doas => Some(["doas", "-n"]),
+            Privilege::Pkexec => Some(["pkexec", "--disable-internal-agent"]),
+        }
+    }
+
+    /// Prepare a [Command] running `program` with root privileges.
+    ///
+    /// The escalation command is run with the [priority](command::set_priority) of
+    /// the commands, which `program` inherits.
+    ///
+    /// The escalation commands reset the environment, hence variables have to
+    /// be passed as `envs` instead of being set on the returned [Command].
+    pub fn command(self, program: impl AsRef<OsStr>, envs: &[(&str, &str)]) -> Command {
+        match self.escalation() {
+            Some([escalation, non_interactive]) => {
+                let mut cmd = command::new(escalation);
+                cmd.arg(non_interactive);
+                if !envs.is_empty() {
+                    cmd.arg("env")
+                        .args(envs.iter().map(|(key, value)| format!("{key}={value}")));
+                }
+                cmd.arg(program);
+                cmd
+            }
+            None => {
+                let mut cmd = command::new(program);
+                cmd.envs(envs.iter().copied());
+                cmd
+            }
+        }
+    }
+
+    /// Checks that the escalation works without asking for a password.
+    pub fn check(self) -> io::Result<()> {
+        let Some([escalation, _]) = self.escalation() else {
+            return Ok(());
+        };
+
+        tracing::trace!(target: "util::privilege", "Running: {escalation} true");
+        let status = self
+            .command("true", &[])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{escalation} requires interaction or isn't permitted: {status}"),
+            ));
+        }
+
+        Ok(())
+    }
+}
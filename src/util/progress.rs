@@ -0,0 +1,185 @@
+//! Reporting the progress of long running operations.
+//!
+//! Backends report the phase they're in and the bytes processed to a
+//! [Progress], which the [BackupRunner](crate::runner::BackupRunner) obtains
+//! for every job from a [ProgressReporter].
+
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Interval between the log lines of [LogProgress].
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Receives the progress of a single job.
+pub trait Progress: Send + Sync {
+    /// Start the phase `name` expected to process `total` bytes if known.
+    ///
+    /// The bytes processed are reset to zero.
+    fn phase(&self, name: &str, total: Option<u64>);
+
+    /// Report that `bytes` more bytes were processed in the current phase.
+    fn advance(&self, bytes: u64);
+
+    /// The job finished.
+    fn finish(&self) {}
+}
+
+/// Creates a [Progress] for every job.
+pub trait ProgressReporter: Send + Sync {
+    /// Returns the [Progress] of the job `name`.
+    fn job(&self, name: &str) -> Box<dyn Progress>;
+}
+
+/// Discards all progress.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn phase(&self, _name: &str, _total: Option<u64>) {}
+
+    fn advance(&self, _bytes: u64) {}
+}
+
+impl ProgressReporter for NoProgress {
+    fn job(&self, _name: &str) -> Box<dyn Progress> {
+        Box::new(NoProgress)
+    }
+}
+
+/// Logs the throughput of every job periodically.
+///
+/// Used if no progress bars are rendered, e.g. when running from a timer, so a
+/// long running transfer can be told apart from a hung one in the logs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogProgress;
+
+impl ProgressReporter for LogProgress {
+    fn job(&self, name: &str) -> Box<dyn Progress> {
+        Box::new(JobLog {
+            job: name.to_string(),
+            state: Mutex::new(JobLogState::new(String::new(), None)),
+        })
+    }
+}
+
+/// [Progress] of a single job logged by [LogProgress].
+struct JobLog {
+    job: String,
+    state: Mutex<JobLogState>,
+}
+
+/// Progress of the current phase of a [JobLog].
+struct JobLogState {
+    phase: String,
+    total: Option<u64>,
+    processed: u64,
+    start: Instant,
+    logged: Instant,
+}
+
+impl JobLogState {
+    fn new(phase: String, total: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            phase,
+            total,
+            processed: 0,
+            start: now,
+            logged: now,
+        }
+    }
+
+    /// Bytes per second processed since the start of the phase.
+    fn throughput(&self) -> u64 {
+        let secs = self.start.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            (self.processed as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+}
+
+impl JobLog {
+    fn log(&self, state: &JobLogState) {
+        const MIB: u64 = 1024 * 1024;
+        let throughput = state.throughput();
+        let eta = match state.total {
+            Some(total) if throughput > 0 => {
+                let remaining = total.saturating_sub(state.processed) / throughput;
+                format!(
+                    " of {} MiB, ETA {:02}:{:02}:{:02}",
+                    total / MIB,
+                    remaining / 3600,
+                    remaining / 60 % 60,
+                    remaining % 60
+                )
+            }
+            _ => String::new(),
+        };
+        tracing::info!(
+            target: "progress",
+            "{}: {} {} MiB{eta} ({:.1} MiB/s)",
+            self.job,
+            state.phase,
+            state.processed / MIB,
+            throughput as f64 / MIB as f64,
+        );
+    }
+}
+
+impl Progress for JobLog {
+    fn phase(&self, name: &str, total: Option<u64>) {
+        let mut state = self
+            .state
+            .lock()
+            .expect("job progress should not be poisoned");
+        if state.processed > 0 {
+            self.log(&state);
+        }
+        *state = JobLogState::new(name.to_string(), total);
+    }
+
+    fn advance(&self, bytes: u64) {
+        let mut state = self
+            .state
+            .lock()
+            .expect("job progress should not be poisoned");
+        state.processed += bytes;
+        if state.logged.elapsed() >= LOG_INTERVAL {
+            state.logged = Instant::now();
+            self.log(&state);
+        }
+    }
+
+    fn finish(&self) {
+        let state = self
+            .state
+            .lock()
+            .expect("job progress should not be poisoned");
+        if state.processed > 0 {
+            self.log(&state);
+        }
+    }
+}
+
+/// [Read] adapter reporting the bytes read to a [Progress].
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a dyn Progress,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a dyn Progress) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.advance(read as u64);
+        Ok(read)
+    }
+}
@@ -81,6 +81,13 @@ impl Retention {
 
     /// Returns if the [Datelike] is to be retained.
     pub fn retain(&mut self, date: impl Datelike) -> bool {
+        !self.retained_by(date).is_empty()
+    }
+
+    /// Returns the categories retaining the [Datelike], e.g. `daily` and `monthly`.
+    ///
+    /// The [Datelike] is to be discarded if there are none.
+    pub fn retained_by(&mut self, date: impl Datelike) -> Vec<&'static str> {
         let Self {
             config,
             daily,
@@ -130,6 +137,15 @@ impl Retention {
                 yearly.insert(yearly_key)
             };
 
-        new_daily || new_weekly || new_monthly || new_quarterly || new_yearly
+        [
+            ("daily", new_daily),
+            ("weekly", new_weekly),
+            ("monthly", new_monthly),
+            ("quarterly", new_quarterly),
+            ("yearly", new_yearly),
+        ]
+        .into_iter()
+        .filter_map(|(category, retained)| retained.then_some(category))
+        .collect()
     }
 }
@@ -0,0 +1,155 @@
+//! Retrying of operations failing due to transient errors.
+//!
+//! Backup destinations on flaky USB disks or network shares occasionally fail
+//! with errors that vanish after a short moment. Instead of failing the whole
+//! backend such operations are retried according to a [RetryConfig].
+//!
+//! Other classes of operations, like `occ` commands or syncs of remote storage,
+//! are retried according to their own [policy](RetryConfig::policy).
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// Raw OS error code of `EIO` on Linux.
+const EIO: i32 = 5;
+
+/// Configure retrying of operations failing due to transient errors.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// How often an operation is attempted in total.
+    ///
+    /// A value of `1` disables retrying.
+    pub attempts: u32,
+
+    /// Seconds to wait before the first retry.
+    ///
+    /// The delay is doubled on every further retry.
+    pub backoff_secs: u64,
+
+    /// Command run by `sh -c` prior to every retry.
+    ///
+    /// This allows you to e.g. remount a network share which went stale.
+    pub remount_command: Option<String>,
+
+    /// Policies overriding `attempts` and `backoff_secs` per class of operation.
+    ///
+    /// Classes are `occ` for `occ` commands and `sync` for syncs of remote storage.
+    pub operations: BTreeMap<String, RetryPolicy>,
+}
+
+/// Overrides of a [RetryConfig] for a class of operations.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// How often an operation is attempted in total.
+    pub attempts: Option<u32>,
+
+    /// Seconds to wait before the first retry.
+    pub backoff_secs: Option<u64>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_secs: 5,
+            remount_command: None,
+            operations: BTreeMap::new(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The [RetryConfig] of the class of operations `operation`, e.g. `occ`.
+    ///
+    /// The `remount_command` only applies to file operations and is dropped.
+    pub fn policy(&self, operation: &str) -> RetryConfig {
+        let policy = self.operations.get(operation).cloned().unwrap_or_default();
+        RetryConfig {
+            attempts: policy.attempts.unwrap_or(self.attempts),
+            backoff_secs: policy.backoff_secs.unwrap_or(self.backoff_secs),
+            remount_command: None,
+            operations: BTreeMap::new(),
+        }
+    }
+}
+
+/// Returns whether the [io::Error] is likely transient and worth retrying.
+///
+/// Besides network related errors this covers `EIO` as reported by flaky USB
+/// devices and `ESTALE` as reported by NFS.
+pub fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    matches!(
+        e.kind(),
+        Interrupted
+            | WouldBlock
+            | TimedOut
+            | ResourceBusy
+            | StaleNetworkFileHandle
+            | ConnectionReset
+            | ConnectionAborted
+            | NetworkDown
+            | NetworkUnreachable
+            | HostUnreachable
+    ) || e.raw_os_error() == Some(EIO)
+}
+
+/// Run `f` and retry it on transient errors as configured by the [RetryConfig].
+///
+/// Whether an error is transient is decided by `is_transient`.
+/// The error of the last attempt is returned if all attempts failed.
+pub fn retry<T, E: Display>(
+    config: &RetryConfig,
+    operation: &str,
+    is_transient: impl Fn(&E) -> bool,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = Duration::from_secs(config.backoff_secs);
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e) if attempt < config.attempts && is_transient(&e) => {
+                tracing::warn!(
+                    target: "util::retry",
+                    "{operation} failed (attempt {attempt}/{}), retrying in {backoff:?}: {e}",
+                    config.attempts
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+
+                if let Some(remount_command) = &config.remount_command {
+                    remount(remount_command);
+                }
+            }
+            res => return res,
+        }
+    }
+}
+
+/// [Retry](retry) `f` on [transient](is_transient) [io::Error]s.
+pub fn retry_io<T>(
+    config: &RetryConfig,
+    operation: &str,
+    f: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    retry(config, operation, is_transient, f)
+}
+
+fn remount(remount_command: &str) {
+    tracing::debug!(target: "util::retry", "Running: sh -c {remount_command:?}");
+    match Command::new("sh").arg("-c").arg(remount_command).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            tracing::warn!(target: "util::retry", "Remount command failed with {status}")
+        }
+        Err(e) => tracing::warn!(target: "util::retry", "Remount command couldn't be run: {e}"),
+    }
+}
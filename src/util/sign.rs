@@ -0,0 +1,197 @@
+//! Detached signatures of files using `minisign` or `gpg`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::util::command;
+
+/// Signing of files by a key, see [Signing::sign].
+///
+/// ```toml
+/// tool = "minisign"
+/// secret_key = "/etc/nc_backup/minisign.key"
+/// public_key = "/etc/nc_backup/minisign.pub"
+/// ```
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "tool", rename_all = "lowercase")]
+pub enum Signing {
+    /// Sign using `minisign`.
+    ///
+    /// The secret key has to be created without password, i.e. `minisign -G -W`.
+    Minisign {
+        /// Secret key signing the files.
+        secret_key: PathBuf,
+        /// Public key verifying the signatures.
+        public_key: PathBuf,
+    },
+    /// Sign using `gpg`.
+    ///
+    /// The key has to be usable without passphrase, signatures are verified
+    /// against the keyring.
+    Gpg {
+        /// Key ID or fingerprint of the signing key.
+        key: String,
+    },
+}
+
+impl Signing {
+    /// Name of the tool.
+    pub fn tool(&self) -> &'static str {
+        match self {
+            Self::Minisign { .. } => "minisign",
+            Self::Gpg { .. } => "gpg",
+        }
+    }
+
+    /// Path of the detached signature of `file`.
+    pub fn signature_path(&self, file: &Path) -> PathBuf {
+        let extension = match self {
+            Self::Minisign { .. } => ".minisig",
+            Self::Gpg { .. } => ".sig",
+        };
+        let mut signature_path = file.as_os_str().to_owned();
+        signature_path.push(extension);
+        signature_path.into()
+    }
+
+    /// Checks the tool is installed and the keys exist.
+    pub fn check(&self) -> io::Result<()> {
+        if let Self::Minisign {
+            secret_key,
+            public_key,
+        } = self
+        {
+            for key in [secret_key, public_key] {
+                if !key.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("minisign key {} doesn't exist", key.display()),
+                    ));
+                }
+            }
+        }
+
+        let version_arg = match self {
+            Self::Minisign { .. } => "-v",
+            Self::Gpg { .. } => "--version",
+        };
+        self.run(Command::new(self.tool()).arg(version_arg))
+            .map(drop)
+    }
+
+    /// Writes the detached [signature](Self::signature_path) of `file`.
+    ///
+    /// Returns the path of the signature.
+    pub fn sign(&self, file: &Path) -> io::Result<PathBuf> {
+        let signature = self.signature_path(file);
+        let mut sign = Command::new(self.tool());
+        match self {
+            Self::Minisign { secret_key, .. } => {
+                sign.arg("-S")
+                    .arg("-s")
+                    .arg(secret_key)
+                    .arg("-m")
+                    .arg(file)
+                    .arg("-x")
+                    .arg(&signature);
+            }
+            Self::Gpg { key } => {
+                sign.args(["--batch", "--yes", "--local-user", key, "--detach-sign"])
+                    .arg("--output")
+                    .arg(&signature)
+                    .arg(file);
+            }
+        }
+        self.run(&mut sign)?;
+
+        Ok(signature)
+    }
+
+    /// Verifies the detached [signature](Self::signature_path) of `file`.
+    ///
+    /// `gpg` signatures have to be made by the configured [key](Self::Gpg),
+    /// not just by any key of the keyring.
+    pub fn verify(&self, file: &Path) -> io::Result<()> {
+        let signature = self.signature_path(file);
+        let mut verify = Command::new(self.tool());
+        match self {
+            Self::Minisign { public_key, .. } => {
+                verify
+                    .args(["-V", "-q", "-p"])
+                    .arg(public_key)
+                    .arg("-m")
+                    .arg(file)
+                    .arg("-x")
+                    .arg(&signature);
+            }
+            Self::Gpg { .. } => {
+                verify
+                    .args(["--batch", "--status-fd", "1", "--verify"])
+                    .arg(&signature)
+                    .arg(file);
+            }
+        }
+        let status = self.run(&mut verify)?;
+
+        if let Self::Gpg { key } = self {
+            let fingerprints = self.fingerprints(key)?;
+            let status = String::from_utf8_lossy(&status);
+            if !valid_signers(&status).any(|signer| fingerprints.iter().any(|f| f == signer)) {
+                return Err(io::Error::other(format!(
+                    "signature {} isn't made by key {key}",
+                    signature.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fingerprints of the gpg `key` and its subkeys.
+    fn fingerprints(&self, key: &str) -> io::Result<Vec<String>> {
+        let listing = self.run(
+            Command::new(self.tool())
+                .args(["--batch", "--with-colons", "--with-subkey-fingerprints"])
+                .args(["--list-keys", key]),
+        )?;
+        Ok(String::from_utf8_lossy(&listing)
+            .lines()
+            .filter(|line| line.starts_with("fpr:"))
+            .filter_map(|line| line.split(':').nth(9))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Runs the tool by `command` failing with its stderr.
+    ///
+    /// Returns the stdout of the tool.
+    fn run(&self, command: &mut Command) -> io::Result<Vec<u8>> {
+        tracing::trace!(target: "util::sign", "Running: {command:?}");
+        let output = command::output(command, self.tool())?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} failed with {}: {}",
+                self.tool(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+/// Fingerprints of the signing keys and their primary keys of the good
+/// signatures reported by the `gpg --status-fd` output `status`.
+fn valid_signers(status: &str) -> impl Iterator<Item = &str> {
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|fields| {
+            let fields: Vec<_> = fields.split_whitespace().collect();
+            // the signing key comes first, its primary key last
+            [fields.first().copied(), fields.get(9).copied()]
+        })
+        .flatten()
+}
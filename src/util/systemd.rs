@@ -0,0 +1,163 @@
+//! Integration with systemd.
+//!
+//! If run by systemd, progress is reported using the
+//! [`sd_notify(3)`](https://man.archlinux.org/man/sd_notify.3) protocol.
+//! All notifications are no-ops otherwise.
+
+use std::env;
+use std::fmt::Write as _;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::util::fs::write_atomic;
+
+/// Name of the service unit written by [install_units].
+pub const SERVICE_UNIT: &str = "nc_backup.service";
+/// Name of the timer unit written by [install_units].
+pub const TIMER_UNIT: &str = "nc_backup.timer";
+
+/// Send the newline separated `state` assignments to the service manager.
+///
+/// Does nothing if not run by systemd. Errors are logged and otherwise ignored
+/// as notifications are purely informational.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let socket = socket.to_string_lossy();
+
+    let send = || -> io::Result<()> {
+        let addr = match socket.strip_prefix('@') {
+            Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name)?,
+            None => SocketAddr::from_pathname(socket.as_ref())?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    };
+
+    if let Err(e) = send() {
+        tracing::debug!(target: "util::systemd", "Notifying systemd failed: {e}");
+    }
+}
+
+/// Notify the service manager that startup finished.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Show `status` in `systemctl status`.
+pub fn status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+/// Notify the service manager that the process is shutting down.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Keep the watchdog of the service manager fed from a background thread.
+///
+/// Only has an effect if `WatchdogSec=` is set for the service.
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tracing::debug!(target: "util::systemd", "Feeding the watchdog every {interval:?}");
+
+    thread::spawn(move || loop {
+        notify("WATCHDOG=1");
+        thread::sleep(interval);
+    });
+}
+
+/// Half of the watchdog timeout requested by the service manager.
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+/// Whether stderr is connected to the journal.
+pub fn is_journald() -> bool {
+    env::var_os("JOURNAL_STREAM").is_some()
+}
+
+/// Write service and timer units backing up daily at `on_calendar` to `dir`.
+///
+/// The service runs `args` with the current executable followed by
+/// `backup` and `retain`.
+pub fn install_units(dir: &Path, args: &[String], on_calendar: &str) -> io::Result<()> {
+    let exe = env::current_exe()?;
+    let args: String = args.iter().fold(String::new(), |mut out, arg| {
+        let _ = write!(out, " {}", quote(arg));
+        out
+    });
+    let exec = format!("{}{args}", quote(&exe.to_string_lossy()));
+
+    let service = format!(
+        "[Unit]
+Description=Nextcloud Backup and Retention
+
+[Service]
+Type=oneshot
+NotifyAccess=main
+ExecStart={exec} backup
+ExecStart={exec} retain
+
+IOSchedulingClass=idle
+CPUSchedulingPolicy=idle
+Nice=10
+
+ProtectSystem=full
+PrivateTmp=true
+NoNewPrivileges=true
+"
+    );
+    let timer = format!(
+        "[Unit]
+Description=Daily Nextcloud Backup and Retention
+
+[Timer]
+OnCalendar={on_calendar}
+Persistent=true
+RandomizedDelaySec=30m
+
+[Install]
+WantedBy=timers.target
+"
+    );
+
+    std::fs::create_dir_all(dir)?;
+    for (unit, contents) in [(SERVICE_UNIT, service), (TIMER_UNIT, timer)] {
+        let path = dir.join(unit);
+        tracing::info!(target: "util::systemd", "Writing {}", path.display());
+        write_atomic(&path, contents.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Quote `arg` for the command line of a systemd unit.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@".contains(c))
+    {
+        return arg.to_string();
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
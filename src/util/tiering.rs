@@ -0,0 +1,64 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Configure moving of old artifacts to cheaper storage.
+///
+/// The cold `destination` mirrors the layout of the backup root. Moved artifacts
+/// are still subject to retention. The backups of an instance or [layout](crate::runner::BackupLayout)
+/// root are moved to its [nested](Self::nested) cold storage.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct TieringConfig {
+    /// Age in days after which an artifact is moved to the `destination`.
+    pub after_days: u32,
+
+    /// Root folder of the cold storage.
+    pub destination: PathBuf,
+}
+
+impl TieringConfig {
+    /// Cold storage of the backups in `instance_root`, an instance or layout root.
+    ///
+    /// The `instance_root` is mirrored below the `destination`: relative to the common
+    /// `backup_root` if it's located in there, otherwise by its absolute path.
+    pub fn nested(&self, backup_root: &Path, instance_root: &Path) -> Self {
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.into());
+        let instance_root = absolute(instance_root);
+        let relative = match instance_root.strip_prefix(absolute(backup_root)) {
+            Ok(relative) => relative,
+            Err(_) => instance_root.strip_prefix("/").unwrap_or(&instance_root),
+        };
+        Self {
+            after_days: self.after_days,
+            destination: self.destination.join(relative),
+        }
+    }
+
+    /// Path `file` of the `backup_root` is moved to in the cold storage.
+    ///
+    /// The `backup_root` is the one of the backends, e.g. the root of an instance,
+    /// whose cold storage this is [nested](Self::nested) for.
+    ///
+    /// Returns [None] if `file` isn't located in the `backup_root`.
+    pub fn cold_path(&self, backup_root: &Path, file: &Path) -> Option<PathBuf> {
+        let backup_root = std::path::absolute(backup_root).ok()?;
+        let relative = file.strip_prefix(backup_root).ok()?;
+        Some(self.destination.join(relative))
+    }
+
+    /// Locate `file` of the `backup_root`, e.g. as listed by a run manifest.
+    ///
+    /// Returns the [cold path](Self::cold_path) if `file` was moved there,
+    /// otherwise `file` unchanged.
+    pub fn locate(&self, backup_root: &Path, file: &Path) -> PathBuf {
+        if fs::symlink_metadata(file).is_ok() {
+            return file.to_path_buf();
+        }
+        match self.cold_path(backup_root, file) {
+            Some(cold_path) if fs::symlink_metadata(&cold_path).is_ok() => {
+                tracing::debug!(target: "util::tiering", "{} was moved to the cold tier: {}", file.display(), cold_path.display());
+                cold_path
+            }
+            _ => file.to_path_buf(),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::fs::{self, File};
+use std::sync::Arc;
+
+use common::Installation;
+use flate2::read::MultiGzDecoder;
+use nc_backup_lib::backends::{Appdata, AppdataConfig, AppdataMode, Artifact, Backup};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::progress::NoProgress;
+
+#[test]
+fn appdata_is_archived_without_previews() {
+    let installation = Installation::new("appdata-previews");
+    let data_dir = installation.root.join("data");
+    for file in [
+        "appdata_oc123/theming/logo",
+        "appdata_oc123/preview/1/64-64.png",
+    ] {
+        let path = data_dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(&["config:system:get", "instanceid"], "\"oc123\"");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let appdata = Appdata::new(&installation.backup_root());
+    let artifacts = appdata.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(tarball)] = artifacts.as_slice() else {
+        panic!("appdata backup should be a single file: {artifacts:?}");
+    };
+
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(File::open(tarball).unwrap()));
+    let files: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|entry| entry.path().unwrap().into_owned())
+        .collect();
+    let logo = data_dir.join("appdata_oc123/theming/logo");
+    assert_eq!(files, [logo.strip_prefix("/").unwrap()]);
+}
+
+#[test]
+fn skipped_appdata_isnt_archived() {
+    let installation = Installation::new("appdata-skip");
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+
+    let appdata = Appdata::new(&installation.backup_root()).config(AppdataConfig {
+        mode: AppdataMode::Skip,
+        ..Default::default()
+    });
+    assert!(appdata
+        .backup(&nextcloud, false, &NoProgress)
+        .unwrap()
+        .is_empty());
+    assert!(runner.calls().is_empty());
+}
+
+#[test]
+fn previews_are_regenerated_after_restore() {
+    let installation = Installation::new("appdata-restore");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["maintenance:mode"],
+            "Maintenance mode is currently disabled",
+        )
+        .expect(&["maintenance:data-fingerprint"], "")
+        .expect(&["maintenance:repair"], "")
+        .expect(&["files:scan", "--all"], "")
+        .expect(
+            &["app:list"],
+            r#"{"enabled":{"previewgenerator":"5.7.0"},"disabled":[]}"#,
+        )
+        .expect(&["preview:generate-all"], "");
+    let nextcloud = installation.nextcloud(&runner);
+
+    nextcloud.after_restore(false, true).unwrap();
+    assert!(runner.finished());
+}
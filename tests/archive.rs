@@ -0,0 +1,72 @@
+mod common;
+
+use std::io;
+
+use common::Installation;
+use nc_backup_lib::util::archive::unpack_tree;
+
+/// Builds a tarball of the directories (ending with `/`) and files `entries` as named.
+fn tarball(entries: &[&str]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for name in entries {
+        let mut header = tar::Header::new_gnu();
+        // set the name verbatim, `set_path` rejects `..`
+        let bytes = name.as_bytes();
+        header.as_gnu_mut().unwrap().name[..bytes.len()].copy_from_slice(bytes);
+        match name.ends_with('/') {
+            true => header.set_entry_type(tar::EntryType::Directory),
+            false => header.set_entry_type(tar::EntryType::Regular),
+        }
+        header.set_mode(0o755);
+        header.set_size(0);
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn unpack(installation: &Installation, entries: &[&str]) -> io::Result<Option<std::path::PathBuf>> {
+    let tarball = tarball(entries);
+    unpack_tree(
+        &mut tar::Archive::new(tarball.as_slice()),
+        &installation.root.join("restored"),
+    )
+}
+
+#[test]
+fn tree_is_unpacked_relative_to_first_entry() {
+    let installation = Installation::new("archive_unpack");
+    let root = unpack(
+        &installation,
+        &["srv/data/", "srv/data/admin/", "srv/data/admin/a"],
+    );
+
+    assert_eq!(root.unwrap(), Some("/srv/data".into()));
+    assert!(installation.root.join("restored/admin/a").is_file());
+}
+
+#[test]
+fn first_entry_must_be_data_dir() {
+    let installation = Installation::new("archive_first_entry");
+    let error = unpack(&installation, &["srv/data/a", "srv/data/b"]).unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(!installation.root.join("restored").exists());
+}
+
+#[test]
+fn entry_outside_of_data_dir_is_rejected() {
+    let installation = Installation::new("archive_outside");
+    let error = unpack(&installation, &["srv/data/", "srv/other/a"]).unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn parent_dir_entry_is_rejected() {
+    let installation = Installation::new("archive_parent_dir");
+    let error = unpack(&installation, &["srv/data/", "srv/data/../../escaped"]).unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(!installation.root.join("escaped").exists());
+}
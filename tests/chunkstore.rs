@@ -0,0 +1,155 @@
+mod common;
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use common::Installation;
+use nc_backup_lib::util::chunkstore::{ChunkStore, Manifest};
+
+/// Pseudo random data, so the chunks are cut by content and don't repeat.
+fn data(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+/// All chunk files of `store`.
+fn chunk_files(store: &ChunkStore) -> Vec<PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(store.dir())
+        .unwrap()
+        .flat_map(|prefix| fs::read_dir(prefix.unwrap().path()).unwrap())
+        .map(|chunk| chunk.unwrap().path())
+        .collect();
+    files.sort();
+    files
+}
+
+/// Date back the modification time of every chunk by two days.
+fn age(store: &ChunkStore) {
+    let past = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+    for chunk in chunk_files(store) {
+        File::options()
+            .write(true)
+            .open(chunk)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+    }
+}
+
+fn write_manifest(store: &ChunkStore, data: &[u8], path: &Path) -> Manifest {
+    let manifest = store.write(data).unwrap();
+    manifest.write(path).unwrap();
+    manifest
+}
+
+#[test]
+fn stream_is_read_back_from_manifest() {
+    let installation = Installation::new("chunkstore-roundtrip");
+    let dump = installation.root.join("database.sql.chunks");
+    let store = ChunkStore::beside(&dump);
+    let original = data(1024 * 1024, 1);
+
+    let manifest = write_manifest(&store, &original, &dump);
+    assert_eq!(manifest.size, original.len() as u64);
+    assert!(manifest.chunks.len() > 1);
+
+    let read = Manifest::read(&dump).unwrap();
+    assert_eq!(read, manifest);
+    let mut restored = Vec::new();
+    store.reader(read).read_to_end(&mut restored).unwrap();
+    assert_eq!(restored, original);
+}
+
+#[test]
+fn unchanged_chunks_are_stored_once() {
+    let installation = Installation::new("chunkstore-dedup");
+    let store = ChunkStore::new(installation.root.join("chunks"));
+    let original = data(1024 * 1024, 2);
+
+    let first = store.write(original.as_slice()).unwrap();
+    let chunks = chunk_files(&store).len();
+    // inserting data at the start only changes the first chunks
+    let mut changed = b"INSERT INTO oc_test VALUES (1);\n".to_vec();
+    changed.extend(&original);
+    let second = store.write(changed.as_slice()).unwrap();
+
+    assert_ne!(first.hex_digest, second.hex_digest);
+    let shared = second
+        .chunks
+        .iter()
+        .filter(|chunk| first.chunks.contains(chunk))
+        .count();
+    assert!(shared >= first.chunks.len() - 2);
+    assert_eq!(
+        chunk_files(&store).len(),
+        chunks + second.chunks.len() - shared
+    );
+}
+
+#[test]
+fn altered_chunk_fails_reading() {
+    let installation = Installation::new("chunkstore-altered");
+    let dump = installation.root.join("database.sql.chunks");
+    let store = ChunkStore::beside(&dump);
+    let manifest = write_manifest(&store, &data(64 * 1024, 3), &dump);
+
+    let chunk = &chunk_files(&store)[0];
+    let other = ChunkStore::new(installation.root.join("other"));
+    other.write(&b"altered"[..]).unwrap();
+    fs::copy(&chunk_files(&other)[0], chunk).unwrap();
+
+    assert!(store.reader(manifest).read_to_end(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn gc_removes_old_unreferenced_chunks() {
+    let installation = Installation::new("chunkstore-gc");
+    let kept = installation.root.join("kept.sql.chunks");
+    let removed = installation.root.join("removed.sql.chunks");
+    let store = ChunkStore::beside(&kept);
+    let kept_manifest = write_manifest(&store, &data(256 * 1024, 4), &kept);
+    write_manifest(&store, &data(256 * 1024, 5), &removed);
+    age(&store);
+    fs::remove_file(&removed).unwrap();
+
+    let dry_run = store.gc([kept.as_path()], true).unwrap();
+    assert!(dry_run > 0);
+    let count = chunk_files(&store).len();
+    assert_eq!(store.gc([kept.as_path()], false).unwrap(), dry_run);
+
+    assert_eq!(chunk_files(&store).len(), count - dry_run);
+    let mut restored = Vec::new();
+    store
+        .reader(kept_manifest)
+        .read_to_end(&mut restored)
+        .unwrap();
+    assert_eq!(restored, data(256 * 1024, 4));
+}
+
+#[test]
+fn gc_keeps_chunks_of_stream_being_written() {
+    let installation = Installation::new("chunkstore-gc-recent");
+    let store = ChunkStore::new(installation.root.join("chunks"));
+    let old = data(256 * 1024, 6);
+    store.write(old.as_slice()).unwrap();
+    age(&store);
+    let chunks = chunk_files(&store);
+    let partial = chunks[0].with_extension("partial");
+    fs::write(&partial, "").unwrap();
+    age(&store);
+
+    // a dump in progress stores the same chunks again, its manifest isn't written yet
+    store.write(old.as_slice()).unwrap();
+    assert_eq!(store.gc([], false).unwrap(), 0);
+    assert!(partial.is_file());
+    assert_eq!(chunk_files(&store).len(), chunks.len() + 1);
+}
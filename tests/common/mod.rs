@@ -0,0 +1,99 @@
+//! Fixtures shared by the tests running against a [ScriptedRunner].
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use nc_backup_lib::nextcloud::{Nextcloud, OccBuilder};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::retry::RetryConfig;
+
+/// Output of `occ status --output=json` of a healthy installation.
+pub const STATUS: &str = r#"{"installed":true,"version":"30.0.4.1","versionstring":"30.0.4","edition":"","maintenance":false,"needsDbUpgrade":false}"#;
+
+/// Temporary directory looking like a Nextcloud installation, removed on drop.
+pub struct Installation {
+    pub root: PathBuf,
+}
+
+impl Installation {
+    /// Create the installation in a directory unique to the test `name`.
+    pub fn new(name: &str) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("nc_backup-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("config")).unwrap();
+        fs::write(root.join("occ"), "<?php\n").unwrap();
+        fs::write(
+            root.join("config/config.php"),
+            "<?php\n$CONFIG = array (\n  'dbpassword' => 'secret',\n);\n",
+        )
+        .unwrap();
+
+        Self { root }
+    }
+
+    /// The [Nextcloud] of the installation running `occ` by `runner`.
+    ///
+    /// Transient failures are retried once without delay.
+    pub fn nextcloud(&self, runner: &Arc<ScriptedRunner>) -> Nextcloud {
+        let retry = RetryConfig {
+            attempts: 2,
+            backoff_secs: 0,
+            ..Default::default()
+        };
+        Nextcloud::builder()
+            .installation_root(self.root.clone())
+            .occ(OccBuilder::default().retry(retry).runner(runner.clone()))
+            .build()
+            .unwrap()
+    }
+
+    /// Directory the backups are written to.
+    pub fn backup_root(&self) -> PathBuf {
+        self.root.join("backups")
+    }
+}
+
+impl Drop for Installation {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Script enabling the maintenance mode on `runner`.
+pub fn expect_maintenance_on(runner: &ScriptedRunner) {
+    runner
+        .expect(&["maintenance:mode", "--on"], "Maintenance mode enabled")
+        .expect(
+            &["maintenance:mode"],
+            "Maintenance mode is currently enabled",
+        );
+}
+
+/// Script disabling the maintenance mode on `runner`.
+pub fn expect_maintenance_off(runner: &ScriptedRunner) {
+    runner
+        .expect(&["maintenance:mode", "--off"], "Maintenance mode disabled")
+        .expect(
+            &["maintenance:mode"],
+            "Maintenance mode is currently disabled",
+        );
+}
+
+/// Whether the command line `call` contains all `args`.
+pub fn called_with(call: &[String], args: &[&str]) -> bool {
+    args.iter().all(|arg| call.iter().any(|a| a == arg))
+}
+
+/// The time of the nightly backup on `day` of January 2025.
+pub fn day(day: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2025, 1, day)
+        .unwrap()
+        .and_hms_opt(2, 30, 0)
+        .unwrap()
+}
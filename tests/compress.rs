@@ -0,0 +1,73 @@
+use std::io::{Read, Write};
+
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use nc_backup_lib::util::compress::CompressionConfig;
+
+/// Compress `data` as configured by `threads`.
+fn compress(data: &[u8], threads: usize) -> Vec<u8> {
+    let config = CompressionConfig {
+        threads,
+        ..Default::default()
+    };
+    let mut encoder = config.encoder(Vec::new());
+    // write in pieces not aligned to the blocks
+    for piece in data.chunks(100_000) {
+        encoder.write_all(piece).unwrap();
+    }
+    encoder.finish().unwrap()
+}
+
+fn decompress(compressed: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    MultiGzDecoder::new(compressed)
+        .read_to_end(&mut data)
+        .unwrap();
+    data
+}
+
+/// Data spanning several blocks, which still compresses well.
+fn data() -> Vec<u8> {
+    (0..5_000_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn parallel_gzip_roundtrips() {
+    let data = data();
+    let compressed = compress(&data, 4);
+
+    assert!(compressed.len() < data.len());
+    assert_eq!(decompress(&compressed), data);
+}
+
+#[test]
+fn parallel_gzip_writes_a_member_per_block() {
+    let data = data();
+    let compressed = compress(&data, 4);
+
+    // a single member decoder stops after the first block
+    let mut first = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut first)
+        .unwrap();
+    assert!(first.len() < data.len());
+}
+
+#[test]
+fn single_thread_writes_a_single_member() {
+    let data = data();
+    let compressed = compress(&data, 1);
+
+    let mut single = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut single)
+        .unwrap();
+    assert_eq!(single, data);
+}
+
+#[test]
+fn empty_input_is_valid_gzip() {
+    let compressed = compress(&[], 4);
+
+    assert!(!compressed.is_empty());
+    assert!(decompress(&compressed).is_empty());
+}
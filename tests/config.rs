@@ -0,0 +1,245 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use common::Installation;
+use flate2::read::MultiGzDecoder;
+use nc_backup_lib::backends::{Artifact, Backup, Config};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::encrypt::Encryption;
+use nc_backup_lib::util::mask::{self, Masker, MaskingConfig};
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+/// Names and contents of the files in the tarball `path`.
+fn read_tarball(path: &Path) -> BTreeMap<String, String> {
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(File::open(path).unwrap()));
+    let mut files = BTreeMap::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path().unwrap().to_string_lossy().into_owned();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        files.insert(name, content);
+    }
+    files
+}
+
+#[test]
+fn config_directory_is_archived_masked() {
+    let installation = Installation::new("config-split");
+    let config_dir = installation.root.join("config");
+    fs::write(
+        config_dir.join("redis.config.php"),
+        "<?php\n$CONFIG = array (\n  'redis' => array ('host' => 'localhost'),\n);\n",
+    )
+    .unwrap();
+    fs::write(config_dir.join("CAN_INSTALL"), "").unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+
+    let config = Config::new(&installation.backup_root());
+    let artifacts = config.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(tarball)] = artifacts.as_slice() else {
+        panic!("config backup should be a single file: {artifacts:?}");
+    };
+
+    let files = read_tarball(tarball);
+    let name = |file: &str| {
+        config_dir
+            .join(file)
+            .strip_prefix("/")
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    };
+    assert_eq!(files.len(), 2);
+    assert!(files[&name("config.php")].contains("'dbpassword' => 'DBPASSWORD',"));
+    assert!(!files[&name("config.php")].contains("secret"));
+    assert!(files[&name("redis.config.php")].contains("'host' => 'localhost'"));
+}
+
+/// `config.php` as written by `var_export`.
+const CONFIG: &str = r#"<?php
+$CONFIG = array (
+  'instanceid' => 'oc1234',
+  'secret' => 'instance-secret',
+  'dbpassword' => 'db-secret',
+  'mail_smtpname' => 'mailer',
+  'redis' => 
+  array (
+    'host' => 'localhost',
+    'password' => 'redis-secret',
+  ),
+  'objectstore' => 
+  array (
+    'class' => '\\OC\\Files\\ObjectStore\\S3',
+    'arguments' => 
+    array (
+      'key' => 'access-key',
+      'secret' => 's3-secret',
+    ),
+  ),
+);
+"#;
+
+#[test]
+fn default_rules_keep_instance_secret() {
+    let masker = Masker::new(&MaskingConfig::default()).unwrap();
+
+    let masked = masker.mask(CONFIG);
+    let keys: Vec<_> = masked
+        .entries
+        .iter()
+        .map(|entry| entry.key.as_str())
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            "dbpassword",
+            "redis.password",
+            "objectstore.arguments.secret"
+        ]
+    );
+    assert!(masked.content.contains("  'dbpassword' => 'DBPASSWORD',\n"));
+    assert!(masked.content.contains("    'password' => 'PASSWORD',\n"));
+    assert!(masked.content.contains("'instance-secret'"));
+    assert!(!masked.content.contains("redis-secret"));
+    assert!(!masked.content.contains("s3-secret"));
+}
+
+#[test]
+fn configured_rules_are_masked() {
+    let masker = Masker::new(&MaskingConfig {
+        instance_secrets: true,
+        rules: vec![
+            "mail_smtpname".into(),
+            r"objectstore\.arguments\.key".into(),
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+
+    let masked = masker.mask(CONFIG);
+    for key in [
+        "instanceid",
+        "secret",
+        "mail_smtpname",
+        "objectstore.arguments.key",
+    ] {
+        assert!(masked.entries.iter().any(|entry| entry.key == key), "{key}");
+    }
+    assert!(!masked.content.contains("instance-secret"));
+    assert!(!masked.content.contains("access-key"));
+}
+
+#[test]
+fn invalid_rule_is_rejected() {
+    let config = MaskingConfig {
+        rules: vec!["(".into()],
+        ..Default::default()
+    };
+    assert!(Masker::new(&config).is_err());
+}
+
+#[test]
+fn disabled_masking_keeps_exact_copy() {
+    let installation = Installation::new("config-unmasked");
+    let config_php = installation.root.join("config/config.php");
+    fs::write(&config_php, CONFIG).unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+
+    let masker = Masker::new(&MaskingConfig {
+        mask: false,
+        ..Default::default()
+    })
+    .unwrap();
+    let config = Config::new(&installation.backup_root()).masking(masker);
+    let artifacts = config.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(tarball)] = artifacts.as_slice() else {
+        panic!("config backup should be a single file: {artifacts:?}");
+    };
+
+    let files = read_tarball(tarball);
+    let name = config_php.strip_prefix("/").unwrap().to_string_lossy();
+    assert_eq!(files[name.as_ref()], CONFIG);
+}
+
+#[test]
+fn masked_entries_restore_config() {
+    let masker = Masker::new(&MaskingConfig {
+        instance_secrets: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let masked = masker.mask(CONFIG);
+    assert!(masked
+        .entries
+        .iter()
+        .any(|entry| entry.key == "dbpassword" && entry.value == "'db-secret'"));
+    assert_eq!(mask::unmask(&masked.content, &masked.entries), CONFIG);
+}
+
+#[test]
+fn secrets_without_recipients_fail_preflight() {
+    let installation = Installation::new("config-no-recipients");
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+
+    let config = Config::new(&installation.backup_root()).secrets(Encryption::Age {
+        recipients: Vec::new(),
+    });
+    let err = config.preflight(&nextcloud).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn partial_artifacts_are_cleaned_up() {
+    let installation = Installation::new("config-partial");
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+    let config = Config::new(&installation.backup_root());
+
+    let artifacts = config.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(tarball)] = artifacts.as_slice() else {
+        panic!("config backup should be a single file: {artifacts:?}");
+    };
+    let backup_dir = tarball.parent().unwrap();
+    let partials = |dir: &Path| -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".partial"))
+            .collect()
+    };
+    assert!(partials(backup_dir).is_empty());
+
+    let stale = backup_dir.join("config-2020-01-01T00-00-00.tar.gz.partial");
+    let running = backup_dir.join("config-2020-01-02T00-00-00.tar.gz.partial");
+    fs::write(&stale, "trunc").unwrap();
+    fs::write(&running, "trunc").unwrap();
+    File::options()
+        .write(true)
+        .open(&stale)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+        .unwrap();
+    let retention = RetentionConfig::default();
+    assert_eq!(config.list(&nextcloud, &retention).unwrap().len(), 1);
+
+    config.retention(&nextcloud, &retention, false).unwrap();
+    assert_eq!(
+        partials(backup_dir),
+        ["config-2020-01-02T00-00-00.tar.gz.partial"]
+    );
+}
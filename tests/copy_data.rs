@@ -0,0 +1,101 @@
+mod common;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+
+use common::{day, Installation};
+use nc_backup_lib::backends::{Artifact, Backup, CopyData};
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+fn expect_data_directory(installation: &Installation, runner: &ScriptedRunner) {
+    runner.expect(
+        &["config:system:get", "datadirectory"],
+        &serde_json::to_string(&installation.root.join("data")).unwrap(),
+    );
+}
+
+#[test]
+fn unchanged_files_are_hard_linked() {
+    let installation = Installation::new("copy-data-link");
+    let data_dir = installation.root.join("data");
+    for file in [
+        "alice/files/notes.md",
+        "alice/files/todo.md",
+        "alice/cache/thumb",
+    ] {
+        let path = data_dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_data_directory(&installation, &runner);
+    expect_data_directory(&installation, &runner);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let backup_root = installation.backup_root();
+    let first = CopyData::new(&backup_root)
+        .clock(Clock::Fixed(day(1)))
+        .backup(&nextcloud, false, &NoProgress)
+        .unwrap();
+    let [Artifact::Directory(first)] = first.as_slice() else {
+        panic!("copy should be a single directory: {first:?}");
+    };
+    fs::write(data_dir.join("alice/files/todo.md"), "done").unwrap();
+    let second = CopyData::new(&backup_root)
+        .clock(Clock::Fixed(day(2)))
+        .backup(&nextcloud, false, &NoProgress)
+        .unwrap();
+    let [Artifact::Directory(second)] = second.as_slice() else {
+        panic!("copy should be a single directory: {second:?}");
+    };
+
+    let inode = |copy: &std::path::Path, file| fs::metadata(copy.join(file)).unwrap().ino();
+    assert_eq!(
+        inode(first, "alice/files/notes.md"),
+        inode(second, "alice/files/notes.md")
+    );
+    assert_ne!(
+        inode(first, "alice/files/todo.md"),
+        inode(second, "alice/files/todo.md")
+    );
+    assert_eq!(
+        fs::read_to_string(second.join("alice/files/todo.md")).unwrap(),
+        "done"
+    );
+    assert!(!second.join("alice/cache/thumb").exists());
+}
+
+#[test]
+fn unretained_copies_are_removed() {
+    let installation = Installation::new("copy-data-retention");
+    let copies = installation.backup_root().join("copy");
+    for copy in [
+        "data-2025-01-01T02-30-00",
+        "data-2025-01-02T02-30-00",
+        "data-2025-01-03T02-30-00",
+    ] {
+        fs::create_dir_all(copies.join(copy).join("alice/files")).unwrap();
+        fs::write(copies.join(copy).join("alice/files/notes.md"), copy).unwrap();
+    }
+    fs::write(copies.join("data-2025-01-01T02-30-00.pin"), "").unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+
+    let cfg = RetentionConfig {
+        daily: Some(1),
+        weekly: Some(0),
+        monthly: Some(0),
+        quarterly: Some(0),
+        yearly: Some(0),
+    };
+    CopyData::new(&installation.backup_root())
+        .retention(&nextcloud, &cfg, false)
+        .unwrap();
+    assert!(copies.join("data-2025-01-01T02-30-00").is_dir());
+    assert!(!copies.join("data-2025-01-02T02-30-00").exists());
+    assert!(copies.join("data-2025-01-03T02-30-00").is_dir());
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use std::fs::{self, File};
+use std::sync::Arc;
+
+use common::Installation;
+use flate2::read::MultiGzDecoder;
+use nc_backup_lib::backends::{Artifact, Backup, Data, DataConfig, DataError};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::glob::Glob;
+use nc_backup_lib::util::progress::NoProgress;
+
+const USERS: &str = r#"{"alice":{"display_name":"Alice"},"bob":{"display_name":"Bob"},"guest-1":{"display_name":"Guest"}}"#;
+
+/// Script listing the data directory `data_dir` and the users on `runner`.
+fn expect_users(runner: &ScriptedRunner, data_dir: &std::path::Path) {
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(data_dir).unwrap(),
+        )
+        .expect(&["user:list", "--info"], USERS);
+}
+
+#[test]
+fn selected_user_directories_are_archived() {
+    let installation = Installation::new("data-users");
+    let data_dir = installation.root.join("data");
+    for file in [
+        "alice/files/notes.md",
+        "alice/files/scratch/huge.bin",
+        "alice/cache/chunk",
+        "alice/files_trashbin/files/old.md",
+        "appdata_oc123/preview/1/64-64.png",
+        "bob/files/photo.jpg",
+        "guest-1/files/readme.md",
+    ] {
+        let path = data_dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_users(&runner, &data_dir);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let config = DataConfig {
+        exclude_users: vec![Glob::new("guest-*").unwrap()],
+        user_excludes: [(
+            "alice".to_string(),
+            vec![Glob::new("files/scratch").unwrap()],
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let data = Data::new(&installation.backup_root()).config(config);
+    let artifacts = data.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(tarball)] = artifacts.as_slice() else {
+        panic!("data backup should be a single file: {artifacts:?}");
+    };
+    assert!(runner.finished());
+
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(File::open(tarball).unwrap()));
+    let mut files: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|entry| entry.path().unwrap().into_owned())
+        .collect();
+    files.sort();
+    let name = |file: &str| data_dir.join(file).strip_prefix("/").unwrap().to_path_buf();
+    assert_eq!(
+        files,
+        [name("alice/files/notes.md"), name("bob/files/photo.jpg")]
+    );
+}
+
+#[test]
+fn unknown_user_is_rejected() {
+    let installation = Installation::new("data-unknown");
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_users(&runner, &data_dir);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let config = DataConfig {
+        users: vec!["alice".into(), "mallory".into()],
+        ..Default::default()
+    };
+    let data = Data::new(&installation.backup_root()).config(config);
+    let err = data.backup(&nextcloud, false, &NoProgress).unwrap_err();
+    assert!(matches!(err, DataError::UnknownUser(user) if user == "mallory"));
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{day, Installation};
+use nc_backup_lib::backends::{Artifact, Backup, Config};
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::generation::BackupGeneration;
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+#[test]
+fn generation_is_ulid() {
+    let generation = BackupGeneration::new(Clock::Fixed(day(1)));
+    let ulid = generation.to_string();
+    assert_eq!(ulid.len(), 26);
+    assert_eq!(ulid.parse::<BackupGeneration>().unwrap(), generation);
+    assert_eq!(
+        ulid.to_lowercase().parse::<BackupGeneration>().unwrap(),
+        generation
+    );
+    assert_eq!(
+        serde_json::to_string(&generation).unwrap(),
+        format!("\"{ulid}\"")
+    );
+
+    // generations sort by the time of their run
+    let later = BackupGeneration::new(Clock::Fixed(day(2)));
+    assert_eq!(later.timestamp_ms() - generation.timestamp_ms(), 86_400_000);
+    assert!(later > generation);
+    assert!(later.to_string() > ulid);
+    assert_ne!(BackupGeneration::new(Clock::Fixed(day(1))), generation);
+
+    for invalid in [
+        "",
+        "01JGQ2C4S8ZQK3N7W5R9T0V6X",
+        "81JGQ2C4S8ZQK3N7W5R9T0V6XB",
+        "01JGQ2C4S8ZQK3N7W5R9T0V6XU",
+        "2025-01-01T02-30-00",
+    ] {
+        assert!(invalid.parse::<BackupGeneration>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn backups_are_named_after_generation() {
+    let installation = Installation::new("generation-config");
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+    let generation = BackupGeneration::new(Clock::Fixed(day(2)));
+
+    // created before generations were introduced
+    let legacy = Config::new(&installation.backup_root()).clock(Clock::Fixed(day(1)));
+    legacy.backup(&nextcloud, false, &NoProgress).unwrap();
+    let config = Config::new(&installation.backup_root())
+        .clock(Clock::Fixed(day(2)))
+        .generation(generation);
+    let artifacts = config.backup(&nextcloud, false, &NoProgress).unwrap();
+
+    let [Artifact::File(backup)] = artifacts.as_slice() else {
+        panic!("config backup should be a single file: {artifacts:?}");
+    };
+    assert_eq!(
+        backup.file_name().unwrap().to_string_lossy(),
+        format!("config-2025-01-02T02-30-00_{generation}.tar.gz")
+    );
+
+    let entries = config
+        .list(&nextcloud, &RetentionConfig::default())
+        .unwrap();
+    let listed: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.date, entry.generation))
+        .collect();
+    assert_eq!(listed, [(day(2), Some(generation)), (day(1), None)]);
+}
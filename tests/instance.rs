@@ -0,0 +1,180 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::Installation;
+use nc_backup_lib::backends::{Artifact, BackendsConfig};
+use nc_backup_lib::nextcloud::{Nextcloud, OccBuilder};
+use nc_backup_lib::report::metrics;
+use nc_backup_lib::runner::{BackupLayout, InstanceConfig, JobReport, LayoutError, RunReport};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::tiering::TieringConfig;
+
+const CONFIG: &str = r#"
+backends = ["config", "mariadb", "snapper"]
+parallel_instances = true
+
+[snapper]
+
+[retention]
+daily = 10
+monthly = 10
+yearly = 10
+
+[instance.cloud1]
+installation_root = "/var/www/cloud1"
+
+[instance.cloud2]
+installation_root = "/var/www/cloud2"
+backup_root = "/mnt/backup/cloud2"
+backends = ["config"]
+retention = { daily = 3 }
+"#;
+
+#[test]
+fn instances_override_config() {
+    let config: BackendsConfig = toml::from_str(CONFIG).unwrap();
+    assert!(config.parallel_instances);
+    assert_eq!(
+        config.instance.keys().collect::<Vec<_>>(),
+        ["cloud1", "cloud2"]
+    );
+
+    let cloud1 = &config.instance["cloud1"];
+    assert_eq!(
+        cloud1.backup_root("cloud1", Path::new("/backup")),
+        Path::new("/backup/cloud1")
+    );
+    let cloud1_config = cloud1.apply(&config);
+    assert_eq!(cloud1_config.backends, config.backends);
+    assert_eq!(cloud1_config.retention.daily, Some(10));
+
+    let cloud2 = &config.instance["cloud2"];
+    assert_eq!(
+        cloud2.backup_root("cloud2", Path::new("/backup")),
+        Path::new("/mnt/backup/cloud2")
+    );
+    let cloud2_config = cloud2.apply(&config);
+    assert_eq!(cloud2_config.backends, Some(vec!["config".to_string()]));
+    assert_eq!(cloud2_config.retention.daily, Some(3));
+    assert_eq!(cloud2_config.retention.monthly, None);
+}
+
+#[test]
+fn instance_metrics_are_labelled() {
+    let dir = std::env::temp_dir().join(format!("nc_backup-test-metrics-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let report = RunReport {
+        jobs: vec![JobReport {
+            name: "snapper".into(),
+            duration: Duration::from_secs(1),
+            artifacts: vec![Artifact::Snapshot {
+                config: "nextcloud".into(),
+                id: 42,
+            }],
+            result: Ok(()),
+        }],
+        ..Default::default()
+    };
+
+    metrics::write(&report, &dir, Some("cloud1")).unwrap();
+    let metrics = fs::read_to_string(dir.join("nc_backup-cloud1.prom")).unwrap();
+    assert!(metrics.contains("nc_backup_run_success{instance=\"cloud1\"} 1\n"));
+    assert!(metrics.contains(
+        "nc_backup_snapshot_id{instance=\"cloud1\",backend=\"snapper\",config=\"nextcloud\"} 42\n"
+    ));
+    assert!(!dir.join(metrics::METRICS_FILE).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn instance_id_layout_nests_backups() {
+    let installation = Installation::new("layout-instance-id");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["config:system:get", "instanceid", "--output=json"],
+        "\"oc1234abcd\"",
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let backup_root = BackupLayout::InstanceId
+        .backup_root(Path::new("/backup"), &nextcloud)
+        .unwrap();
+    assert_eq!(backup_root, Path::new("/backup/oc1234abcd"));
+    assert_eq!(
+        BackupLayout::Flat
+            .backup_root(Path::new("/backup"), &nextcloud)
+            .unwrap(),
+        Path::new("/backup")
+    );
+    assert!(runner.finished());
+}
+
+#[test]
+fn unusable_instance_id_is_rejected() {
+    let installation = Installation::new("layout-invalid");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["config:system:get", "instanceid", "--output=json"],
+        "\"../oc1234\"",
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let err = BackupLayout::InstanceId
+        .backup_root(Path::new("/backup"), &nextcloud)
+        .unwrap_err();
+    assert!(matches!(err, LayoutError::InvalidName(..)));
+}
+
+#[test]
+fn instances_run_their_own_occ() {
+    let runner = Arc::new(ScriptedRunner::new());
+    let occ = OccBuilder::default().runner(runner.clone());
+    for name in ["occ-instance1", "occ-instance2"] {
+        let installation = Installation::new(name);
+        let instance: InstanceConfig =
+            toml::from_str(&format!("installation_root = {:?}", installation.root)).unwrap();
+        runner.expect(
+            &["config:system:get", "instanceid", "--output=json"],
+            &format!("\"{}\"", name.replace('-', "")),
+        );
+        let nextcloud = Nextcloud::builder()
+            .occ(instance.occ(&occ))
+            .installation_root(instance.installation_root.clone())
+            .build()
+            .unwrap();
+        BackupLayout::InstanceId
+            .backup_root(Path::new("/backup"), &nextcloud)
+            .unwrap();
+
+        let call = runner.calls().pop().unwrap();
+        let occ_path = installation.root.join("occ");
+        assert_eq!(call[..2], ["php", &*occ_path.to_string_lossy()]);
+    }
+
+    let instance: InstanceConfig = toml::from_str("installation_root = \"/var/www\"").unwrap();
+    let php = instance.occ(&occ.php("php8.2".into())).php;
+    assert_eq!(php, Some("php8.2".into()));
+}
+
+#[test]
+fn cold_storage_is_nested_per_instance() {
+    let tiering = TieringConfig {
+        after_days: 30,
+        destination: "/cold".into(),
+    };
+    let backup_root = Path::new("/backup");
+
+    let flat = tiering.nested(backup_root, backup_root);
+    assert_eq!(flat.destination, Path::new("/cold"));
+    let cloud1 = tiering.nested(backup_root, Path::new("/backup/cloud1"));
+    assert_eq!(cloud1.destination, Path::new("/cold/cloud1"));
+    let cloud2 = tiering.nested(backup_root, Path::new("/mnt/backup/cloud2"));
+    assert_eq!(cloud2.destination, Path::new("/cold/mnt/backup/cloud2"));
+    assert_eq!(cloud2.after_days, 30);
+}
@@ -0,0 +1,286 @@
+//! Disposable Nextcloud and MariaDB containers run by docker or podman.
+//!
+//! Both containers share the network of the host, so the database dumped by
+//! the client of the host is reachable at the `dbhost` Nextcloud reports.
+//! Nextcloud's webroot is bind mounted to a temporary directory of the host,
+//! `occ` is run inside the container by the [ContainerRunner].
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nc_backup_lib::nextcloud::{Nextcloud, NextcloudStatus, OccBuilder};
+use nc_backup_lib::util::command::{self, CommandRunner};
+
+/// Enables the integration tests if set.
+const ENABLE_VAR: &str = "NC_BACKUP_INTEGRATION";
+/// Container engine to use, defaults to `podman` or `docker`, whichever is found first.
+const ENGINE_VAR: &str = "NC_BACKUP_CONTAINER_ENGINE";
+/// Image of Nextcloud, has to run `php-fpm` as the Apache images need port 80.
+const NEXTCLOUD_IMAGE_VAR: &str = "NC_BACKUP_NEXTCLOUD_IMAGE";
+/// Image of MariaDB.
+const MARIADB_IMAGE_VAR: &str = "NC_BACKUP_MARIADB_IMAGE";
+/// Port MariaDB listens on, it has to be free on the host.
+const DB_PORT_VAR: &str = "NC_BACKUP_DB_PORT";
+
+const NEXTCLOUD_IMAGE: &str = "docker.io/library/nextcloud:30-fpm";
+const MARIADB_IMAGE: &str = "docker.io/library/mariadb:11.4";
+const DB_PORT: u16 = 33306;
+
+/// Webroot of Nextcloud inside the container.
+const CONTAINER_ROOT: &str = "/var/www/html";
+/// Time the installation of Nextcloud may take.
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(600);
+
+pub const DB_NAME: &str = "nextcloud";
+pub const DB_USER: &str = "nextcloud";
+pub const DB_PASSWORD: &str = "nc_backup-integration";
+
+/// Runs the commands of [Occ](nc_backup_lib::nextcloud::Occ) inside the Nextcloud container.
+///
+/// Paths of the host's webroot are mapped to the webroot of the container.
+/// `occ` is always run as `www-data`, the owner of `config.php` on the host
+/// depends on the user namespace of the engine.
+#[derive(Debug)]
+pub struct ContainerRunner {
+    engine: String,
+    container: String,
+    host_root: PathBuf,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+impl ContainerRunner {
+    /// The command lines run since the last call, as run inside the container.
+    pub fn take_calls(&self) -> Vec<Vec<String>> {
+        std::mem::take(&mut self.calls.lock().expect("calls should not be poisoned"))
+    }
+}
+
+impl CommandRunner for ContainerRunner {
+    fn output(&self, cmd: &mut Command, name: &str) -> io::Result<Output> {
+        let mut line: Vec<String> = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        if line.first().is_some_and(|program| program == "sudo") {
+            // sudo -n -u <user>
+            line.drain(..4.min(line.len()));
+        }
+        let host_root = self.host_root.to_string_lossy();
+        for arg in &mut line {
+            if let Some(path) = arg.strip_prefix(host_root.as_ref()) {
+                *arg = format!("{CONTAINER_ROOT}{path}");
+            }
+        }
+        self.calls
+            .lock()
+            .expect("calls should not be poisoned")
+            .push(line.clone());
+
+        let mut exec = Command::new(&self.engine);
+        exec.args(["exec", "-u", "www-data"]);
+        for (key, value) in cmd.get_envs() {
+            if let Some(value) = value {
+                exec.arg("-e").arg(format!(
+                    "{}={}",
+                    key.to_string_lossy(),
+                    value.to_string_lossy()
+                ));
+            }
+        }
+        exec.arg(&self.container).args(&line);
+        command::output(&mut exec, name)
+    }
+}
+
+/// Running Nextcloud and MariaDB containers, removed on drop.
+pub struct Environment {
+    engine: String,
+    name: String,
+    dir: PathBuf,
+    pub db_port: u16,
+    pub runner: Arc<ContainerRunner>,
+}
+
+impl Environment {
+    /// Start the containers and wait for Nextcloud to be installed.
+    ///
+    /// Returns [None] if the integration tests aren't enabled.
+    pub fn start() -> Option<Self> {
+        if env::var_os(ENABLE_VAR).is_none() {
+            eprintln!("{ENABLE_VAR} isn't set, skipping the integration tests");
+            return None;
+        }
+
+        let engine = env::var(ENGINE_VAR).unwrap_or_else(|_| {
+            ["podman", "docker"]
+                .into_iter()
+                .find(|engine| Command::new(engine).arg("--version").output().is_ok())
+                .expect("neither podman nor docker is installed")
+                .to_string()
+        });
+        let db_port = env::var(DB_PORT_VAR)
+            .map(|port| port.parse().expect("db port should be a number"))
+            .unwrap_or(DB_PORT);
+        let name = format!("nc_backup-integration-{}", std::process::id());
+        let dir = env::temp_dir().join(&name);
+        fs::create_dir_all(dir.join("html")).unwrap();
+        let runner = Arc::new(ContainerRunner {
+            engine: engine.clone(),
+            container: format!("{name}-nextcloud"),
+            host_root: dir.join("html"),
+            calls: Mutex::default(),
+        });
+
+        // removes the containers started so far on failure
+        let environment = Self {
+            engine,
+            name,
+            dir,
+            db_port,
+            runner,
+        };
+        environment.run_container(
+            "mariadb",
+            &env::var(MARIADB_IMAGE_VAR).unwrap_or(MARIADB_IMAGE.into()),
+            &[],
+            &[
+                ("MARIADB_RANDOM_ROOT_PASSWORD", "1"),
+                ("MARIADB_DATABASE", DB_NAME),
+                ("MARIADB_USER", DB_USER),
+                ("MARIADB_PASSWORD", DB_PASSWORD),
+            ],
+            &[&format!("--port={db_port}")],
+        );
+        environment.run_container(
+            "nextcloud",
+            &env::var(NEXTCLOUD_IMAGE_VAR).unwrap_or(NEXTCLOUD_IMAGE.into()),
+            &[&format!(
+                "{}:{CONTAINER_ROOT}:Z",
+                environment.root().display()
+            )],
+            &[
+                ("MYSQL_HOST", &format!("127.0.0.1:{db_port}")),
+                ("MYSQL_DATABASE", DB_NAME),
+                ("MYSQL_USER", DB_USER),
+                ("MYSQL_PASSWORD", DB_PASSWORD),
+                ("NEXTCLOUD_ADMIN_USER", "admin"),
+                ("NEXTCLOUD_ADMIN_PASSWORD", "nc_backup-integration-admin"),
+            ],
+            &[],
+        );
+        environment.wait_installed();
+
+        Some(environment)
+    }
+
+    /// Run the `image` detached as container `suffix` sharing the network of the host.
+    fn run_container(
+        &self,
+        suffix: &str,
+        image: &str,
+        volumes: &[&str],
+        envs: &[(&str, &str)],
+        args: &[&str],
+    ) {
+        let mut run = Command::new(&self.engine);
+        run.args(["run", "-d", "--network", "host", "--name"])
+            .arg(format!("{}-{suffix}", self.name));
+        for volume in volumes {
+            run.arg("-v").arg(volume);
+        }
+        for (key, value) in envs {
+            run.arg("-e").arg(format!("{key}={value}"));
+        }
+        run.arg(image).args(args);
+
+        let output = run.output().expect("container engine should run");
+        assert!(
+            output.status.success(),
+            "starting {image} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Poll `occ status` until the installation completed.
+    fn wait_installed(&self) {
+        let start = Instant::now();
+        while start.elapsed() < INSTALL_TIMEOUT {
+            let mut status = Command::new("php");
+            status
+                .arg(self.root().join("occ"))
+                .args(["status", "--output=json"]);
+            let installed = self
+                .runner
+                .output(&mut status, "occ")
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| serde_json::from_slice::<NextcloudStatus>(&output.stdout).ok())
+                .is_some_and(|status| status.installed);
+            if installed {
+                self.runner.take_calls();
+                return;
+            }
+            thread::sleep(Duration::from_secs(5));
+        }
+
+        let logs = Command::new(&self.engine)
+            .args(["logs", "--tail", "50"])
+            .arg(&self.runner.container)
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stderr).into_owned())
+            .unwrap_or_default();
+        panic!("Nextcloud wasn't installed after {INSTALL_TIMEOUT:?}:\n{logs}");
+    }
+
+    /// Webroot of Nextcloud on the host.
+    pub fn root(&self) -> PathBuf {
+        self.dir.join("html")
+    }
+
+    /// Directory the backups are written to.
+    pub fn backup_root(&self) -> PathBuf {
+        self.dir.join("backups")
+    }
+
+    /// The [Nextcloud] running `occ` inside the container.
+    pub fn nextcloud(&self) -> Nextcloud {
+        let occ = OccBuilder::default()
+            .php(PathBuf::from("php"))
+            .runner(self.runner.clone());
+        Nextcloud::builder()
+            .installation_root(self.root())
+            .occ(occ)
+            .build()
+            .unwrap()
+    }
+
+    /// Map the path `path` inside the container to the host.
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        let relative = path.strip_prefix(CONTAINER_ROOT).unwrap_or(path);
+        self.root().join(relative)
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        // the files of the webroot may belong to a user of the engine's namespace
+        let _ = Command::new(&self.engine)
+            .args(["exec", "-u", "0"])
+            .arg(&self.runner.container)
+            .args(["find", CONTAINER_ROOT, "-mindepth", "1", "-delete"])
+            .output();
+        for suffix in ["nextcloud", "mariadb"] {
+            let _ = Command::new(&self.engine)
+                .args(["rm", "-f"])
+                .arg(format!("{}-{suffix}", self.name))
+                .output();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,140 @@
+//! Backups of a real Nextcloud installation run in containers.
+//!
+//! The tests only run if `NC_BACKUP_INTEGRATION` is set and require docker or
+//! podman as well as `mariadb-dump` or `mysqldump` on the host:
+//!
+//! ```sh
+//! NC_BACKUP_INTEGRATION=1 cargo test --test integration -- --nocapture
+//! ```
+//!
+//! See [harness] for the other variables configuring the containers.
+
+mod harness;
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use harness::{Environment, DB_NAME, DB_PASSWORD, DB_USER};
+use nc_backup_lib::backends::{Artifact, BackupError, Config, MariaDb};
+use nc_backup_lib::runner::{BackupRunner, Job};
+
+/// The containers are started once as the installation takes minutes.
+#[test]
+fn nextcloud() {
+    let Some(environment) = Environment::start() else {
+        return;
+    };
+
+    occ_output_is_parsed(&environment);
+    maintenance_guard_toggles_maintenance_mode(&environment);
+    full_backup(&environment);
+}
+
+fn occ_output_is_parsed(environment: &Environment) {
+    let nextcloud = environment.nextcloud();
+    let occ = nextcloud.occ();
+
+    let status = occ.status().unwrap();
+    assert!(status.installed);
+    assert!(!status.maintenance);
+    assert!(status.major_version().is_some());
+    status.check().unwrap();
+
+    assert!(!occ.encryption_enabled().unwrap());
+    assert_eq!(occ.db_name().unwrap(), DB_NAME);
+
+    let db = occ.db_config().unwrap();
+    assert_eq!(db.dbtype, "mysql");
+    assert_eq!(db.host.as_deref(), Some("127.0.0.1"));
+    assert_eq!(db.port, Some(environment.db_port));
+    assert_eq!(db.name, DB_NAME);
+    // the installer may create a dedicated user if permitted
+    if db.user == DB_USER {
+        assert_eq!(db.password.as_deref(), Some(DB_PASSWORD));
+    }
+
+    let data_directory: PathBuf = occ.config_system_get_json(&["datadirectory"]).unwrap();
+    assert!(environment.host_path(&data_directory).is_dir());
+}
+
+fn maintenance_guard_toggles_maintenance_mode(environment: &Environment) {
+    let nextcloud = environment.nextcloud();
+    let occ = nextcloud.occ();
+
+    let guard = occ.maintenance_guard().unwrap();
+    assert!(occ.maintenance().unwrap());
+    assert!(occ.status().unwrap().maintenance);
+    guard.disable().unwrap();
+    assert!(!occ.maintenance().unwrap());
+}
+
+fn full_backup(environment: &Environment) {
+    let backup_root = environment.backup_root();
+    let db_password = environment
+        .nextcloud()
+        .occ()
+        .db_config()
+        .unwrap()
+        .password
+        .unwrap();
+    environment.runner.take_calls();
+
+    let report = BackupRunner::new(environment.nextcloud())
+        .job(Job::backup("mariadb", Box::new(MariaDb::new(&backup_root))))
+        .job(Job::backup("config", Box::new(Config::new(&backup_root))))
+        .job(Job::new(
+            "maintenance",
+            true,
+            |nextcloud, _dry_run| match nextcloud.occ().maintenance() {
+                Ok(true) => Ok(Vec::new()),
+                Ok(false) => Err(BackupError::Other("maintenance mode is disabled".into())),
+                Err(e) => Err(BackupError::Other(e.into())),
+            },
+        ))
+        .run()
+        .unwrap();
+    for job in report.failed() {
+        eprintln!("job {} failed: {:?}", job.name, job.result);
+    }
+    assert!(report.success());
+
+    // maintenance mode was enabled once around the jobs requiring it
+    let toggles: Vec<_> = environment
+        .runner
+        .take_calls()
+        .into_iter()
+        .filter(|call| call.iter().any(|arg| arg == "maintenance:mode"))
+        .filter_map(|call| call.into_iter().find(|arg| arg == "--on" || arg == "--off"))
+        .collect();
+    assert_eq!(toggles, ["--on", "--off"]);
+    assert!(!environment.nextcloud().occ().maintenance().unwrap());
+
+    let dump = decompress(single_file(&report, "mariadb"));
+    assert!(dump.contains("CREATE TABLE `oc_users`"));
+
+    let config = decompress(single_file(&report, "config"));
+    assert!(config.contains("'dbpassword'"));
+    assert!(!config.contains(&db_password));
+}
+
+/// The only file created by the job `name`.
+fn single_file<'a>(report: &'a nc_backup_lib::runner::RunReport, name: &str) -> &'a Path {
+    let job = report
+        .jobs
+        .iter()
+        .find(|job| job.name == name)
+        .expect("job should be reported");
+    match job.artifacts.as_slice() {
+        [Artifact::File(file)] => file,
+        artifacts => panic!("job {name} should create a single file: {artifacts:?}"),
+    }
+}
+
+fn decompress(file: &Path) -> String {
+    let mut content = String::new();
+    MultiGzDecoder::new(std::fs::File::open(file).unwrap())
+        .read_to_string(&mut content)
+        .unwrap();
+    content
+}
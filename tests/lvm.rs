@@ -0,0 +1,139 @@
+mod common;
+
+use std::fs::{self, File};
+use std::sync::Arc;
+
+use common::Installation;
+use flate2::read::MultiGzDecoder;
+use nc_backup_lib::backends::{Artifact, Backup, Lvm, LvmConfig, LvmError};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::progress::NoProgress;
+
+/// Script the data directory of `installation` on `runner` located on the
+/// logical volume `vg0/nextcloud` mounted at the root of the installation.
+fn expect_volume(installation: &Installation, runner: &ScriptedRunner) {
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(
+            &["findmnt", "--target"],
+            &format!(
+                "/dev/mapper/vg0-nextcloud ext4 {}\n",
+                installation.root.display()
+            ),
+        )
+        .expect(
+            &["lvs", "vg_name,lv_name", "/dev/mapper/vg0-nextcloud"],
+            "  vg0/nextcloud\n",
+        );
+}
+
+#[test]
+fn snapshot_of_data_volume_is_archived() {
+    let installation = Installation::new("lvm-snapshot");
+    // the mounted snapshot of the volume
+    let mount_dir = installation.root.join("snapshot");
+    for file in ["data/alice/files/notes.md", "data/nextcloud.log"] {
+        let path = mount_dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_volume(&installation, &runner);
+    runner
+        .expect_exit(&["lvs", "vg0/nextcloud-nc_backup"], 5, "", "not found")
+        .expect(
+            &[
+                "lvcreate",
+                "-s",
+                "-n",
+                "nextcloud-nc_backup",
+                "-l",
+                "10%ORIGIN",
+                "vg0/nextcloud",
+            ],
+            "",
+        );
+    expect_volume(&installation, &runner);
+    runner
+        .expect(
+            &[
+                "mount",
+                "ro",
+                "/dev/vg0/nextcloud-nc_backup",
+                &mount_dir.to_string_lossy(),
+            ],
+            "",
+        )
+        .expect(&["umount", &mount_dir.to_string_lossy()], "")
+        .expect(&["lvremove", "-y", "vg0/nextcloud-nc_backup"], "");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let lvm = Lvm::new(&installation.backup_root())
+        .runner(runner.clone())
+        .config(LvmConfig {
+            mount_dir: mount_dir.clone(),
+            ..Default::default()
+        });
+    let artifacts = lvm.backup(&nextcloud, false, &NoProgress).unwrap();
+    let artifacts = lvm
+        .finalize(&nextcloud, artifacts, false, &NoProgress)
+        .unwrap();
+    assert!(runner.finished());
+    let [Artifact::File(tarball)] = artifacts.as_slice() else {
+        panic!("lvm backup should be a single file: {artifacts:?}");
+    };
+
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(File::open(tarball).unwrap()));
+    let mut files: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|entry| entry.path().unwrap().into_owned())
+        .collect();
+    files.sort();
+    // archived under the data directory, not the mount of the snapshot
+    let data_dir = installation.root.join("data");
+    assert_eq!(
+        files,
+        [
+            data_dir
+                .join("alice/files/notes.md")
+                .strip_prefix("/")
+                .unwrap(),
+            data_dir.join("nextcloud.log").strip_prefix("/").unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn data_directory_outside_lvm_is_error() {
+    let installation = Installation::new("lvm-no-volume");
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(&["findmnt", "--target"], "/dev/sda2 ext4 /\n")
+        .expect_exit(
+            &["lvs", "/dev/sda2"],
+            5,
+            "",
+            "Failed to find logical volume",
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let lvm = Lvm::new(&installation.backup_root()).runner(runner.clone());
+    assert!(matches!(
+        lvm.backup(&nextcloud, false, &NoProgress),
+        Err(LvmError::NoVolume(_))
+    ));
+}
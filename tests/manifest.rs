@@ -0,0 +1,133 @@
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use common::Installation;
+use nc_backup_lib::backends::Artifact;
+use nc_backup_lib::nextcloud::AppList;
+use nc_backup_lib::report::manifest::{self, ManifestConfig, RunManifest};
+use nc_backup_lib::runner::{JobReport, RunReport};
+use nc_backup_lib::util::sign::Signing;
+
+#[test]
+fn manifest_detects_altered_and_missing_backups() {
+    let installation = Installation::new("manifest");
+    let backup_root = installation.backup_root();
+    fs::create_dir_all(&backup_root).unwrap();
+    let files: Vec<_> = ["config.tar.gz", "database.sql.gz", "apps.tar.gz"]
+        .iter()
+        .map(|name| {
+            let path = backup_root.join(name);
+            fs::write(&path, name.as_bytes()).unwrap();
+            path
+        })
+        .collect();
+    let mut artifacts: Vec<_> = files.iter().cloned().map(Artifact::File).collect();
+    artifacts.push(Artifact::Snapshot {
+        config: "nextcloud".into(),
+        id: 42,
+    });
+    let report = RunReport {
+        jobs: vec![JobReport {
+            name: "all".into(),
+            duration: Duration::ZERO,
+            artifacts,
+            result: Ok(()),
+        }],
+        ..Default::default()
+    };
+    let apps = AppList {
+        enabled: [("calendar".to_string(), "5.0.1".to_string())].into(),
+        disabled: Default::default(),
+    };
+    let created = NaiveDate::from_ymd_opt(2025, 1, 1)
+        .unwrap()
+        .and_hms_opt(2, 30, 0)
+        .unwrap();
+
+    let mut run_manifest = RunManifest::new(&report, created).unwrap();
+    run_manifest.apps = Some(apps.clone());
+    run_manifest.nextcloud_version = Some("30.0.4.1".into());
+    let path = manifest::write(&run_manifest, &backup_root, &ManifestConfig::default()).unwrap();
+    assert_eq!(
+        path,
+        backup_root.join("manifests/run-2025-01-01T02-30-00.json")
+    );
+    let written: RunManifest = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(written.files.len(), 3);
+    assert_eq!(written.artifacts, ["snapper:nextcloud:42"]);
+    assert_eq!(written.apps, Some(apps));
+    assert_eq!(written.nextcloud_version.as_deref(), Some("30.0.4.1"));
+    let (found, _) = manifest::find(&backup_root, &files[1]).unwrap().unwrap();
+    assert_eq!(found, path);
+    assert!(
+        manifest::find(&backup_root, &backup_root.join("other.sql.gz"))
+            .unwrap()
+            .is_none()
+    );
+
+    let verification = manifest::verify(&path, None).unwrap();
+    assert!(verification.success());
+    assert_eq!(verification.verified, files);
+
+    fs::write(&files[1], "tampered").unwrap();
+    fs::remove_file(&files[2]).unwrap();
+    let verification = manifest::verify(&path, None).unwrap();
+    assert!(!verification.success());
+    assert_eq!(verification.verified, files[..1]);
+    assert_eq!(verification.altered, files[1..2]);
+    assert_eq!(verification.missing, files[2..]);
+}
+
+#[test]
+fn missing_minisign_key_fails_check() {
+    let signing = Signing::Minisign {
+        secret_key: "/nonexistent/minisign.key".into(),
+        public_key: "/nonexistent/minisign.pub".into(),
+    };
+    let err = signing.check().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn gpg_signature_of_other_key_is_rejected() {
+    let installation = Installation::new("manifest_gpg");
+    let home = installation.root.join("gnupg");
+    fs::create_dir_all(&home).unwrap();
+    fs::set_permissions(&home, fs::Permissions::from_mode(0o700)).unwrap();
+    // only this test runs gpg
+    std::env::set_var("GNUPGHOME", &home);
+    let gpg = |args: &[&str]| {
+        Command::new("gpg")
+            .args(["--batch", "--passphrase", "", "--quick-gen-key"])
+            .args(args)
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    };
+    if !gpg(&["signer@example.com", "ed25519", "sign", "never"]) {
+        eprintln!("Skipping test, gpg can't generate keys");
+        return;
+    }
+    assert!(gpg(&["other@example.com", "ed25519", "sign", "never"]));
+
+    let file = installation.root.join("manifest.json");
+    fs::write(&file, "{}").unwrap();
+    let signer = Signing::Gpg {
+        key: "signer@example.com".into(),
+    };
+    let other = Signing::Gpg {
+        key: "other@example.com".into(),
+    };
+    signer.sign(&file).unwrap();
+
+    signer.verify(&file).unwrap();
+    assert!(other.verify(&file).is_err());
+    let _ = Command::new("gpgconf")
+        .args(["--kill", "gpg-agent"])
+        .status();
+}
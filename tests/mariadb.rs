@@ -0,0 +1,284 @@
+mod common;
+
+use std::fs;
+use std::io::{self, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use common::{called_with, Installation};
+use nc_backup_lib::backends::mariadb;
+use nc_backup_lib::backends::{
+    Backup, MariaDb, MariaDbConfig, MariaDbError, MariaDbPhysical, MariaDbPhysicalConfig,
+    MariaDbPhysicalError,
+};
+use nc_backup_lib::util::artifact::base_path;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::compress::CompressionConfig;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+/// Output of `occ config:list system --private --output=json` for `dbtype`.
+fn config_list(dbtype: &str) -> String {
+    format!(
+        r#"{{"system":{{"dbtype":"{dbtype}","dbhost":"localhost","dbname":"nextcloud","dbuser":"nc","dbpassword":"secret"}}}}"#
+    )
+}
+
+#[test]
+fn other_databases_are_rejected() {
+    let installation = Installation::new("mariadb-sqlite");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["config:list"], &config_list("sqlite3"));
+    let nextcloud = installation.nextcloud(&runner);
+
+    let mariadb = MariaDb::new(&installation.backup_root()).runner(runner.clone());
+    let err = mariadb.preflight(&nextcloud).unwrap_err();
+    assert!(matches!(err, MariaDbError::UnsupportedDatabase(dbtype) if dbtype == "sqlite3"));
+    assert!(runner.finished());
+}
+
+#[test]
+fn preflight_estimates_database_size() {
+    let installation = Installation::new("mariadb-preflight");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect(
+            &["mariadb-dump", "--version"],
+            "mariadb-dump from 11.4.2-MariaDB",
+        )
+        .expect(&["mariadb", "--host=localhost", "SELECT 1"], "1\n")
+        .expect(
+            &["mariadb", "--user=nc", "--host=localhost", "-e"],
+            "1048576\n",
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let mariadb = MariaDb::new(&installation.backup_root()).runner(runner.clone());
+    mariadb.preflight(&nextcloud).unwrap();
+    assert!(runner.finished());
+}
+
+#[test]
+fn mysqldump_is_used_without_mariadb_dump() {
+    let installation = Installation::new("mariadb-mysqldump");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect_error(&["mariadb-dump", "--version"], io::ErrorKind::NotFound)
+        .expect(
+            &["mysqldump", "--version"],
+            "mysqldump  Ver 10.19 Distrib 10.11.6-MariaDB",
+        )
+        .expect(&["SELECT 1"], "1\n")
+        .expect(&["-e"], "0\n");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let mariadb = MariaDb::new(&installation.backup_root()).runner(runner.clone());
+    mariadb.preflight(&nextcloud).unwrap();
+    let calls = runner.calls();
+    assert!(called_with(&calls[3], &["mysql", "--user=nc"]));
+}
+
+#[test]
+fn missing_dump_client_is_error() {
+    let installation = Installation::new("mariadb-no-client");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect_error(&["mariadb-dump"], io::ErrorKind::NotFound)
+        .expect_error(&["mysqldump"], io::ErrorKind::NotFound);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let mariadb = MariaDb::new(&installation.backup_root()).runner(runner.clone());
+    let err = mariadb.preflight(&nextcloud).unwrap_err();
+    assert!(matches!(err, MariaDbError::NoDumpClient));
+}
+
+#[test]
+fn unreachable_server_fails_preflight() {
+    let installation = Installation::new("mariadb-unreachable");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect(
+            &["mariadb-dump", "--version"],
+            "mariadb-dump from 11.4.2-MariaDB",
+        )
+        .expect_exit(
+            &["SELECT 1"],
+            1,
+            "",
+            "ERROR 2002 (HY000): Can't connect to server on 'localhost' (115)",
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let mariadb = MariaDb::new(&installation.backup_root()).runner(runner.clone());
+    let err = mariadb.preflight(&nextcloud).unwrap_err();
+    assert!(matches!(err, MariaDbError::Unreachable { endpoint, .. } if endpoint == "localhost"));
+}
+
+#[test]
+fn configured_host_overrides_dbhost() {
+    let installation = Installation::new("mariadb-remote");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect(
+            &["mariadb-dump", "--version"],
+            "mariadb-dump from 11.4.2-MariaDB",
+        )
+        .expect(&["--host=db.example.org", "--port=3307", "SELECT 1"], "1\n")
+        .expect(&["--host=db.example.org", "--port=3307", "-e"], "0\n");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let config = MariaDbConfig {
+        host: Some("db.example.org".into()),
+        port: Some(3307),
+        ..Default::default()
+    };
+    let mariadb = MariaDb::new(&installation.backup_root())
+        .config(config)
+        .runner(runner.clone());
+    mariadb.preflight(&nextcloud).unwrap();
+    assert!(runner.finished());
+}
+
+#[test]
+fn missing_mariabackup_is_error() {
+    let installation = Installation::new("mariadb-no-mariabackup");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect_error(&["mariabackup", "--version"], io::ErrorKind::NotFound);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let physical = MariaDbPhysical::new(&installation.backup_root()).runner(runner.clone());
+    let err = physical.preflight(&nextcloud).unwrap_err();
+    assert!(matches!(err, MariaDbPhysicalError::NoMariabackup));
+}
+
+#[test]
+fn physical_backup_connects_as_configured_user() {
+    let installation = Installation::new("mariadb-physical-user");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(&["config:list"], &config_list("mysql"))
+        .expect(&["mariabackup", "--version"], "")
+        .expect(&["mariadb", "--user=root", "SELECT 1"], "1\n")
+        .expect(&["mariadb", "--user=root", "-e"], "0\n");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let physical = MariaDbPhysical::new(&installation.backup_root())
+        .config(MariaDbPhysicalConfig {
+            user: Some("root".into()),
+            ..Default::default()
+        })
+        .runner(runner.clone());
+    physical.preflight(&nextcloud).unwrap();
+    assert!(runner.finished());
+}
+
+#[test]
+fn discarded_diff_is_removed_with_its_base_sidecar() {
+    let installation = Installation::new("mariadb-diff-retention");
+    let db = installation.backup_root().join("db");
+    fs::create_dir_all(&db).unwrap();
+    let base = db.join("database-2025-01-01T02-30-00.sql.gz");
+    let diff = db.join("database-2025-01-02T02-30-00.sql.zst");
+    let latest = db.join("database-2025-01-03T02-30-00.sql.gz");
+    for dump in [&base, &diff, &latest] {
+        fs::write(dump, "dump").unwrap();
+    }
+    let base_sidecar = db.join("database-2025-01-02T02-30-00.sql.zst.base");
+    fs::write(&base_sidecar, "database-2025-01-01T02-30-00.sql.gz").unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+
+    let config = MariaDbConfig {
+        full_every: NonZeroU32::new(7),
+        ..Default::default()
+    };
+    let retention = RetentionConfig {
+        daily: Some(1),
+        ..Default::default()
+    };
+    MariaDb::new(&installation.backup_root())
+        .config(config)
+        .retention(&nextcloud, &retention, false)
+        .unwrap();
+
+    assert!(latest.is_file());
+    assert!(!base.exists());
+    assert!(!diff.exists());
+    assert!(!base_sidecar.exists());
+}
+
+/// Writes the full dump `base` with `full` and the differential dump `diff` of `dump` against it.
+///
+/// Returns `false` if `zstd` is missing.
+fn diff_dump(base: &Path, full: &str, diff: &Path, dump: &str) -> bool {
+    let mut encoder = CompressionConfig::default().encoder(fs::File::create(base).unwrap());
+    encoder.write_all(full.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+    let decompressed = base.with_extension("tmp");
+    let spool = diff.with_extension("sql");
+    fs::write(&decompressed, full).unwrap();
+    fs::write(&spool, dump).unwrap();
+    let status = Command::new("zstd")
+        .args(["-q", "-f", "--long=31"])
+        .arg(format!("--patch-from={}", decompressed.display()))
+        .arg(&spool)
+        .arg("-o")
+        .arg(diff)
+        .status();
+    fs::remove_file(decompressed).unwrap();
+    fs::remove_file(spool).unwrap();
+    let name = base.file_name().unwrap().to_str().unwrap();
+    fs::write(base_path(diff), name).unwrap();
+    status.is_ok_and(|status| status.success())
+}
+
+#[test]
+fn diff_dump_is_restored_against_its_full_dump() {
+    let installation = Installation::new("mariadb-diff-restore");
+    let db = installation.backup_root().join("db");
+    fs::create_dir_all(&db).unwrap();
+    let base = db.join("database-2025-01-01T02-30-00.sql.gz");
+    let diff = db.join("database-2025-01-02T02-30-00.sql.zst");
+    let full = "INSERT INTO oc_users VALUES ('admin');\n".repeat(1000);
+    let dump = format!("{full}INSERT INTO oc_users VALUES ('alice');\n");
+    if !diff_dump(&base, &full, &diff, &dump) {
+        eprintln!("Skipping test, zstd is missing");
+        return;
+    }
+
+    assert_eq!(mariadb::diff_base(&diff).unwrap(), base);
+    let restored = installation.root.join("restored.sql");
+    mariadb::restore_diff(&diff, None, Some(&restored)).unwrap();
+    assert_eq!(fs::read_to_string(&restored).unwrap(), dump);
+    assert!(!base.with_extension("gz.tmp").exists());
+
+    let err = mariadb::restore_diff(&diff, None, Some(&restored)).unwrap_err();
+    assert!(matches!(err, MariaDbError::DestinationExists(_)));
+}
+
+#[test]
+fn diff_dump_without_full_dump_is_error() {
+    let installation = Installation::new("mariadb-diff-base-missing");
+    let db = installation.backup_root().join("db");
+    fs::create_dir_all(&db).unwrap();
+    let base = db.join("database-2025-01-01T02-30-00.sql.gz");
+    let diff = db.join("database-2025-01-02T02-30-00.sql.zst");
+    if !diff_dump(&base, "full\n", &diff, "diff\n") {
+        eprintln!("Skipping test, zstd is missing");
+        return;
+    }
+    fs::remove_file(&base).unwrap();
+
+    let restored = installation.root.join("restored.sql");
+    let err = mariadb::restore_diff(&diff, None, Some(&restored)).unwrap_err();
+    assert!(matches!(err, MariaDbError::DiffBaseMissing(missing) if missing == base));
+    assert!(!restored.exists());
+}
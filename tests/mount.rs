@@ -0,0 +1,218 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use common::{day, Installation};
+use nc_backup_lib::backends::{Artifact, BackendEntry, BackupEntry};
+use nc_backup_lib::restore::mount::{Generation, Mount, MountError};
+use nc_backup_lib::util::archive::{write_tarball, Excludes};
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::compress::CompressionConfig;
+use nc_backup_lib::util::generation::BackupGeneration;
+use nc_backup_lib::util::privilege::Privilege;
+use nc_backup_lib::util::progress::NoProgress;
+
+fn backup(backend: &str, artifact: Artifact, date: NaiveDateTime) -> BackendEntry {
+    BackendEntry {
+        backend: backend.into(),
+        entry: BackupEntry {
+            artifact,
+            date,
+            size: None,
+            pinned: false,
+            generation: None,
+            retained_by: Vec::new(),
+        },
+    }
+}
+
+#[test]
+fn latest_backups_before_time_are_selected() {
+    let backups = [
+        backup(
+            "snapper",
+            Artifact::Snapshot {
+                config: "nextcloud".into(),
+                id: 41,
+            },
+            day(1),
+        ),
+        backup(
+            "snapper",
+            Artifact::Snapshot {
+                config: "nextcloud".into(),
+                id: 42,
+            },
+            day(2),
+        ),
+        // streams of the snapshots can't be mounted
+        backup(
+            "snapper",
+            Artifact::File("snapper/snapshot-43.btrfs.zst".into()),
+            day(3),
+        ),
+        backup(
+            "mariadb",
+            Artifact::File("db/database-2025-01-02T02-30-00.sql.gz".into()),
+            day(2),
+        ),
+        backup(
+            "config",
+            Artifact::File("config/config-2025-01-03T02-30-00.tar.gz".into()),
+            day(3),
+        ),
+    ];
+
+    let generation = Generation::select(&backups, day(3)).unwrap();
+    assert_eq!(
+        generation.data.unwrap().entry.artifact,
+        Artifact::Snapshot {
+            config: "nextcloud".into(),
+            id: 42
+        }
+    );
+    assert_eq!(generation.database.unwrap().entry.date, day(2));
+
+    let generation = Generation::select(&backups, day(1)).unwrap();
+    assert_eq!(generation.data.unwrap().entry.date, day(1));
+    assert!(generation.database.is_none());
+
+    assert!(matches!(
+        Generation::select(&backups, day(1) - chrono::TimeDelta::days(1)),
+        Err(MountError::NoBackup(_))
+    ));
+}
+
+#[test]
+fn dump_of_same_generation_is_preferred() {
+    let generation = BackupGeneration::new(Clock::Fixed(day(1)));
+    let mut snapshot = backup(
+        "snapper",
+        Artifact::Snapshot {
+            config: "nextcloud".into(),
+            id: 41,
+        },
+        day(1),
+    );
+    snapshot.entry.generation = Some(generation);
+    let mut dump = backup(
+        "mariadb",
+        Artifact::File(format!("db/database-2025-01-01T02-30-00_{generation}.sql.gz").into()),
+        day(1),
+    );
+    dump.entry.generation = Some(generation);
+    let later_dump = backup(
+        "mariadb",
+        Artifact::File("db/database-2025-01-02T02-30-00.sql.gz".into()),
+        day(2),
+    );
+    let backups = [snapshot, dump.clone(), later_dump.clone()];
+
+    let selected = Generation::select(&backups, day(2)).unwrap();
+    assert_eq!(
+        selected.database.unwrap().entry.artifact,
+        dump.entry.artifact
+    );
+
+    // without generation the latest dump is selected
+    let mut backups = backups;
+    backups[0].entry.generation = None;
+    let selected = Generation::select(&backups, day(2)).unwrap();
+    assert_eq!(
+        selected.database.unwrap().entry.artifact,
+        later_dump.entry.artifact
+    );
+}
+
+#[test]
+fn copy_is_bind_mounted_read_only() {
+    let installation = Installation::new("mount-copy");
+    let copy = installation
+        .backup_root()
+        .join("copy/data-2025-01-02T02-30-00");
+    fs::create_dir_all(&copy).unwrap();
+    let mount_dir = installation.root.join("mnt");
+    let data = mount_dir.join("data");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &[
+                "mount",
+                "--bind",
+                "-o",
+                "ro",
+                &copy.to_string_lossy(),
+                &data.to_string_lossy(),
+            ],
+            "",
+        )
+        .expect(&["umount", &data.to_string_lossy()], "");
+
+    let generation = Generation {
+        data: Some(backup("copy_data", Artifact::Directory(copy), day(2))),
+        database: None,
+    };
+    let mount = Mount::new(&mount_dir)
+        .privilege(Privilege::Direct)
+        .runner(runner.clone());
+    let state = mount
+        .mount(&generation, None, None, false, &NoProgress)
+        .unwrap();
+    assert_eq!(state.data, Some(data.clone()));
+    assert!(state.bind);
+    assert!(matches!(
+        mount.mount(&generation, None, None, false, &NoProgress),
+        Err(MountError::AlreadyMounted(_))
+    ));
+
+    mount.unmount(None).unwrap();
+    assert!(runner.finished());
+    assert!(!data.exists());
+    assert!(matches!(
+        mount.unmount(None),
+        Err(MountError::NotMounted(_))
+    ));
+}
+
+#[test]
+fn tarball_is_extracted() {
+    let installation = Installation::new("mount-tarball");
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(data_dir.join("alice/files")).unwrap();
+    fs::write(data_dir.join("alice/files/notes.md"), "notes").unwrap();
+    let tarball = installation
+        .backup_root()
+        .join("data-2025-01-02T02-30-00.tar.gz");
+    fs::create_dir_all(installation.backup_root()).unwrap();
+    write_tarball(
+        &tarball,
+        std::slice::from_ref(&data_dir),
+        &Excludes::default(),
+        &CompressionConfig::default(),
+        false,
+        &NoProgress,
+    )
+    .unwrap();
+
+    let mount_dir = installation.root.join("mnt");
+    let generation = Generation {
+        data: Some(backup("data", Artifact::File(tarball), day(2))),
+        database: None,
+    };
+    let mount = Mount::new(&mount_dir).runner(Arc::new(ScriptedRunner::new()));
+    let state = mount
+        .mount(&generation, None, None, false, &NoProgress)
+        .unwrap();
+    assert!(!state.bind);
+    // the archived data directory is extracted in place of the mount point
+    assert_eq!(
+        fs::read_to_string(mount_dir.join("data/alice/files/notes.md")).unwrap(),
+        "notes"
+    );
+
+    mount.unmount(None).unwrap();
+    assert!(!mount_dir.join("data").exists());
+}
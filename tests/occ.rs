@@ -0,0 +1,136 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::{expect_maintenance_off, expect_maintenance_on, Installation, STATUS};
+use nc_backup_lib::nextcloud::OccError;
+use nc_backup_lib::util::command::ScriptedRunner;
+
+#[test]
+fn status_is_parsed() {
+    let installation = Installation::new("occ-status");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["status", "--output=json"], STATUS);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let status = nextcloud.occ().status().unwrap();
+    assert!(status.installed);
+    assert!(!status.maintenance);
+    assert_eq!(status.version_string, "30.0.4");
+    assert!(runner.finished());
+}
+
+#[test]
+fn maintenance_guard_toggles_maintenance_mode() {
+    let installation = Installation::new("occ-maintenance");
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_maintenance_on(&runner);
+    expect_maintenance_off(&runner);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let guard = nextcloud.occ().maintenance_guard().unwrap();
+    assert_eq!(runner.calls().len(), 2);
+    guard.disable().unwrap();
+    assert!(runner.finished());
+}
+
+#[test]
+fn dropped_maintenance_guard_disables_maintenance_mode() {
+    let installation = Installation::new("occ-maintenance-drop");
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_maintenance_on(&runner);
+    expect_maintenance_off(&runner);
+    let nextcloud = installation.nextcloud(&runner);
+
+    drop(nextcloud.occ().maintenance_guard().unwrap());
+    assert!(runner.finished());
+}
+
+#[test]
+fn maintenance_mode_not_enabled_is_reverted() {
+    let installation = Installation::new("occ-maintenance-failed");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["maintenance:mode", "--on"], "").expect(
+        &["maintenance:mode"],
+        "Maintenance mode is currently disabled",
+    );
+    expect_maintenance_off(&runner);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let err = nextcloud.occ().maintenance_guard().unwrap_err();
+    assert!(matches!(
+        err,
+        OccError::MaintenanceNotToggled {
+            expected: "enabled"
+        }
+    ));
+    assert!(runner.finished());
+}
+
+#[test]
+fn transient_failure_is_retried() {
+    let installation = Installation::new("occ-transient");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect_exit(
+            &["status"],
+            1,
+            "",
+            "SQLSTATE[HY000] [2002] Connection refused",
+        )
+        .expect(&["status"], STATUS);
+    let nextcloud = installation.nextcloud(&runner);
+
+    nextcloud.occ().status().unwrap();
+    assert_eq!(runner.calls().len(), 2);
+}
+
+#[test]
+fn permanent_failure_is_not_retried() {
+    let installation = Installation::new("occ-permanent");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect_exit(&["status"], 1, "", "Command \"status\" is not defined.");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let err = nextcloud.occ().status().unwrap_err();
+    assert!(matches!(err, OccError::OccCommandFailed { .. }));
+    assert_eq!(runner.calls().len(), 1);
+}
+
+#[test]
+fn db_config_is_parsed() {
+    let installation = Installation::new("occ-db-config");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["config:list", "system", "--private"],
+        r#"{"system":{"dbtype":"mysql","dbhost":"db.example.org:3307","dbname":"nextcloud","dbuser":"nc","dbpassword":"secret"}}"#,
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let db = nextcloud.occ().db_config().unwrap();
+    assert_eq!(db.dbtype, "mysql");
+    assert_eq!(db.host.as_deref(), Some("db.example.org"));
+    assert_eq!(db.port, Some(3307));
+    assert_eq!(db.name, "nextcloud");
+    assert_eq!(db.user, "nc");
+    assert_eq!(db.password.as_deref(), Some("secret"));
+}
+
+#[test]
+fn app_list_is_parsed() {
+    let installation = Installation::new("occ-app-list");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["app:list", "--output=json"],
+        r#"{"enabled":{"calendar":"5.0.1","files":"2.3.0"},"disabled":[]}"#,
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let apps = nextcloud.occ().app_list().unwrap();
+    assert_eq!(
+        apps.enabled.keys().collect::<Vec<_>>(),
+        ["calendar", "files"]
+    );
+    assert_eq!(apps.enabled["calendar"], "5.0.1");
+    assert!(apps.disabled.is_empty());
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use common::Installation;
+use nc_backup_lib::backends::{Artifact, Pinner};
+use nc_backup_lib::util::artifact::{is_pinned, pin_path};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::privilege::Privilege;
+
+#[test]
+fn artifact_is_parsed_as_listed() {
+    let artifacts = [
+        Artifact::File("/backup/db/database-2025-01-01T02-30-00.sql.gz".into()),
+        Artifact::Directory("/backup/copy/data-2025-01-01T02-30-00".into()),
+        Artifact::Snapshot {
+            config: "nextcloud".into(),
+            id: 42,
+        },
+        Artifact::ZfsSnapshot("tank/nextcloud@nc_backup-2025-01-01T02-30-00".into()),
+    ];
+    for artifact in artifacts {
+        assert_eq!(artifact.to_string().parse::<Artifact>().unwrap(), artifact);
+    }
+
+    for invalid in ["", "snapper:nextcloud", "snapper:nextcloud:x", "zfs:tank"] {
+        assert!(invalid.parse::<Artifact>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn file_is_pinned_by_sidecar() {
+    let installation = Installation::new("pin-file");
+    let backup_root = installation.backup_root();
+    fs::create_dir_all(&backup_root).unwrap();
+    let dump = backup_root.join("database-2025-01-01T02-30-00.sql.gz");
+    fs::write(&dump, "dump").unwrap();
+    let artifact = Artifact::File(dump.clone());
+    let pinner = Pinner::new().runner(Arc::new(ScriptedRunner::new()));
+
+    pinner.set(&artifact, true, true).unwrap();
+    assert!(!is_pinned(&dump));
+    pinner.set(&artifact, true, false).unwrap();
+    assert!(is_pinned(&dump));
+    pinner.set(&artifact, false, false).unwrap();
+    assert!(!pin_path(&dump).exists());
+    // unpinning twice is fine
+    pinner.set(&artifact, false, false).unwrap();
+
+    let missing = Artifact::File(backup_root.join("database-2025-01-02T02-30-00.sql.gz"));
+    assert!(pinner.set(&missing, true, false).is_err());
+    assert_eq!(fs::read_dir(&backup_root).unwrap().count(), 1);
+}
+
+#[test]
+fn zfs_snapshot_is_pinned_by_property() {
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["zfs", "set", "nc_backup:pinned=true", "tank/nc@nc_backup-1"],
+            "",
+        )
+        .expect(
+            &["zfs", "inherit", "nc_backup:pinned", "tank/nc@nc_backup-1"],
+            "",
+        )
+        .expect_exit(&["zfs", "set"], 1, "", "dataset does not exist");
+    let pinner = Pinner::new()
+        .privilege(Privilege::Direct)
+        .runner(runner.clone());
+    let snapshot = Artifact::ZfsSnapshot("tank/nc@nc_backup-1".into());
+
+    pinner.set(&snapshot, true, false).unwrap();
+    pinner.set(&snapshot, false, false).unwrap();
+    let err = pinner.set(&snapshot, true, false).unwrap_err();
+    assert!(err.to_string().contains("dataset does not exist"), "{err}");
+    assert!(runner.finished());
+}
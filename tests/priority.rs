@@ -0,0 +1,38 @@
+use nc_backup_lib::util::command::{self, IoniceClass, PriorityConfig};
+use nc_backup_lib::util::privilege::Privilege;
+
+fn command_line(cmd: &std::process::Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+// the priority is set once per process, so this is the only test of this file
+#[test]
+fn commands_are_run_with_priority() {
+    command::set_priority(PriorityConfig {
+        nice: Some(10),
+        ionice_class: Some(IoniceClass::BestEffort),
+        ionice_level: Some(7),
+    });
+
+    let mut zstd = command::new("zstd");
+    zstd.arg("-q");
+    assert_eq!(
+        command_line(&zstd),
+        ["nice", "-n", "10", "ionice", "-t", "-c", "2", "-n", "7", "zstd", "-q"]
+    );
+
+    let send = Privilege::Sudo.command("btrfs", &[]);
+    let line = command_line(&send);
+    // the escalation inherits the priority unless running as root already
+    assert_eq!(
+        line[..9],
+        ["nice", "-n", "10", "ionice", "-t", "-c", "2", "-n", "7"]
+    );
+    assert_eq!(line.last().unwrap(), "btrfs");
+
+    let status = command::new("true").status().unwrap();
+    assert!(status.success());
+}
@@ -0,0 +1,641 @@
+mod common;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use chrono::{NaiveDateTime, TimeDelta};
+use common::{called_with, day, Installation};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nc_backup_lib::backends::snapper::SnapperVersion;
+use nc_backup_lib::backends::{Artifact, MariaDbError, MaskedSecrets};
+use nc_backup_lib::nextcloud::DbConfig;
+use nc_backup_lib::report::manifest::{self, ManifestConfig, ManifestFile, RunManifest};
+use nc_backup_lib::restore::config::{restore_config, ConfigRestore};
+use nc_backup_lib::restore::data::DataRestore;
+use nc_backup_lib::restore::database::DatabaseRestore;
+use nc_backup_lib::restore::plan::{Component, RestorePlan, RestorePoint};
+use nc_backup_lib::restore::version::{config_version, RestoreVersions, VersionError};
+use nc_backup_lib::util::archive::{write_tarball, Excludes};
+use nc_backup_lib::util::artifact::{secrets_path, ArtifactDir};
+use nc_backup_lib::util::checksum::HashingReader;
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::compress::CompressionConfig;
+use nc_backup_lib::util::encrypt::Encryption;
+use nc_backup_lib::util::generation::BackupGeneration;
+use nc_backup_lib::util::mask::{MaskedEntry, Masker, MaskingConfig};
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::tiering::TieringConfig;
+
+fn db_config(dbtype: &str) -> DbConfig {
+    DbConfig {
+        dbtype: dbtype.into(),
+        host: Some("localhost".into()),
+        port: None,
+        name: "nextcloud".into(),
+        user: "nextcloud".into(),
+        password: Some("secret".into()),
+        socket: None,
+    }
+}
+
+#[test]
+fn dry_run_leaves_database_alone() {
+    let runner = Arc::new(ScriptedRunner::new());
+    let restorer = DatabaseRestore::new()
+        .admin_user("root".into())
+        .runner(runner.clone());
+    let dump = Path::new("db/database-2025-01-01T02-30-00.sql.gz");
+
+    restorer
+        .restore(dump, &db_config("mysql"), true, &NoProgress)
+        .unwrap();
+    assert!(runner.calls().is_empty());
+
+    let error = restorer
+        .restore(dump, &db_config("pgsql"), true, &NoProgress)
+        .unwrap_err();
+    assert!(matches!(error, MariaDbError::UnsupportedDatabase(dbtype) if dbtype == "pgsql"));
+}
+
+#[test]
+fn database_is_dropped_as_admin_user() {
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["mariadb-dump", "--version"],
+            "mariadb-dump from 11.4.2-MariaDB",
+        )
+        .expect_exit(
+            &["mariadb", "DROP DATABASE IF EXISTS `nextcloud`"],
+            1,
+            "",
+            "ERROR 1044 (42000): Access denied",
+        );
+
+    let error = DatabaseRestore::new()
+        .admin_user("root".into())
+        .runner(runner.clone())
+        .restore(
+            Path::new("db/database-2025-01-01T02-30-00.sql.gz"),
+            &db_config("mysql"),
+            false,
+            &NoProgress,
+        )
+        .unwrap_err();
+
+    assert!(matches!(error, MariaDbError::QueryFailed(..)));
+    assert!(runner.finished());
+    let drop = runner
+        .calls()
+        .into_iter()
+        .find(|call| call[0] == "mariadb")
+        .unwrap();
+    assert!(called_with(&drop, &["--user=root"]));
+    assert!(!called_with(&drop, &["--user=nextcloud"]));
+}
+
+fn versions(installed: &str, manifest: Option<&str>, config: Option<&str>) -> RestoreVersions {
+    RestoreVersions {
+        installed: installed.into(),
+        manifest: manifest.map(Into::into),
+        config: config.map(Into::into),
+    }
+}
+
+#[test]
+fn restore_across_versions_is_refused() {
+    versions("30.0.4.1", Some("30.0.4.1"), Some("30.0.4.1"))
+        .check()
+        .unwrap();
+    versions("30.0.4", None, Some("30.0.4.0")).check().unwrap();
+
+    let error = versions("30.0.4.1", Some("31.0.0.18"), None)
+        .check()
+        .unwrap_err();
+    assert!(matches!(error, VersionError::Downgrade { backup, .. } if backup == "31.0.0.18"));
+    let error = versions("30.0.10.1", None, Some("30.0.4.1"))
+        .check()
+        .unwrap_err();
+    assert!(matches!(error, VersionError::Upgrade { installed, .. } if installed == "30.0.10.1"));
+    let error = versions("30.0.4.1", Some("30.0.4.1"), Some("29.0.9.2"))
+        .check()
+        .unwrap_err();
+    assert!(matches!(error, VersionError::Inconsistent { .. }));
+    let error = versions("30.0.4.1", None, None).check().unwrap_err();
+    assert!(matches!(error, VersionError::Unknown));
+}
+
+#[test]
+fn version_is_read_from_config_backup() {
+    let installation = Installation::new("restore-config-version");
+    fs::write(
+        installation.root.join("config/config.php"),
+        "<?php\n$CONFIG = array (\n  'dbpassword' => 'DBPASSWORD',\n  'version' => '30.0.4.1',\n);\n",
+    )
+    .unwrap();
+    let backup_root = installation.backup_root();
+    fs::create_dir_all(&backup_root).unwrap();
+    let config_backup = backup_root.join("config-2025-01-01T02-30-00.tar.gz");
+    write_tarball(
+        &config_backup,
+        &[installation.root.join("config")],
+        &Excludes::default(),
+        &CompressionConfig::default(),
+        false,
+        &NoProgress,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config_version(&config_backup).unwrap().as_deref(),
+        Some("30.0.4.1")
+    );
+}
+
+fn run(created: NaiveDateTime, files: &[&str], artifacts: &[&str]) -> (PathBuf, RunManifest) {
+    let manifest = RunManifest {
+        created,
+        generation: None,
+        files: files
+            .iter()
+            .map(|path| ManifestFile {
+                path: path.into(),
+                size: 0,
+                sha256: String::new(),
+            })
+            .collect(),
+        artifacts: artifacts.iter().map(ToString::to_string).collect(),
+        nextcloud_version: None,
+        apps: None,
+        appdata: None,
+        changes: Vec::new(),
+    };
+    (format!("manifests/run-{created}.json").into(), manifest)
+}
+
+#[test]
+fn components_are_restored_from_latest_run_backing_them_up() {
+    let catalog = [
+        run(
+            day(1),
+            &[
+                "/backup/config/config-2025-01-01T02-30-00.tar.gz",
+                "/backup/db/database-2025-01-01T02-30-00.sql.gz",
+                "/backup/apps/apps-2025-01-01T02-30-00.tar.gz",
+            ],
+            &["snapper:nextcloud:41", "snapper:nextcloud-alice:7"],
+        ),
+        run(
+            day(2),
+            &["/backup/db/database-2025-01-02T02-30-00.sql.gz"],
+            &[],
+        ),
+    ];
+    let all = [Component::Db, Component::Config, Component::Data];
+
+    let plan = RestorePlan::select(&catalog, RestorePoint::Time(day(3)), &all).unwrap();
+    assert_eq!(
+        plan.database,
+        Some("/backup/db/database-2025-01-02T02-30-00.sql.gz".into())
+    );
+    assert_eq!(
+        plan.config,
+        Some("/backup/config/config-2025-01-01T02-30-00.tar.gz".into())
+    );
+    assert_eq!(
+        plan.data,
+        [
+            Artifact::Snapshot {
+                config: "nextcloud".into(),
+                id: 41
+            },
+            Artifact::Snapshot {
+                config: "nextcloud-alice".into(),
+                id: 7
+            },
+        ]
+    );
+
+    let point: RestorePoint = "2025-01-01T02-30-00".parse().unwrap();
+    assert_eq!(point, RestorePoint::Run(day(1)));
+    let plan = RestorePlan::select(&catalog, point, &[Component::Db]).unwrap();
+    assert_eq!(
+        plan.database,
+        Some("/backup/db/database-2025-01-01T02-30-00.sql.gz".into())
+    );
+    assert_eq!(plan.config, None);
+
+    let point = "2025-01-02T02:00:00".parse().unwrap();
+    let plan = RestorePlan::select(&catalog, point, &[Component::Db]).unwrap();
+    assert_eq!(
+        plan.database,
+        Some("/backup/db/database-2025-01-01T02-30-00.sql.gz".into())
+    );
+    // the second run didn't back up the data directory
+    let error =
+        RestorePlan::select(&catalog, RestorePoint::Run(day(2)), &[Component::Data]).unwrap_err();
+    assert_eq!(error.component, Component::Data);
+    assert!("yesterday".parse::<RestorePoint>().is_err());
+}
+
+#[test]
+fn generation_is_restored_from_its_run_only() {
+    let generation = BackupGeneration::new(Clock::Fixed(day(1)));
+    let (path, mut manifest) = run(
+        day(1),
+        &[
+            &format!("/backup/config/config-2025-01-01T02-30-00_{generation}.tar.gz"),
+            &format!("/backup/db/database-2025-01-01T02-30-00_{generation}.sql.gz"),
+        ],
+        &[],
+    );
+    manifest.generation = Some(generation);
+    let catalog = [
+        (path, manifest),
+        run(
+            day(2),
+            &["/backup/db/database-2025-01-02T02-30-00.sql.gz"],
+            &["snapper:nextcloud:42"],
+        ),
+    ];
+
+    let point: RestorePoint = generation.to_string().parse().unwrap();
+    assert_eq!(point, RestorePoint::Generation(generation));
+    let plan = RestorePlan::select(&catalog, point, &[Component::Db, Component::Config]).unwrap();
+    assert_eq!(
+        plan.database,
+        Some(format!("/backup/db/database-2025-01-01T02-30-00_{generation}.sql.gz").into())
+    );
+    assert!(plan.config.is_some());
+
+    // the snapshot of another run isn't mixed into the generation
+    let error = RestorePlan::select(&catalog, point, &[Component::Data]).unwrap_err();
+    assert_eq!(error.component, Component::Data);
+}
+
+#[test]
+fn config_is_restored_keeping_live_secrets() {
+    let installation = Installation::new("restore-config");
+    let backup_root = installation.backup_root();
+    let archived = backup_root.join("archived/config");
+    fs::create_dir_all(&archived).unwrap();
+    fs::write(
+        archived.join("config.php"),
+        "<?php\n$CONFIG = array (\n  'dbpassword' => 'DBPASSWORD',\n  'version' => '30.0.4.1',\n);\n",
+    )
+    .unwrap();
+    fs::write(archived.join("custom.json"), "{}").unwrap();
+    let config_backup = backup_root.join("config-2025-01-01T02-30-00.tar.gz");
+    write_tarball(
+        &config_backup,
+        &[archived],
+        &Excludes::default(),
+        &CompressionConfig::default(),
+        false,
+        &NoProgress,
+    )
+    .unwrap();
+
+    let config_dir = installation.root.join("config");
+    let masker = Masker::new(&MaskingConfig::default()).unwrap();
+    let restored = restore_config(&config_backup, &config_dir, &masker, false).unwrap();
+
+    assert_eq!(restored.len(), 2);
+    let config = fs::read_to_string(config_dir.join("config.php")).unwrap();
+    assert!(config.contains("'dbpassword' => 'secret',"));
+    assert!(config.contains("'version' => '30.0.4.1',"));
+    assert_eq!(
+        fs::read_to_string(config_dir.join("custom.json")).unwrap(),
+        "{}"
+    );
+}
+
+/// Runs `gpg` with the keyring in `homedir`, `false` if it isn't installed.
+fn gpg(homedir: &Path, args: &[&str]) -> bool {
+    let status = Command::new("gpg")
+        .arg("--homedir")
+        .arg(homedir)
+        .arg("--batch")
+        .args(args)
+        .status();
+    match status {
+        Ok(status) => {
+            assert!(status.success(), "gpg {args:?} failed with {status}");
+            true
+        }
+        Err(_) => {
+            eprintln!("gpg isn't installed, skipping");
+            false
+        }
+    }
+}
+
+#[test]
+fn config_is_restored_unmasked_from_secrets_sidecar() {
+    let installation = Installation::new("restore-config-secrets");
+    let backup_root = installation.backup_root();
+    let homedir = installation.root.join("gnupg");
+    fs::create_dir_all(&homedir).unwrap();
+    fs::set_permissions(&homedir, fs::Permissions::from_mode(0o700)).unwrap();
+    let recipient = "nc_backup@example.org";
+    let generated = gpg(
+        &homedir,
+        &[
+            "--passphrase",
+            "",
+            "--quick-gen-key",
+            recipient,
+            "future-default",
+            "default",
+            "never",
+        ],
+    );
+    if !generated {
+        return;
+    }
+
+    let archived = backup_root.join("archived/config");
+    fs::create_dir_all(&archived).unwrap();
+    fs::write(
+        archived.join("config.php"),
+        "<?php\n$CONFIG = array (\n  'dbpassword' => 'DBPASSWORD',\n  'secret' => 'SECRET',\n);\n",
+    )
+    .unwrap();
+    let config_backup = backup_root.join("config-2025-01-01T02-30-00.tar.gz");
+    write_tarball(
+        &config_backup,
+        std::slice::from_ref(&archived),
+        &Excludes::default(),
+        &CompressionConfig::default(),
+        false,
+        &NoProgress,
+    )
+    .unwrap();
+    let name = archived.join("config.php");
+    let name = name.strip_prefix("/").unwrap().to_string_lossy();
+    let secrets = MaskedSecrets::from([(
+        name.into_owned(),
+        vec![
+            MaskedEntry {
+                key: "dbpassword".into(),
+                value: "'db-secret'".into(),
+            },
+            MaskedEntry {
+                key: "secret".into(),
+                value: "'instance-secret'".into(),
+            },
+        ],
+    )]);
+    let plaintext = backup_root.join("secrets.json");
+    fs::write(&plaintext, serde_json::to_vec(&secrets).unwrap()).unwrap();
+    let encryption = Encryption::Gpg {
+        recipients: vec![recipient.into()],
+    };
+    let sidecar = secrets_path(&config_backup, encryption.tool());
+    let sidecar_str = sidecar.to_str().unwrap();
+    let plaintext_str = plaintext.to_str().unwrap();
+    gpg(
+        &homedir,
+        &[
+            "--trust-model",
+            "always",
+            "--recipient",
+            recipient,
+            "--output",
+            sidecar_str,
+            "--encrypt",
+            plaintext_str,
+        ],
+    );
+
+    let config_dir = installation.root.join("config");
+    let masker = Masker::new(&MaskingConfig {
+        instance_secrets: true,
+        ..Default::default()
+    })
+    .unwrap();
+    let restored = ConfigRestore::new(masker)
+        .secrets(encryption, homedir.clone())
+        .restore(&config_backup, &config_dir, false);
+    let _ = Command::new("gpgconf")
+        .arg("--homedir")
+        .arg(&homedir)
+        .args(["--kill", "gpg-agent"])
+        .status();
+
+    assert_eq!(restored.unwrap().len(), 1);
+    let config = fs::read_to_string(config_dir.join("config.php")).unwrap();
+    // the live dbpassword 'secret' is overwritten by the one of the sidecar
+    assert!(config.contains("'dbpassword' => 'db-secret',"));
+    assert!(config.contains("'secret' => 'instance-secret',"));
+}
+
+/// The file `path` as listed by a run manifest.
+fn listed(path: &Path) -> ManifestFile {
+    let mut reader = HashingReader::new(File::open(path).unwrap());
+    let size = io::copy(&mut reader, &mut io::sink()).unwrap();
+    ManifestFile {
+        path: path.to_path_buf(),
+        size,
+        sha256: reader.hex_digest(),
+    }
+}
+
+#[test]
+fn tiered_backups_are_restored_from_cold_storage() {
+    let installation = Installation::new("restore-tiered");
+    let backup_root = installation.backup_root();
+    let archived = backup_root.join("archived/config");
+    fs::create_dir_all(&archived).unwrap();
+    fs::write(
+        archived.join("config.php"),
+        "<?php\n$CONFIG = array (\n  'version' => '30.0.4.1',\n);\n",
+    )
+    .unwrap();
+    fs::create_dir_all(backup_root.join("config")).unwrap();
+    let config_backup = backup_root.join("config/config-2025-01-01T02-30-00.tar.gz");
+    write_tarball(
+        &config_backup,
+        &[archived],
+        &Excludes::default(),
+        &CompressionConfig::default(),
+        false,
+        &NoProgress,
+    )
+    .unwrap();
+    fs::create_dir_all(backup_root.join("db")).unwrap();
+    let dump = backup_root.join("db/database-2025-01-01T02-30-00.sql.gz");
+    let mut encoder = GzEncoder::new(File::create(&dump).unwrap(), Compression::default());
+    encoder
+        .write_all(b"CREATE TABLE oc_test (id INT);\n")
+        .unwrap();
+    encoder.finish().unwrap();
+    let (_, mut manifest) = run(day(1), &[], &[]);
+    manifest.files = vec![listed(&dump), listed(&config_backup)];
+    let manifest_path =
+        manifest::write(&manifest, &backup_root, &ManifestConfig::default()).unwrap();
+
+    let tiering = TieringConfig {
+        after_days: 1,
+        destination: installation.root.join("cold"),
+    };
+    for (dir, prefix, suffix) in [
+        ("db", "database-", ".sql.gz"),
+        ("config", "config-", ".tar.gz"),
+    ] {
+        ArtifactDir::new(backup_root.join(dir), prefix, suffix)
+            .with_cold_tier(tiering.destination.join(dir), TimeDelta::days(1))
+            .tier(false)
+            .unwrap();
+    }
+    assert!(!dump.exists());
+    assert!(!config_backup.exists());
+
+    let verification =
+        manifest::verify_tiered(&manifest_path, None, &backup_root, &tiering).unwrap();
+    assert_eq!(verification.verified.len(), 2);
+    assert!(verification.missing.is_empty());
+
+    let catalog = manifest::catalog(&backup_root).unwrap();
+    let plan = RestorePlan::select(
+        &catalog,
+        RestorePoint::Time(day(2)),
+        &[Component::Db, Component::Config],
+    )
+    .unwrap()
+    .locate(&backup_root, &tiering);
+    let cold_dump = tiering
+        .destination
+        .join("db/database-2025-01-01T02-30-00.sql.gz");
+    assert_eq!(plan.database.as_ref(), Some(&cold_dump));
+    let mut sql = String::new();
+    MultiGzDecoder::new(File::open(&cold_dump).unwrap())
+        .read_to_string(&mut sql)
+        .unwrap();
+    assert_eq!(sql, "CREATE TABLE oc_test (id INT);\n");
+    DatabaseRestore::new()
+        .runner(Arc::new(ScriptedRunner::new()))
+        .restore(&cold_dump, &db_config("mysql"), true, &NoProgress)
+        .unwrap();
+
+    let config_dir = installation.root.join("config");
+    let masker = Masker::new(&MaskingConfig::default()).unwrap();
+    let config = plan.config.unwrap();
+    assert!(config.starts_with(&tiering.destination));
+    restore_config(&config, &config_dir, &masker, false).unwrap();
+    let restored = fs::read_to_string(config_dir.join("config.php")).unwrap();
+    assert!(restored.contains("'version' => '30.0.4.1',"));
+}
+
+#[test]
+fn data_is_restored_from_copy() {
+    let installation = Installation::new("restore-data");
+    let copy = installation
+        .backup_root()
+        .join("copy/data-2025-01-01T02-30-00");
+    fs::create_dir_all(copy.join("alice/files")).unwrap();
+    fs::write(copy.join("alice/files/notes.md"), "notes").unwrap();
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(data_dir.join("alice/files")).unwrap();
+    fs::write(data_dir.join("alice/files/notes.md"), "overwritten").unwrap();
+
+    DataRestore::new()
+        .runner(Arc::new(ScriptedRunner::new()))
+        .restore(&[Artifact::Directory(copy)], &data_dir, false, &NoProgress)
+        .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(data_dir.join("alice/files/notes.md")).unwrap(),
+        "notes"
+    );
+}
+
+#[test]
+fn snapshot_of_parent_subvolume_restores_data_dir_only() {
+    if !SnapperVersion::detect().supports_jsonout() {
+        eprintln!("snapper of this host doesn't support --jsonout, skipping");
+        return;
+    }
+    let installation = Installation::new("restore-data-parent-subvolume");
+    let snapshot = installation.root.join(".snapshots/42/snapshot");
+    fs::create_dir_all(snapshot.join("data/alice/files")).unwrap();
+    fs::write(snapshot.join("data/alice/files/notes.md"), "notes").unwrap();
+    fs::create_dir_all(snapshot.join("config")).unwrap();
+    fs::write(snapshot.join("config/config.php"), "<?php // snapshot\n").unwrap();
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(data_dir.join("alice/files")).unwrap();
+    fs::write(data_dir.join("alice/files/notes.md"), "overwritten").unwrap();
+    let config = fs::read_to_string(installation.root.join("config/config.php")).unwrap();
+
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["--jsonout", "-c", "root", "get-config"],
+        &format!(r#"{{"SUBVOLUME":{:?}}}"#, installation.root),
+    );
+    runner.expect(
+        &["-c", "root", "list"],
+        r#"{"root":[{"number":42,"userdata":{"nc_backup":"true"},"cleanup":"","date":"2025-01-01 02:30:00","description":"Full Nextcloud Backup"}]}"#,
+    );
+    let artifact = Artifact::Snapshot {
+        config: "root".into(),
+        id: 42,
+    };
+    DataRestore::new()
+        .runner(runner.clone())
+        .restore(&[artifact], &data_dir, false, &NoProgress)
+        .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(data_dir.join("alice/files/notes.md")).unwrap(),
+        "notes"
+    );
+    // the rest of the subvolume is left untouched
+    assert_eq!(
+        fs::read_to_string(installation.root.join("config/config.php")).unwrap(),
+        config
+    );
+    assert!(!data_dir.join("data").exists());
+    assert!(runner.finished());
+}
+
+#[test]
+fn tarball_is_restored_into_data_dir() {
+    let installation = Installation::new("restore-data-tarball");
+    let old_data_dir = installation.root.join("old-data");
+    fs::create_dir_all(old_data_dir.join("alice/files")).unwrap();
+    fs::write(old_data_dir.join("alice/files/notes.md"), "notes").unwrap();
+    let backup_root = installation.backup_root();
+    fs::create_dir_all(&backup_root).unwrap();
+    let tarball = backup_root.join("data-2025-01-01T02-30-00.tar.gz");
+    write_tarball(
+        &tarball,
+        std::slice::from_ref(&old_data_dir),
+        &Excludes::default(),
+        &CompressionConfig::default(),
+        false,
+        &NoProgress,
+    )
+    .unwrap();
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(data_dir.join("alice/files")).unwrap();
+    fs::write(data_dir.join("alice/files/notes.md"), "overwritten").unwrap();
+
+    DataRestore::new()
+        .runner(Arc::new(ScriptedRunner::new()))
+        .restore(&[Artifact::File(tarball)], &data_dir, false, &NoProgress)
+        .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(data_dir.join("alice/files/notes.md")).unwrap(),
+        "notes"
+    );
+    // nothing is extracted to the archived paths below the data directory
+    assert_eq!(fs::read_dir(&data_dir).unwrap().count(), 1);
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use std::cell::Cell;
+use std::io;
+
+use common::Installation;
+use nc_backup_lib::util::retry::{is_transient, retry_io, RetryConfig, RetryPolicy};
+
+/// Retry `attempts` times in total without delay.
+fn config(attempts: u32) -> RetryConfig {
+    RetryConfig {
+        attempts,
+        backoff_secs: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn flaky_device_and_network_errors_are_transient() {
+    for kind in [
+        io::ErrorKind::TimedOut,
+        io::ErrorKind::StaleNetworkFileHandle,
+        io::ErrorKind::ConnectionReset,
+        io::ErrorKind::NetworkUnreachable,
+    ] {
+        assert!(is_transient(&kind.into()), "{kind:?} should be transient");
+    }
+    // EIO
+    assert!(is_transient(&io::Error::from_raw_os_error(5)));
+}
+
+#[test]
+fn persistent_errors_are_not_transient() {
+    for kind in [
+        io::ErrorKind::NotFound,
+        io::ErrorKind::PermissionDenied,
+        io::ErrorKind::AlreadyExists,
+        io::ErrorKind::StorageFull,
+    ] {
+        assert!(
+            !is_transient(&kind.into()),
+            "{kind:?} shouldn't be transient"
+        );
+    }
+}
+
+#[test]
+fn transient_error_is_retried() {
+    let attempts = Cell::new(0);
+    let result = retry_io(&config(3), "write", || {
+        attempts.set(attempts.get() + 1);
+        match attempts.get() {
+            1 => Err(io::ErrorKind::TimedOut.into()),
+            _ => Ok("written"),
+        }
+    });
+
+    assert_eq!(result.unwrap(), "written");
+    assert_eq!(attempts.get(), 2);
+}
+
+#[test]
+fn persistent_error_is_not_retried() {
+    let attempts = Cell::new(0);
+    let result: io::Result<()> = retry_io(&config(3), "write", || {
+        attempts.set(attempts.get() + 1);
+        Err(io::ErrorKind::PermissionDenied.into())
+    });
+
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(attempts.get(), 1);
+}
+
+#[test]
+fn last_error_is_returned_after_all_attempts() {
+    let attempts = Cell::new(0);
+    let result: io::Result<()> = retry_io(&config(3), "write", || {
+        attempts.set(attempts.get() + 1);
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("attempt {}", attempts.get()),
+        ))
+    });
+
+    assert_eq!(result.unwrap_err().to_string(), "attempt 3");
+    assert_eq!(attempts.get(), 3);
+}
+
+#[test]
+fn remount_command_runs_before_every_retry() {
+    let installation = Installation::new("retry_remount");
+    let remounts = installation.root.join("remounts");
+    let config = RetryConfig {
+        remount_command: Some(format!("echo >> '{}'", remounts.display())),
+        ..config(3)
+    };
+
+    let _ = retry_io(&config, "write", || -> io::Result<()> {
+        Err(io::ErrorKind::TimedOut.into())
+    });
+
+    assert_eq!(
+        std::fs::read_to_string(remounts).unwrap().lines().count(),
+        2
+    );
+}
+
+#[test]
+fn policy_overrides_attempts_per_operation() {
+    let mut config = config(3);
+    config.remount_command = Some("true".into());
+    config.operations.insert(
+        "occ".into(),
+        RetryPolicy {
+            attempts: Some(5),
+            backoff_secs: None,
+        },
+    );
+
+    let occ = config.policy("occ");
+    assert_eq!(occ.attempts, 5);
+    assert_eq!(occ.backoff_secs, 0);
+    assert_eq!(occ.remount_command, None);
+    assert_eq!(config.policy("sync").attempts, 3);
+}
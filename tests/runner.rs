@@ -0,0 +1,153 @@
+mod common;
+
+use std::fs;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use common::{called_with, expect_maintenance_off, expect_maintenance_on, Installation, STATUS};
+use nc_backup_lib::backends::{Artifact, BackupError, Config};
+use nc_backup_lib::nextcloud::StatusError;
+use nc_backup_lib::runner::{BackupRunner, Hooks, Job, RunnerError};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::encrypt::Encryption;
+
+#[test]
+fn jobs_requiring_maintenance_run_in_maintenance_mode() {
+    let installation = Installation::new("runner-maintenance");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["status"], STATUS);
+    expect_maintenance_on(&runner);
+    runner.expect(
+        &["maintenance:mode"],
+        "Maintenance mode is currently enabled",
+    );
+    expect_maintenance_off(&runner);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let report = BackupRunner::new(nextcloud)
+        .concurrency(NonZeroUsize::new(1))
+        .job(Job::new("db", true, |nextcloud, _dry_run| {
+            let maintenance = nextcloud.occ().maintenance().map_err(Box::from);
+            assert!(maintenance.map_err(BackupError::Other)?);
+            Ok(Vec::new())
+        }))
+        .job(Job::backup(
+            "config",
+            Box::new(Config::new(&installation.backup_root())),
+        ))
+        .run()
+        .unwrap();
+
+    assert!(report.success());
+    assert!(runner.finished());
+    assert_eq!(
+        report.nextcloud.map(|status| status.version_string),
+        Some("30.0.4".into())
+    );
+
+    let [Artifact::File(config)] = report.jobs[1].artifacts.as_slice() else {
+        panic!("config job should create a single file");
+    };
+    assert!(config.starts_with(installation.backup_root()));
+    assert!(config.is_file());
+}
+
+#[test]
+fn failing_job_leaves_maintenance_mode() {
+    let installation = Installation::new("runner-failing");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["status"], STATUS);
+    expect_maintenance_on(&runner);
+    expect_maintenance_off(&runner);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let report = BackupRunner::new(nextcloud)
+        .job(Job::new("db", true, |_nextcloud, _dry_run| {
+            Err(BackupError::Other("dump failed".into()))
+        }))
+        .run()
+        .unwrap();
+
+    assert!(!report.success());
+    assert_eq!(report.failed().count(), 1);
+    assert!(runner.finished());
+}
+
+#[test]
+fn jobs_without_maintenance_keep_maintenance_mode() {
+    let installation = Installation::new("runner-no-maintenance");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["status"], STATUS);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let report = BackupRunner::new(nextcloud)
+        .dry_run(true)
+        .job(Job::backup(
+            "config",
+            Box::new(Config::new(&installation.backup_root())),
+        ))
+        .run()
+        .unwrap();
+
+    assert!(report.success());
+    assert!(report.jobs[0].artifacts.is_empty());
+    let calls = runner.calls();
+    assert_eq!(calls.len(), 1);
+    assert!(called_with(&calls[0], &["status", "--output=json"]));
+}
+
+#[test]
+fn failing_preflight_runs_post_hook() {
+    let installation = Installation::new("runner-preflight-hook");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["status"], STATUS);
+    let nextcloud = installation.nextcloud(&runner);
+    let pre = installation.root.join("pre");
+    let post = installation.root.join("post");
+    let config = Config::new(&installation.backup_root()).secrets(Encryption::Age {
+        recipients: Vec::new(),
+    });
+
+    let error = BackupRunner::new(nextcloud)
+        .hooks(Hooks {
+            pre: Some(format!("touch '{}'", pre.display())),
+            post: Some(format!(
+                "printf '%s' \"$NC_BACKUP_STATUS: $NC_BACKUP_ERROR\" > '{}'",
+                post.display()
+            )),
+        })
+        .job(Job::backup("config", Box::new(config)))
+        .run()
+        .unwrap_err();
+
+    assert!(matches!(error, RunnerError::Preflight { job, .. } if job == "config"));
+    assert!(pre.is_file());
+    let post = fs::read_to_string(post).unwrap();
+    assert!(post.starts_with("failure: Preflight check of config failed"));
+    assert!(runner.finished());
+}
+
+#[test]
+fn status_is_checked_without_preflight() {
+    let installation = Installation::new("runner-status-no-preflight");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["status"],
+        &STATUS.replace("\"needsDbUpgrade\":false", "\"needsDbUpgrade\":true"),
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let error = BackupRunner::new(nextcloud)
+        .preflight(false)
+        .job(Job::new("db", true, |_nextcloud, _dry_run| {
+            panic!("job shouldn't run")
+        }))
+        .run()
+        .unwrap_err();
+
+    assert!(matches!(
+        error,
+        RunnerError::Status(StatusError::NeedsDbUpgrade(_))
+    ));
+    assert!(runner.finished());
+}
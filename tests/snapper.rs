@@ -0,0 +1,542 @@
+mod common;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use common::Installation;
+use nc_backup_lib::backends::snapper::{
+    stream_files, SendConfig, Snapper, SnapperBackupError, SnapperConfig, SnapperConfigError,
+    SnapperVersion,
+};
+use nc_backup_lib::backends::{Artifact, Backup};
+use nc_backup_lib::util::artifact::pin_path;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::privilege::Privilege;
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+/// Output of `snapper --jsonout list-configs`.
+const CONFIGS: &str = r#"{"configs":[{"config":"root","subvolume":"/"},{"config":"nextcloud","subvolume":"/srv/nextcloud"}]}"#;
+
+/// Output of `snapper --jsonout -c nextcloud list`.
+const SNAPSHOTS: &str = r#"{"nextcloud":[
+    {"number":0,"userdata":null,"cleanup":"","date":"","description":"current"},
+    {"number":41,"userdata":{"nc_backup":"true"},"cleanup":"number","date":"2025-01-01 02:30:00","description":"Full Nextcloud Backup"},
+    {"number":42,"userdata":{"nc_backup":"true"},"cleanup":"","date":"2025-01-02 02:30:00","description":"Full Nextcloud Backup"}
+]}"#;
+
+/// The scripted output is `--jsonout`, which the detected snapper has to support.
+fn jsonout() -> bool {
+    let supported = SnapperVersion::detect().supports_jsonout();
+    if !supported {
+        eprintln!("snapper of this host doesn't support --jsonout, skipping");
+    }
+    supported
+}
+
+fn config(runner: &Arc<ScriptedRunner>) -> SnapperConfig {
+    runner.expect(&["--jsonout", "list-configs"], CONFIGS);
+    SnapperConfig::by_dir(
+        Path::new("/srv/nextcloud"),
+        Privilege::Direct,
+        runner.clone(),
+    )
+    .unwrap()
+    .unwrap()
+}
+
+#[test]
+fn config_is_found_by_dir() {
+    if !jsonout() {
+        return;
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+
+    let config = config(&runner);
+    assert_eq!(config.config_id(), "nextcloud");
+    assert_eq!(config.subvolume(), Path::new("/srv/nextcloud"));
+    assert!(runner.finished());
+}
+
+#[test]
+fn config_of_innermost_subvolume_is_found() {
+    if !jsonout() {
+        return;
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(&["list-configs"], CONFIGS);
+
+    let config = SnapperConfig::by_dir(
+        Path::new("/srv/nextcloud/data"),
+        Privilege::Direct,
+        runner.clone(),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(config.config_id(), "nextcloud");
+}
+
+#[test]
+fn unknown_dir_has_no_config() {
+    if !jsonout() {
+        return;
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["list-configs"],
+        r#"{"configs":[{"config":"nextcloud","subvolume":"/srv/nextcloud"}]}"#,
+    );
+
+    let config =
+        SnapperConfig::by_dir(Path::new("/srv/other"), Privilege::Direct, runner.clone()).unwrap();
+    assert!(config.is_none());
+}
+
+#[test]
+fn snapshots_are_listed() {
+    if !jsonout() {
+        return;
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    let config = config(&runner);
+    runner.expect(&["-c", "nextcloud", "list"], SNAPSHOTS);
+
+    let snapshots = config.snapshots().unwrap();
+    // the current state has no date
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(
+        snapshots[0]
+            .user_data()
+            .get("nc_backup")
+            .map(String::as_str),
+        Some("true")
+    );
+    assert_eq!(
+        snapshots[1].snapshot_path(),
+        Path::new("/srv/nextcloud/.snapshots/42/snapshot")
+    );
+}
+
+#[test]
+fn created_snapshot_is_returned() {
+    if !jsonout() {
+        return;
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    let config = config(&runner);
+    runner
+        .expect(&["-c", "nextcloud", "create", "-p"], "42\n")
+        .expect(&["-c", "nextcloud", "list"], SNAPSHOTS)
+        .expect(&["-c", "nextcloud", "delete", "42"], "");
+
+    let snapshot = config.create_snapshot(None).unwrap();
+    assert_eq!(snapshot.date().to_string(), "2025-01-02 02:30:00");
+    snapshot.delete().unwrap();
+    assert!(runner.finished());
+}
+
+#[test]
+fn missing_created_snapshot_is_error() {
+    if !jsonout() {
+        return;
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    let config = config(&runner);
+    runner
+        .expect(&["create"], "43\n")
+        .expect(&["list"], SNAPSHOTS);
+
+    let err = config.create_snapshot(None).unwrap_err();
+    assert!(matches!(err, SnapperConfigError::SnapshotNotFound(43)));
+}
+
+#[test]
+fn failing_snapper_is_error() {
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect_exit(&["create-config"], 1, "", "Creating config failed.");
+
+    let err = SnapperConfig::new(
+        "/srv/nextcloud".into(),
+        "nextcloud".into(),
+        Privilege::Direct,
+        runner.clone(),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        SnapperConfigError::SnapperCommandFailed { .. }
+    ));
+}
+
+#[test]
+fn relative_sync_destination_is_rejected() {
+    let err = Snapper::builder()
+        .sync_destination("offsite".into())
+        .build()
+        .unwrap_err();
+    assert!(matches!(err, SnapperBackupError::InvalidConfig(..)));
+}
+
+#[test]
+fn duplicate_sync_destination_is_rejected() {
+    let err = Snapper::builder()
+        .sync_destination("/mnt/offsite".into())
+        .sync_destination("/mnt/offsite".into())
+        .build()
+        .unwrap_err();
+    assert!(matches!(err, SnapperBackupError::InvalidConfig(..)));
+}
+
+#[test]
+fn configured_config_is_used() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-config-id");
+    let data_dir = installation.root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(
+            &["-c", "nextcloud", "get-config"],
+            r#"{"SUBVOLUME":"/srv/nextcloud"}"#,
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let snapper = Snapper::builder()
+        .config_id("nextcloud".into())
+        .privilege(Privilege::Direct)
+        .runner(runner.clone())
+        .build()
+        .unwrap();
+    snapper.preflight(&nextcloud).unwrap();
+    assert!(runner.finished());
+}
+
+#[test]
+fn nested_subvolumes_are_snapshotted() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-nested");
+    let data_dir = installation.root.join("data");
+    std::fs::create_dir_all(data_dir.join("alice")).unwrap();
+    let configs = serde_json::json!({"configs": [
+        {"config": "root", "subvolume": "/"},
+        {"config": "nextcloud", "subvolume": data_dir},
+        {"config": "alice", "subvolume": data_dir.join("alice")},
+    ]})
+    .to_string();
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect_exit(&["config:system:get", "objectstore"], 1, "", "")
+        .expect(&["list-configs"], &configs)
+        .expect(&["list-configs"], &configs)
+        .expect(&["-c", "nextcloud", "create", "-p"], "42\n")
+        .expect(&["-c", "nextcloud", "list"], SNAPSHOTS)
+        .expect(&["-c", "alice", "create", "-p"], "7\n")
+        .expect(
+            &["-c", "alice", "list"],
+            r#"{"alice":[{"number":7,"userdata":{"nc_backup":"true"},"cleanup":"","date":"2025-01-02 02:30:00","description":"Full Nextcloud Backup"}]}"#,
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let snapper = Snapper::builder()
+        .cleanup(None)
+        .subvolume("alice".into())
+        .privilege(Privilege::Direct)
+        .runner(runner.clone())
+        .build()
+        .unwrap();
+    let artifacts = snapper.backup(&nextcloud, false, &NoProgress).unwrap();
+    assert_eq!(
+        artifacts,
+        [
+            Artifact::Snapshot {
+                config: "nextcloud".into(),
+                id: 42
+            },
+            Artifact::Snapshot {
+                config: "alice".into(),
+                id: 7
+            },
+        ]
+    );
+    assert!(runner.finished());
+}
+
+#[test]
+fn writable_snapshot_is_not_sent() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-writable");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["-c", "nextcloud", "get-config"],
+            r#"{"SUBVOLUME":"/srv/nextcloud"}"#,
+        )
+        .expect(&["-c", "nextcloud", "list"], SNAPSHOTS)
+        .expect(
+            &[
+                "property",
+                "get",
+                "-ts",
+                "/srv/nextcloud/.snapshots/42/snapshot",
+                "ro",
+            ],
+            "ro=false\n",
+        )
+        .expect_exit(
+            &["property", "set", "ro", "true"],
+            1,
+            "",
+            "ERROR: Operation not permitted",
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let snapper = Snapper::builder()
+        .send(SendConfig::default())
+        .streams_root(&installation.backup_root())
+        .privilege(Privilege::Direct)
+        .runner(runner.clone())
+        .build()
+        .unwrap();
+    let artifacts = vec![Artifact::Snapshot {
+        config: "nextcloud".into(),
+        id: 42,
+    }];
+    let err = snapper
+        .finalize(&nextcloud, artifacts, false, &NoProgress)
+        .unwrap_err();
+    assert!(matches!(err, SnapperBackupError::SnapshotNotReadOnly(_)));
+    assert!(runner.finished());
+}
+
+#[test]
+fn changes_since_previous_snapshot_are_summarized() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-diff");
+    let subvolume = installation.root.join("data");
+    let snapshot = subvolume.join(".snapshots/42/snapshot");
+    std::fs::create_dir_all(snapshot.join("alice/files")).unwrap();
+    std::fs::write(snapshot.join("alice/files/vm.img"), vec![0; 4096]).unwrap();
+    std::fs::write(snapshot.join("alice/files/notes.md"), "notes").unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["-c", "nextcloud", "get-config"],
+            &serde_json::json!({ "SUBVOLUME": subvolume }).to_string(),
+        )
+        .expect(&["-c", "nextcloud", "list"], SNAPSHOTS)
+        .expect(
+            &["-c", "nextcloud", "status", "41..42"],
+            &format!(
+                "c..... {0}/alice/files/vm.img\n\
+                 +..... {0}/alice/files/notes.md\n\
+                 -..... {0}/alice/files/old.md\n",
+                subvolume.display()
+            ),
+        );
+
+    let snapper = Snapper::builder()
+        .diff(true)
+        .privilege(Privilege::Direct)
+        .runner(runner.clone())
+        .build()
+        .unwrap();
+    let changes = snapper
+        .changes(&[Artifact::Snapshot {
+            config: "nextcloud".into(),
+            id: 42,
+        }])
+        .unwrap();
+    assert_eq!(changes.len(), 1);
+    let diff = &changes[0];
+    assert_eq!(
+        (
+            diff.from,
+            diff.to,
+            diff.created,
+            diff.modified,
+            diff.deleted
+        ),
+        (41, 42, 1, 1, 1)
+    );
+    assert_eq!(diff.changed_bytes, 4096 + 5);
+    assert_eq!(diff.largest[0].path, subvolume.join("alice/files/vm.img"));
+    assert!(runner.finished());
+}
+
+#[test]
+fn deleted_directory_is_restored_from_snapshot() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-restore-file");
+    let data_dir = installation.root.join("data");
+    let snapshot = data_dir.join(".snapshots/42/snapshot");
+    for file in ["alice/files/Photos/2024/beach.jpg", "alice/files/notes.md"] {
+        let path = snapshot.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, file).unwrap();
+    }
+    std::fs::create_dir_all(data_dir.join("alice/files")).unwrap();
+    std::fs::write(data_dir.join("alice/files/notes.md"), "edited").unwrap();
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(
+            &["-c", "nextcloud", "get-config"],
+            &serde_json::json!({ "SUBVOLUME": data_dir }).to_string(),
+        )
+        .expect(&["-c", "nextcloud", "list"], SNAPSHOTS)
+        .expect(
+            &["maintenance:mode"],
+            "Maintenance mode is currently disabled",
+        )
+        .expect(&["files:scan", "--path=/alice/files/Photos"], "");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let snapper = Snapper::builder()
+        .config_id("nextcloud".into())
+        .privilege(Privilege::Direct)
+        .runner(runner.clone())
+        .build()
+        .unwrap();
+    let restored = snapper
+        .restore_path(
+            &nextcloud,
+            42,
+            Path::new("alice/files/Photos"),
+            None,
+            false,
+            &NoProgress,
+        )
+        .unwrap();
+    assert_eq!(restored, data_dir.join("alice/files/Photos"));
+    assert_eq!(
+        std::fs::read_to_string(restored.join("2024/beach.jpg")).unwrap(),
+        "alice/files/Photos/2024/beach.jpg"
+    );
+    // files outside the restored path are left alone
+    assert_eq!(
+        std::fs::read_to_string(data_dir.join("alice/files/notes.md")).unwrap(),
+        "edited"
+    );
+    assert!(runner.finished());
+
+    assert!(matches!(
+        snapper.restore_path(
+            &nextcloud,
+            42,
+            Path::new("../etc"),
+            None,
+            false,
+            &NoProgress
+        ),
+        Err(SnapperBackupError::InvalidRestorePath(_))
+    ));
+}
+
+/// Retention of the streams keeping only the latest one.
+const LATEST_STREAM: RetentionConfig = RetentionConfig {
+    daily: Some(0),
+    weekly: Some(0),
+    monthly: Some(0),
+    quarterly: Some(0),
+    yearly: Some(0),
+};
+
+/// Applies the retention to the full streams of the snapshots 40 to 42 in the
+/// backup root of `installation` given the snapshots listed by `snapshots`.
+///
+/// Returns the remaining streams.
+fn retain_streams(installation: &Installation, snapshots: &str, pin: Option<u64>) -> Vec<u64> {
+    let data_dir = installation.root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let streams = installation.backup_root().join("snapper");
+    std::fs::create_dir_all(&streams).unwrap();
+    for id in 40..=42 {
+        std::fs::write(streams.join(format!("snapshot-{id}.btrfs.zst")), "stream").unwrap();
+    }
+    if let Some(id) = pin {
+        let stream = streams.join(format!("snapshot-{id}.btrfs.zst"));
+        std::fs::write(pin_path(&stream), "").unwrap();
+    }
+    let runner = Arc::new(ScriptedRunner::new());
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(
+            &["-c", "nextcloud", "get-config"],
+            r#"{"SUBVOLUME":"/srv/nextcloud"}"#,
+        )
+        .expect(&["-c", "nextcloud", "list"], snapshots);
+    let nextcloud = installation.nextcloud(&runner);
+
+    let snapper = Snapper::builder()
+        .config_id("nextcloud".into())
+        .send(SendConfig {
+            retention: Some(LATEST_STREAM),
+            ..Default::default()
+        })
+        .streams_root(&installation.backup_root())
+        .privilege(Privilege::Direct)
+        .runner(runner.clone())
+        .build()
+        .unwrap();
+    snapper
+        .retention(&nextcloud, &RetentionConfig::default(), false)
+        .unwrap();
+    assert!(runner.finished());
+
+    stream_files(&streams)
+        .unwrap()
+        .into_iter()
+        .map(|stream| stream.id)
+        .collect()
+}
+
+#[test]
+fn pinned_stream_is_kept() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-pinned-stream");
+
+    let streams = retain_streams(&installation, SNAPSHOTS, Some(40));
+    assert_eq!(streams, [40, 42]);
+}
+
+#[test]
+fn streams_of_pinned_snapshot_are_kept() {
+    if !jsonout() {
+        return;
+    }
+    let installation = Installation::new("snapper-pinned-snapshot-stream");
+    let snapshots = SNAPSHOTS.replace(
+        r#"{"number":41,"userdata":{"nc_backup":"true"}"#,
+        r#"{"number":41,"userdata":{"nc_backup":"true","pinned":"true"}"#,
+    );
+    assert_ne!(snapshots, SNAPSHOTS);
+
+    let streams = retain_streams(&installation, &snapshots, None);
+    assert_eq!(streams, [41, 42]);
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use common::Installation;
+use nc_backup_lib::backends::tar_data::{extract, extract_data_dir, read_index, restore_chain};
+use nc_backup_lib::backends::{Artifact, Backup, TarData};
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+/// Clock fixed at 02:00 on `day` of January 2025.
+fn clock(day: u32) -> Clock {
+    Clock::Fixed(
+        NaiveDate::from_ymd_opt(2025, 1, day)
+            .unwrap()
+            .and_hms_opt(2, 0, 0)
+            .unwrap(),
+    )
+}
+
+/// Names of the regular files in the zstd compressed tarball `path`.
+fn tarball_files(path: &Path) -> Vec<String> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path).unwrap()).unwrap());
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|entry| {
+            let path = entry.path().unwrap();
+            path.file_name().unwrap().to_string_lossy().into_owned()
+        })
+        .collect()
+}
+
+/// Backup the data directory at `day` returning the tarball.
+fn backup(installation: &Installation, data_dir: &Path, day: u32) -> std::path::PathBuf {
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["config:system:get", "datadirectory"],
+        &serde_json::to_string(data_dir).unwrap(),
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let tar_data = TarData::new(&installation.backup_root()).clock(clock(day));
+    let artifacts = tar_data.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(tarball), Artifact::File(index)] = artifacts.as_slice() else {
+        panic!("backup should be the tarball and its index: {artifacts:?}");
+    };
+    assert!(index.is_file());
+    tarball.clone()
+}
+
+#[test]
+fn only_changed_files_are_archived_incrementally() {
+    let installation = Installation::new("tar-data-incremental");
+    let data_dir = installation.root.join("data");
+    for file in [
+        "alice/files/notes.md",
+        "alice/files/old.md",
+        "bob/files/photo.jpg",
+    ] {
+        let path = data_dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    let full = backup(&installation, &data_dir, 1);
+    assert!(full
+        .to_string_lossy()
+        .ends_with("data-2025-01-01T02-00-00.tar.zst"));
+    assert_eq!(tarball_files(&full).len(), 3);
+
+    fs::write(data_dir.join("alice/files/notes.md"), "changed notes").unwrap();
+    fs::remove_file(data_dir.join("alice/files/old.md")).unwrap();
+    fs::write(data_dir.join("bob/files/new.jpg"), "new").unwrap();
+    let incr = backup(&installation, &data_dir, 2);
+    assert!(incr
+        .to_string_lossy()
+        .ends_with("data-2025-01-02T02-00-00.incr.tar.zst"));
+    assert_eq!(tarball_files(&incr), ["notes.md", "new.jpg"]);
+    assert_eq!(
+        read_index(&incr).unwrap().deleted,
+        [data_dir.join("alice/files/old.md")]
+    );
+    assert_eq!(restore_chain(&incr).unwrap(), [full, incr.clone()]);
+
+    let restored = installation.root.join("restored");
+    extract(&incr, &restored).unwrap();
+    let restored_data = restored.join(data_dir.strip_prefix("/").unwrap());
+    assert_eq!(
+        fs::read_to_string(restored_data.join("alice/files/notes.md")).unwrap(),
+        "changed notes"
+    );
+    assert!(restored_data.join("bob/files/new.jpg").is_file());
+    assert!(!restored_data.join("alice/files/old.md").exists());
+
+    // restored to another data directory
+    let moved = installation.root.join("moved");
+    extract_data_dir(&incr, &moved).unwrap();
+    assert_eq!(
+        fs::read_to_string(moved.join("alice/files/notes.md")).unwrap(),
+        "changed notes"
+    );
+    assert!(moved.join("bob/files/new.jpg").is_file());
+    assert!(!moved.join("alice/files/old.md").exists());
+}
+
+#[test]
+fn retention_keeps_bases_of_retained_backups() {
+    let installation = Installation::new("tar-data-retention");
+    let data_dir = installation.root.join("data");
+    fs::create_dir_all(&data_dir).unwrap();
+    let mut tarballs = Vec::new();
+    for day in 1..=3 {
+        fs::write(data_dir.join(format!("file-{day}")), "content").unwrap();
+        tarballs.push(backup(&installation, &data_dir, day));
+    }
+
+    let runner = Arc::new(ScriptedRunner::new());
+    let nextcloud = installation.nextcloud(&runner);
+    let tar_data = TarData::new(&installation.backup_root()).clock(clock(3));
+    let cfg = RetentionConfig {
+        daily: Some(1),
+        weekly: Some(0),
+        monthly: Some(0),
+        quarterly: Some(0),
+        yearly: Some(0),
+    };
+    let entries = tar_data.list(&nextcloud, &cfg).unwrap();
+    let retained_by: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.retained_by.clone())
+        .collect();
+    assert_eq!(retained_by, [["daily"], ["base"], ["base"]]);
+
+    tar_data.retention(&nextcloud, &cfg, false).unwrap();
+    assert!(tarballs.iter().all(|tarball| tarball.is_file()));
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use chrono::TimeDelta;
+use common::{day, Installation};
+use nc_backup_lib::util::artifact::{pin_path, ArtifactDir};
+use nc_backup_lib::util::checksum::checksum_path;
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::tiering::TieringConfig;
+
+/// Dump directory of `backup_root` moving dumps older than a day to `cold_dir`.
+fn dumps(backup_root: &Path, cold_dir: &Path) -> ArtifactDir {
+    ArtifactDir::new(backup_root.join("db"), "database-", ".sql.gz")
+        .with_clock(Clock::Fixed(day(10)))
+        .with_cold_tier(cold_dir.to_path_buf(), TimeDelta::days(1))
+}
+
+#[test]
+fn artifact_is_moved_with_its_sidecars() {
+    let installation = Installation::new("tiering-move");
+    let backup_root = installation.backup_root();
+    let cold_dir = installation.root.join("cold/db");
+    let dump = backup_root.join("db/database-2025-01-01T02-30-00.sql.gz");
+    let recent = backup_root.join("db/database-2025-01-10T02-30-00.sql.gz");
+    fs::create_dir_all(dump.parent().unwrap()).unwrap();
+    for file in [&dump, &checksum_path(&dump), &pin_path(&dump), &recent] {
+        fs::write(file, "dump").unwrap();
+    }
+
+    dumps(&backup_root, &cold_dir).tier(false).unwrap();
+
+    let cold_dump = cold_dir.join(dump.file_name().unwrap());
+    assert!(cold_dump.is_file());
+    assert!(checksum_path(&cold_dump).is_file());
+    assert!(pin_path(&cold_dump).is_file());
+    assert_eq!(fs::read_dir(dump.parent().unwrap()).unwrap().count(), 1);
+    assert!(recent.is_file());
+}
+
+#[test]
+fn partially_moved_artifact_is_completed() {
+    let installation = Installation::new("tiering-partial");
+    let backup_root = installation.backup_root();
+    let cold_dir = installation.root.join("cold/db");
+    let dump = backup_root.join("db/database-2025-01-01T02-30-00.sql.gz");
+    let cold_dump = cold_dir.join(dump.file_name().unwrap());
+    fs::create_dir_all(dump.parent().unwrap()).unwrap();
+    fs::create_dir_all(&cold_dir).unwrap();
+    // duplicated to the cold tier, but removing the dump and moving its sidecar failed
+    for file in [&dump, &cold_dump, &checksum_path(&dump)] {
+        fs::write(file, "dump").unwrap();
+    }
+
+    dumps(&backup_root, &cold_dir).tier(false).unwrap();
+
+    assert!(!dump.exists());
+    assert!(!checksum_path(&dump).exists());
+    assert!(cold_dump.is_file());
+    assert!(checksum_path(&cold_dump).is_file());
+}
+
+#[test]
+fn tiered_artifact_of_instance_is_located() {
+    let installation = Installation::new("tiering-instance");
+    let backup_root = installation.backup_root();
+    let instance_root = backup_root.join("cloud1");
+    let tiering = TieringConfig {
+        after_days: 1,
+        destination: installation.root.join("cold"),
+    }
+    .nested(&backup_root, &instance_root);
+    let dump = instance_root.join("db/database-2025-01-01T02-30-00.sql.gz");
+    fs::create_dir_all(dump.parent().unwrap()).unwrap();
+    fs::write(&dump, "dump").unwrap();
+
+    dumps(&instance_root, &tiering.destination.join("db"))
+        .tier(false)
+        .unwrap();
+
+    let located = tiering.locate(&instance_root, &dump);
+    assert_eq!(
+        located,
+        installation
+            .root
+            .join("cold/cloud1/db/database-2025-01-01T02-30-00.sql.gz")
+    );
+    assert!(located.is_file());
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use common::Installation;
+use nc_backup_lib::backends::{Artifact, Backup, UserExport, Users};
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::progress::NoProgress;
+
+#[test]
+fn users_and_groups_are_exported() {
+    let installation = Installation::new("users-export");
+    let runner = Arc::new(ScriptedRunner::new());
+    runner.expect(
+        &["user:list", "--info", "--offset=0", "--output=json"],
+        r#"{"alice":{"user_id":"alice","display_name":"Alice","email":"alice@example.org","enabled":true,"groups":["admin"],"quota":"none","storage":{"used":1024}},"bob":{"user_id":"bob","display_name":"Bob","email":null,"enabled":false,"groups":[],"quota":"1 GB"}}"#,
+    );
+    runner.expect(
+        &["group:list", "--offset=0", "--output=json"],
+        r#"{"admin":["alice"],"empty":[]}"#,
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let users = Users::new(&installation.backup_root());
+    let artifacts = users.backup(&nextcloud, false, &NoProgress).unwrap();
+    let [Artifact::File(export)] = artifacts.as_slice() else {
+        panic!("user export should be a single file: {artifacts:?}");
+    };
+    assert!(runner.finished());
+
+    let export: UserExport = serde_json::from_slice(&fs::read(export).unwrap()).unwrap();
+    assert_eq!(export.users.len(), 2);
+    let alice = &export.users["alice"];
+    assert_eq!(alice.display_name, "Alice");
+    assert_eq!(alice.groups, ["admin"]);
+    assert_eq!(alice.other["storage"]["used"], 1024);
+    assert!(!export.users["bob"].enabled);
+    assert_eq!(export.users["bob"].email, None);
+    assert_eq!(export.groups["admin"], ["alice"]);
+    assert!(export.groups["empty"].is_empty());
+}
+
+#[test]
+fn users_are_listed_page_by_page() {
+    let installation = Installation::new("users-paged");
+    let runner = Arc::new(ScriptedRunner::new());
+    let page: String = (0..500)
+        .map(|i| format!("\"user{i:03}\":{{\"display_name\":\"User {i}\"}}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    runner.expect(&["user:list", "--offset=0"], &format!("{{{page}}}"));
+    runner.expect(
+        &["user:list", "--offset=500"],
+        r#"{"zoe":{"display_name":"Zoe"}}"#,
+    );
+    runner.expect(&["group:list", "--offset=0"], "[]");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let users = nextcloud.occ().user_list().unwrap();
+    assert_eq!(users.len(), 501);
+    assert!(nextcloud.occ().group_list().unwrap().is_empty());
+    assert!(runner.finished());
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::AtomicBool;
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use nc_backup_lib::runner::window::{Deferral, WindowConfig};
+
+fn at(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2025, 1, day)
+        .unwrap()
+        .and_hms_opt(hour, min, 0)
+        .unwrap()
+}
+
+fn window(start: u32, end: u32) -> WindowConfig {
+    WindowConfig {
+        start: NaiveTime::from_hms_opt(start, 0, 0),
+        end: NaiveTime::from_hms_opt(end, 0, 0),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn window_opens_at_start() {
+    let window = window(1, 6);
+    assert_eq!(window.opens_after(at(1, 3, 0)), None);
+    assert_eq!(window.opens_after(at(1, 0, 30)), Some(at(1, 1, 0)));
+    assert_eq!(window.opens_after(at(1, 9, 0)), Some(at(2, 1, 0)));
+    assert_eq!(window.opens_after(at(1, 6, 0)), Some(at(2, 1, 0)));
+}
+
+#[test]
+fn window_spans_midnight() {
+    let window = window(22, 5);
+    assert_eq!(window.opens_after(at(1, 23, 0)), None);
+    assert_eq!(window.opens_after(at(2, 4, 59)), None);
+    assert_eq!(window.opens_after(at(2, 9, 0)), Some(at(2, 22, 0)));
+    assert_eq!(WindowConfig::default().opens_after(at(1, 9, 0)), None);
+}
+
+#[test]
+fn backup_outside_window_is_refused() {
+    let window = window(1, 6);
+    let deferral = window.check(at(1, 9, 0)).unwrap_err();
+    assert_eq!(deferral, Deferral::OutsideWindow { opens: at(2, 1, 0) });
+
+    // one-shot runs never wait for the window
+    let always_closed = WindowConfig {
+        start: NaiveTime::from_hms_opt(0, 0, 0),
+        end: NaiveTime::from_hms_opt(0, 0, 0),
+        ..Default::default()
+    };
+    let deferral = always_closed
+        .wait(false, &AtomicBool::new(false))
+        .unwrap_err();
+    assert!(matches!(deferral, Deferral::OutsideWindow { .. }));
+}
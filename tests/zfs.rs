@@ -0,0 +1,144 @@
+mod common;
+
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use common::Installation;
+use nc_backup_lib::backends::{Artifact, Backup, Zfs, ZfsError};
+use nc_backup_lib::util::clock::Clock;
+use nc_backup_lib::util::command::ScriptedRunner;
+use nc_backup_lib::util::generation::BackupGeneration;
+use nc_backup_lib::util::progress::NoProgress;
+use nc_backup_lib::util::retention::RetentionConfig;
+
+/// Script the data directory of `installation` on `runner` returning the
+/// `zfs list` output of datasets mounted at, above and next to it.
+fn expect_datasets(installation: &Installation, runner: &ScriptedRunner) {
+    let data_dir = installation.root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let root = installation.root.display();
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(
+            &["list", "name,mountpoint"],
+            &format!("tank\t/\ntank/nextcloud\t{root}\ntank/other\t{root}-other\nswap\tnone\n"),
+        );
+}
+
+#[test]
+fn dataset_of_data_directory_is_snapshotted() {
+    let installation = Installation::new("zfs-snapshot");
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_datasets(&installation, &runner);
+    runner.expect(
+        &["snapshot", "tank/nextcloud@nc_backup-2025-01-01T02-30-00"],
+        "",
+    );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let now = NaiveDate::from_ymd_opt(2025, 1, 1)
+        .unwrap()
+        .and_hms_opt(2, 30, 0)
+        .unwrap();
+    let zfs = Zfs::new().runner(runner.clone()).clock(Clock::Fixed(now));
+    let artifacts = zfs.backup(&nextcloud, false, &NoProgress).unwrap();
+    assert_eq!(
+        artifacts,
+        [Artifact::ZfsSnapshot(
+            "tank/nextcloud@nc_backup-2025-01-01T02-30-00".to_string()
+        )]
+    );
+    assert!(runner.finished());
+}
+
+#[test]
+fn snapshots_are_named_after_generation() {
+    let installation = Installation::new("zfs-generation");
+    let runner = Arc::new(ScriptedRunner::new());
+    let now = NaiveDate::from_ymd_opt(2025, 1, 2)
+        .unwrap()
+        .and_hms_opt(2, 30, 0)
+        .unwrap();
+    let generation = BackupGeneration::new(Clock::Fixed(now));
+    let snapshot = format!("tank/nextcloud@nc_backup-2025-01-02T02-30-00_{generation}");
+    expect_datasets(&installation, &runner);
+    runner.expect(&["snapshot", &snapshot], "");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let zfs = Zfs::new()
+        .runner(runner.clone())
+        .clock(Clock::Fixed(now))
+        .generation(generation);
+    let artifacts = zfs.backup(&nextcloud, false, &NoProgress).unwrap();
+    assert_eq!(artifacts, [Artifact::ZfsSnapshot(snapshot.clone())]);
+
+    expect_datasets(&installation, &runner);
+    runner.expect(
+        &["list", "snapshot", "name,nc_backup:pinned"],
+        &format!("tank/nextcloud@nc_backup-2025-01-01T02-30-00\t-\n{snapshot}\t-\n"),
+    );
+    let entries = zfs.list(&nextcloud, &RetentionConfig::default()).unwrap();
+    let generations: Vec<_> = entries.iter().map(|entry| entry.generation).collect();
+    assert_eq!(generations, [Some(generation), None]);
+    assert!(runner.finished());
+}
+
+#[test]
+fn data_directory_outside_zfs_is_error() {
+    let installation = Installation::new("zfs-no-dataset");
+    let runner = Arc::new(ScriptedRunner::new());
+    let data_dir = installation.root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    runner
+        .expect(
+            &["config:system:get", "datadirectory"],
+            &serde_json::to_string(&data_dir).unwrap(),
+        )
+        .expect(&["list", "name,mountpoint"], "tank\t/tank\nswap\tnone\n");
+    let nextcloud = installation.nextcloud(&runner);
+
+    let zfs = Zfs::new().runner(runner.clone());
+    assert!(matches!(
+        zfs.preflight(&nextcloud),
+        Err(ZfsError::NoDataset(_))
+    ));
+}
+
+#[test]
+fn unretained_snapshots_are_destroyed() {
+    let installation = Installation::new("zfs-retention");
+    let runner = Arc::new(ScriptedRunner::new());
+    expect_datasets(&installation, &runner);
+    runner
+        .expect(
+            &[
+                "list",
+                "snapshot",
+                "name,nc_backup:pinned",
+                "tank/nextcloud",
+            ],
+            "tank/nextcloud@nc_backup-2025-01-01T02-30-00\ttrue\n\
+             tank/nextcloud@manual\t-\n\
+             tank/nextcloud@nc_backup-2025-01-02T02-30-00\t-\n\
+             tank/nextcloud@nc_backup-2025-01-03T02-30-00\t-\n",
+        )
+        .expect(
+            &["destroy", "tank/nextcloud@nc_backup-2025-01-02T02-30-00"],
+            "",
+        );
+    let nextcloud = installation.nextcloud(&runner);
+
+    let cfg = RetentionConfig {
+        daily: Some(1),
+        weekly: Some(0),
+        monthly: Some(0),
+        quarterly: Some(0),
+        yearly: Some(0),
+    };
+    let zfs = Zfs::new().runner(runner.clone());
+    zfs.retention(&nextcloud, &cfg, false).unwrap();
+    assert!(runner.finished());
+}